@@ -5,13 +5,25 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     let cmd = UseAws {
         service_name: "s3".to_string(),
         operation_name: "list-buckets".to_string(),
-        parameters: Some(std::collections::HashMap::from([
+        parameters: Some(indexmap::IndexMap::from([
             ("max-items".to_string(), serde_json::Value::String("10".to_string())),
             ("query".to_string(), serde_json::Value::String("Buckets[].Name".to_string())),
         ])),
+        positional_args: None,
         region: "us-west-2".to_string(),
         profile_name: Some("development".to_string()),
         label: Some("List S3 buckets with query".to_string()),
+        endpoint: None,
+        endpoint_url: None,
+        max_items: None,
+        cursor: None,
+        query: None,
+        output_format: None,
+        cache: None,
+        single_page: false,
+        redaction: Default::default(),
+        retry: Default::default(),
+        identity: None,
     };
 
     // Generate and display the human-readable description
@@ -0,0 +1,624 @@
+//! Append-only audit log of tool calls. Every call, including the ones the policy refuses, is
+//! written as one JSON object per line, so questions like "what ran against prod last
+//! Tuesday" can be answered with `jq` or `grep`.
+//!
+//! Entries are hash-chained: each one carries a sequence number and the SHA-256 of the line
+//! before it, continuing across rotated files, so [verify] detects edited and deleted lines.
+//! [read_entries] and [AuditFilter] back the `audit query` and `audit replay` subcommands.
+
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use chrono::{DateTime, NaiveDate, Utc};
+use eyre::{Result, WrapErr};
+use indexmap::IndexMap;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::executor::{AwsExecutor, CommandOutput};
+use crate::output_format::OutputFormat;
+use crate::redact;
+use crate::use_aws::{RiskTier, UseAws};
+
+/// Rotated files kept unless configured otherwise
+const DEFAULT_MAX_FILES: usize = 5;
+
+/// The previous hash of the first entry of a log
+pub const GENESIS_HASH: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+
+/// Audit logging, configured under `audit` in the config file
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuditConfig {
+    /// The JSONL file calls are appended to. Nothing is logged without it.
+    pub path: Option<PathBuf>,
+    /// Size in bytes after which the file is rotated. Never rotated without it.
+    pub max_bytes: Option<u64>,
+    /// Rotated files kept as `<path>.1` (the newest) to `<path>.<max_files>`
+    pub max_files: usize,
+}
+
+impl Default for AuditConfig {
+    fn default() -> Self {
+        Self {
+            path: None,
+            max_bytes: None,
+            max_files: DEFAULT_MAX_FILES,
+        }
+    }
+}
+
+/// What the server decided about a call before running it
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Decision {
+    Allowed,
+    /// Refused by the policy or the local file sandbox
+    Denied,
+    /// Rejected as an invalid request, e.g. an unknown parameter
+    Invalid,
+    /// Refused because a rate limit or call budget was reached
+    Limited,
+}
+
+/// One tool call
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AuditEntry {
+    /// Position in the log, counting from 0 across rotated files
+    #[serde(default)]
+    pub seq: u64,
+    pub timestamp: DateTime<Utc>,
+    /// The client name from `initialize`, e.g. `claude-ai`
+    pub client: Option<String>,
+    pub service: String,
+    pub operation: String,
+    /// Parameters with secret values masked
+    pub parameters: Option<IndexMap<String, serde_json::Value>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub positional_args: Option<Vec<String>>,
+    pub region: String,
+    pub profile: Option<String>,
+    pub label: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub endpoint: Option<String>,
+    /// The server-side `query` of the request
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub query: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub output_format: Option<OutputFormat>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_items: Option<usize>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cursor: Option<String>,
+    /// The account the command acted as, if the identity preflight resolved it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub account: Option<String>,
+    pub risk_tier: RiskTier,
+    pub decision: Decision,
+    /// Exit code of the last command run, none if nothing ran
+    pub exit_status: Option<i32>,
+    pub duration_ms: u64,
+    /// Size in bytes of the result or error returned to the client
+    pub output_bytes: usize,
+    /// Why the call was refused or failed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// SHA-256 of the previous line, [GENESIS_HASH] for the first entry
+    #[serde(default)]
+    pub prev_hash: String,
+}
+
+impl AuditEntry {
+    /// An entry for `command` as the client sent it. The outcome is filled in by the caller.
+    pub fn new(command: &UseAws, client: Option<String>, decision: Decision) -> Self {
+        let redacted = redact::redact_command(command);
+        Self {
+            seq: 0,
+            timestamp: Utc::now(),
+            client,
+            service: redacted.service_name,
+            operation: redacted.operation_name,
+            parameters: redacted.parameters,
+            positional_args: redacted.positional_args,
+            region: redacted.region,
+            profile: redacted.profile_name,
+            label: redacted.label,
+            endpoint: redacted.endpoint,
+            query: redacted.query,
+            output_format: redacted.output_format,
+            max_items: redacted.max_items,
+            cursor: redacted.cursor,
+            account: command.identity.as_ref().map(|identity| identity.account.clone()),
+            risk_tier: command.risk_tier(),
+            decision,
+            exit_status: None,
+            duration_ms: 0,
+            output_bytes: 0,
+            error: None,
+            prev_hash: String::new(),
+        }
+    }
+
+    /// The tool call arguments that repeat the recorded call against the current state of
+    /// AWS, bypassing the result cache. Fails for calls that may modify resources and for
+    /// calls whose parameters had secrets masked.
+    pub fn replay_arguments(&self) -> Result<serde_json::Value> {
+        if self.risk_tier != RiskTier::ReadOnly {
+            eyre::bail!("Entry {} is '{} {}', which isn't read-only", self.seq, self.service, self.operation);
+        }
+        let masked = self
+            .parameters
+            .iter()
+            .flat_map(|parameters| parameters.values())
+            .any(|value| value.to_string().contains(redact::REDACTED));
+        if masked {
+            eyre::bail!("Entry {} had secret parameters, which weren't recorded", self.seq);
+        }
+        Ok(serde_json::json!({
+            "service_name": self.service,
+            "operation_name": self.operation,
+            "parameters": self.parameters,
+            "positional_args": self.positional_args,
+            "region": self.region,
+            "profile_name": self.profile,
+            "label": format!("Replay of audit entry {}", self.seq),
+            "endpoint": self.endpoint,
+            "query": self.query,
+            "output_format": self.output_format,
+            "max_items": self.max_items,
+            "cursor": self.cursor,
+            "cache": "bypass",
+        }))
+    }
+
+    /// Records how the call ended
+    pub fn finish(mut self, exit_status: Option<i32>, duration: Duration, output_bytes: usize, error: Option<String>) -> Self {
+        self.exit_status = exit_status;
+        self.duration_ms = duration.as_millis() as u64;
+        self.output_bytes = output_bytes;
+        self.error = error;
+        self
+    }
+}
+
+/// The end of the chain new entries are linked to
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Chain {
+    next_seq: u64,
+    prev_hash: String,
+}
+
+/// A JSONL audit log file, rotated by size
+#[derive(Debug)]
+pub struct AuditLog {
+    path: PathBuf,
+    max_bytes: Option<u64>,
+    max_files: usize,
+    /// Read from the end of the existing log on the first append. The lock also serializes
+    /// appends and rotation.
+    chain: Mutex<Option<Chain>>,
+}
+
+impl AuditLog {
+    /// The log configured by `config`, if a path is configured
+    pub fn from_config(config: &AuditConfig) -> Option<Self> {
+        let path = config.path.clone()?;
+        Some(Self {
+            path,
+            max_bytes: config.max_bytes,
+            max_files: config.max_files,
+            chain: Mutex::new(None),
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Appends `entry` as one line linked to the previous one, rotating the file first if the
+    /// line would take it past `max_bytes`
+    pub fn append(&self, entry: &AuditEntry) -> Result<()> {
+        let mut guard = self.chain.lock().unwrap_or_else(|e| e.into_inner());
+        let chain = match guard.as_ref() {
+            Some(chain) => chain.clone(),
+            None => guard.insert(self.load_chain()?).clone(),
+        };
+        let entry = AuditEntry {
+            seq: chain.next_seq,
+            prev_hash: chain.prev_hash,
+            ..entry.clone()
+        };
+        let mut line = serde_json::to_string(&entry)?;
+        if let Some(max_bytes) = self.max_bytes {
+            let size = std::fs::metadata(&self.path).map(|metadata| metadata.len()).unwrap_or(0);
+            if size > 0 && size + line.len() as u64 > max_bytes {
+                self.rotate()?;
+            }
+        }
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .wrap_err_with(|| format!("Unable to open audit log '{}'", self.path.display()))?;
+        let hash = line_hash(&line);
+        line.push('\n');
+        file.write_all(line.as_bytes())
+            .wrap_err_with(|| format!("Unable to write audit log '{}'", self.path.display()))?;
+        *guard = Some(Chain {
+            next_seq: entry.seq + 1,
+            prev_hash: hash,
+        });
+        Ok(())
+    }
+
+    /// Finds the last entry of the log, or of the newest rotated file if the log is empty
+    fn load_chain(&self) -> Result<Chain> {
+        for path in [self.path.clone(), self.rotated(1)] {
+            let contents = match std::fs::read_to_string(&path) {
+                Ok(contents) => contents,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e).wrap_err_with(|| format!("Unable to read audit log '{}'", path.display())),
+            };
+            if let Some(line) = contents.lines().rev().find(|line| !line.trim().is_empty()) {
+                let entry: AuditEntry = serde_json::from_str(line)
+                    .wrap_err_with(|| format!("The last line of audit log '{}' is not an entry", path.display()))?;
+                return Ok(Chain {
+                    next_seq: entry.seq + 1,
+                    prev_hash: line_hash(line),
+                });
+            }
+        }
+        Ok(Chain {
+            next_seq: 0,
+            prev_hash: GENESIS_HASH.to_string(),
+        })
+    }
+
+    fn rotated(&self, n: usize) -> PathBuf {
+        let mut path = self.path.as_os_str().to_owned();
+        path.push(format!(".{}", n));
+        PathBuf::from(path)
+    }
+
+    /// Shifts `<path>.N` to `<path>.N+1`, dropping the oldest, and moves the log to `<path>.1`
+    fn rotate(&self) -> Result<()> {
+        let rotated = |n: usize| self.rotated(n);
+        if self.max_files == 0 {
+            return std::fs::remove_file(&self.path)
+                .wrap_err_with(|| format!("Unable to rotate audit log '{}'", self.path.display()));
+        }
+        let _ = std::fs::remove_file(rotated(self.max_files));
+        for n in (1..self.max_files).rev() {
+            if rotated(n).exists() {
+                std::fs::rename(rotated(n), rotated(n + 1))
+                    .wrap_err_with(|| format!("Unable to rotate audit log '{}'", self.path.display()))?;
+            }
+        }
+        std::fs::rename(&self.path, rotated(1))
+            .wrap_err_with(|| format!("Unable to rotate audit log '{}'", self.path.display()))
+    }
+}
+
+/// The hash linking the entry after `line` to it
+fn line_hash(line: &str) -> String {
+    hex::encode(Sha256::digest(line.as_bytes()))
+}
+
+/// The outcome of [verify]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Verification {
+    pub entries: usize,
+    /// Sequence numbers of the first and last entry
+    pub first_seq: Option<u64>,
+    pub last_seq: Option<u64>,
+    /// Hash of the last line, to compare with a copy kept elsewhere since deleting entries
+    /// from the end of the log can't be detected from the log alone
+    pub last_hash: Option<String>,
+    /// Broken links, as `<file>:<line>: <problem>`
+    pub problems: Vec<String>,
+}
+
+/// Checks the hash chain of audit log files, given oldest first, e.g. `audit.jsonl.2
+/// audit.jsonl.1 audit.jsonl`. A chain starting after sequence 0 is fine, it continues a
+/// rotated file that wasn't given.
+pub fn verify(paths: &[PathBuf]) -> Result<Verification> {
+    let mut verification = Verification::default();
+    let mut previous: Option<(u64, String)> = None;
+    for path in paths {
+        let contents = std::fs::read_to_string(path)
+            .wrap_err_with(|| format!("Unable to read audit log '{}'", path.display()))?;
+        for (index, line) in contents.lines().enumerate() {
+            if line.trim().is_empty() {
+                continue;
+            }
+            let mut problem = |message: String| {
+                verification.problems.push(format!("{}:{}: {}", path.display(), index + 1, message))
+            };
+            let entry: AuditEntry = match serde_json::from_str(line) {
+                Ok(entry) => entry,
+                Err(e) => {
+                    problem(format!("not an audit entry: {}", e));
+                    previous = None;
+                    continue;
+                },
+            };
+            match &previous {
+                Some((seq, _)) if entry.seq != seq + 1 => {
+                    problem(format!("entry {} follows entry {}, so entries were deleted or reordered", entry.seq, seq))
+                },
+                Some((_, hash)) if entry.prev_hash != *hash => {
+                    problem(format!("the previous entry {} was edited", entry.seq.saturating_sub(1)))
+                },
+                None if entry.seq == 0 && entry.prev_hash != GENESIS_HASH => {
+                    problem("the first entry doesn't start the chain".to_string())
+                },
+                _ => {},
+            }
+            verification.entries += 1;
+            verification.first_seq.get_or_insert(entry.seq);
+            verification.last_seq = Some(entry.seq);
+            let hash = line_hash(line);
+            verification.last_hash = Some(hash.clone());
+            previous = Some((entry.seq, hash));
+        }
+    }
+    Ok(verification)
+}
+
+/// Reads the entries of audit log files in order. Lines that aren't entries are skipped,
+/// [verify] reports them.
+pub fn read_entries(paths: &[PathBuf]) -> Result<Vec<AuditEntry>> {
+    let mut entries = Vec::new();
+    for path in paths {
+        let contents = std::fs::read_to_string(path)
+            .wrap_err_with(|| format!("Unable to read audit log '{}'", path.display()))?;
+        entries.extend(contents.lines().filter_map(|line| serde_json::from_str(line).ok()));
+    }
+    Ok(entries)
+}
+
+/// Selects entries by time range, profile, service, risk tier and exit status. Unset fields
+/// match everything.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct AuditFilter {
+    /// Entries at or after this time
+    pub since: Option<DateTime<Utc>>,
+    /// Entries before this time
+    pub until: Option<DateTime<Utc>>,
+    pub profile: Option<String>,
+    pub account: Option<String>,
+    pub service: Option<String>,
+    pub risk_tier: Option<RiskTier>,
+    pub exit_status: Option<i32>,
+}
+
+impl AuditFilter {
+    pub fn matches(&self, entry: &AuditEntry) -> bool {
+        self.since.is_none_or(|since| entry.timestamp >= since)
+            && self.until.is_none_or(|until| entry.timestamp < until)
+            && self.profile.as_ref().is_none_or(|profile| entry.profile.as_ref() == Some(profile))
+            && self.account.as_ref().is_none_or(|account| entry.account.as_ref() == Some(account))
+            && self.service.as_ref().is_none_or(|service| entry.service == *service)
+            && self.risk_tier.is_none_or(|risk_tier| entry.risk_tier == risk_tier)
+            && self.exit_status.is_none_or(|exit_status| entry.exit_status == Some(exit_status))
+    }
+}
+
+/// Parses an RFC 3339 time like `2024-05-14T09:00:00Z`, or a date like `2024-05-14` meaning
+/// its start in UTC
+pub fn parse_time(time: &str) -> Result<DateTime<Utc>> {
+    if let Ok(time) = DateTime::parse_from_rfc3339(time) {
+        return Ok(time.with_timezone(&Utc));
+    }
+    NaiveDate::parse_from_str(time, "%Y-%m-%d")
+        .ok()
+        .and_then(|date| date.and_hms_opt(0, 0, 0))
+        .map(|time| time.and_utc())
+        .ok_or_else(|| eyre::eyre!("Invalid time '{}', expected a date like 2024-05-14 or an RFC 3339 time", time))
+}
+
+/// Runs commands through another executor and remembers the exit code of the last one, so
+/// the audit log can record it even when the call fails
+#[derive(Debug)]
+pub struct Observed<'a> {
+    executor: &'a dyn AwsExecutor,
+    exit_status: Mutex<Option<i32>>,
+}
+
+impl<'a> Observed<'a> {
+    pub fn new(executor: &'a dyn AwsExecutor) -> Self {
+        Self {
+            executor,
+            exit_status: Mutex::new(None),
+        }
+    }
+
+    pub fn exit_status(&self) -> Option<i32> {
+        *self.exit_status.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+#[async_trait]
+impl AwsExecutor for Observed<'_> {
+    async fn execute(&self, command: &UseAws) -> Result<CommandOutput> {
+        let output = self.executor.execute(command).await?;
+        *self.exit_status.lock().unwrap_or_else(|e| e.into_inner()) = Some(output.exit_code);
+        Ok(output)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(operation_name: &str) -> AuditEntry {
+        let command: UseAws = serde_json::from_value(serde_json::json!({
+            "service_name": "secretsmanager",
+            "operation_name": operation_name,
+            "parameters": {"secret-id": "prod/db", "secret-string": "hunter2"},
+            "region": "us-east-1",
+            "profile_name": "prod"
+        }))
+        .unwrap();
+        AuditEntry::new(&command, Some("test-client".to_string()), Decision::Allowed)
+    }
+
+    #[test]
+    fn test_append_and_rotate() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("audit.jsonl");
+        let log = AuditLog::from_config(&AuditConfig {
+            path: Some(path.clone()),
+            max_bytes: Some(1000),
+            max_files: 2,
+        })
+        .unwrap();
+
+        let written = entry("put-secret-value").finish(Some(0), Duration::from_millis(42), 120, None);
+        log.append(&written).unwrap();
+        let contents = std::fs::read_to_string(&path).unwrap();
+        assert!(!contents.contains("hunter2"));
+        let read: AuditEntry = serde_json::from_str(contents.trim_end()).unwrap();
+        assert_eq!(read, AuditEntry {
+            prev_hash: GENESIS_HASH.to_string(),
+            ..written
+        });
+        assert_eq!(read.risk_tier, RiskTier::Write);
+        assert_eq!(read.parameters.unwrap()["secret-string"], redact::REDACTED);
+
+        // Each entry is a few hundred bytes, so every few appends rotate the log
+        for _ in 0..12 {
+            log.append(&entry("describe-secret")).unwrap();
+        }
+        let rotated = |n: usize| dir.path().join(format!("audit.jsonl.{}", n));
+        assert!(rotated(1).exists() && rotated(2).exists() && !rotated(3).exists());
+        for path in [path, rotated(1), rotated(2)] {
+            assert!(std::fs::metadata(&path).unwrap().len() <= 1000);
+        }
+    }
+
+    #[test]
+    fn test_disabled_without_path() {
+        assert!(AuditLog::from_config(&AuditConfig::default()).is_none());
+    }
+
+    #[test]
+    fn test_verify() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("audit.jsonl");
+        let config = AuditConfig {
+            path: Some(path.clone()),
+            max_bytes: Some(1000),
+            max_files: 5,
+        };
+        let log = AuditLog::from_config(&config).unwrap();
+        for _ in 0..4 {
+            log.append(&entry("describe-secret")).unwrap();
+        }
+        // A restarted server continues the chain
+        let log = AuditLog::from_config(&config).unwrap();
+        for _ in 0..4 {
+            log.append(&entry("describe-secret")).unwrap();
+        }
+        let mut files: Vec<PathBuf> = (1..=5).rev().map(|n| dir.path().join(format!("audit.jsonl.{}", n))).filter(|path| path.exists()).collect();
+        files.push(path.clone());
+        assert!(files.len() > 2);
+
+        let verification = verify(&files).unwrap();
+        assert_eq!(verification.problems, Vec::<String>::new());
+        assert_eq!((verification.entries, verification.first_seq, verification.last_seq), (8, Some(0), Some(7)));
+        // The newest files alone are a valid chain too
+        assert!(verify(&files[1..]).unwrap().problems.is_empty());
+
+        let path = dir.path().join("unrotated.jsonl");
+        let log = AuditLog::from_config(&AuditConfig {
+            path: Some(path.clone()),
+            ..AuditConfig::default()
+        })
+        .unwrap();
+        for _ in 0..3 {
+            log.append(&entry("describe-secret")).unwrap();
+        }
+        let contents = std::fs::read_to_string(&path).unwrap();
+        let lines: Vec<&str> = contents.lines().collect();
+        let files = [path.clone()];
+
+        std::fs::write(&path, [lines[0].replace("us-east-1", "us-west-2").as_str(), lines[1], lines[2]].join("\n")).unwrap();
+        let problems = verify(&files).unwrap().problems;
+        assert_eq!(problems, [format!("{}:2: the previous entry 0 was edited", path.display())]);
+
+        std::fs::write(&path, [lines[0], lines[2]].join("\n")).unwrap();
+        let problems = verify(&files).unwrap().problems;
+        assert_eq!(problems, [format!("{}:2: entry 2 follows entry 0, so entries were deleted or reordered", path.display())]);
+
+        std::fs::write(&path, [lines[1], lines[2]].join("\n")).unwrap();
+        assert!(verify(&files).unwrap().problems.is_empty(), "a chain may continue a rotated file");
+    }
+
+    #[test]
+    fn test_filter_and_replay() {
+        let mut read = entry("get-secret-value");
+        read.risk_tier = RiskTier::ReadOnly;
+        read.timestamp = parse_time("2024-05-14T09:30:00+02:00").unwrap();
+        read.exit_status = Some(0);
+        let mut write = entry("put-secret-value").finish(Some(254), Duration::ZERO, 0, None);
+        write.timestamp = parse_time("2024-05-15").unwrap();
+
+        let tuesday = AuditFilter {
+            since: Some(parse_time("2024-05-14").unwrap()),
+            until: Some(parse_time("2024-05-15").unwrap()),
+            profile: Some("prod".to_string()),
+            ..AuditFilter::default()
+        };
+        assert!(tuesday.matches(&read));
+        assert!(!tuesday.matches(&write));
+        let failed_writes = AuditFilter {
+            service: Some("secretsmanager".to_string()),
+            risk_tier: Some(RiskTier::Write),
+            exit_status: Some(254),
+            ..AuditFilter::default()
+        };
+        assert!(!failed_writes.matches(&read));
+        assert!(failed_writes.matches(&write));
+        assert!(parse_time("last tuesday").is_err());
+
+        // The secret-string parameter was masked when the entry was made
+        assert!(read.replay_arguments().unwrap_err().to_string().contains("secret parameters"));
+        assert!(write.replay_arguments().unwrap_err().to_string().contains("isn't read-only"));
+        read.parameters = Some(IndexMap::from([("secret-id".to_string(), "prod/db".into())]));
+        let arguments = read.replay_arguments().unwrap();
+        let command: UseAws = serde_json::from_value(arguments).unwrap();
+        assert_eq!(command.operation_name, "get-secret-value");
+        assert_eq!(command.profile_name.as_deref(), Some("prod"));
+        assert_eq!(command.label.as_deref(), Some("Replay of audit entry 0"));
+        assert_eq!(command.cache, Some(crate::cache::CacheMode::Bypass));
+    }
+
+    #[test]
+    fn test_replay_shapes_output() {
+        let command: UseAws = serde_json::from_value(serde_json::json!({
+            "service_name": "ec2",
+            "operation_name": "describe-instances",
+            "region": "us-east-1",
+            "query": "Reservations[].Instances[].InstanceId",
+            "output_format": "table",
+            "max_items": 10,
+            "cursor": "eyJwYWdlIjoyfQ",
+            "cache": "use"
+        }))
+        .unwrap();
+        let line = serde_json::to_string(&AuditEntry::new(&command, None, Decision::Allowed)).unwrap();
+        let entry: AuditEntry = serde_json::from_str(&line).unwrap();
+
+        let replayed: UseAws = serde_json::from_value(entry.replay_arguments().unwrap()).unwrap();
+        assert_eq!(replayed.query, command.query);
+        assert_eq!(replayed.output_format, Some(OutputFormat::Table));
+        assert_eq!(replayed.max_items, Some(10));
+        assert_eq!(replayed.cursor, command.cursor);
+        assert_eq!(replayed.cache, Some(crate::cache::CacheMode::Bypass));
+    }
+}
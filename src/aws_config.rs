@@ -0,0 +1,164 @@
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+
+/// Environment variable overriding the location of the shared config file.
+const CONFIG_FILE_ENV_VAR: &str = "AWS_CONFIG_FILE";
+/// Environment variable overriding the location of the shared credentials file.
+const CREDENTIALS_FILE_ENV_VAR: &str = "AWS_SHARED_CREDENTIALS_FILE";
+
+/// A named profile merged from the shared config and credentials files
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Profile {
+    pub name: String,
+    pub properties: BTreeMap<String, String>,
+}
+
+impl Profile {
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.properties.get(key).map(|s| s.as_str())
+    }
+
+    /// The account the profile signs in to, as far as the configuration says: its
+    /// `aws_account_id`, its `sso_account_id`, or the account of its `role_arn`
+    pub fn account_id(&self) -> Option<&str> {
+        self.get("aws_account_id")
+            .or_else(|| self.get("sso_account_id"))
+            .or_else(|| self.get("role_arn").and_then(|arn| arn.split(':').nth(4)))
+            .filter(|account| !account.is_empty())
+    }
+}
+
+/// Profiles defined in the local AWS configuration, as read by the AWS CLI
+#[derive(Debug, Clone, Default)]
+pub struct AwsConfig {
+    profiles: BTreeMap<String, Profile>,
+}
+
+impl AwsConfig {
+    /// Loads `~/.aws/config` and `~/.aws/credentials`, honoring `AWS_CONFIG_FILE` and
+    /// `AWS_SHARED_CREDENTIALS_FILE`. Missing or unreadable files are treated as empty.
+    pub fn load() -> Self {
+        let config = config_file_path()
+            .and_then(|path| std::fs::read_to_string(path).ok())
+            .unwrap_or_default();
+        let credentials = credentials_file_path()
+            .and_then(|path| std::fs::read_to_string(path).ok())
+            .unwrap_or_default();
+        Self::parse(&config, &credentials)
+    }
+
+    /// Builds the configuration from the contents of the config and credentials files.
+    pub fn parse(config: &str, credentials: &str) -> Self {
+        let mut profiles = BTreeMap::new();
+        for (section, properties) in parse_ini(config) {
+            // The config file prefixes every profile but the default one with "profile "
+            let name = match section.strip_prefix("profile ") {
+                Some(name) => name.trim().to_string(),
+                None if section == "default" => section,
+                None => continue,
+            };
+            merge_profile(&mut profiles, name, properties);
+        }
+        for (section, properties) in parse_ini(credentials) {
+            merge_profile(&mut profiles, section, properties);
+        }
+        Self { profiles }
+    }
+
+    pub fn profile(&self, name: &str) -> Option<&Profile> {
+        self.profiles.get(name)
+    }
+
+    /// Returns the names of all configured profiles in sorted order
+    pub fn profile_names(&self) -> Vec<String> {
+        self.profiles.keys().cloned().collect()
+    }
+}
+
+/// Returns the path of the shared config file
+pub fn config_file_path() -> Option<PathBuf> {
+    std::env::var_os(CONFIG_FILE_ENV_VAR)
+        .map(PathBuf::from)
+        .or_else(|| home_dir().map(|home| home.join(".aws").join("config")))
+}
+
+/// Returns the path of the shared credentials file
+pub fn credentials_file_path() -> Option<PathBuf> {
+    std::env::var_os(CREDENTIALS_FILE_ENV_VAR)
+        .map(PathBuf::from)
+        .or_else(|| home_dir().map(|home| home.join(".aws").join("credentials")))
+}
+
+fn home_dir() -> Option<PathBuf> {
+    std::env::var_os("HOME")
+        .or_else(|| std::env::var_os("USERPROFILE"))
+        .map(PathBuf::from)
+}
+
+fn merge_profile(profiles: &mut BTreeMap<String, Profile>, name: String, properties: BTreeMap<String, String>) {
+    let profile = profiles.entry(name.clone()).or_insert_with(|| Profile {
+        name,
+        properties: BTreeMap::new(),
+    });
+    profile.properties.extend(properties);
+}
+
+/// Parses the INI dialect used by the AWS shared files. Nested sub-sections (indented
+/// lines following a `key =` line) are skipped since nothing here needs them.
+fn parse_ini(contents: &str) -> Vec<(String, BTreeMap<String, String>)> {
+    let mut sections: Vec<(String, BTreeMap<String, String>)> = Vec::new();
+    for line in contents.lines() {
+        if line.starts_with([' ', '\t']) {
+            continue;
+        }
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') || line.starts_with(';') {
+            continue;
+        }
+        if let Some(section) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+            sections.push((section.trim().to_string(), BTreeMap::new()));
+        } else if let (Some((key, value)), Some((_, properties))) = (line.split_once('='), sections.last_mut()) {
+            properties.insert(key.trim().to_string(), value.trim().to_string());
+        }
+    }
+    sections
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_profiles() {
+        let config = "\
+[default]
+region = us-east-1
+
+[profile development]
+region = us-west-2
+s3 =
+    max_concurrent_requests = 20
+role_arn = arn:aws:iam::123456789012:role/dev
+
+[sso-session corp]
+sso_region = us-east-1
+";
+        let credentials = "\
+[default]
+aws_access_key_id = AKIDEXAMPLE
+
+[ci]
+aws_access_key_id = AKIDCI
+";
+        let config = AwsConfig::parse(config, credentials);
+        assert_eq!(config.profile_names(), vec!["ci", "default", "development"]);
+        let default = config.profile("default").unwrap();
+        assert_eq!(default.get("region"), Some("us-east-1"));
+        assert_eq!(default.get("aws_access_key_id"), Some("AKIDEXAMPLE"));
+        let development = config.profile("development").unwrap();
+        assert_eq!(development.get("region"), Some("us-west-2"));
+        assert_eq!(development.get("max_concurrent_requests"), None);
+        assert_eq!(development.account_id(), Some("123456789012"));
+        assert_eq!(default.account_id(), None);
+    }
+}
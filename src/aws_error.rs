@@ -0,0 +1,416 @@
+//! Structured errors of failed AWS commands. The AWS CLI reports failures as text on stderr;
+//! this parses the error code, message and operation out of it, sorts the error into a
+//! category and suggests how to fix it.
+
+use convert_case::{Case, Casing};
+use serde::Serialize;
+
+use crate::use_aws::UseAws;
+
+/// Exit code of the AWS CLI for invalid commands and parameters
+const EXIT_USAGE_ERROR: i32 = 252;
+
+const AUTH_CODES: [&str; 13] = [
+    "ExpiredToken",
+    "ExpiredTokenException",
+    "RequestExpired",
+    "InvalidClientTokenId",
+    "UnrecognizedClientException",
+    "SignatureDoesNotMatch",
+    "InvalidSignatureException",
+    "IncompleteSignature",
+    "MissingAuthenticationToken",
+    "MissingAuthenticationTokenException",
+    "AuthFailure",
+    "InvalidAccessKeyId",
+    "InvalidToken",
+];
+
+const ACCESS_DENIED_CODES: [&str; 8] = [
+    "AccessDenied",
+    "AccessDeniedException",
+    "UnauthorizedOperation",
+    "UnauthorizedAccess",
+    "UnauthorizedException",
+    "AuthorizationError",
+    "Forbidden",
+    "403",
+];
+
+const THROTTLING_CODES: [&str; 9] = [
+    "Throttling",
+    "ThrottlingException",
+    "ThrottledException",
+    "RequestThrottled",
+    "RequestThrottledException",
+    "RequestLimitExceeded",
+    "TooManyRequestsException",
+    "ProvisionedThroughputExceededException",
+    "SlowDown",
+];
+
+const UNAVAILABLE_CODES: [&str; 10] = [
+    "ServiceUnavailable",
+    "ServiceUnavailableException",
+    "InternalError",
+    "InternalFailure",
+    "InternalServerError",
+    "InternalServerException",
+    "RequestTimeout",
+    "RequestTimeoutException",
+    "500",
+    "503",
+];
+
+const VALIDATION_CODES: [&str; 9] = [
+    "ValidationException",
+    "ValidationError",
+    "MissingParameter",
+    "MissingRequiredParameter",
+    "SerializationException",
+    "BadRequest",
+    "BadRequestException",
+    "IllegalArgumentException",
+    "400",
+];
+
+/// Messages of the AWS CLI when a request never got a response
+const CONNECTION_ERRORS: [&str; 6] = [
+    "Could not connect to the endpoint URL",
+    "Connection was closed before we received a valid response",
+    "Connect timeout on endpoint URL",
+    "Read timeout on endpoint URL",
+    "Connection reset by peer",
+    "SSL validation failed",
+];
+
+/// Messages of the AWS CLI when it has no usable credentials
+const CREDENTIAL_ERRORS: [&str; 5] = [
+    "Unable to locate credentials",
+    "Error when retrieving token from sso",
+    "Error loading SSO Token",
+    "The SSO session associated with this profile has expired",
+    "Partial credentials found",
+];
+
+/// What went wrong, for deciding how to react to an error
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ErrorCategory {
+    /// Credentials are missing, expired or invalid
+    Auth,
+    /// The identity isn't allowed to perform the operation
+    AccessDenied,
+    NotFound,
+    /// The service rejected the parameters
+    Validation,
+    Throttling,
+    /// The service failed or timed out
+    Unavailable,
+    /// The CLI rejected the command before sending it
+    CliUsage,
+    /// The request never got a response
+    Network,
+    Other,
+}
+
+impl ErrorCategory {
+    /// Whether the same command may succeed when run again later
+    pub fn is_transient(self) -> bool {
+        matches!(self, Self::Throttling | Self::Unavailable | Self::Network)
+    }
+}
+
+/// A failed AWS command
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct AwsError {
+    pub category: ErrorCategory,
+    /// The AWS error code, e.g. `AccessDenied`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub code: Option<String>,
+    pub message: String,
+    /// The API operation that failed, e.g. `ListBuckets`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub operation: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub http_status: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
+    /// How to fix the error
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hint: Option<String>,
+    pub exit_code: i32,
+    /// Runs of the command, reported when it was retried
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub attempts: Option<u32>,
+    /// The stderr of the command as returned to the client
+    #[serde(skip)]
+    pub stderr: String,
+}
+
+impl AwsError {
+    /// Parses the stderr of a command that exited with `exit_code`
+    pub fn parse(stderr: &str, exit_code: i32) -> Self {
+        let mut error = Self {
+            category: ErrorCategory::Other,
+            code: None,
+            message: stderr.trim().to_string(),
+            operation: None,
+            http_status: None,
+            request_id: None,
+            hint: None,
+            exit_code,
+            attempts: None,
+            stderr: stderr.to_string(),
+        };
+
+        // An error occurred (Code) when calling the Operation operation (reached max retries: 2): message
+        if let Some((_, rest)) = stderr.split_once("An error occurred (") {
+            if let Some((code, rest)) = rest.split_once(')') {
+                error.code = Some(code.to_string());
+                let mut rest = rest;
+                if let Some((operation, after)) = rest
+                    .trim_start()
+                    .strip_prefix("when calling the ")
+                    .and_then(|rest| rest.split_once(" operation"))
+                {
+                    error.operation = Some(operation.to_string());
+                    rest = after.strip_prefix(" (").and_then(|after| after.split_once(')')).map_or(after, |(_, after)| after);
+                }
+                if let Some(message) = rest.strip_prefix(": ") {
+                    error.message = message.trim().to_string();
+                }
+            }
+        } else if let Some(line) = stderr.lines().find_map(|line| line.strip_prefix("aws: error: ")) {
+            error.message = line.trim().to_string();
+        }
+
+        error.http_status = error
+            .code
+            .as_deref()
+            .and_then(|code| code.parse().ok())
+            .or_else(|| field_after(stderr, &["Status Code: ", "status code: "]).and_then(|status| status.parse().ok()));
+        error.request_id = field_after(stderr, &["Request ID: ", "RequestId: ", "request id: "]);
+        error.category = error.classify();
+        error
+    }
+
+    fn classify(&self) -> ErrorCategory {
+        if let Some(code) = self.code.as_deref() {
+            return if AUTH_CODES.contains(&code) {
+                ErrorCategory::Auth
+            } else if ACCESS_DENIED_CODES.contains(&code) {
+                ErrorCategory::AccessDenied
+            } else if THROTTLING_CODES.contains(&code) {
+                ErrorCategory::Throttling
+            } else if UNAVAILABLE_CODES.contains(&code) {
+                ErrorCategory::Unavailable
+            } else if code == "404" || code.starts_with("NoSuch") || code.ends_with("NotFound") || code.ends_with("NotFoundException") {
+                ErrorCategory::NotFound
+            } else if VALIDATION_CODES.contains(&code) || code.starts_with("Invalid") || code.starts_with("Malformed") {
+                ErrorCategory::Validation
+            } else {
+                ErrorCategory::Other
+            };
+        }
+
+        let stderr = &self.stderr;
+        if CREDENTIAL_ERRORS.iter().any(|message| stderr.contains(message)) || is_missing_profile(stderr) {
+            ErrorCategory::Auth
+        } else if stderr.contains("Parameter validation failed") {
+            ErrorCategory::Validation
+        } else if CONNECTION_ERRORS.iter().any(|message| stderr.contains(message)) {
+            ErrorCategory::Network
+        } else if self.exit_code == EXIT_USAGE_ERROR || stderr.contains("aws: error: ") || stderr.contains("usage: aws") {
+            ErrorCategory::CliUsage
+        } else {
+            ErrorCategory::Other
+        }
+    }
+
+    /// Adds a hint on fixing the error of `command`
+    pub fn with_hint(mut self, command: &UseAws) -> Self {
+        self.hint = self.hint(command);
+        self
+    }
+
+    fn hint(&self, command: &UseAws) -> Option<String> {
+        let (profile, profile_flag) = match &command.profile_name {
+            Some(profile) => (format!("profile '{}'", profile), format!(" --profile {}", profile)),
+            None => ("the default profile".to_string(), String::new()),
+        };
+        let code = self.code.as_deref().unwrap_or_default();
+        let stderr = &self.stderr;
+        let help = format!("`aws {} {} help`", command.service_name, command.operation_name);
+        let hint = match self.category {
+            ErrorCategory::Auth if stderr.contains("SSO") || stderr.contains("sso") => {
+                format!("The SSO session of {} expired. Run `aws sso login{}` and retry", profile, profile_flag)
+            },
+            ErrorCategory::Auth if stderr.contains("Unable to locate credentials") => format!(
+                "No credentials were found for {}. Configure them with `aws configure{}` or `aws sso login{}`, or use another profile_name",
+                profile, profile_flag, profile_flag
+            ),
+            ErrorCategory::Auth if is_missing_profile(stderr) => format!(
+                "{} isn't configured. Use a profile from ~/.aws/config, or add it with `aws configure{}`",
+                capitalize(&profile),
+                profile_flag
+            ),
+            ErrorCategory::Auth if code.contains("Expired") || stderr.contains("expired") => format!(
+                "The credentials of {} expired. Refresh them, e.g. with `aws sso login{}`, and retry",
+                profile, profile_flag
+            ),
+            ErrorCategory::Auth => format!(
+                "The credentials of {} were rejected. Check that the access key is active and the system clock is correct",
+                profile
+            ),
+            ErrorCategory::AccessDenied => {
+                let action = field_after(&self.message, &["not authorized to perform: "]).unwrap_or_else(|| {
+                    let service = if command.service_name == "s3api" { "s3" } else { &command.service_name };
+                    let operation = self
+                        .operation
+                        .clone()
+                        .unwrap_or_else(|| command.operation_name.to_case(Case::Pascal));
+                    format!("{}:{}", service, operation)
+                });
+                format!(
+                    "The identity of {} isn't allowed to perform `{}`. Grant it in an IAM policy, check permission boundaries and SCPs, or use a profile that has access",
+                    profile, action
+                )
+            },
+            ErrorCategory::NotFound => format!(
+                "Check the identifier, and that the resource exists in region {} of the account {} uses",
+                command.region, profile
+            ),
+            ErrorCategory::Validation => format!("Fix the parameters named in the message; {} lists the accepted values", help),
+            ErrorCategory::Throttling => {
+                "AWS throttled the requests. Wait before retrying and make fewer calls, e.g. by narrowing the request or using max_items".to_string()
+            },
+            ErrorCategory::Unavailable => "The service failed to handle the request. Retry later".to_string(),
+            ErrorCategory::CliUsage => format!("Check the operation and parameter names with {}", help),
+            ErrorCategory::Network => match &command.endpoint_url {
+                Some(url) => format!("Check that the endpoint {} is running and reachable", url),
+                None => format!(
+                    "Check the network connection and proxy settings, and that '{}' is a valid region",
+                    command.region
+                ),
+            },
+            ErrorCategory::Other => return None,
+        };
+        Some(hint)
+    }
+}
+
+impl std::fmt::Display for AwsError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.stderr.trim())?;
+        if let Some(attempts) = self.attempts {
+            write!(f, "\n(gave up after {} attempts)", attempts)?;
+        }
+        if let Some(hint) = &self.hint {
+            write!(f, "\nHint: {}", hint)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for AwsError {}
+
+/// Whether stderr says the profile isn't configured, e.g. `The config profile (prod) could not be found`
+fn is_missing_profile(stderr: &str) -> bool {
+    stderr.contains("The config profile (") && stderr.contains("could not be found")
+}
+
+/// The value following the first of `labels` found in `text`, up to a separator
+fn field_after(text: &str, labels: &[&str]) -> Option<String> {
+    labels.iter().find_map(|label| {
+        let (_, rest) = text.split_once(label)?;
+        let value: String = rest
+            .chars()
+            .take_while(|c| !c.is_whitespace() && !matches!(c, ';' | ',' | ')'))
+            .collect();
+        let value = value.trim_end_matches('.');
+        (!value.is_empty()).then(|| value.to_string())
+    })
+}
+
+fn capitalize(s: &str) -> String {
+    let mut chars = s.chars();
+    chars
+        .next()
+        .map(|first| first.to_uppercase().chain(chars).collect())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn command(service_name: &str, operation_name: &str, profile_name: Option<&str>) -> UseAws {
+        serde_json::from_value(serde_json::json!({
+            "service_name": service_name,
+            "operation_name": operation_name,
+            "region": "eu-west-1",
+            "profile_name": profile_name
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_parse() {
+        let error = AwsError::parse(
+            "\nAn error occurred (ThrottlingException) when calling the ListFunctions operation (reached max retries: 2): Rate exceeded\n",
+            254,
+        );
+        assert_eq!(error.category, ErrorCategory::Throttling);
+        assert_eq!(error.code.as_deref(), Some("ThrottlingException"));
+        assert_eq!(error.operation.as_deref(), Some("ListFunctions"));
+        assert_eq!(error.message, "Rate exceeded");
+
+        let error = AwsError::parse("\nAn error occurred (404) when calling the HeadObject operation: Not Found\n", 254);
+        assert_eq!((error.category, error.http_status), (ErrorCategory::NotFound, Some(404)));
+        let error = AwsError::parse(
+            "An error occurred (InvalidInstanceID.NotFound) when calling the DescribeInstances operation: The instance ID 'i-2' does not exist",
+            254,
+        );
+        assert_eq!(error.category, ErrorCategory::NotFound);
+        let error = AwsError::parse(
+            "An error occurred (InvalidParameterValue) when calling the CreateFunction operation: Status Code: 400; Request ID: 7c1f-22ab.",
+            254,
+        );
+        assert_eq!(error.category, ErrorCategory::Validation);
+        assert_eq!((error.http_status, error.request_id.as_deref()), (Some(400), Some("7c1f-22ab")));
+
+        let error = AwsError::parse(
+            "usage: aws [options] <command> <subcommand> [<subcommand> ...] [parameters]\naws: error: argument --instance-ids: expected one argument\n",
+            252,
+        );
+        assert_eq!(error.category, ErrorCategory::CliUsage);
+        assert_eq!(error.message, "argument --instance-ids: expected one argument");
+        let error = AwsError::parse("\nCould not connect to the endpoint URL: \"https://lambda.xx-east-1.amazonaws.com/\"\n", 255);
+        assert!(error.category.is_transient());
+    }
+
+    #[test]
+    fn test_hints() {
+        let hint = |stderr: &str, command: &UseAws| AwsError::parse(stderr, 255).with_hint(command).hint.unwrap();
+        let dev = command("s3api", "list-buckets", Some("dev"));
+
+        let sso = "\nError when retrieving token from sso: Token has expired and refresh failed\n";
+        assert_eq!(AwsError::parse(sso, 255).category, ErrorCategory::Auth);
+        assert_eq!(hint(sso, &dev), "The SSO session of profile 'dev' expired. Run `aws sso login --profile dev` and retry");
+        assert_eq!(
+            hint(sso, &command("s3api", "list-buckets", None)),
+            "The SSO session of the default profile expired. Run `aws sso login` and retry"
+        );
+        assert_eq!(
+            hint("\nThe config profile (dev) could not be found\n", &dev),
+            "Profile 'dev' isn't configured. Use a profile from ~/.aws/config, or add it with `aws configure --profile dev`"
+        );
+
+        let denied = "\nAn error occurred (AccessDenied) when calling the ListBuckets operation: Access Denied\n";
+        assert!(hint(denied, &dev).starts_with("The identity of profile 'dev' isn't allowed to perform `s3:ListBuckets`."));
+        let denied = "An error occurred (AccessDeniedException) when calling the GetSecretValue operation: User: arn:aws:iam::123456789012:user/ci is not authorized to perform: secretsmanager:GetSecretValue on resource: db because no identity-based policy allows it";
+        assert!(hint(denied, &command("secretsmanager", "get-secret-value", Some("ci"))).contains("`secretsmanager:GetSecretValue`"));
+        assert_eq!(AwsError::parse("Something unexpected", 255).with_hint(&dev).hint, None);
+    }
+}
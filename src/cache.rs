@@ -0,0 +1,438 @@
+//! Cache of read-only command results. Models repeat identical `describe` and `list` calls
+//! within a conversation; cached results are returned until their service's TTL runs out or
+//! a write to the same service in the same account invalidates them.
+
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use eyre::{Result, WrapErr};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::executor::{AwsExecutor, CommandOutput};
+use crate::redact::Redaction;
+use crate::use_aws::UseAws;
+
+/// Whether a request may be answered from the cache
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CacheMode {
+    /// Returns a cached result if there is a fresh one
+    #[default]
+    Use,
+    /// Runs the command and replaces the cached result
+    Bypass,
+}
+
+/// The result cache, configured under `cache` in the config file
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CacheConfig {
+    pub enabled: bool,
+    /// How long results are returned from the cache, unless `services` says otherwise
+    pub ttl_secs: u64,
+    /// TTLs by service name, e.g. `{"iam": 300, "cloudwatch": 0}`. 0 turns caching off for a
+    /// service.
+    pub services: BTreeMap<String, u64>,
+    /// Results kept at most; the oldest are dropped first
+    pub max_entries: usize,
+    /// File the cache is kept in across sessions. Results containing secrets are only kept
+    /// in memory.
+    pub path: Option<PathBuf>,
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            ttl_secs: 30,
+            services: BTreeMap::new(),
+            max_entries: 500,
+            path: None,
+        }
+    }
+}
+
+/// A cached result
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Entry {
+    /// The service name of the command, e.g. `s3api`, which its TTL is configured by
+    #[serde(default)]
+    service_name: String,
+    /// The service the entry is invalidated with, see [service]
+    service: String,
+    account: Option<String>,
+    stored_at: DateTime<Utc>,
+    output: CommandOutput,
+    /// Whether the output contains secrets, which are never written to disk
+    #[serde(skip)]
+    secret: bool,
+}
+
+/// The on-disk form of the cache
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct CacheFile {
+    entries: HashMap<String, Entry>,
+}
+
+/// Results of read-only commands by command and account
+#[derive(Debug, Default)]
+pub struct ResultCache {
+    config: CacheConfig,
+    entries: Mutex<HashMap<String, Entry>>,
+    /// Tells which results contain secrets, so they are kept out of the cache file
+    redaction: Redaction,
+}
+
+impl ResultCache {
+    /// Creates the cache, loading the results persisted at `path` that are still fresh
+    pub fn new(config: CacheConfig) -> Self {
+        let cache = Self {
+            config,
+            entries: Mutex::default(),
+            redaction: Redaction::default(),
+        };
+        if let Some(path) = cache.config.path.as_deref().filter(|path| path.exists()) {
+            match load(path) {
+                Ok(file) => {
+                    let now = Utc::now();
+                    let fresh = file.entries.into_iter().filter(|(_, entry)| cache.is_fresh(entry, now));
+                    cache.lock().extend(fresh);
+                },
+                Err(e) => tracing::warn!("Ignoring the result cache: {:#}", e),
+            }
+        }
+        cache
+    }
+
+    /// Recognizes secrets as `redaction` does, e.g. under its additional secret `fields`.
+    /// Results are checked even if redaction of output is off.
+    pub fn with_redaction(mut self, redaction: Redaction) -> Self {
+        self.redaction = Redaction {
+            enabled: true,
+            ..redaction
+        };
+        self
+    }
+
+    /// Whether results of `command` are cached: it is read-only, doesn't write local files,
+    /// and its service has a TTL
+    pub fn applies_to(&self, command: &UseAws) -> bool {
+        self.config.enabled
+            && !command.requires_acceptance()
+            && command.local_positional_paths().is_empty()
+            && self.ttl(&command.service_name) > 0
+    }
+
+    /// Returns the result of `command` in `account` if it was cached less than its TTL before
+    /// `now`, with the time it was stored
+    pub fn get(&self, command: &UseAws, account: Option<&str>, now: DateTime<Utc>) -> Option<(CommandOutput, DateTime<Utc>)> {
+        let entries = self.lock();
+        let entry = entries.get(&key(command, account))?;
+        self.is_fresh(entry, now).then(|| (entry.output.clone(), entry.stored_at))
+    }
+
+    /// Caches the result of `command` in `account`
+    pub fn put(&self, command: &UseAws, account: Option<&str>, output: &CommandOutput, now: DateTime<Utc>) {
+        let mut entries = self.lock();
+        entries.retain(|_, entry| self.is_fresh(entry, now));
+        while entries.len() >= self.config.max_entries.max(1) {
+            let Some(oldest) = entries.iter().min_by_key(|(_, entry)| entry.stored_at).map(|(key, _)| key.clone()) else {
+                break;
+            };
+            entries.remove(&oldest);
+        }
+        entries.insert(key(command, account), Entry {
+            service_name: command.service_name.clone(),
+            service: service(&command.service_name).to_string(),
+            account: account.map(str::to_string),
+            stored_at: now,
+            output: output.clone(),
+            secret: self.has_secrets(output),
+        });
+        self.save(&entries);
+    }
+
+    /// Drops the results of `service_name` in `account`. Results of unknown accounts are
+    /// dropped for any account, and any account's for an unknown account.
+    pub fn invalidate(&self, service_name: &str, account: Option<&str>) {
+        let mut entries = self.lock();
+        let before = entries.len();
+        entries.retain(|_, entry| {
+            entry.service != service(service_name)
+                || matches!((entry.account.as_deref(), account), (Some(cached), Some(account)) if cached != account)
+        });
+        if entries.len() != before {
+            self.save(&entries);
+        }
+    }
+
+    fn ttl(&self, service_name: &str) -> u64 {
+        self.config
+            .services
+            .get(service_name)
+            .or_else(|| self.config.services.get(service(service_name)))
+            .copied()
+            .unwrap_or(self.config.ttl_secs)
+    }
+
+    fn is_fresh(&self, entry: &Entry, now: DateTime<Utc>) -> bool {
+        let age = now.signed_duration_since(entry.stored_at);
+        age >= chrono::TimeDelta::zero() && age < chrono::TimeDelta::seconds(self.ttl(&entry.service_name).min(i64::MAX as u64) as i64)
+    }
+
+    fn has_secrets(&self, output: &CommandOutput) -> bool {
+        match serde_json::from_str(&output.stdout) {
+            Ok(mut value) => !self.redaction.redact_json(&mut value).is_empty(),
+            Err(_) => !self.redaction.redact_text(&output.stdout, "stdout").1.is_empty(),
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<String, Entry>> {
+        self.entries.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Writes the entries without secrets to the cache file, if one is configured. A
+    /// failure to write is logged but doesn't fail the call.
+    fn save(&self, entries: &HashMap<String, Entry>) {
+        let Some(path) = &self.config.path else {
+            return;
+        };
+        let file = CacheFile {
+            entries: entries
+                .iter()
+                .filter(|(_, entry)| !entry.secret)
+                .map(|(key, entry)| (key.clone(), entry.clone()))
+                .collect(),
+        };
+        let written = serde_json::to_vec(&file)
+            .map_err(eyre::Report::from)
+            .and_then(|contents| {
+                let temp = path.with_extension("tmp");
+                std::fs::write(&temp, contents)?;
+                std::fs::rename(&temp, path)?;
+                Ok(())
+            });
+        if let Err(e) = written {
+            tracing::warn!("Failed to write the result cache '{}': {:#}", path.display(), e);
+        }
+    }
+}
+
+fn load(path: &Path) -> Result<CacheFile> {
+    let contents = std::fs::read(path).wrap_err_with(|| format!("Unable to read '{}'", path.display()))?;
+    serde_json::from_slice(&contents).wrap_err_with(|| format!("Invalid result cache '{}'", path.display()))
+}
+
+/// The key of a command's result: a hash, so the cache file doesn't reveal parameters. The
+/// canonical command includes the profile and region.
+fn key(command: &UseAws, account: Option<&str>) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(account.unwrap_or_default());
+    hasher.update("\n");
+    hasher.update(command.canonical_command(None));
+    hex::encode(hasher.finalize())
+}
+
+/// The service a command belongs to for invalidation, which is shared by the `s3`
+/// customizations and `s3api`
+fn service(service_name: &str) -> &str {
+    match service_name {
+        "s3api" => "s3",
+        name => name,
+    }
+}
+
+/// Runs commands through an executor, answering read-only commands from the cache and
+/// invalidating it on writes
+#[derive(Debug)]
+pub struct Cached<'a> {
+    executor: &'a dyn AwsExecutor,
+    cache: &'a ResultCache,
+    /// The account the commands run in, if known
+    account: Mutex<Option<String>>,
+    /// When the oldest result returned from the cache was stored
+    oldest_hit: Mutex<Option<DateTime<Utc>>>,
+}
+
+impl<'a> Cached<'a> {
+    pub fn new(executor: &'a dyn AwsExecutor, cache: &'a ResultCache, account: Option<String>) -> Self {
+        Self {
+            executor,
+            cache,
+            account: Mutex::new(account),
+            oldest_hit: Mutex::new(None),
+        }
+    }
+
+    /// Sets the account the commands run in, once it has been resolved
+    pub fn set_account(&self, account: Option<String>) {
+        *self.account.lock().unwrap_or_else(|e| e.into_inner()) = account;
+    }
+
+    /// Says how old the cached results returned are, if any were
+    pub fn note(&self) -> Option<String> {
+        let stored_at = (*self.oldest_hit.lock().unwrap_or_else(|e| e.into_inner()))?;
+        let age = Utc::now().signed_duration_since(stored_at).num_seconds().max(0);
+        Some(format!(
+            "Returned from the cache, fetched {}s ago. Pass `cache: bypass` for a fresh result",
+            age
+        ))
+    }
+}
+
+#[async_trait]
+impl AwsExecutor for Cached<'_> {
+    async fn execute(&self, command: &UseAws) -> Result<CommandOutput> {
+        let account = self.account.lock().unwrap_or_else(|e| e.into_inner()).clone();
+        let account = account.as_deref();
+        if command.requires_acceptance() {
+            // A failed write may still have changed something
+            let output = self.executor.execute(command).await;
+            self.cache.invalidate(&command.service_name, account);
+            return output;
+        }
+        if !self.cache.applies_to(command) {
+            return self.executor.execute(command).await;
+        }
+
+        if command.cache != Some(CacheMode::Bypass) {
+            if let Some((output, stored_at)) = self.cache.get(command, account, Utc::now()) {
+                let mut oldest_hit = self.oldest_hit.lock().unwrap_or_else(|e| e.into_inner());
+                *oldest_hit = Some(oldest_hit.map_or(stored_at, |oldest| oldest.min(stored_at)));
+                return Ok(output);
+            }
+        }
+        let output = self.executor.execute(command).await?;
+        if output.exit_code == 0 {
+            self.cache.put(command, account, &output, Utc::now());
+        }
+        Ok(output)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::executor::MockExecutor;
+
+    fn command(service_name: &str, operation_name: &str) -> UseAws {
+        serde_json::from_value(serde_json::json!({
+            "service_name": service_name,
+            "operation_name": operation_name,
+            "region": "us-east-1",
+            "profile_name": "dev"
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_result_cache() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("cache.json");
+        let cache = ResultCache::new(CacheConfig {
+            services: BTreeMap::from([("iam".to_string(), 300), ("cloudwatch".to_string(), 0)]),
+            path: Some(path.clone()),
+            ..CacheConfig::default()
+        });
+        let list_buckets = command("s3api", "list-buckets");
+        let list_roles = command("iam", "list-roles");
+        let start = Utc::now();
+        assert!(cache.applies_to(&list_buckets));
+        assert!(!cache.applies_to(&command("cloudwatch", "get-metric-data")));
+        assert!(!cache.applies_to(&command("s3api", "delete-bucket")));
+
+        let buckets = CommandOutput::success(r#"{"Buckets": []}"#);
+        cache.put(&list_buckets, Some("111111111111"), &buckets, start);
+        cache.put(&list_roles, Some("111111111111"), &CommandOutput::success(r#"{"Roles": []}"#), start);
+        cache.put(&command("ssm", "get-parameter"), Some("111111111111"), &CommandOutput::success(r#"{"Parameter": {"Type": "SecureString", "Value": "hunter2"}}"#), start);
+        assert_eq!(cache.get(&list_buckets, Some("111111111111"), start).unwrap().0, buckets);
+        assert!(cache.get(&list_buckets, Some("222222222222"), start).is_none());
+        assert!(cache.get(&list_buckets, Some("111111111111"), start + chrono::TimeDelta::seconds(30)).is_none());
+        assert!(cache.get(&list_roles, Some("111111111111"), start + chrono::TimeDelta::seconds(299)).is_some());
+
+        // Persisted without the secret parameter, and reloaded while fresh
+        let persisted = std::fs::read_to_string(&path).unwrap();
+        assert!(!persisted.contains("hunter2") && persisted.contains("Roles"));
+        let reloaded = ResultCache::new(cache.config.clone());
+        assert!(reloaded.get(&list_roles, Some("111111111111"), Utc::now()).is_some());
+
+        // Writes invalidate their service in their account, including the s3 customizations
+        cache.invalidate("iam", Some("222222222222"));
+        assert!(cache.get(&list_roles, Some("111111111111"), start).is_some());
+        cache.invalidate("s3", Some("111111111111"));
+        assert!(cache.get(&list_buckets, Some("111111111111"), start).is_none());
+        cache.invalidate("iam", None);
+        assert!(cache.get(&list_roles, Some("111111111111"), start).is_none());
+    }
+
+    #[test]
+    fn test_result_cache_s3api_ttl() {
+        let cache = ResultCache::new(CacheConfig {
+            services: BTreeMap::from([("s3api".to_string(), 600)]),
+            ..CacheConfig::default()
+        });
+        let list_buckets = command("s3api", "list-buckets");
+        let start = Utc::now();
+        cache.put(&list_buckets, None, &CommandOutput::success(r#"{"Buckets": []}"#), start);
+        assert!(cache.get(&list_buckets, None, start + chrono::TimeDelta::seconds(599)).is_some());
+        assert!(cache.get(&list_buckets, None, start + chrono::TimeDelta::seconds(600)).is_none());
+        // Still invalidated by writes of the s3 customizations
+        cache.invalidate("s3", None);
+        assert!(cache.get(&list_buckets, None, start).is_none());
+    }
+
+    #[test]
+    fn test_result_cache_custom_secret_fields() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("cache.json");
+        let redaction = Redaction {
+            enabled: false,
+            fields: vec!["ApiKey".to_string()],
+            ..Redaction::default()
+        };
+        let cache = ResultCache::new(CacheConfig {
+            path: Some(path.clone()),
+            ..CacheConfig::default()
+        })
+        .with_redaction(redaction);
+        let get_api_key = command("apigateway", "get-api-key");
+        let now = Utc::now();
+        cache.put(&get_api_key, None, &CommandOutput::success(r#"{"Name": "ci", "ApiKey": "s3cr3t"}"#), now);
+        cache.put(&command("apigateway", "get-rest-apis"), None, &CommandOutput::success(r#"{"Items": []}"#), now);
+
+        assert!(cache.get(&get_api_key, None, now).is_some());
+        let persisted = std::fs::read_to_string(&path).unwrap();
+        assert!(!persisted.contains("s3cr3t") && persisted.contains("Items"));
+    }
+
+    #[tokio::test]
+    async fn test_cached() {
+        let executor = MockExecutor::new()
+            .respond(CommandOutput::success(r#"{"Buckets": [{"Name": "a"}]}"#))
+            .respond(CommandOutput::success("{}"))
+            .respond(CommandOutput::success(r#"{"Buckets": [{"Name": "a"}, {"Name": "b"}]}"#));
+        let cache = ResultCache::new(CacheConfig::default());
+        let cached = Cached::new(&executor, &cache, Some("111111111111".to_string()));
+        let list_buckets = command("s3api", "list-buckets");
+
+        cached.execute(&list_buckets).await.unwrap();
+        assert_eq!(cached.note(), None);
+        let output = cached.execute(&list_buckets).await.unwrap();
+        assert!(output.stdout.contains(r#""a""#));
+        assert!(cached.note().unwrap().starts_with("Returned from the cache"));
+        assert_eq!(executor.calls().len(), 1);
+
+        cached.execute(&command("s3api", "create-bucket")).await.unwrap();
+        let output = cached.execute(&list_buckets).await.unwrap();
+        assert!(output.stdout.contains(r#""b""#));
+        assert_eq!(executor.calls().len(), 3);
+
+        let mut bypass = list_buckets.clone();
+        bypass.cache = Some(CacheMode::Bypass);
+        assert!(cached.execute(&bypass).await.is_err(), "bypass runs the command");
+    }
+}
@@ -0,0 +1,121 @@
+use serde::{Deserialize, Serialize};
+
+use crate::aws_config::AwsConfig;
+use crate::service_model::ServiceCatalog;
+
+/// Maximum number of values returned in a single completion result, as required by MCP
+const MAX_COMPLETION_VALUES: usize = 100;
+
+/// Parameters of a `completion/complete` request
+#[derive(Debug, Clone, Deserialize)]
+pub struct CompleteParams {
+    #[serde(rename = "ref")]
+    pub reference: serde_json::Value,
+    pub argument: CompletionArgument,
+    #[serde(default)]
+    pub context: Option<CompletionContext>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct CompletionArgument {
+    pub name: String,
+    pub value: String,
+}
+
+/// Previously resolved arguments the client can send along, e.g. `service_name` when
+/// completing `operation_name`
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct CompletionContext {
+    #[serde(default)]
+    pub arguments: std::collections::HashMap<String, String>,
+}
+
+/// The `completion` object of a `completion/complete` result
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Completion {
+    pub values: Vec<String>,
+    pub total: usize,
+    pub has_more: bool,
+}
+
+/// Completes a `use_aws` argument value from the service models and the local AWS config.
+/// Unknown arguments complete to nothing.
+pub fn complete(catalog: &ServiceCatalog, aws_config: &AwsConfig, params: &CompleteParams) -> Completion {
+    let candidates = match params.argument.name.as_str() {
+        "service_name" => catalog.service_names(),
+        "operation_name" => params
+            .context
+            .as_ref()
+            .and_then(|context| context.arguments.get("service_name"))
+            .map(|service_name| catalog.operation_names(service_name))
+            .unwrap_or_default(),
+        "region" => catalog.regions(),
+        "profile_name" => aws_config.profile_names(),
+        _ => Vec::new(),
+    };
+
+    let prefix = params.argument.value.as_str();
+    let matches: Vec<String> = candidates.into_iter().filter(|c| c.starts_with(prefix)).collect();
+    let total = matches.len();
+    Completion {
+        values: matches.into_iter().take(MAX_COMPLETION_VALUES).collect(),
+        total,
+        has_more: total > MAX_COMPLETION_VALUES,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::service_model::tests::write_data_dir;
+
+    fn params(value: serde_json::Value) -> CompleteParams {
+        serde_json::from_value(value).unwrap()
+    }
+
+    #[test]
+    fn test_complete() {
+        let dir = tempfile::tempdir().unwrap();
+        write_data_dir(dir.path());
+        let catalog = ServiceCatalog::from_data_dir(dir.path());
+        let aws_config = AwsConfig::parse("[profile dev]\n[profile prod]\n", "[default]\n");
+
+        let completion = complete(&catalog, &aws_config, &params(serde_json::json!({
+            "ref": {"type": "ref/prompt", "name": "use_aws"},
+            "argument": {"name": "service_name", "value": "s3"}
+        })));
+        assert_eq!(completion.values, vec!["s3", "s3api"]);
+        assert_eq!(completion.total, 2);
+        assert!(!completion.has_more);
+
+        let completion = complete(&catalog, &aws_config, &params(serde_json::json!({
+            "ref": {"type": "ref/prompt", "name": "use_aws"},
+            "argument": {"name": "operation_name", "value": "desc"},
+            "context": {"arguments": {"service_name": "ec2"}}
+        })));
+        assert_eq!(completion.values, vec!["describe-instances"]);
+
+        let completion = complete(&catalog, &aws_config, &params(serde_json::json!({
+            "ref": {"type": "ref/prompt", "name": "use_aws"},
+            "argument": {"name": "region", "value": "us-"}
+        })));
+        assert_eq!(completion.values, vec!["us-east-1"]);
+
+        let completion = complete(&catalog, &aws_config, &params(serde_json::json!({
+            "ref": {"type": "ref/prompt", "name": "use_aws"},
+            "argument": {"name": "profile_name", "value": ""}
+        })));
+        assert_eq!(completion.values, vec!["default", "dev", "prod"]);
+    }
+
+    #[test]
+    fn test_complete_operation_without_service() {
+        let catalog = ServiceCatalog::default();
+        let completion = complete(&catalog, &AwsConfig::default(), &params(serde_json::json!({
+            "ref": {"type": "ref/resource", "uri": "aws://{service_name}/{operation_name}"},
+            "argument": {"name": "operation_name", "value": "list"}
+        })));
+        assert_eq!(completion, Completion::default());
+    }
+}
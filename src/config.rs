@@ -0,0 +1,112 @@
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use eyre::{Result, WrapErr};
+use serde::Deserialize;
+
+use crate::audit::AuditConfig;
+use crate::cache::CacheConfig;
+use crate::environment::EnvironmentConfig;
+use crate::limits::LimitsConfig;
+use crate::policy::{Access, Endpoint};
+use crate::redact::Redaction;
+use crate::retry::RetryConfig;
+
+/// Server configuration, read from the JSON file given with `--config`. Command line options
+/// take precedence over the file.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// How commands are run
+    pub backend: Backend,
+    /// The AWS CLI executable, e.g. `/opt/awscli-v2/aws`. Defaults to `aws` on `PATH`.
+    pub aws_cli_path: Option<PathBuf>,
+    /// Arguments inserted before the AWS CLI arguments, for wrappers like
+    /// `aws-vault exec prod -- aws`
+    pub aws_cli_args: Vec<String>,
+    /// The environment variables the AWS CLI runs with
+    pub environment: EnvironmentConfig,
+    /// Access to real AWS accounts
+    pub access: Access,
+    /// Access to particular AWS accounts by account id, which applies whatever profile a
+    /// command uses. The stricter of this and `access` applies.
+    pub accounts: BTreeMap<String, Access>,
+    /// Resolves the account and principal of each profile with `sts get-caller-identity`
+    /// before running its commands. Off by default, always on while `accounts` is set.
+    pub identity_preflight: Option<bool>,
+    /// Named endpoints of local emulators like LocalStack or moto
+    pub endpoints: BTreeMap<String, Endpoint>,
+    /// Endpoints used by every command of an AWS profile, by profile name
+    pub profile_endpoints: BTreeMap<String, String>,
+    /// Items returned per call of list and describe commands unless the request sets
+    /// `max_items`, 100 by default
+    pub max_items: Option<usize>,
+    /// Masking of secrets in command output
+    pub redaction: Redaction,
+    /// The audit log of tool calls
+    pub audit: AuditConfig,
+    /// Rate limits and call budgets
+    pub limits: LimitsConfig,
+    /// Retries of throttled and transiently failing commands
+    pub retry: RetryConfig,
+    /// The cache of read-only results
+    pub cache: CacheConfig,
+}
+
+/// Executors the server can run commands with
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Backend {
+    /// The AWS CLI executable
+    #[default]
+    Cli,
+    /// Signed HTTP requests sent by the server itself, for a core set of services
+    Native,
+}
+
+impl Config {
+    pub fn load(path: &Path) -> Result<Self> {
+        let contents = std::fs::read_to_string(path)
+            .wrap_err_with(|| format!("Unable to read config '{}'", path.display()))?;
+        let config: Self =
+            serde_json::from_str(&contents).wrap_err_with(|| format!("Invalid config '{}'", path.display()))?;
+        for (profile, endpoint) in &config.profile_endpoints {
+            if !config.endpoints.contains_key(endpoint) {
+                eyre::bail!("Profile '{}' uses unknown endpoint '{}'", profile, endpoint);
+            }
+        }
+        Ok(config)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_load() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("config.json");
+        std::fs::write(
+            &path,
+            r#"{"aws_cli_path": "/usr/local/bin/aws-vault", "aws_cli_args": ["exec", "prod", "--", "aws"]}"#,
+        )
+        .unwrap();
+        let config = Config::load(&path).unwrap();
+        assert_eq!(config.aws_cli_path, Some(PathBuf::from("/usr/local/bin/aws-vault")));
+        assert_eq!(config.aws_cli_args, ["exec", "prod", "--", "aws"]);
+        assert_eq!(config.backend, Backend::Cli);
+
+        std::fs::write(&path, r#"{"backend": "native"}"#).unwrap();
+        assert_eq!(Config::load(&path).unwrap().backend, Backend::Native);
+
+        std::fs::write(&path, "{}").unwrap();
+        assert_eq!(Config::load(&path).unwrap(), Config::default());
+
+        std::fs::write(&path, r#"{"aws_cli": "/opt/aws"}"#).unwrap();
+        assert!(Config::load(&path).is_err());
+
+        std::fs::write(&path, r#"{"profile_endpoints": {"dev": "localstack"}}"#).unwrap();
+        assert!(Config::load(&path).is_err());
+    }
+}
@@ -0,0 +1,168 @@
+/// What a positional argument of a CLI command refers to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PositionalKind {
+    /// A file or directory on the local machine
+    LocalPath,
+    /// An `s3://bucket/key` URI
+    S3Uri,
+    /// Either of the above, e.g. the source and destination of `s3 cp`
+    LocalPathOrS3Uri,
+    /// Anything else, e.g. a log group name
+    Other,
+}
+
+/// A command taking positional arguments, either a CLI customization like `s3 cp` or a
+/// generated operation with a streaming output file like `s3api get-object`
+#[derive(Debug, Clone, Copy)]
+pub struct Customization {
+    pub service: &'static str,
+    pub command: &'static str,
+    /// Whether the command is added by the CLI rather than generated from the service model
+    pub is_customization: bool,
+    /// Positional arguments in order; trailing ones may be omitted
+    pub positionals: &'static [PositionalKind],
+}
+
+use PositionalKind::*;
+
+const fn customization(service: &'static str, command: &'static str, positionals: &'static [PositionalKind]) -> Customization {
+    Customization {
+        service,
+        command,
+        is_customization: true,
+        positionals,
+    }
+}
+
+const fn outfile(service: &'static str, command: &'static str) -> Customization {
+    Customization {
+        service,
+        command,
+        is_customization: false,
+        positionals: &[LocalPath],
+    }
+}
+
+/// Commands with positional arguments known to the server
+pub const COMMANDS: [Customization; 16] = [
+    customization("s3", "cp", &[LocalPathOrS3Uri, LocalPathOrS3Uri]),
+    customization("s3", "ls", &[S3Uri]),
+    customization("s3", "mb", &[S3Uri]),
+    customization("s3", "mv", &[LocalPathOrS3Uri, LocalPathOrS3Uri]),
+    customization("s3", "presign", &[S3Uri]),
+    customization("s3", "rb", &[S3Uri]),
+    customization("s3", "rm", &[S3Uri]),
+    customization("s3", "sync", &[LocalPathOrS3Uri, LocalPathOrS3Uri]),
+    customization("s3", "website", &[S3Uri]),
+    customization("logs", "tail", &[Other]),
+    outfile("s3api", "get-object"),
+    outfile("s3api", "get-object-torrent"),
+    outfile("lambda", "invoke"),
+    outfile("lambda", "invoke-with-response-stream"),
+    outfile("bedrock-runtime", "invoke-model"),
+    outfile("sagemaker-runtime", "invoke-endpoint"),
+];
+
+/// Looks up the positional layout of a command
+pub fn find(service_name: &str, command: &str) -> Option<&'static Customization> {
+    COMMANDS
+        .iter()
+        .find(|c| c.service == service_name && c.command == command)
+}
+
+/// Returns whether the command is a CLI customization rather than a generated operation
+pub fn is_customization(service_name: &str, command: &str) -> bool {
+    find(service_name, command).is_some_and(|c| c.is_customization)
+}
+
+/// Returns the customization commands the CLI adds to a service
+pub fn commands(service_name: &str) -> impl Iterator<Item = &'static str> + '_ {
+    COMMANDS
+        .iter()
+        .filter(move |c| c.is_customization && c.service == service_name)
+        .map(|c| c.command)
+}
+
+/// Returns the services the CLI adds customization commands to
+pub fn services() -> impl Iterator<Item = &'static str> {
+    COMMANDS.iter().filter(|c| c.is_customization).map(|c| c.service)
+}
+
+/// Classifies each positional argument of a command. Fails if the command doesn't take
+/// positional arguments, gets too many, or an argument looks like an option.
+pub fn classify<'a>(service_name: &str, command: &str, args: &'a [String]) -> Result<Vec<(PositionalKind, &'a str)>, String> {
+    if args.is_empty() {
+        return Ok(Vec::new());
+    }
+    let Some(customization) = find(service_name, command) else {
+        return Err(format!("'{} {}' does not take positional arguments", service_name, command));
+    };
+    if args.len() > customization.positionals.len() {
+        return Err(format!(
+            "'{} {}' takes at most {} positional argument(s), got {}",
+            service_name,
+            command,
+            customization.positionals.len(),
+            args.len()
+        ));
+    }
+    let mut classified = Vec::with_capacity(args.len());
+    for (kind, arg) in customization.positionals.iter().zip(args) {
+        // A lone dash streams from stdin/to stdout, anything else starting with one is an option
+        if arg.starts_with('-') && arg != "-" {
+            return Err(format!("Positional argument '{}' must not start with '-'", arg));
+        }
+        let kind = match kind {
+            LocalPathOrS3Uri if arg.starts_with("s3://") => S3Uri,
+            LocalPathOrS3Uri => LocalPath,
+            S3Uri if !arg.starts_with("s3://") => {
+                return Err(format!("Positional argument '{}' must be an s3:// URI", arg));
+            },
+            kind => *kind,
+        };
+        classified.push((kind, arg.as_str()));
+    }
+    Ok(classified)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|a| a.to_string()).collect()
+    }
+
+    #[test]
+    fn test_classify() {
+        let cp = args(&["./build", "s3://bucket/prefix/"]);
+        assert_eq!(classify("s3", "cp", &cp).unwrap(), vec![
+            (LocalPath, "./build"),
+            (S3Uri, "s3://bucket/prefix/")
+        ]);
+        let ls = args(&["s3://bucket/logs/"]);
+        assert_eq!(classify("s3", "ls", &ls).unwrap(), vec![(S3Uri, "s3://bucket/logs/")]);
+        assert_eq!(classify("s3", "ls", &[]).unwrap(), vec![]);
+        let tail = args(&["/aws/lambda/my-function"]);
+        assert_eq!(classify("logs", "tail", &tail).unwrap(), vec![(Other, "/aws/lambda/my-function")]);
+        let stdout = args(&["s3://bucket/key", "-"]);
+        assert_eq!(classify("s3", "cp", &stdout).unwrap()[1], (LocalPath, "-"));
+    }
+
+    #[test]
+    fn test_classify_rejects() {
+        assert!(classify("ec2", "describe-instances", &args(&["i-1"])).is_err());
+        assert!(classify("s3", "rm", &args(&["s3://a/b", "s3://a/c"])).is_err());
+        assert!(classify("s3", "rm", &args(&["/etc/passwd"])).is_err());
+        assert!(classify("s3", "cp", &args(&["--endpoint-url=http://evil", "s3://a/b"])).is_err());
+    }
+
+    #[test]
+    fn test_commands() {
+        assert!(is_customization("s3", "sync"));
+        assert!(!is_customization("s3api", "get-object"));
+        assert!(commands("logs").eq(["tail"]));
+        // A model operation (StartLiveTail), so its parameters are mapped by the model
+        assert!(!is_customization("logs", "start-live-tail"));
+    }
+}
@@ -1,5 +1,7 @@
 use thiserror::Error;
 
+use crate::aws_error::AwsError;
+
 #[derive(Error, Debug)]
 pub enum McpError {
     #[error("JSON-RPC error: {0}")]
@@ -11,8 +13,9 @@ pub enum McpError {
     #[error("IO error: {0}")]
     Io(#[from] std::io::Error),
     
-    #[error("AWS CLI execution error: {0}")]
-    AwsCli(String),
+    /// An AWS command that ran and failed
+    #[error("{0}")]
+    AwsCli(Box<AwsError>),
     
     #[error("Tool execution error: {0}")]
     ToolExecution(String),
@@ -0,0 +1,550 @@
+use std::collections::VecDeque;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::sync::{Arc, Mutex};
+
+use async_trait::async_trait;
+use bstr::ByteSlice;
+use eyre::{Result, WrapErr};
+use serde::{Deserialize, Serialize};
+
+use crate::cassette::Cassette;
+use crate::environment::EnvironmentConfig;
+use crate::redact;
+use crate::service_model::ServiceCatalog;
+use crate::use_aws::UseAws;
+
+/// The environment variable name where we set additional metadata for the AWS CLI user agent.
+const USER_AGENT_ENV_VAR: &str = "AWS_EXECUTION_ENV";
+const USER_AGENT_APP_NAME: &str = "UseAws-MCP-Server";
+const USER_AGENT_VERSION_KEY: &str = "Version";
+const USER_AGENT_VERSION_VALUE: &str = env!("CARGO_PKG_VERSION");
+
+/// The exit code reported for an `aws` process killed by a signal, e.g. by the OOM killer.
+/// Its output may be cut short, so it must never look like a success.
+const SIGNALED_EXIT_CODE: i32 = 255;
+
+/// The raw result of running an AWS command
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CommandOutput {
+    pub exit_code: i32,
+    pub stdout: String,
+    pub stderr: String,
+}
+
+impl CommandOutput {
+    /// A successful result with the given stdout
+    pub fn success(stdout: impl Into<String>) -> Self {
+        Self {
+            exit_code: 0,
+            stdout: stdout.into(),
+            stderr: String::new(),
+        }
+    }
+
+    /// A failed result with the given exit code and stderr
+    pub fn failure(exit_code: i32, stderr: impl Into<String>) -> Self {
+        Self {
+            exit_code,
+            stdout: String::new(),
+            stderr: stderr.into(),
+        }
+    }
+}
+
+/// Runs [UseAws] commands against AWS. Errors are reserved for failing to run the command at
+/// all; a command that ran and failed is reported through [CommandOutput::exit_code].
+#[async_trait]
+pub trait AwsExecutor: std::fmt::Debug + Send + Sync {
+    async fn execute(&self, command: &UseAws) -> Result<CommandOutput>;
+}
+
+#[async_trait]
+impl<T: AwsExecutor + ?Sized> AwsExecutor for Arc<T> {
+    async fn execute(&self, command: &UseAws) -> Result<CommandOutput> {
+        (**self).execute(command).await
+    }
+}
+
+/// Runs commands through the `aws` executable
+#[derive(Debug, Clone)]
+pub struct CliExecutor {
+    program: PathBuf,
+    /// Arguments placed before the AWS CLI arguments, for wrapper programs
+    program_args: Vec<String>,
+    catalog: Arc<ServiceCatalog>,
+    environment: EnvironmentConfig,
+}
+
+/// The version reported by `aws --version`, e.g. `aws-cli/2.15.0 Python/3.11.6 Linux/6.5.0`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CliVersion {
+    pub major: u32,
+    pub minor: u32,
+    pub patch: u32,
+}
+
+impl CliVersion {
+    /// The oldest major version the server supports. v1 lacks customizations and output
+    /// handling the server relies on.
+    pub const MIN_MAJOR: u32 = 2;
+
+    /// Parses the output of `aws --version`
+    pub fn parse(output: &str) -> Option<Self> {
+        let version = output.split_whitespace().next()?.strip_prefix("aws-cli/")?;
+        let mut parts = version.splitn(3, '.').map(|part| part.parse().ok());
+        Some(Self {
+            major: parts.next()??,
+            minor: parts.next()??,
+            patch: parts.next()??,
+        })
+    }
+}
+
+impl std::fmt::Display for CliVersion {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}.{}.{}", self.major, self.minor, self.patch)
+    }
+}
+
+impl CliExecutor {
+    /// Creates an executor mapping parameters according to the service models in `catalog`
+    pub fn new(catalog: Arc<ServiceCatalog>) -> Self {
+        Self {
+            program: PathBuf::from("aws"),
+            program_args: Vec::new(),
+            catalog,
+            environment: EnvironmentConfig::default(),
+        }
+    }
+
+    /// Runs `program` instead of the `aws` found on `PATH`
+    pub fn with_program(mut self, program: impl Into<PathBuf>) -> Self {
+        self.program = program.into();
+        self
+    }
+
+    /// Places `args` before the AWS CLI arguments, e.g. `exec prod -- aws` when the program is
+    /// `aws-vault`
+    pub fn with_program_args(mut self, args: Vec<String>) -> Self {
+        self.program_args = args;
+        self
+    }
+
+    /// Runs commands in the environment `environment` describes instead of the default one
+    pub fn with_environment(mut self, environment: EnvironmentConfig) -> Self {
+        self.environment = environment;
+        self
+    }
+
+    /// Runs `aws --version`, failing if the program is missing or older than
+    /// [CliVersion::MIN_MAJOR]
+    pub async fn version(&self) -> Result<CliVersion> {
+        let output = tokio::process::Command::new(&self.program)
+            .args(&self.program_args)
+            .arg("--version")
+            .stdin(Stdio::null())
+            .output()
+            .await
+            .wrap_err_with(|| format!("Unable to run '{}'; is the AWS CLI installed?", self.program.display()))?;
+        // AWS CLI v1 prints its version to stderr on some Python versions
+        let text = format!("{}{}", output.stdout.to_str_lossy(), output.stderr.to_str_lossy());
+        let version = CliVersion::parse(text.trim())
+            .ok_or_else(|| eyre::eyre!("Unrecognized output of '{} --version': {}", self.program.display(), text.trim()))?;
+        if version.major < CliVersion::MIN_MAJOR {
+            eyre::bail!(
+                "AWS CLI {} at '{}' is not supported, install AWS CLI v{} or later",
+                version,
+                self.program.display(),
+                CliVersion::MIN_MAJOR
+            );
+        }
+        Ok(version)
+    }
+
+    /// The environment of `command`: the allowed variables of the server's environment and the
+    /// overrides of its profile, see [EnvironmentConfig::child_env]
+    fn env_vars(&self, command: &UseAws) -> Result<std::collections::HashMap<String, String>> {
+        let mut env_vars = self.environment.child_env(std::env::vars(), command.profile_name.as_deref())?;
+
+        // Set up additional metadata for the AWS CLI user agent
+        let user_agent_metadata_value = format!(
+            "{} {}/{}",
+            USER_AGENT_APP_NAME, USER_AGENT_VERSION_KEY, USER_AGENT_VERSION_VALUE
+        );
+
+        // If the user agent metadata env var already exists, append to it, otherwise set it
+        if let Some(existing_value) = env_vars.get(USER_AGENT_ENV_VAR) {
+            if !existing_value.is_empty() {
+                env_vars.insert(
+                    USER_AGENT_ENV_VAR.to_string(),
+                    format!("{} {}", existing_value, user_agent_metadata_value),
+                );
+            } else {
+                env_vars.insert(USER_AGENT_ENV_VAR.to_string(), user_agent_metadata_value);
+            }
+        } else {
+            env_vars.insert(USER_AGENT_ENV_VAR.to_string(), user_agent_metadata_value);
+        }
+        Ok(env_vars)
+    }
+}
+
+impl Default for CliExecutor {
+    fn default() -> Self {
+        Self::new(ServiceCatalog::shared())
+    }
+}
+
+#[async_trait]
+impl AwsExecutor for CliExecutor {
+    async fn execute(&self, command: &UseAws) -> Result<CommandOutput> {
+        let model = self.catalog.model(&command.service_name);
+        tracing::info!("Running {}", redact::redact_command(command).canonical_command(model.as_deref()));
+        let output = tokio::process::Command::new(&self.program)
+            .env_clear()
+            .envs(self.env_vars(command)?)
+            .args(&self.program_args)
+            .args(command.cli_args(model.as_deref()))
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .wrap_err_with(|| format!("Unable to spawn command '{:?}'", command))?
+            .wait_with_output()
+            .await
+            .wrap_err_with(|| format!("Unable to spawn command '{:?}'", command))?;
+        let mut stderr = output.stderr.to_str_lossy().into_owned();
+        let exit_code = output.status.code().unwrap_or_else(|| {
+            stderr.push_str(&format!("\n'{}' was terminated by a signal\n", self.program.display()));
+            SIGNALED_EXIT_CODE
+        });
+        Ok(CommandOutput {
+            exit_code,
+            stdout: output.stdout.to_str_lossy().into_owned(),
+            stderr,
+        })
+    }
+}
+
+/// A recorded command and the output it produced, with secrets redacted
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Recording {
+    /// The canonical command line, see [UseAws::canonical_command]
+    pub command: String,
+    pub output: CommandOutput,
+    #[serde(default)]
+    pub duration_ms: u64,
+    #[serde(default)]
+    pub recorded_at: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+impl Recording {
+    /// The key recordings are matched by. Rendered without a service model so recordings
+    /// don't depend on the CLI version installed where they are replayed.
+    pub fn key(command: &UseAws) -> String {
+        redact::redact_command(command).canonical_command(None)
+    }
+}
+
+/// Masks secrets in recorded output as the command's [redact::Redaction] masks them in
+/// responses, even if redaction of responses is off
+fn redact_recorded(command: &UseAws, output: &str, location: &str) -> String {
+    let redaction = redact::Redaction {
+        enabled: true,
+        ..command.redaction.clone()
+    };
+    let Ok(mut value) = serde_json::from_str(output) else {
+        return redaction.redact_text(output, location).0;
+    };
+    if redaction.redact_json(&mut value).is_empty() {
+        output.to_string()
+    } else {
+        crate::native::cli_json(&value)
+    }
+}
+
+#[derive(Debug)]
+enum Mode {
+    Record {
+        inner: Arc<dyn AwsExecutor>,
+        cassette: Option<PathBuf>,
+    },
+    Replay,
+}
+
+/// Records the results of another executor, or replays previously recorded results without
+/// running anything
+#[derive(Debug)]
+pub struct RecordReplayExecutor {
+    mode: Mode,
+    recordings: Mutex<Vec<Recording>>,
+}
+
+impl RecordReplayExecutor {
+    /// Passes commands through to `inner`, recording every result
+    pub fn record(inner: Arc<dyn AwsExecutor>) -> Self {
+        Self {
+            mode: Mode::Record { inner, cassette: None },
+            recordings: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Like [RecordReplayExecutor::record], also saving the recordings to a cassette file
+    /// after every command
+    pub fn record_to_cassette(inner: Arc<dyn AwsExecutor>, path: &Path) -> Self {
+        Self {
+            mode: Mode::Record {
+                inner,
+                cassette: Some(path.to_path_buf()),
+            },
+            recordings: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Replays the recordings of a cassette file
+    pub fn replay_cassette(path: &Path) -> Result<Self> {
+        Ok(Self::replay(Cassette::load(path)?.interactions))
+    }
+
+    /// Serves results from `recordings`. Identical commands are answered in the order they
+    /// were recorded, the last recording repeating once the others are used up.
+    pub fn replay(recordings: Vec<Recording>) -> Self {
+        Self {
+            mode: Mode::Replay,
+            recordings: Mutex::new(recordings),
+        }
+    }
+
+    /// Returns the recordings made so far, or the ones not yet replayed
+    pub fn recordings(&self) -> Vec<Recording> {
+        self.recordings.lock().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+}
+
+#[async_trait]
+impl AwsExecutor for RecordReplayExecutor {
+    async fn execute(&self, command: &UseAws) -> Result<CommandOutput> {
+        let key = Recording::key(command);
+        match &self.mode {
+            Mode::Record { inner, cassette } => {
+                let recorded_at = chrono::Utc::now();
+                let started = std::time::Instant::now();
+                let output = inner.execute(command).await?;
+                let recording = Recording {
+                    command: key,
+                    output: CommandOutput {
+                        exit_code: output.exit_code,
+                        stdout: redact_recorded(command, &output.stdout, "stdout"),
+                        stderr: redact_recorded(command, &output.stderr, "stderr"),
+                    },
+                    duration_ms: started.elapsed().as_millis() as u64,
+                    recorded_at: Some(recorded_at),
+                };
+                let mut recordings = self.recordings.lock().unwrap_or_else(|e| e.into_inner());
+                recordings.push(recording);
+                // The command already ran, so a failure to save must not make it look failed
+                if let Some(path) = cassette {
+                    if let Err(e) = Cassette::new(recordings.clone()).save(path) {
+                        tracing::warn!("Failed to save the cassette '{}': {:#}", path.display(), e);
+                    }
+                }
+                Ok(output)
+            },
+            Mode::Replay => {
+                let mut recordings = self.recordings.lock().unwrap_or_else(|e| e.into_inner());
+                let matching: Vec<usize> = recordings
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, recording)| recording.command == key)
+                    .map(|(i, _)| i)
+                    .collect();
+                match matching.as_slice() {
+                    [] => Err(eyre::eyre!("No recording found for '{}'", key)),
+                    [only] => Ok(recordings[*only].output.clone()),
+                    [first, ..] => Ok(recordings.remove(*first).output),
+                }
+            },
+        }
+    }
+}
+
+/// A scripted response, optionally limited to one `service operation` pair
+#[derive(Debug)]
+struct ScriptedResponse {
+    matcher: Option<(String, String)>,
+    response: std::result::Result<CommandOutput, String>,
+}
+
+/// Answers commands with scripted results, for tests
+#[derive(Debug, Default)]
+pub struct MockExecutor {
+    responses: Mutex<VecDeque<ScriptedResponse>>,
+    calls: Mutex<Vec<UseAws>>,
+}
+
+impl MockExecutor {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Scripts the output of the next command, whatever it is
+    pub fn respond(self, output: CommandOutput) -> Self {
+        self.script(None, Ok(output))
+    }
+
+    /// Scripts the output of the next command running `service_name operation_name`
+    pub fn respond_to(self, service_name: &str, operation_name: &str, output: CommandOutput) -> Self {
+        self.script(Some((service_name.to_string(), operation_name.to_string())), Ok(output))
+    }
+
+    /// Scripts the next command to fail to run at all, like a missing `aws` executable
+    pub fn fail(self, message: &str) -> Self {
+        self.script(None, Err(message.to_string()))
+    }
+
+    /// Returns the commands executed so far
+    pub fn calls(&self) -> Vec<UseAws> {
+        self.calls.lock().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+
+    fn script(self, matcher: Option<(String, String)>, response: std::result::Result<CommandOutput, String>) -> Self {
+        self.responses
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .push_back(ScriptedResponse { matcher, response });
+        self
+    }
+}
+
+#[async_trait]
+impl AwsExecutor for MockExecutor {
+    async fn execute(&self, command: &UseAws) -> Result<CommandOutput> {
+        self.calls.lock().unwrap_or_else(|e| e.into_inner()).push(command.clone());
+        let mut responses = self.responses.lock().unwrap_or_else(|e| e.into_inner());
+        let position = responses.iter().position(|scripted| match &scripted.matcher {
+            Some((service_name, operation_name)) => {
+                *service_name == command.service_name && *operation_name == command.operation_name
+            },
+            None => true,
+        });
+        match position.and_then(|i| responses.remove(i)) {
+            Some(scripted) => scripted.response.map_err(|e| eyre::eyre!(e)),
+            None => Err(eyre::eyre!(
+                "No scripted response for {} {}",
+                command.service_name,
+                command.operation_name
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    macro_rules! use_aws {
+        ($value:tt) => {
+            serde_json::from_value::<UseAws>(serde_json::json!($value)).unwrap()
+        };
+    }
+
+    fn list_buckets() -> UseAws {
+        use_aws! {{
+            "service_name": "s3api",
+            "operation_name": "list-buckets",
+            "region": "us-west-2"
+        }}
+    }
+
+    #[tokio::test]
+    async fn test_mock_executor() {
+        let executor = MockExecutor::new()
+            .respond_to("sts", "get-caller-identity", CommandOutput::success("{}"))
+            .respond(CommandOutput::failure(254, "AccessDenied"));
+
+        let output = executor.execute(&list_buckets()).await.unwrap();
+        assert_eq!(output.exit_code, 254);
+        assert!(executor.execute(&list_buckets()).await.is_err());
+        assert_eq!(executor.calls().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_cli_executor_missing_program() {
+        let executor = CliExecutor::new(Arc::new(ServiceCatalog::default())).with_program("/nonexistent/aws");
+        let err = executor.execute(&list_buckets()).await.unwrap_err();
+        assert!(err.to_string().contains("Unable to spawn command"));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_cli_executor_killed() {
+        let executor = CliExecutor::new(Arc::new(ServiceCatalog::default()))
+            .with_program("/bin/sh")
+            .with_program_args(vec!["-c".to_string(), r#"printf '{"Buckets": ['; kill -9 $$"#.to_string(), "aws".to_string()]);
+        let output = executor.execute(&list_buckets()).await.unwrap();
+        assert_eq!(output.exit_code, 255);
+        assert_eq!(output.stdout, r#"{"Buckets": ["#);
+        assert!(output.stderr.contains("terminated by a signal"));
+    }
+
+    #[test]
+    fn test_cli_version_parse() {
+        let version = CliVersion::parse("aws-cli/2.15.0 Python/3.11.6 Linux/6.5.0 exe/x86_64.ubuntu.22 prompt/off");
+        assert_eq!(version, Some(CliVersion { major: 2, minor: 15, patch: 0 }));
+        assert_eq!(version.unwrap().to_string(), "2.15.0");
+        assert_eq!(CliVersion::parse("aws-cli/1.45.11 Python/3.11.7").unwrap().major, 1);
+        assert_eq!(CliVersion::parse("command not found"), None);
+        assert_eq!(CliVersion::parse(""), None);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_cli_executor_version() {
+        let fake = crate::fake_aws::FakeAws::new();
+        fake.respond(CommandOutput::success("aws-cli/2.15.0 Python/3.11.6 Linux/6.5.0\n"))
+            .respond(CommandOutput {
+                exit_code: 0,
+                stdout: String::new(),
+                stderr: "aws-cli/1.18.69 Python/2.7.18 Linux/5.4.0 botocore/1.16.19\n".to_string(),
+            });
+        let executor = fake.executor().with_program_args(vec!["exec".to_string(), "--".to_string()]);
+        assert_eq!(executor.version().await.unwrap().to_string(), "2.15.0");
+        let err = executor.version().await.unwrap_err();
+        assert!(err.to_string().contains("AWS CLI 1.18.69"));
+        assert_eq!(fake.invocations()[0].args, ["exec", "--", "--version"]);
+
+        let missing = CliExecutor::new(Arc::new(ServiceCatalog::default())).with_program("/nonexistent/aws");
+        assert!(missing.version().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_record_replay() {
+        let mock = Arc::new(
+            MockExecutor::new()
+                .respond(CommandOutput::success(r#"{"Buckets": []}"#))
+                .respond(CommandOutput::success(r#"{"Buckets": [{"Name": "a"}]}"#)),
+        );
+        let recorder = RecordReplayExecutor::record(mock);
+        recorder.execute(&list_buckets()).await.unwrap();
+        recorder.execute(&list_buckets()).await.unwrap();
+        let recordings = recorder.recordings();
+        assert_eq!(recordings.len(), 2);
+        assert_eq!(recordings[0].command, "aws --region us-west-2 s3api list-buckets");
+
+        let replayer = RecordReplayExecutor::replay(recordings);
+        let first = replayer.execute(&list_buckets()).await.unwrap();
+        assert_eq!(first.stdout, r#"{"Buckets": []}"#);
+        for _ in 0..2 {
+            let repeated = replayer.execute(&list_buckets()).await.unwrap();
+            assert_eq!(repeated.stdout, r#"{"Buckets": [{"Name": "a"}]}"#);
+        }
+
+        let other_region = use_aws! {{
+            "service_name": "s3api",
+            "operation_name": "list-buckets",
+            "region": "eu-west-1"
+        }};
+        assert!(replayer.execute(&other_region).await.is_err());
+    }
+}
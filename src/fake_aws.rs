@@ -0,0 +1,104 @@
+//! A scriptable stand-in for the `aws` executable, so tests can exercise the real subprocess
+//! path without the AWS CLI or credentials.
+
+use std::collections::HashMap;
+use std::os::unix::fs::PermissionsExt;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::executor::{CliExecutor, CommandOutput};
+use crate::service_model::ServiceCatalog;
+
+/// Records its argv and environment under `calls/<n>/`, then answers with the files in
+/// `responses/<n>/`, falling back to `responses/default/`
+const SCRIPT: &str = r#"#!/bin/sh
+dir="$(dirname "$0")"
+n=$(( $(cat "$dir/count" 2>/dev/null || echo 0) + 1 ))
+echo "$n" > "$dir/count"
+mkdir -p "$dir/calls/$n"
+for arg in "$@"; do printf '%s\0' "$arg"; done > "$dir/calls/$n/argv"
+env > "$dir/calls/$n/env"
+response="$dir/responses/$n"
+[ -d "$response" ] || response="$dir/responses/default"
+[ -f "$response/stdout" ] && cat "$response/stdout"
+[ -f "$response/stderr" ] && cat "$response/stderr" >&2
+exit "$(cat "$response/exit_code" 2>/dev/null || echo 0)"
+"#;
+
+/// A single invocation of the fake executable
+#[derive(Debug, Clone)]
+pub struct Invocation {
+    pub args: Vec<String>,
+    pub env: HashMap<String, String>,
+}
+
+pub struct FakeAws {
+    dir: tempfile::TempDir,
+    queued: AtomicUsize,
+}
+
+impl FakeAws {
+    pub fn new() -> Self {
+        let dir = tempfile::tempdir().unwrap();
+        let program = dir.path().join("aws");
+        std::fs::write(&program, SCRIPT).unwrap();
+        std::fs::set_permissions(&program, std::fs::Permissions::from_mode(0o755)).unwrap();
+        Self {
+            dir,
+            queued: AtomicUsize::new(0),
+        }
+    }
+
+    /// The path of the fake `aws` executable
+    pub fn program(&self) -> PathBuf {
+        self.dir.path().join("aws")
+    }
+
+    /// A CLI executor running the fake executable, without service models
+    pub fn executor(&self) -> CliExecutor {
+        CliExecutor::new(std::sync::Arc::new(ServiceCatalog::default())).with_program(self.program())
+    }
+
+    /// Scripts the output of the invocation following the previously scripted ones
+    pub fn respond(&self, output: CommandOutput) -> &Self {
+        let n = self.queued.fetch_add(1, Ordering::SeqCst) + 1;
+        self.write_response(&n.to_string(), &output);
+        self
+    }
+
+    /// Scripts the output of every invocation without a scripted response
+    pub fn respond_always(&self, output: CommandOutput) -> &Self {
+        self.write_response("default", &output);
+        self
+    }
+
+    /// Returns all invocations so far, in order
+    pub fn invocations(&self) -> Vec<Invocation> {
+        let count: usize = std::fs::read_to_string(self.dir.path().join("count"))
+            .map(|count| count.trim().parse().unwrap())
+            .unwrap_or(0);
+        (1..=count)
+            .map(|n| {
+                let call_dir = self.dir.path().join("calls").join(n.to_string());
+                let argv = std::fs::read_to_string(call_dir.join("argv")).unwrap();
+                let env = std::fs::read_to_string(call_dir.join("env")).unwrap();
+                Invocation {
+                    args: argv.split_terminator('\0').map(|a| a.to_string()).collect(),
+                    env: env
+                        .lines()
+                        .filter_map(|line| line.split_once('='))
+                        .map(|(k, v)| (k.to_string(), v.to_string()))
+                        .collect(),
+                }
+            })
+            .collect()
+    }
+
+    fn write_response(&self, name: &str, output: &CommandOutput) {
+        let response_dir = self.dir.path().join("responses").join(name);
+        std::fs::create_dir_all(&response_dir).unwrap();
+        std::fs::write(response_dir.join("stdout"), &output.stdout).unwrap();
+        std::fs::write(response_dir.join("stderr"), &output.stderr).unwrap();
+        std::fs::write(response_dir.join("exit_code"), output.exit_code.to_string()).unwrap();
+    }
+}
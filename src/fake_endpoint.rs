@@ -0,0 +1,95 @@
+//! A local HTTP stand-in for AWS service endpoints, so the native executor can be tested
+//! without network access or credentials.
+
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpListener;
+
+use crate::protocol::{HttpRequest, HttpResponse};
+
+/// Records every request and answers with the scripted responses in order, then with an
+/// empty JSON object. Received requests keep the raw request target in `path`.
+pub struct FakeEndpoint {
+    url: String,
+    requests: Arc<Mutex<Vec<HttpRequest>>>,
+    responses: Arc<Mutex<VecDeque<HttpResponse>>>,
+}
+
+impl FakeEndpoint {
+    pub async fn start() -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let responses = Arc::new(Mutex::new(VecDeque::new()));
+        let (received, scripted) = (requests.clone(), responses.clone());
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let mut stream = BufReader::new(stream);
+                let Some(request) = read_request(&mut stream).await else {
+                    continue;
+                };
+                received.lock().unwrap().push(request);
+                let response = scripted.lock().unwrap().pop_front().unwrap_or_else(|| HttpResponse {
+                    status: 200,
+                    headers: vec![],
+                    body: b"{}".to_vec(),
+                });
+                let mut head = format!(
+                    "HTTP/1.1 {} Fake\r\nContent-Length: {}\r\nConnection: close\r\n",
+                    response.status,
+                    response.body.len()
+                );
+                for (name, value) in &response.headers {
+                    head.push_str(&format!("{}: {}\r\n", name, value));
+                }
+                head.push_str("\r\n");
+                let stream = stream.get_mut();
+                let _ = stream.write_all(head.as_bytes()).await;
+                let _ = stream.write_all(&response.body).await;
+                let _ = stream.shutdown().await;
+            }
+        });
+        Self { url, requests, responses }
+    }
+
+    /// The base URL of the endpoint, e.g. `http://127.0.0.1:41234`
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    /// Scripts the response to the request following the previously scripted ones
+    pub fn respond(&self, status: u16, body: &str) -> &Self {
+        self.responses.lock().unwrap().push_back(HttpResponse {
+            status,
+            headers: vec![],
+            body: body.as_bytes().to_vec(),
+        });
+        self
+    }
+
+    /// Returns all requests received so far, in order
+    pub fn requests(&self) -> Vec<HttpRequest> {
+        self.requests.lock().unwrap().clone()
+    }
+}
+
+async fn read_request(stream: &mut BufReader<tokio::net::TcpStream>) -> Option<HttpRequest> {
+    let mut line = String::new();
+    stream.read_line(&mut line).await.ok()?;
+    let mut parts = line.split_whitespace();
+    let mut request = HttpRequest::new(parts.next()?, parts.next()?);
+    loop {
+        line.clear();
+        stream.read_line(&mut line).await.ok()?;
+        let Some((name, value)) = line.trim_end().split_once(':') else {
+            break;
+        };
+        request.headers.push((name.to_string(), value.trim().to_string()));
+    }
+    let length = request.header("content-length").and_then(|length| length.parse().ok()).unwrap_or(0);
+    request.body = vec![0; length];
+    stream.read_exact(&mut request.body).await.ok()?;
+    Some(request)
+}
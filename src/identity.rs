@@ -0,0 +1,240 @@
+//! Identity preflight: which account and principal a profile acts as. Profile names differ
+//! between teammates, but account ids don't, so the resolved account is shown before commands
+//! run and account policies match on it.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+
+use crate::executor::AwsExecutor;
+use crate::use_aws::UseAws;
+
+/// How long a failed resolution is remembered before it is tried again, e.g. after
+/// `aws sso login`
+const FAILURE_TTL: Duration = Duration::from_secs(60);
+
+/// How long a resolved identity is used before it is resolved again, so signing in as a
+/// different principal under the same profile is picked up
+const IDENTITY_TTL: Duration = Duration::from_secs(300);
+
+/// The account and principal a command acts as
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Identity {
+    pub account: String,
+    /// The ARN of the principal, e.g. `arn:aws:sts::123456789012:assumed-role/Admin/alice`
+    pub arn: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub account_alias: Option<String>,
+}
+
+impl std::fmt::Display for Identity {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.account_alias {
+            Some(alias) => write!(f, "{} ({})", self.account, alias),
+            None => write!(f, "{}", self.account),
+        }
+    }
+}
+
+/// The output of `sts get-caller-identity`
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct CallerIdentity {
+    account: String,
+    arn: String,
+}
+
+/// The output of `iam list-account-aliases`
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct AccountAliases {
+    account_aliases: Vec<String>,
+}
+
+#[derive(Debug, Clone)]
+enum Resolution {
+    Resolved(Identity, Instant),
+    Failed(Instant),
+}
+
+/// Resolves and caches the identity of each profile, region and endpoint
+#[derive(Debug)]
+pub struct IdentityResolver {
+    resolutions: Mutex<HashMap<String, Resolution>>,
+    /// Account aliases by account id, none if the account has none or they can't be listed
+    aliases: Mutex<HashMap<String, Option<String>>>,
+    /// How long a resolved identity is used
+    ttl: Duration,
+}
+
+impl Default for IdentityResolver {
+    fn default() -> Self {
+        Self {
+            resolutions: Mutex::default(),
+            aliases: Mutex::default(),
+            ttl: IDENTITY_TTL,
+        }
+    }
+}
+
+impl IdentityResolver {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Uses resolved identities for `ttl` instead of five minutes
+    pub fn with_ttl(mut self, ttl: Duration) -> Self {
+        self.ttl = ttl;
+        self
+    }
+
+    /// Returns the identity `command` acts as, running `sts get-caller-identity` and
+    /// `iam list-account-aliases` through `executor` once the previous resolution expired.
+    /// None if the identity can't be resolved, e.g. for lack of credentials.
+    pub async fn resolve(&self, executor: &dyn AwsExecutor, command: &UseAws) -> Option<Identity> {
+        let key = format!(
+            "{}\n{}\n{}",
+            command.profile_name.as_deref().unwrap_or_default(),
+            command.region,
+            command.endpoint_url.as_deref().unwrap_or_default()
+        );
+        match self.resolutions.lock().unwrap_or_else(|e| e.into_inner()).get(&key) {
+            Some(Resolution::Resolved(identity, at)) if at.elapsed() < self.ttl => return Some(identity.clone()),
+            Some(Resolution::Failed(at)) if at.elapsed() < FAILURE_TTL => return None,
+            _ => {},
+        }
+
+        let resolution = match self.caller_identity(executor, command).await {
+            Ok(caller) => {
+                let account_alias = self.alias(executor, command, &caller.account).await;
+                Resolution::Resolved(
+                    Identity {
+                        account: caller.account,
+                        arn: caller.arn,
+                        account_alias,
+                    },
+                    Instant::now(),
+                )
+            },
+            Err(e) => {
+                tracing::warn!(
+                    "Unable to resolve the identity of profile '{}': {:#}",
+                    command.profile_name.as_deref().unwrap_or("default"),
+                    e
+                );
+                Resolution::Failed(Instant::now())
+            },
+        };
+        self.resolutions.lock().unwrap_or_else(|e| e.into_inner()).insert(key, resolution.clone());
+        match resolution {
+            Resolution::Resolved(identity, _) => Some(identity),
+            Resolution::Failed(_) => None,
+        }
+    }
+
+    async fn caller_identity(&self, executor: &dyn AwsExecutor, command: &UseAws) -> eyre::Result<CallerIdentity> {
+        let stdout = run(executor, command, "sts", "get-caller-identity").await?;
+        Ok(serde_json::from_str(&stdout)?)
+    }
+
+    /// The alias of `account`, looked up once per account. Listing aliases needs
+    /// `iam:ListAccountAliases`, which many roles lack, so failures are only logged.
+    async fn alias(&self, executor: &dyn AwsExecutor, command: &UseAws, account: &str) -> Option<String> {
+        if let Some(alias) = self.aliases.lock().unwrap_or_else(|e| e.into_inner()).get(account) {
+            return alias.clone();
+        }
+        let alias = match run(executor, command, "iam", "list-account-aliases").await {
+            Ok(stdout) => serde_json::from_str::<AccountAliases>(&stdout)
+                .ok()
+                .and_then(|aliases| aliases.account_aliases.into_iter().next()),
+            Err(e) => {
+                tracing::debug!("Unable to list the aliases of account {}: {:#}", account, e);
+                None
+            },
+        };
+        self.aliases
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .insert(account.to_string(), alias.clone());
+        alias
+    }
+}
+
+/// Runs `service_name operation_name` with the profile, region and endpoint of `command` and
+/// returns its stdout. The result cache is bypassed, so identities expire as configured here.
+async fn run(executor: &dyn AwsExecutor, command: &UseAws, service_name: &str, operation_name: &str) -> eyre::Result<String> {
+    let mut preflight: UseAws = serde_json::from_value(serde_json::json!({
+        "service_name": service_name,
+        "operation_name": operation_name,
+        "region": command.region,
+        "profile_name": command.profile_name,
+        "endpoint": command.endpoint,
+        "label": "Identity preflight",
+        "cache": "bypass"
+    }))?;
+    preflight.endpoint_url = command.endpoint_url.clone();
+    let output = executor.execute(&preflight).await?;
+    if output.exit_code != 0 {
+        eyre::bail!("{}", output.stderr.trim());
+    }
+    Ok(output.stdout)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::executor::{CommandOutput, MockExecutor};
+
+    fn command(profile_name: &str) -> UseAws {
+        serde_json::from_value(serde_json::json!({
+            "service_name": "ec2",
+            "operation_name": "reboot-instances",
+            "region": "us-east-1",
+            "profile_name": profile_name
+        }))
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_resolve() {
+        let executor = MockExecutor::new()
+            .respond_to(
+                "sts",
+                "get-caller-identity",
+                CommandOutput::success(r#"{"UserId": "AROA:alice", "Account": "123456789012", "Arn": "arn:aws:sts::123456789012:assumed-role/Admin/alice"}"#),
+            )
+            .respond_to("iam", "list-account-aliases", CommandOutput::success(r#"{"AccountAliases": ["acme-prod"]}"#))
+            .respond_to("sts", "get-caller-identity", CommandOutput::failure(255, "Error when retrieving token from sso: Token has expired and refresh failed"));
+        let resolver = IdentityResolver::new();
+
+        let identity = resolver.resolve(&executor, &command("prod")).await.unwrap();
+        assert_eq!(identity.to_string(), "123456789012 (acme-prod)");
+        assert_eq!(identity.arn, "arn:aws:sts::123456789012:assumed-role/Admin/alice");
+        assert_eq!(resolver.resolve(&executor, &command("prod")).await, Some(identity));
+
+        // Failures are remembered for a while too
+        assert_eq!(resolver.resolve(&executor, &command("expired")).await, None);
+        assert_eq!(resolver.resolve(&executor, &command("expired")).await, None);
+        let calls = executor.calls();
+        assert_eq!(calls.len(), 3);
+        assert_eq!(calls[2].profile_name.as_deref(), Some("expired"));
+    }
+
+    #[tokio::test]
+    async fn test_resolve_expires() {
+        let executor = MockExecutor::new()
+            .respond_to("sts", "get-caller-identity", CommandOutput::success(r#"{"Account": "111111111111", "Arn": "arn:aws:sts::111111111111:assumed-role/ReadOnly/alice"}"#))
+            .respond_to("sts", "get-caller-identity", CommandOutput::success(r#"{"Account": "111111111111", "Arn": "arn:aws:sts::111111111111:assumed-role/Admin/alice"}"#))
+            .respond_to("iam", "list-account-aliases", CommandOutput::success(r#"{"AccountAliases": []}"#));
+        let resolver = IdentityResolver::new().with_ttl(Duration::ZERO);
+
+        let identity = resolver.resolve(&executor, &command("prod")).await.unwrap();
+        assert_eq!(identity.arn, "arn:aws:sts::111111111111:assumed-role/ReadOnly/alice");
+        // Signed in again as another role
+        let identity = resolver.resolve(&executor, &command("prod")).await.unwrap();
+        assert_eq!(identity.arn, "arn:aws:sts::111111111111:assumed-role/Admin/alice");
+        assert!(executor.calls().iter().all(|call| call.cache == Some(crate::cache::CacheMode::Bypass)));
+    }
+}
@@ -0,0 +1,1015 @@
+//! A [JMESPath](https://jmespath.org/specification.html) interpreter for the `query` field.
+//! Queries are evaluated by the server on the parsed command output rather than passed to
+//! the AWS CLI as `--query`, so they also apply to paginated and recorded results. Like the
+//! AWS CLI, ordering comparisons are only defined between numbers.
+
+use std::cmp::Ordering;
+
+use serde_json::{Map, Value};
+
+/// Errors raised when parsing or evaluating a query
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum QueryError {
+    #[error("Invalid query at position {position}: {message}")]
+    Syntax { position: usize, message: String },
+    #[error("Unknown function '{0}()' in query")]
+    UnknownFunction(String),
+    #[error("{function}() takes {expected} arguments, got {actual}")]
+    Arity {
+        function: String,
+        expected: String,
+        actual: usize,
+    },
+    #[error("{function}() expected {expected}, got {actual}")]
+    InvalidType {
+        function: String,
+        expected: String,
+        actual: String,
+    },
+}
+
+type Result<T> = std::result::Result<T, QueryError>;
+
+/// A parsed query
+#[derive(Debug, Clone, PartialEq)]
+pub struct Expression {
+    root: Node,
+}
+
+impl Expression {
+    pub fn parse(expression: &str) -> Result<Self> {
+        let tokens = tokenize(expression)?;
+        let mut parser = Parser { tokens, index: 0 };
+        let root = parser.expression(0)?;
+        match parser.current() {
+            Token::Eof => Ok(Self { root }),
+            token => Err(parser.error(format!("unexpected {}", token.describe()))),
+        }
+    }
+
+    /// Evaluates the query against `value`
+    pub fn search(&self, value: &Value) -> Result<Value> {
+        evaluate(&self.root, value)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Comparator {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Identifier(String),
+    QuotedIdentifier(String),
+    Number(i64),
+    Literal(Value),
+    Comparator(Comparator),
+    Dot,
+    Star,
+    Flatten,
+    Filter,
+    LBracket,
+    RBracket,
+    LBrace,
+    RBrace,
+    LParen,
+    RParen,
+    Comma,
+    Colon,
+    Pipe,
+    Or,
+    And,
+    Not,
+    At,
+    Ampersand,
+    Eof,
+}
+
+impl Token {
+    fn binding_power(&self) -> u8 {
+        match self {
+            Token::Pipe => 1,
+            Token::Or => 2,
+            Token::And => 3,
+            Token::Comparator(_) => 5,
+            Token::Flatten => 9,
+            Token::Star => 20,
+            Token::Filter => 21,
+            Token::Dot => 40,
+            Token::Not => 45,
+            Token::LBrace => 50,
+            Token::LBracket => 55,
+            Token::LParen => 60,
+            _ => 0,
+        }
+    }
+
+    fn describe(&self) -> String {
+        match self {
+            Token::Identifier(name) | Token::QuotedIdentifier(name) => format!("identifier '{}'", name),
+            Token::Number(number) => format!("number {}", number),
+            Token::Literal(value) => format!("literal {}", value),
+            Token::Comparator(_) => "comparator".to_string(),
+            Token::Dot => "'.'".to_string(),
+            Token::Star => "'*'".to_string(),
+            Token::Flatten => "'[]'".to_string(),
+            Token::Filter => "'[?'".to_string(),
+            Token::LBracket => "'['".to_string(),
+            Token::RBracket => "']'".to_string(),
+            Token::LBrace => "'{'".to_string(),
+            Token::RBrace => "'}'".to_string(),
+            Token::LParen => "'('".to_string(),
+            Token::RParen => "')'".to_string(),
+            Token::Comma => "','".to_string(),
+            Token::Colon => "':'".to_string(),
+            Token::Pipe => "'|'".to_string(),
+            Token::Or => "'||'".to_string(),
+            Token::And => "'&&'".to_string(),
+            Token::Not => "'!'".to_string(),
+            Token::At => "'@'".to_string(),
+            Token::Ampersand => "'&'".to_string(),
+            Token::Eof => "end of query".to_string(),
+        }
+    }
+}
+
+fn tokenize(expression: &str) -> Result<Vec<(Token, usize)>> {
+    let chars: Vec<(usize, char)> = expression.char_indices().collect();
+    let syntax = |position: usize, message: String| QueryError::Syntax { position, message };
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    while i < chars.len() {
+        let (position, c) = chars[i];
+        let next = chars.get(i + 1).map(|(_, c)| *c);
+        let (token, length) = match c {
+            c if c.is_whitespace() => {
+                i += 1;
+                continue;
+            },
+            'a'..='z' | 'A'..='Z' | '_' => {
+                let length = chars[i..]
+                    .iter()
+                    .take_while(|(_, c)| c.is_ascii_alphanumeric() || *c == '_')
+                    .count();
+                let name: String = chars[i..i + length].iter().map(|(_, c)| c).collect();
+                (Token::Identifier(name), length)
+            },
+            '0'..='9' | '-' => {
+                let digits = chars[i + 1..].iter().take_while(|(_, c)| c.is_ascii_digit()).count();
+                let text: String = chars[i..=i + digits].iter().map(|(_, c)| c).collect();
+                let number = text
+                    .parse()
+                    .map_err(|_| syntax(position, format!("invalid number '{}'", text)))?;
+                (Token::Number(number), digits + 1)
+            },
+            '"' | '\'' | '`' => {
+                let end = chars[i + 1..]
+                    .iter()
+                    .enumerate()
+                    .scan(false, |escaped, (offset, (_, ch))| {
+                        let closes = !*escaped && *ch == c;
+                        *escaped = !*escaped && *ch == '\\';
+                        Some((offset, closes))
+                    })
+                    .find(|(_, closes)| *closes)
+                    .map(|(offset, _)| i + 1 + offset)
+                    .ok_or_else(|| syntax(position, format!("unterminated {}", c)))?;
+                let raw: String = chars[i + 1..end].iter().map(|(_, c)| c).collect();
+                let token = match c {
+                    '"' => Token::QuotedIdentifier(
+                        serde_json::from_str(&format!("\"{}\"", raw))
+                            .map_err(|_| syntax(position, format!("invalid quoted identifier \"{}\"", raw)))?,
+                    ),
+                    '\'' => Token::Literal(Value::String(raw.replace("\\'", "'").replace("\\\\", "\\"))),
+                    _ => Token::Literal(
+                        serde_json::from_str(raw.replace("\\`", "`").trim())
+                            .map_err(|_| syntax(position, format!("invalid JSON literal `{}`", raw)))?,
+                    ),
+                };
+                (token, end - i + 1)
+            },
+            '[' => match next {
+                Some(']') => (Token::Flatten, 2),
+                Some('?') => (Token::Filter, 2),
+                _ => (Token::LBracket, 1),
+            },
+            '|' if next == Some('|') => (Token::Or, 2),
+            '|' => (Token::Pipe, 1),
+            '&' if next == Some('&') => (Token::And, 2),
+            '&' => (Token::Ampersand, 1),
+            '!' if next == Some('=') => (Token::Comparator(Comparator::Ne), 2),
+            '!' => (Token::Not, 1),
+            '=' if next == Some('=') => (Token::Comparator(Comparator::Eq), 2),
+            '<' if next == Some('=') => (Token::Comparator(Comparator::Le), 2),
+            '<' => (Token::Comparator(Comparator::Lt), 1),
+            '>' if next == Some('=') => (Token::Comparator(Comparator::Ge), 2),
+            '>' => (Token::Comparator(Comparator::Gt), 1),
+            '.' => (Token::Dot, 1),
+            '*' => (Token::Star, 1),
+            ']' => (Token::RBracket, 1),
+            '{' => (Token::LBrace, 1),
+            '}' => (Token::RBrace, 1),
+            '(' => (Token::LParen, 1),
+            ')' => (Token::RParen, 1),
+            ',' => (Token::Comma, 1),
+            ':' => (Token::Colon, 1),
+            '@' => (Token::At, 1),
+            c => return Err(syntax(position, format!("unexpected character '{}'", c))),
+        };
+        tokens.push((token, position));
+        i += length;
+    }
+    tokens.push((Token::Eof, expression.len()));
+    Ok(tokens)
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Node {
+    Identity,
+    Field(String),
+    Subexpression(Box<Node>, Box<Node>),
+    Index(i64),
+    Slice(Option<i64>, Option<i64>, Option<i64>),
+    /// Applies the right side to every element of the array on the left
+    Projection(Box<Node>, Box<Node>),
+    /// Applies the right side to every value of the object on the left
+    ValueProjection(Box<Node>, Box<Node>),
+    /// A projection over the elements matching a condition
+    FilterProjection(Box<Node>, Box<Node>, Box<Node>),
+    Flatten(Box<Node>),
+    Literal(Value),
+    MultiSelectList(Vec<Node>),
+    MultiSelectHash(Vec<(String, Node)>),
+    Compare(Comparator, Box<Node>, Box<Node>),
+    Or(Box<Node>, Box<Node>),
+    And(Box<Node>, Box<Node>),
+    Not(Box<Node>),
+    Pipe(Box<Node>, Box<Node>),
+    Function(String, Vec<Node>),
+    ExpressionReference(Box<Node>),
+}
+
+/// A Pratt parser following the grammar and binding powers of the reference implementation
+struct Parser {
+    tokens: Vec<(Token, usize)>,
+    index: usize,
+}
+
+impl Parser {
+    fn current(&self) -> &Token {
+        self.lookahead(0)
+    }
+
+    fn lookahead(&self, offset: usize) -> &Token {
+        self.tokens
+            .get(self.index + offset)
+            .map_or(&Token::Eof, |(token, _)| token)
+    }
+
+    fn advance(&mut self) -> Token {
+        let token = self.current().clone();
+        self.index += 1;
+        token
+    }
+
+    /// A syntax error at the current token
+    fn error(&self, message: String) -> QueryError {
+        let (_, position) = self.tokens.get(self.index).or(self.tokens.last()).expect("tokens end with Eof");
+        QueryError::Syntax {
+            position: *position,
+            message,
+        }
+    }
+
+    fn expect(&mut self, expected: Token) -> Result<()> {
+        if *self.current() == expected {
+            self.advance();
+            Ok(())
+        } else {
+            Err(self.error(format!("expected {}, found {}", expected.describe(), self.current().describe())))
+        }
+    }
+
+    fn expression(&mut self, binding_power: u8) -> Result<Node> {
+        let token = self.advance();
+        let mut left = self.nud(token)?;
+        while binding_power < self.current().binding_power() {
+            let token = self.advance();
+            left = self.led(token, left)?;
+        }
+        Ok(left)
+    }
+
+    fn nud(&mut self, token: Token) -> Result<Node> {
+        match token {
+            Token::Literal(value) => Ok(Node::Literal(value)),
+            Token::Identifier(name) => Ok(Node::Field(name)),
+            Token::QuotedIdentifier(name) => {
+                if *self.current() == Token::LParen {
+                    return Err(self.error("quoted identifiers can't be function names".to_string()));
+                }
+                Ok(Node::Field(name))
+            },
+            Token::Star => {
+                let right = if *self.current() == Token::RBracket {
+                    Node::Identity
+                } else {
+                    self.projection_rhs(Token::Star.binding_power())?
+                };
+                Ok(Node::ValueProjection(Box::new(Node::Identity), Box::new(right)))
+            },
+            Token::Filter => self.led(Token::Filter, Node::Identity),
+            Token::LBrace => self.multi_select_hash(),
+            Token::LParen => {
+                let inner = self.expression(0)?;
+                self.expect(Token::RParen)?;
+                Ok(inner)
+            },
+            Token::Flatten => {
+                let left = Node::Flatten(Box::new(Node::Identity));
+                let right = self.projection_rhs(Token::Flatten.binding_power())?;
+                Ok(Node::Projection(Box::new(left), Box::new(right)))
+            },
+            Token::Not => Ok(Node::Not(Box::new(self.expression(Token::Not.binding_power())?))),
+            Token::LBracket => match (self.current(), self.lookahead(1)) {
+                (Token::Number(_) | Token::Colon, _) => {
+                    let right = self.index_expression()?;
+                    self.project_if_slice(Node::Identity, right)
+                },
+                (Token::Star, Token::RBracket) => {
+                    self.advance();
+                    self.advance();
+                    let right = self.projection_rhs(Token::Star.binding_power())?;
+                    Ok(Node::Projection(Box::new(Node::Identity), Box::new(right)))
+                },
+                _ => self.multi_select_list(),
+            },
+            Token::At => Ok(Node::Identity),
+            Token::Ampersand => Ok(Node::ExpressionReference(Box::new(self.expression(0)?))),
+            token => {
+                self.index -= 1;
+                Err(self.error(format!("unexpected {}", token.describe())))
+            },
+        }
+    }
+
+    fn led(&mut self, token: Token, left: Node) -> Result<Node> {
+        let binding_power = token.binding_power();
+        match token {
+            Token::Dot => {
+                if *self.current() == Token::Star {
+                    self.advance();
+                    let right = self.projection_rhs(binding_power)?;
+                    Ok(Node::ValueProjection(Box::new(left), Box::new(right)))
+                } else {
+                    let right = self.dot_rhs(binding_power)?;
+                    Ok(Node::Subexpression(Box::new(left), Box::new(right)))
+                }
+            },
+            Token::Pipe => Ok(Node::Pipe(Box::new(left), Box::new(self.expression(binding_power)?))),
+            Token::Or => Ok(Node::Or(Box::new(left), Box::new(self.expression(binding_power)?))),
+            Token::And => Ok(Node::And(Box::new(left), Box::new(self.expression(binding_power)?))),
+            Token::Comparator(comparator) => Ok(Node::Compare(
+                comparator,
+                Box::new(left),
+                Box::new(self.expression(binding_power)?),
+            )),
+            Token::LParen => {
+                let Node::Field(name) = left else {
+                    self.index -= 1;
+                    return Err(self.error("only function names can be called".to_string()));
+                };
+                let mut arguments = Vec::new();
+                while *self.current() != Token::RParen {
+                    arguments.push(self.expression(0)?);
+                    if *self.current() == Token::Comma {
+                        self.advance();
+                    }
+                }
+                self.expect(Token::RParen)?;
+                check_arity(&name, arguments.len())?;
+                Ok(Node::Function(name, arguments))
+            },
+            Token::Filter => {
+                let condition = self.expression(0)?;
+                self.expect(Token::RBracket)?;
+                let right = if *self.current() == Token::Flatten {
+                    Node::Identity
+                } else {
+                    self.projection_rhs(binding_power)?
+                };
+                Ok(Node::FilterProjection(Box::new(left), Box::new(right), Box::new(condition)))
+            },
+            Token::Flatten => {
+                let left = Node::Flatten(Box::new(left));
+                let right = self.projection_rhs(binding_power)?;
+                Ok(Node::Projection(Box::new(left), Box::new(right)))
+            },
+            Token::LBracket => match self.current() {
+                Token::Number(_) | Token::Colon => {
+                    let right = self.index_expression()?;
+                    self.project_if_slice(left, right)
+                },
+                _ => {
+                    self.expect(Token::Star)?;
+                    self.expect(Token::RBracket)?;
+                    let right = self.projection_rhs(Token::Star.binding_power())?;
+                    Ok(Node::Projection(Box::new(left), Box::new(right)))
+                },
+            },
+            token => {
+                self.index -= 1;
+                Err(self.error(format!("unexpected {}", token.describe())))
+            },
+        }
+    }
+
+    fn project_if_slice(&mut self, left: Node, right: Node) -> Result<Node> {
+        let is_slice = matches!(right, Node::Slice(..));
+        let indexed = Node::Subexpression(Box::new(left), Box::new(right));
+        if is_slice {
+            let right = self.projection_rhs(Token::Star.binding_power())?;
+            Ok(Node::Projection(Box::new(indexed), Box::new(right)))
+        } else {
+            Ok(indexed)
+        }
+    }
+
+    /// Parses `n]` or a slice `start:stop:step]` after an opening bracket
+    fn index_expression(&mut self) -> Result<Node> {
+        if *self.current() != Token::Colon && *self.lookahead(1) != Token::Colon {
+            let Token::Number(index) = self.advance() else {
+                unreachable!("index expressions start with a number or a colon");
+            };
+            self.expect(Token::RBracket)?;
+            return Ok(Node::Index(index));
+        }
+        let mut parts = [None; 3];
+        let mut part = 0;
+        while *self.current() != Token::RBracket {
+            match self.current() {
+                Token::Colon if part < 2 => part += 1,
+                Token::Number(number) => parts[part] = Some(*number),
+                token => return Err(self.error(format!("unexpected {} in slice", token.describe()))),
+            }
+            self.advance();
+        }
+        self.expect(Token::RBracket)?;
+        if parts[2] == Some(0) {
+            self.index -= 1;
+            return Err(self.error("slice step can't be 0".to_string()));
+        }
+        Ok(Node::Slice(parts[0], parts[1], parts[2]))
+    }
+
+    fn projection_rhs(&mut self, binding_power: u8) -> Result<Node> {
+        match self.current() {
+            token if token.binding_power() < 10 => Ok(Node::Identity),
+            Token::LBracket | Token::Filter => self.expression(binding_power),
+            Token::Dot => {
+                self.advance();
+                self.dot_rhs(binding_power)
+            },
+            token => Err(self.error(format!("unexpected {} after projection", token.describe()))),
+        }
+    }
+
+    fn dot_rhs(&mut self, binding_power: u8) -> Result<Node> {
+        match self.current() {
+            Token::Identifier(_) | Token::QuotedIdentifier(_) | Token::Star => self.expression(binding_power),
+            Token::LBracket => {
+                self.advance();
+                self.multi_select_list()
+            },
+            Token::LBrace => {
+                self.advance();
+                self.multi_select_hash()
+            },
+            token => Err(self.error(format!("expected identifier after '.', found {}", token.describe()))),
+        }
+    }
+
+    fn multi_select_list(&mut self) -> Result<Node> {
+        let mut items = vec![self.expression(0)?];
+        while *self.current() == Token::Comma {
+            self.advance();
+            items.push(self.expression(0)?);
+        }
+        self.expect(Token::RBracket)?;
+        Ok(Node::MultiSelectList(items))
+    }
+
+    fn multi_select_hash(&mut self) -> Result<Node> {
+        let mut pairs = Vec::new();
+        loop {
+            let key = match self.advance() {
+                Token::Identifier(key) | Token::QuotedIdentifier(key) => key,
+                token => {
+                    self.index -= 1;
+                    return Err(self.error(format!("expected a key, found {}", token.describe())));
+                },
+            };
+            self.expect(Token::Colon)?;
+            pairs.push((key, self.expression(0)?));
+            match self.advance() {
+                Token::Comma => continue,
+                Token::RBrace => return Ok(Node::MultiSelectHash(pairs)),
+                token => {
+                    self.index -= 1;
+                    return Err(self.error(format!("expected ',' or '}}', found {}", token.describe())));
+                },
+            }
+        }
+    }
+}
+
+fn evaluate(node: &Node, value: &Value) -> Result<Value> {
+    Ok(match node {
+        Node::Identity => value.clone(),
+        Node::Field(name) => value.get(name).cloned().unwrap_or(Value::Null),
+        Node::Subexpression(left, right) | Node::Pipe(left, right) => evaluate(right, &evaluate(left, value)?)?,
+        Node::Index(index) => match value.as_array() {
+            Some(items) => resolve_index(items.len(), *index)
+                .and_then(|index| items.get(index))
+                .cloned()
+                .unwrap_or(Value::Null),
+            None => Value::Null,
+        },
+        Node::Slice(start, stop, step) => match value {
+            Value::Array(items) => Value::Array(slice(items, *start, *stop, *step)),
+            _ => Value::Null,
+        },
+        Node::Projection(left, right) => match evaluate(left, value)? {
+            Value::Array(items) => project(items.iter(), right)?,
+            _ => Value::Null,
+        },
+        Node::ValueProjection(left, right) => match evaluate(left, value)? {
+            Value::Object(entries) => project(entries.values(), right)?,
+            _ => Value::Null,
+        },
+        Node::FilterProjection(left, right, condition) => match evaluate(left, value)? {
+            Value::Array(items) => {
+                let mut matching = Vec::new();
+                for item in items {
+                    if is_truthy(&evaluate(condition, &item)?) {
+                        matching.push(item);
+                    }
+                }
+                project(matching.iter(), right)?
+            },
+            _ => Value::Null,
+        },
+        Node::Flatten(inner) => match evaluate(inner, value)? {
+            Value::Array(items) => Value::Array(
+                items
+                    .into_iter()
+                    .flat_map(|item| match item {
+                        Value::Array(nested) => nested,
+                        item => vec![item],
+                    })
+                    .collect(),
+            ),
+            _ => Value::Null,
+        },
+        Node::Literal(literal) => literal.clone(),
+        Node::MultiSelectList(_) | Node::MultiSelectHash(_) if value.is_null() => Value::Null,
+        Node::MultiSelectList(items) => {
+            Value::Array(items.iter().map(|item| evaluate(item, value)).collect::<Result<_>>()?)
+        },
+        Node::MultiSelectHash(pairs) => {
+            let mut object = Map::new();
+            for (key, item) in pairs {
+                object.insert(key.clone(), evaluate(item, value)?);
+            }
+            Value::Object(object)
+        },
+        Node::Compare(comparator, left, right) => compare(*comparator, &evaluate(left, value)?, &evaluate(right, value)?),
+        Node::Or(left, right) => {
+            let left = evaluate(left, value)?;
+            if is_truthy(&left) { left } else { evaluate(right, value)? }
+        },
+        Node::And(left, right) => {
+            let left = evaluate(left, value)?;
+            if is_truthy(&left) { evaluate(right, value)? } else { left }
+        },
+        Node::Not(inner) => Value::Bool(!is_truthy(&evaluate(inner, value)?)),
+        Node::Function(name, arguments) => call(name, arguments, value)?,
+        // Expression references are only meaningful as function arguments
+        Node::ExpressionReference(_) => Value::Null,
+    })
+}
+
+/// Applies `right` to each item, dropping null results
+fn project<'a>(items: impl Iterator<Item = &'a Value>, right: &Node) -> Result<Value> {
+    let mut projected = Vec::new();
+    for item in items {
+        let value = evaluate(right, item)?;
+        if !value.is_null() {
+            projected.push(value);
+        }
+    }
+    Ok(Value::Array(projected))
+}
+
+fn resolve_index(length: usize, index: i64) -> Option<usize> {
+    if index < 0 {
+        length.checked_sub(index.unsigned_abs() as usize)
+    } else {
+        Some(index as usize)
+    }
+}
+
+fn slice(items: &[Value], start: Option<i64>, stop: Option<i64>, step: Option<i64>) -> Vec<Value> {
+    let length = items.len() as i64;
+    let step = step.unwrap_or(1);
+    let clamp = |index: i64, low: i64, high: i64| {
+        let index = if index < 0 { index + length } else { index };
+        index.clamp(low, high)
+    };
+    let mut selected = Vec::new();
+    if step > 0 {
+        let start = start.map_or(0, |start| clamp(start, 0, length));
+        let stop = stop.map_or(length, |stop| clamp(stop, 0, length));
+        let mut i = Some(start);
+        while let Some(index) = i.filter(|index| *index < stop) {
+            selected.push(items[index as usize].clone());
+            i = index.checked_add(step);
+        }
+    } else {
+        let start = start.map_or(length - 1, |start| clamp(start, -1, length - 1));
+        let stop = stop.map_or(-1, |stop| clamp(stop, -1, length - 1));
+        let mut i = Some(start);
+        while let Some(index) = i.filter(|index| *index > stop) {
+            selected.push(items[index as usize].clone());
+            i = index.checked_add(step);
+        }
+    }
+    selected
+}
+
+fn is_truthy(value: &Value) -> bool {
+    match value {
+        Value::Null | Value::Bool(false) => false,
+        Value::String(s) => !s.is_empty(),
+        Value::Array(items) => !items.is_empty(),
+        Value::Object(entries) => !entries.is_empty(),
+        Value::Number(_) | Value::Bool(true) => true,
+    }
+}
+
+/// Compares JSON values, treating numbers of different representations as equal
+fn json_eq(left: &Value, right: &Value) -> bool {
+    match (left, right) {
+        (Value::Number(l), Value::Number(r)) => l.as_f64() == r.as_f64(),
+        (Value::Array(l), Value::Array(r)) => l.len() == r.len() && l.iter().zip(r).all(|(l, r)| json_eq(l, r)),
+        (Value::Object(l), Value::Object(r)) => {
+            l.len() == r.len() && l.iter().all(|(key, l)| r.get(key).is_some_and(|r| json_eq(l, r)))
+        },
+        (l, r) => l == r,
+    }
+}
+
+fn compare(comparator: Comparator, left: &Value, right: &Value) -> Value {
+    match comparator {
+        Comparator::Eq => Value::Bool(json_eq(left, right)),
+        Comparator::Ne => Value::Bool(!json_eq(left, right)),
+        _ => match (left.as_f64(), right.as_f64()) {
+            (Some(l), Some(r)) => Value::Bool(match comparator {
+                Comparator::Lt => l < r,
+                Comparator::Le => l <= r,
+                Comparator::Gt => l > r,
+                _ => l >= r,
+            }),
+            _ => Value::Null,
+        },
+    }
+}
+
+/// Converts a computed number back to JSON, as an integer when it has no fraction
+fn number(value: f64) -> Value {
+    if value.fract() == 0.0 && value.abs() < 9e15 {
+        Value::from(value as i64)
+    } else {
+        serde_json::Number::from_f64(value).map_or(Value::Null, Value::Number)
+    }
+}
+
+fn type_name(value: &Value) -> &'static str {
+    match value {
+        Value::Null => "null",
+        Value::Bool(_) => "boolean",
+        Value::Number(_) => "number",
+        Value::String(_) => "string",
+        Value::Array(_) => "array",
+        Value::Object(_) => "object",
+    }
+}
+
+/// Describes the type of a value for errors, including the element types of arrays
+fn describe(value: &Value) -> String {
+    match value {
+        Value::Array(items) if !items.is_empty() => {
+            let mut types: Vec<&str> = items.iter().map(type_name).collect();
+            types.sort();
+            types.dedup();
+            format!("array containing {}", types.join(", "))
+        },
+        value => type_name(value).to_string(),
+    }
+}
+
+/// Names and arities of the built-in functions; `None` as maximum means variadic
+const FUNCTIONS: [(&str, usize, Option<usize>); 26] = [
+    ("abs", 1, Some(1)),
+    ("avg", 1, Some(1)),
+    ("ceil", 1, Some(1)),
+    ("contains", 2, Some(2)),
+    ("ends_with", 2, Some(2)),
+    ("floor", 1, Some(1)),
+    ("join", 2, Some(2)),
+    ("keys", 1, Some(1)),
+    ("length", 1, Some(1)),
+    ("map", 2, Some(2)),
+    ("max", 1, Some(1)),
+    ("max_by", 2, Some(2)),
+    ("merge", 1, None),
+    ("min", 1, Some(1)),
+    ("min_by", 2, Some(2)),
+    ("not_null", 1, None),
+    ("reverse", 1, Some(1)),
+    ("sort", 1, Some(1)),
+    ("sort_by", 2, Some(2)),
+    ("starts_with", 2, Some(2)),
+    ("sum", 1, Some(1)),
+    ("to_array", 1, Some(1)),
+    ("to_number", 1, Some(1)),
+    ("to_string", 1, Some(1)),
+    ("type", 1, Some(1)),
+    ("values", 1, Some(1)),
+];
+
+fn check_arity(name: &str, actual: usize) -> Result<()> {
+    let (_, min, max) = FUNCTIONS
+        .iter()
+        .find(|(function, ..)| *function == name)
+        .ok_or_else(|| QueryError::UnknownFunction(name.to_string()))?;
+    let expected = match max {
+        Some(max) if actual > *max || actual < *min => min.to_string(),
+        None if actual < *min => format!("at least {}", min),
+        _ => return Ok(()),
+    };
+    Err(QueryError::Arity {
+        function: name.to_string(),
+        expected,
+        actual,
+    })
+}
+
+fn call(name: &str, arguments: &[Node], current: &Value) -> Result<Value> {
+    let invalid = |expected: &str, actual: &Value| QueryError::InvalidType {
+        function: name.to_string(),
+        expected: expected.to_string(),
+        actual: describe(actual),
+    };
+    // Expression references are passed unevaluated to the functions that take them
+    let expression_argument = |index: usize| match &arguments[index] {
+        Node::ExpressionReference(expression) => Ok(expression.as_ref()),
+        _ => Err(QueryError::InvalidType {
+            function: name.to_string(),
+            expected: "an expression reference like &Name".to_string(),
+            actual: "a value".to_string(),
+        }),
+    };
+    let values = arguments
+        .iter()
+        .map(|argument| match argument {
+            Node::ExpressionReference(_) => Ok(Value::Null),
+            argument => evaluate(argument, current),
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let first = &values[0];
+    let numbers = |value: &Value| -> Result<Vec<f64>> {
+        value
+            .as_array()
+            .and_then(|items| items.iter().map(Value::as_f64).collect::<Option<Vec<_>>>())
+            .ok_or_else(|| invalid("an array of numbers", value))
+    };
+
+    Ok(match name {
+        "abs" => number(first.as_f64().ok_or_else(|| invalid("a number", first))?.abs()),
+        "ceil" => number(first.as_f64().ok_or_else(|| invalid("a number", first))?.ceil()),
+        "floor" => number(first.as_f64().ok_or_else(|| invalid("a number", first))?.floor()),
+        "avg" => {
+            let numbers = numbers(first)?;
+            if numbers.is_empty() {
+                Value::Null
+            } else {
+                number(numbers.iter().sum::<f64>() / numbers.len() as f64)
+            }
+        },
+        "sum" => number(numbers(first)?.iter().sum()),
+        "contains" => match first {
+            Value::Array(items) => Value::Bool(items.iter().any(|item| json_eq(item, &values[1]))),
+            Value::String(s) => Value::Bool(values[1].as_str().is_some_and(|needle| s.contains(needle))),
+            _ => return Err(invalid("an array or a string", first)),
+        },
+        "starts_with" | "ends_with" => {
+            let s = first.as_str().ok_or_else(|| invalid("a string", first))?;
+            let affix = values[1].as_str().ok_or_else(|| invalid("a string", &values[1]))?;
+            Value::Bool(if name == "starts_with" { s.starts_with(affix) } else { s.ends_with(affix) })
+        },
+        "join" => {
+            let separator = first.as_str().ok_or_else(|| invalid("a string separator", first))?;
+            let parts = values[1]
+                .as_array()
+                .and_then(|items| items.iter().map(Value::as_str).collect::<Option<Vec<_>>>())
+                .ok_or_else(|| invalid("an array of strings", &values[1]))?;
+            Value::String(parts.join(separator))
+        },
+        "keys" | "values" => {
+            let entries = first.as_object().ok_or_else(|| invalid("an object", first))?;
+            if name == "keys" {
+                Value::Array(entries.keys().cloned().map(Value::String).collect())
+            } else {
+                Value::Array(entries.values().cloned().collect())
+            }
+        },
+        "length" => Value::from(match first {
+            Value::String(s) => s.chars().count(),
+            Value::Array(items) => items.len(),
+            Value::Object(entries) => entries.len(),
+            _ => return Err(invalid("a string, an array or an object", first)),
+        }),
+        "map" => {
+            let expression = expression_argument(0)?;
+            let items = values[1].as_array().ok_or_else(|| invalid("an array", &values[1]))?;
+            Value::Array(items.iter().map(|item| evaluate(expression, item)).collect::<Result<_>>()?)
+        },
+        "max" | "min" | "sort" => {
+            let items = first.as_array().ok_or_else(|| invalid("an array", first))?;
+            let mut sorted = items.clone();
+            sort_values(&mut sorted).map_err(|actual| invalid("an array of numbers or strings", &actual))?;
+            match name {
+                "max" => sorted.pop().unwrap_or(Value::Null),
+                "min" => sorted.into_iter().next().unwrap_or(Value::Null),
+                _ => Value::Array(sorted),
+            }
+        },
+        "max_by" | "min_by" | "sort_by" => {
+            let expression = expression_argument(1)?;
+            let items = first.as_array().ok_or_else(|| invalid("an array", first))?;
+            let mut keyed = Vec::with_capacity(items.len());
+            for item in items {
+                keyed.push((evaluate(expression, item)?, item.clone()));
+            }
+            let mut keys: Vec<Value> = keyed.iter().map(|(key, _)| key.clone()).collect();
+            sort_values(&mut keys).map_err(|actual| invalid("expression results that are numbers or strings", &actual))?;
+            keyed.sort_by(|(l, _), (r, _)| order(l, r));
+            match name {
+                "max_by" => keyed.pop().map_or(Value::Null, |(_, item)| item),
+                "min_by" => keyed.into_iter().next().map_or(Value::Null, |(_, item)| item),
+                _ => Value::Array(keyed.into_iter().map(|(_, item)| item).collect()),
+            }
+        },
+        "merge" => {
+            let mut merged = Map::new();
+            for value in &values {
+                merged.extend(value.as_object().ok_or_else(|| invalid("objects", value))?.clone());
+            }
+            Value::Object(merged)
+        },
+        "not_null" => values.into_iter().find(|value| !value.is_null()).unwrap_or(Value::Null),
+        "reverse" => match first {
+            Value::Array(items) => Value::Array(items.iter().rev().cloned().collect()),
+            Value::String(s) => Value::String(s.chars().rev().collect()),
+            _ => return Err(invalid("an array or a string", first)),
+        },
+        "to_array" => match first {
+            Value::Array(_) => first.clone(),
+            value => Value::Array(vec![value.clone()]),
+        },
+        "to_number" => match first {
+            Value::Number(_) => first.clone(),
+            Value::String(s) => s
+                .parse::<i64>()
+                .map(Value::from)
+                .or_else(|_| s.parse::<f64>().map(number))
+                .unwrap_or(Value::Null),
+            _ => Value::Null,
+        },
+        "to_string" => match first {
+            Value::String(_) => first.clone(),
+            value => Value::String(value.to_string()),
+        },
+        "type" => Value::String(type_name(first).to_string()),
+        name => return Err(QueryError::UnknownFunction(name.to_string())),
+    })
+}
+
+/// Sorts numbers or strings, failing with the first value of another type or a mix of both
+fn sort_values(values: &mut [Value]) -> std::result::Result<(), Value> {
+    let kind = values.first().map(type_name);
+    if let Some(value) = values
+        .iter()
+        .find(|value| !matches!(value, Value::Number(_) | Value::String(_)) || Some(type_name(value)) != kind)
+    {
+        return Err(value.clone());
+    }
+    values.sort_by(order);
+    Ok(())
+}
+
+fn order(left: &Value, right: &Value) -> Ordering {
+    match (left, right) {
+        (Value::Number(l), Value::Number(r)) => l.as_f64().partial_cmp(&r.as_f64()).unwrap_or(Ordering::Equal),
+        (Value::String(l), Value::String(r)) => l.cmp(r),
+        _ => Ordering::Equal,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn search(expression: &str, value: &Value) -> Value {
+        Expression::parse(expression).unwrap().search(value).unwrap()
+    }
+
+    fn instances() -> Value {
+        json!({
+            "Reservations": [
+                {"Instances": [
+                    {"InstanceId": "i-1", "State": {"Name": "running"}, "CpuCount": 2, "Tags": [{"Key": "Name", "Value": "web"}]},
+                    {"InstanceId": "i-2", "State": {"Name": "stopped"}, "CpuCount": 8, "Tags": []}
+                ]},
+                {"Instances": [
+                    {"InstanceId": "i-3", "State": {"Name": "running"}, "CpuCount": 4}
+                ]}
+            ]
+        })
+    }
+
+    #[test]
+    fn test_projections() {
+        let value = instances();
+        assert_eq!(search("Reservations[].Instances[].InstanceId", &value), json!(["i-1", "i-2", "i-3"]));
+        assert_eq!(search("Reservations[*].Instances[*].InstanceId", &value), json!([["i-1", "i-2"], ["i-3"]]));
+        assert_eq!(search("Reservations[0].Instances[-1].State.Name", &value), json!("stopped"));
+        assert_eq!(
+            search("Reservations[].Instances[?State.Name == 'running'].InstanceId[]", &value),
+            json!(["i-1", "i-3"])
+        );
+        assert_eq!(
+            search("Reservations[].Instances[?CpuCount > `2`].{id: InstanceId, cpus: CpuCount}", &value),
+            json!([[{"id": "i-2", "cpus": 8}], [{"id": "i-3", "cpus": 4}]])
+        );
+        assert_eq!(search("Reservations[].Instances[].[InstanceId, Tags[0].Value]", &value), json!([["i-1", "web"], ["i-2", null], ["i-3", null]]));
+        assert_eq!(search("Reservations[0].Instances[0].State.*", &value), json!(["running"]));
+        assert_eq!(search("Reservations[].Instances[] | [1:].InstanceId", &value), json!(["i-2", "i-3"]));
+        assert_eq!(search("Reservations[].Instances[] | [::-2].InstanceId", &value), json!(["i-3", "i-1"]));
+        assert_eq!(search("Reservations[].Instances[] | [1::9223372036854775807].InstanceId", &value), json!(["i-2"]));
+        assert_eq!(search("Reservations[].Instances[] | [::-9223372036854775808].InstanceId", &value), json!(["i-3"]));
+        assert_eq!(search("Missing.Field", &value), Value::Null);
+        assert_eq!(search("\"Reservations\"[0].Instances[0].InstanceId", &value), json!("i-1"));
+        assert_eq!(search("Reservations[?!Instances[1]] | length(@)", &value), json!(1));
+    }
+
+    #[test]
+    fn test_functions() {
+        let value = instances();
+        let all = "Reservations[].Instances[]";
+        assert_eq!(search(&format!("length({})", all), &value), json!(3));
+        assert_eq!(search(&format!("sort_by({}, &CpuCount)[].InstanceId", all), &value), json!(["i-1", "i-3", "i-2"]));
+        assert_eq!(search(&format!("max_by({}, &CpuCount).InstanceId", all), &value), json!("i-2"));
+        assert_eq!(search(&format!("sum({}[].CpuCount)", all), &value), json!(14));
+        assert_eq!(search(&format!("avg({}[].CpuCount)", all), &value), json!(number(14.0 / 3.0)));
+        assert_eq!(search(&format!("join(', ', {}[].InstanceId)", all), &value), json!("i-1, i-2, i-3"));
+        assert_eq!(search(&format!("{} | [?starts_with(InstanceId, 'i-3')].CpuCount", all), &value), json!([4]));
+        assert_eq!(search("Reservations[0].Instances | [?contains(Tags[].Value, 'web')].InstanceId", &value), json!(["i-1"]));
+        assert_eq!(search("keys(Reservations[0].Instances[0].State)", &value), json!(["Name"]));
+        assert_eq!(search("to_string(`[1, 2]`)", &value), json!("[1,2]"));
+        assert_eq!(search("not_null(Missing, 'default')", &value), json!("default"));
+    }
+
+    #[test]
+    fn test_errors() {
+        assert!(matches!(
+            Expression::parse("Reservations[*.InstanceId"),
+            Err(QueryError::Syntax { position: 14, .. })
+        ));
+        assert!(matches!(Expression::parse("Reservations[0"), Err(QueryError::Syntax { .. })));
+        assert!(matches!(Expression::parse("a.`{invalid`"), Err(QueryError::Syntax { .. })));
+        assert_eq!(Expression::parse("lenght(@)"), Err(QueryError::UnknownFunction("lenght".to_string())));
+        assert!(matches!(Expression::parse("length(a, b)"), Err(QueryError::Arity { actual: 2, .. })));
+
+        let error = Expression::parse("sum(Reservations)").unwrap().search(&instances()).unwrap_err();
+        assert_eq!(error.to_string(), "sum() expected an array of numbers, got array containing object");
+    }
+}
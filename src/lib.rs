@@ -1,9 +1,39 @@
+pub mod audit;
+pub mod aws_error;
+pub mod aws_config;
+pub mod cache;
+pub mod cassette;
+pub mod completion;
+pub mod config;
+pub mod customizations;
+pub mod environment;
 pub mod error;
+pub mod executor;
+#[cfg(all(test, unix))]
+mod fake_aws;
+#[cfg(test)]
+mod fake_endpoint;
+pub mod identity;
+pub mod jmespath;
+pub mod limits;
 pub mod mcp_server;
+pub mod native;
+pub mod output_format;
+pub mod pagination;
+pub mod policy;
+pub mod protocol;
+pub mod redact;
+pub mod retry;
+pub mod roots;
+pub mod service_model;
+pub mod sigv4;
+pub mod truncate;
 pub mod use_aws;
 
 pub use error::McpError;
+pub use executor::{AwsExecutor, CliExecutor, CliVersion, CommandOutput, MockExecutor, RecordReplayExecutor};
 pub use mcp_server::AwsMcpServer;
+pub use native::NativeExecutor;
 pub use use_aws::{UseAws, UseAwsRequest, UseAwsResponse};
 
 /// Maximum size for tool response output
@@ -35,4 +65,11 @@ impl InvokeOutput {
             OutputKind::Json(j) => j.as_str().unwrap_or_default(),
         }
     }
+
+    pub fn as_json(&self) -> Option<&serde_json::Value> {
+        match &self.output {
+            OutputKind::Json(j) => Some(j),
+            OutputKind::Text(_) => None,
+        }
+    }
 } 
\ No newline at end of file
@@ -0,0 +1,269 @@
+//! Rate limits and call budgets. A looping agent can otherwise hammer APIs until the whole
+//! account is throttled. Limits apply to the session and to each profile: a token bucket
+//! smooths bursts of calls, and budgets cap the calls and writes within a sliding window or
+//! the whole session.
+
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::time::{Duration, Instant};
+
+use serde::Deserialize;
+
+use crate::use_aws::UseAws;
+
+/// The profile name limits of calls without one are looked up under
+const DEFAULT_PROFILE: &str = "default";
+
+/// Rate limits and budgets, configured under `limits` in the config file
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LimitsConfig {
+    /// Limits on all calls of a session
+    pub session: Limits,
+    /// Limits on the calls of each profile, by profile name. Calls without a profile use
+    /// `default`.
+    pub profiles: BTreeMap<String, Limits>,
+}
+
+/// Limits on a set of calls. Unset limits don't apply.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Limits {
+    /// Calls per minute sustained by the token bucket
+    pub calls_per_minute: Option<u32>,
+    /// Calls allowed in a burst, `calls_per_minute` by default
+    pub burst: Option<u32>,
+    /// Calls allowed within `window_secs`
+    pub max_calls: Option<u32>,
+    /// Calls that may modify resources allowed within `window_secs`
+    pub max_writes: Option<u32>,
+    /// The sliding window budgets apply to. Without it they apply to the whole session.
+    pub window_secs: Option<u64>,
+}
+
+/// Errors raised when a call exceeds a limit
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum LimitError {
+    #[error("Rate limit of {calls_per_minute} calls per minute reached for {scope}; retry in {retry_after_secs}s")]
+    RateLimited {
+        scope: String,
+        calls_per_minute: u32,
+        retry_after_secs: u64,
+    },
+    #[error("Budget of {budget} reached for {scope}; {next}")]
+    BudgetExhausted { scope: String, budget: String, next: String },
+}
+
+/// Tokens of a bucket refilled at a constant rate
+#[derive(Debug, Clone)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// Usage of the calls one set of limits applies to
+#[derive(Debug, Clone, Default)]
+struct Usage {
+    bucket: Option<Bucket>,
+    calls: VecDeque<Instant>,
+    writes: VecDeque<Instant>,
+}
+
+impl Usage {
+    /// Checks `limits` without using them up
+    fn check(&mut self, limits: &Limits, scope: &str, is_write: bool, now: Instant) -> Result<(), LimitError> {
+        if let Some(calls_per_minute) = limits.calls_per_minute.filter(|rate| *rate > 0) {
+            let capacity = limits.burst.unwrap_or(calls_per_minute).max(1) as f64;
+            let per_second = calls_per_minute as f64 / 60.0;
+            let bucket = self.bucket.get_or_insert(Bucket {
+                tokens: capacity,
+                updated: now,
+            });
+            let elapsed = now.saturating_duration_since(bucket.updated).as_secs_f64();
+            bucket.tokens = (bucket.tokens + elapsed * per_second).min(capacity);
+            bucket.updated = now;
+            if bucket.tokens < 1.0 {
+                return Err(LimitError::RateLimited {
+                    scope: scope.to_string(),
+                    calls_per_minute,
+                    retry_after_secs: ((1.0 - bucket.tokens) / per_second).ceil() as u64,
+                });
+            }
+        }
+
+        let window = limits.window_secs.map(Duration::from_secs);
+        for times in [&mut self.calls, &mut self.writes] {
+            while let (Some(window), Some(oldest)) = (window, times.front()) {
+                if now.saturating_duration_since(*oldest) < window {
+                    break;
+                }
+                times.pop_front();
+            }
+        }
+        let budgets = [
+            (limits.max_calls, &self.calls, "calls", true),
+            (limits.max_writes, &self.writes, "writes", is_write),
+        ];
+        for (max, times, kind, applies) in budgets {
+            let Some(max) = max.filter(|_| applies) else {
+                continue;
+            };
+            if times.len() >= max as usize {
+                let (budget, next) = match (window, times.front()) {
+                    (Some(window), Some(oldest)) => (
+                        format!("{} {} per {}", max, kind, format_duration(window)),
+                        format!(
+                            "the next call is allowed in {}",
+                            format_duration(window.saturating_sub(now.saturating_duration_since(*oldest)))
+                        ),
+                    ),
+                    _ => (
+                        format!("{} {}", max, kind),
+                        "start a new session to continue".to_string(),
+                    ),
+                };
+                return Err(LimitError::BudgetExhausted {
+                    scope: scope.to_string(),
+                    budget,
+                    next,
+                });
+            }
+        }
+        Ok(())
+    }
+
+    fn record(&mut self, is_write: bool, now: Instant) {
+        if let Some(bucket) = &mut self.bucket {
+            bucket.tokens -= 1.0;
+        }
+        self.calls.push_back(now);
+        if is_write {
+            self.writes.push_back(now);
+        }
+    }
+}
+
+/// Formats a duration like `1h`, `10m` or `1m30s`, rounding up to seconds
+fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs() + u64::from(duration.subsec_nanos() > 0);
+    let (hours, minutes, seconds) = (secs / 3600, secs % 3600 / 60, secs % 60);
+    let parts: Vec<String> = [(hours, "h"), (minutes, "m"), (seconds, "s")]
+        .iter()
+        .filter(|(value, _)| *value > 0)
+        .map(|(value, unit)| format!("{}{}", value, unit))
+        .collect();
+    if parts.is_empty() { "0s".to_string() } else { parts.concat() }
+}
+
+/// Tracks the calls of a session against the configured limits
+#[derive(Debug, Clone, Default)]
+pub struct Limiter {
+    config: LimitsConfig,
+    session: Usage,
+    profiles: HashMap<String, Usage>,
+}
+
+impl Limiter {
+    pub fn new(config: LimitsConfig) -> Self {
+        Self {
+            config,
+            ..Self::default()
+        }
+    }
+
+    /// Fails if any limit of the session or of the profile of `command` is reached, without
+    /// counting the command
+    pub fn check(&mut self, command: &UseAws, now: Instant) -> Result<(), LimitError> {
+        let is_write = command.requires_acceptance();
+        let profile = command.profile_name.as_deref().unwrap_or(DEFAULT_PROFILE);
+        self.session.check(&self.config.session, "this session", is_write, now)?;
+        if let Some(limits) = self.config.profiles.get(profile) {
+            let scope = format!("profile '{}'", profile);
+            self.profiles.entry(profile.to_string()).or_default().check(limits, &scope, is_write, now)?;
+        }
+        Ok(())
+    }
+
+    /// Counts `command` against the limits of the session and of its profile, or fails
+    /// without counting it if any limit is reached
+    pub fn admit(&mut self, command: &UseAws, now: Instant) -> Result<(), LimitError> {
+        self.check(command, now)?;
+        let is_write = command.requires_acceptance();
+        let profile = command.profile_name.as_deref().unwrap_or(DEFAULT_PROFILE);
+        self.session.record(is_write, now);
+        if self.config.profiles.contains_key(profile) {
+            if let Some(usage) = self.profiles.get_mut(profile) {
+                usage.record(is_write, now);
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn command(operation_name: &str, profile_name: &str) -> UseAws {
+        serde_json::from_value(serde_json::json!({
+            "service_name": "ec2",
+            "operation_name": operation_name,
+            "region": "us-east-1",
+            "profile_name": profile_name
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_rate_limit() {
+        let mut limiter = Limiter::new(LimitsConfig {
+            session: Limits {
+                calls_per_minute: Some(60),
+                burst: Some(2),
+                ..Limits::default()
+            },
+            ..LimitsConfig::default()
+        });
+        let describe = command("describe-instances", "dev");
+        let start = Instant::now();
+        limiter.admit(&describe, start).unwrap();
+        limiter.admit(&describe, start).unwrap();
+        assert_eq!(
+            limiter.admit(&describe, start).unwrap_err().to_string(),
+            "Rate limit of 60 calls per minute reached for this session; retry in 1s"
+        );
+        // One call per second refills the bucket
+        limiter.admit(&describe, start + Duration::from_secs(1)).unwrap();
+        assert!(limiter.admit(&describe, start + Duration::from_secs(1)).is_err());
+    }
+
+    #[test]
+    fn test_budgets() {
+        let mut limiter = Limiter::new(LimitsConfig {
+            session: Limits {
+                max_calls: Some(5),
+                ..Limits::default()
+            },
+            profiles: BTreeMap::from([("prod".to_string(), Limits {
+                max_writes: Some(2),
+                window_secs: Some(3600),
+                ..Limits::default()
+            })]),
+        });
+        let start = Instant::now();
+        let reboot = command("reboot-instances", "prod");
+        limiter.admit(&reboot, start).unwrap();
+        limiter.admit(&reboot, start + Duration::from_secs(600)).unwrap();
+        let err = limiter.admit(&reboot, start + Duration::from_secs(1200)).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Budget of 2 writes per 1h reached for profile 'prod'; the next call is allowed in 40m"
+        );
+        // Reads and other profiles aren't affected, and the window slides
+        limiter.admit(&command("describe-instances", "prod"), start + Duration::from_secs(1200)).unwrap();
+        limiter.admit(&command("reboot-instances", "dev"), start + Duration::from_secs(1200)).unwrap();
+        limiter.admit(&reboot, start + Duration::from_secs(3600)).unwrap();
+
+        let err = limiter.admit(&reboot, start + Duration::from_secs(7200)).unwrap_err();
+        assert_eq!(err.to_string(), "Budget of 5 calls reached for this session; start a new session to continue");
+    }
+}
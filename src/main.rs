@@ -1,21 +1,290 @@
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use clap::{Parser, Subcommand};
+use chrono::SecondsFormat;
+use eyre::{Result, WrapErr};
+use use_aws_mcp::audit::{self, AuditEntry, AuditFilter, AuditLog};
+use use_aws_mcp::cache::ResultCache;
+use use_aws_mcp::config::{Backend, Config};
+use use_aws_mcp::executor::{AwsExecutor, CliExecutor, RecordReplayExecutor};
 use use_aws_mcp::mcp_server::AwsMcpServer;
-use use_aws_mcp::error::Result;
+use use_aws_mcp::native::NativeExecutor;
+use use_aws_mcp::policy::{Access, Policy};
+use use_aws_mcp::service_model::ServiceCatalog;
+use use_aws_mcp::use_aws::RiskTier;
+
+/// MCP server for AWS CLI operations
+#[derive(Debug, Parser)]
+#[command(version, about)]
+struct Cli {
+    /// JSON configuration file
+    #[arg(long, value_name = "FILE")]
+    config: Option<PathBuf>,
+
+    /// How commands are run, overriding `backend` from the config file
+    #[arg(long, value_enum)]
+    backend: Option<Backend>,
+
+    /// The AWS CLI executable, overriding `aws_cli_path` from the config file
+    #[arg(long, value_name = "PATH")]
+    aws_cli: Option<PathBuf>,
+
+    /// Only allow read-only operations against real AWS accounts
+    #[arg(long)]
+    read_only: bool,
+
+    /// Record every AWS CLI invocation to this cassette file, with secrets redacted
+    #[arg(long, value_name = "FILE", conflicts_with = "replay")]
+    record: Option<PathBuf>,
+
+    /// Serve AWS CLI results from this cassette file instead of calling AWS
+    #[arg(long, value_name = "FILE")]
+    replay: Option<PathBuf>,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Inspect audit logs instead of running the server
+    Audit {
+        #[command(subcommand)]
+        command: AuditCommand,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+enum AuditCommand {
+    /// List audited calls matching all the given filters, oldest first
+    Query {
+        /// Calls at or after this date or RFC 3339 time, e.g. `2024-05-14`
+        #[arg(long, value_name = "TIME")]
+        since: Option<String>,
+        /// Calls before this date or RFC 3339 time
+        #[arg(long, value_name = "TIME")]
+        until: Option<String>,
+        #[arg(long)]
+        profile: Option<String>,
+        /// Account id the calls acted as
+        #[arg(long)]
+        account: Option<String>,
+        /// Service name, e.g. `ec2`
+        #[arg(long)]
+        service: Option<String>,
+        #[arg(long, value_enum)]
+        risk_tier: Option<RiskTier>,
+        #[arg(long, value_name = "CODE")]
+        exit_status: Option<i32>,
+        /// Print the matching entries as JSON lines
+        #[arg(long)]
+        json: bool,
+        #[arg(required = true, value_name = "FILE")]
+        files: Vec<PathBuf>,
+    },
+    /// Run a read-only call of the log again, with the server's configuration, to compare
+    /// the current result with what the model saw
+    Replay {
+        /// Sequence number of the entry, as listed by `audit query`
+        #[arg(long)]
+        seq: u64,
+        #[arg(required = true, value_name = "FILE")]
+        files: Vec<PathBuf>,
+    },
+    /// Check the hash chain of audit log files, given oldest first, e.g. `audit.jsonl.1
+    /// audit.jsonl`. Exits with status 1 if entries were edited or deleted.
+    Verify {
+        #[arg(required = true, value_name = "FILE")]
+        files: Vec<PathBuf>,
+    },
+}
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    // Initialize logging
+    let mut cli = Cli::parse();
+
+    // Initialize logging. stdout carries the MCP protocol, so logs go to stderr.
     tracing_subscriber::fmt()
         .with_env_filter("use_aws=info")
+        .with_writer(std::io::stderr)
         .init();
 
-    tracing::info!("Starting use_aws MCP server...");
+    match cli.command.take() {
+        Some(Command::Audit { command }) => run_audit(command, cli).await,
+        None => {
+            tracing::info!("Starting use_aws MCP server...");
+            let mut server = server(cli).await?;
+            server.run().await.wrap_err("Server error")
+        },
+    }
+}
+
+/// Builds the server from the command line options and the config file
+async fn server(cli: Cli) -> Result<AwsMcpServer> {
+    let mut config = match &cli.config {
+        Some(path) => Config::load(path)?,
+        None => Config::default(),
+    };
+    if cli.read_only {
+        config.access = Access::ReadOnly;
+    }
+    let policy = Policy::from_config(&config);
+
+    let catalog = ServiceCatalog::shared();
+    let backend = cli.backend.unwrap_or(config.backend);
+    let mut cli_executor = CliExecutor::new(catalog.clone())
+        .with_program_args(config.aws_cli_args)
+        .with_environment(config.environment);
+    if let Some(program) = cli.aws_cli.or(config.aws_cli_path) {
+        cli_executor = cli_executor.with_program(program);
+    }
+
+    // Replaying and the native backend need no AWS CLI at all
+    let mut aws_cli_version = None;
+    if cli.replay.is_none() && backend == Backend::Cli {
+        let version = cli_executor.version().await.wrap_err("AWS CLI version check failed")?;
+        tracing::info!("Using AWS CLI {}", version);
+        aws_cli_version = Some(version.to_string());
+    }
 
-    let mut server = AwsMcpServer::new();
-    
-    if let Err(e) = server.run().await {
-        tracing::error!("Server error: {}", e);
-        std::process::exit(1);
+    let live_executor: Arc<dyn AwsExecutor> = match backend {
+        Backend::Cli => Arc::new(cli_executor),
+        Backend::Native => {
+            tracing::info!("Using the native backend");
+            Arc::new(NativeExecutor::new(catalog.clone()))
+        },
+    };
+    let executor: Arc<dyn AwsExecutor> = match (cli.record, cli.replay) {
+        (Some(path), _) => {
+            tracing::info!("Recording AWS CLI invocations to {}", path.display());
+            Arc::new(RecordReplayExecutor::record_to_cassette(live_executor, &path))
+        },
+        (None, Some(path)) => {
+            tracing::info!("Replaying AWS CLI invocations from {}", path.display());
+            let replay = RecordReplayExecutor::replay_cassette(&path)?;
+            Arc::new(replay)
+        },
+        (None, None) => live_executor,
+    };
+
+    let mut server = AwsMcpServer::with_executor(catalog, executor)
+        .with_policy(policy)
+        .with_redaction(config.redaction.clone())
+        .with_retry(config.retry)
+        .with_cache(ResultCache::new(config.cache).with_redaction(config.redaction))
+        .with_limits(config.limits);
+    if let Some(version) = aws_cli_version {
+        server = server.with_aws_cli_version(version);
+    }
+    // Account policies need the identity of every command
+    if config.identity_preflight.unwrap_or(false) || !config.accounts.is_empty() {
+        server = server.with_identity_preflight();
+    }
+    if let Some(max_items) = config.max_items {
+        server = server.with_max_items(max_items);
+    }
+    if let Some(audit_log) = AuditLog::from_config(&config.audit) {
+        tracing::info!("Writing the audit log to {}", audit_log.path().display());
+        server = server.with_audit_log(audit_log);
     }
 
+    Ok(server)
+}
+
+async fn run_audit(command: AuditCommand, cli: Cli) -> Result<()> {
+    match command {
+        AuditCommand::Verify { files } => {
+            let verification = audit::verify(&files)?;
+            for problem in &verification.problems {
+                println!("{}", problem);
+            }
+            match (verification.first_seq, verification.last_seq, &verification.last_hash) {
+                (Some(first_seq), Some(last_seq), Some(last_hash)) => println!(
+                    "{} entries, sequence {} to {}, last hash {}",
+                    verification.entries, first_seq, last_seq, last_hash
+                ),
+                _ => println!("No entries"),
+            }
+            if !verification.problems.is_empty() {
+                std::process::exit(1);
+            }
+            Ok(())
+        },
+        AuditCommand::Query {
+            since,
+            until,
+            profile,
+            account,
+            service,
+            risk_tier,
+            exit_status,
+            json,
+            files,
+        } => {
+            let filter = AuditFilter {
+                since: since.as_deref().map(audit::parse_time).transpose()?,
+                until: until.as_deref().map(audit::parse_time).transpose()?,
+                profile,
+                account,
+                service,
+                risk_tier,
+                exit_status,
+            };
+            for entry in audit::read_entries(&files)? {
+                if !filter.matches(&entry) {
+                    continue;
+                }
+                if json {
+                    println!("{}", serde_json::to_string(&entry)?);
+                } else {
+                    println!("{}", summary(&entry));
+                }
+            }
+            Ok(())
+        },
+        AuditCommand::Replay { seq, files } => {
+            let entry = audit::read_entries(&files)?
+                .into_iter()
+                .find(|entry| entry.seq == seq)
+                .ok_or_else(|| eyre::eyre!("No entry {} in the audit log", seq))?;
+            let arguments = entry.replay_arguments()?;
+            let mut server = server(cli).await?;
+            replay_entry(&mut server, &entry, arguments).await
+        },
+    }
+}
+
+/// One line per entry: sequence number, time, profile, command, risk tier, decision, exit
+/// status and label
+fn summary(entry: &AuditEntry) -> String {
+    let risk_tier = serde_json::to_value(entry.risk_tier).unwrap_or_default();
+    let decision = serde_json::to_value(entry.decision).unwrap_or_default();
+    [
+        entry.seq.to_string(),
+        entry.timestamp.to_rfc3339_opts(SecondsFormat::Secs, true),
+        entry.profile.clone().unwrap_or_else(|| "-".to_string()),
+        format!("{} {}", entry.service, entry.operation),
+        risk_tier.as_str().unwrap_or_default().to_string(),
+        decision.as_str().unwrap_or_default().to_string(),
+        entry.exit_status.map_or_else(|| "-".to_string(), |exit_status| exit_status.to_string()),
+        entry.label.clone().unwrap_or_default(),
+    ]
+    .join("\t")
+}
+
+/// Runs an audited call again and prints its result next to what was recorded
+async fn replay_entry(server: &mut AwsMcpServer, entry: &AuditEntry, arguments: serde_json::Value) -> Result<()> {
+    println!("Recorded: {}", summary(entry));
+    println!("Recorded output: {} bytes", entry.output_bytes);
+    let response = server.call_tool(arguments).await?;
+    match (response.result, response.error) {
+        (Some(result), _) => {
+            println!("Replay output: {} bytes\n", result.to_string().len());
+            println!("{}", result["content"][0]["text"].as_str().unwrap_or_default());
+        },
+        (None, Some(error)) => println!("Replay failed: {}", error.message),
+        (None, None) => {},
+    }
     Ok(())
 }
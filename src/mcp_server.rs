@@ -1,7 +1,24 @@
 use std::io::{BufRead, BufReader, Write};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 use serde::{Deserialize, Serialize};
 
+use crate::audit::{AuditEntry, AuditLog, Decision, Observed};
+use crate::aws_config::AwsConfig;
+use crate::cache::{Cached, ResultCache};
+use crate::completion::{self, CompleteParams};
 use crate::error::{McpError, Result};
+use crate::identity::IdentityResolver;
+use crate::customizations;
+use crate::executor::{AwsExecutor, CliExecutor};
+use crate::output_format::OutputFormat;
+use crate::pagination::{self, DEFAULT_MAX_ITEMS};
+use crate::limits::{Limiter, LimitsConfig};
+use crate::policy::Policy;
+use crate::redact::Redaction;
+use crate::retry::{RetryConfig, Retrying};
+use crate::roots::{ListRootsResult, Roots};
+use crate::service_model::{Paginator, ServiceCatalog};
 use crate::use_aws::{UseAws, UseAwsRequest, UseAwsResponse};
 
 /// JSON-RPC message types
@@ -18,6 +35,7 @@ pub struct JsonRpcRequest {
     pub jsonrpc: String,
     pub id: serde_json::Value,
     pub method: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub params: Option<serde_json::Value>,
 }
 
@@ -48,44 +66,178 @@ pub struct JsonRpcError {
 
 /// MCP Server implementation
 pub struct AwsMcpServer {
-    stdin: std::io::Stdin,
-    stdout: std::io::Stdout,
+    catalog: Arc<ServiceCatalog>,
+    executor: Arc<dyn AwsExecutor>,
+    /// The AWS CLI version checked at startup, reported in `serverInfo`
+    aws_cli_version: Option<String>,
+    policy: Policy,
+    /// Items returned per call of commands the server paginates
+    max_items: usize,
+    redaction: Redaction,
+    retry: RetryConfig,
+    cache: ResultCache,
+    /// Resolves the identity of commands before they run, if the preflight is on
+    identities: Option<IdentityResolver>,
+    audit_log: Option<AuditLog>,
+    limiter: Limiter,
+    /// The client name from `initialize`, recorded in the audit log
+    client_name: Option<String>,
+    roots: Roots,
+    client_supports_roots: bool,
+    /// Requests to the client waiting to be written after the current message
+    outgoing: Vec<JsonRpcRequest>,
+    next_request_id: u64,
+    pending_roots_request: Option<serde_json::Value>,
 }
 
 impl AwsMcpServer {
     pub fn new() -> Self {
+        Self::with_catalog(ServiceCatalog::shared())
+    }
+
+    /// Creates a server backed by the given service model catalog
+    pub fn with_catalog(catalog: Arc<ServiceCatalog>) -> Self {
+        let executor = Arc::new(CliExecutor::new(catalog.clone()));
+        Self::with_executor(catalog, executor)
+    }
+
+    /// Reports `version` as the AWS CLI version in `serverInfo`
+    pub fn with_aws_cli_version(mut self, version: impl Into<String>) -> Self {
+        self.aws_cli_version = Some(version.into());
+        self
+    }
+
+    /// Applies `policy` to every command, see [Policy::apply]
+    pub fn with_policy(mut self, policy: Policy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    /// Returns at most `max_items` items per call of paginated commands unless the request
+    /// asks for another number
+    pub fn with_max_items(mut self, max_items: usize) -> Self {
+        self.max_items = max_items;
+        self
+    }
+
+    /// Masks secrets in command output as configured by `redaction`
+    pub fn with_redaction(mut self, redaction: Redaction) -> Self {
+        self.redaction = redaction;
+        self
+    }
+
+    /// Retries throttled and transiently failing commands as configured by `retry`
+    pub fn with_retry(mut self, retry: RetryConfig) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    /// Answers read-only commands from `cache`, see [ResultCache]
+    pub fn with_cache(mut self, cache: ResultCache) -> Self {
+        self.cache = cache;
+        self
+    }
+
+    /// Resolves the account and principal of each profile before running its commands, so
+    /// they show in descriptions and responses and account policies can apply
+    pub fn with_identity_preflight(mut self) -> Self {
+        self.identities = Some(IdentityResolver::new());
+        self
+    }
+
+    /// Appends every tool call to `audit_log`
+    pub fn with_audit_log(mut self, audit_log: AuditLog) -> Self {
+        self.audit_log = Some(audit_log);
+        self
+    }
+
+    /// Refuses calls beyond the rate limits and budgets of `limits`
+    pub fn with_limits(mut self, limits: LimitsConfig) -> Self {
+        self.limiter = Limiter::new(limits);
+        self
+    }
+
+    /// Creates a server running commands through `executor` instead of the AWS CLI
+    pub fn with_executor(catalog: Arc<ServiceCatalog>, executor: Arc<dyn AwsExecutor>) -> Self {
         Self {
-            stdin: std::io::stdin(),
-            stdout: std::io::stdout(),
+            catalog,
+            executor,
+            aws_cli_version: None,
+            policy: Policy::default(),
+            max_items: DEFAULT_MAX_ITEMS,
+            redaction: Redaction::default(),
+            retry: RetryConfig::default(),
+            cache: ResultCache::default(),
+            identities: None,
+            audit_log: None,
+            limiter: Limiter::default(),
+            client_name: None,
+            roots: Roots::default(),
+            client_supports_roots: false,
+            outgoing: Vec::new(),
+            next_request_id: 0,
+            pending_roots_request: None,
         }
     }
 
+    /// Runs one `use_aws` call outside of a client session, e.g. to replay an audited call
+    pub async fn call_tool(&mut self, arguments: serde_json::Value) -> Result<JsonRpcResponse> {
+        self.handle_tool_call(JsonRpcRequest {
+            jsonrpc: "2.0".to_string(),
+            id: serde_json::Value::from(0),
+            method: "tools/call".to_string(),
+            params: Some(serde_json::json!({"name": "use_aws", "arguments": arguments})),
+        })
+        .await
+    }
+
     pub async fn run(&mut self) -> Result<()> {
-        let reader = BufReader::new(self.stdin.lock());
-        
+        let stdin = std::io::stdin();
+        self.serve(BufReader::new(stdin.lock()), std::io::stdout()).await
+    }
+
+    /// Serves JSON-RPC messages read line by line from `reader`, writing replies to `writer`
+    pub async fn serve(&mut self, reader: impl BufRead, mut writer: impl Write) -> Result<()> {
         for line in reader.lines() {
-            let line = line.map_err(|e| McpError::Io(e))?;
+            let line = line.map_err(McpError::Io)?;
             if line.trim().is_empty() {
                 continue;
             }
 
             let message: JsonRpcMessage = serde_json::from_str(&line)
-                .map_err(|e| McpError::Serialization(e))?;
+                .map_err(McpError::Serialization)?;
 
             let response = self.handle_message(message).await?;
             
             if let Some(response) = response {
-                let response_str = serde_json::to_string(&response)
-                    .map_err(|e| McpError::Serialization(e))?;
-                writeln!(self.stdout, "{}", response_str)
-                    .map_err(|e| McpError::Io(e))?;
-                self.stdout.flush().map_err(|e| McpError::Io(e))?;
+                write_message(&mut writer, &response)?;
+            }
+            for request in std::mem::take(&mut self.outgoing) {
+                write_message(&mut writer, &request)?;
             }
         }
 
         Ok(())
     }
 
+    /// Queues a request to the client and returns its id
+    fn send_request(&mut self, method: &str, params: Option<serde_json::Value>) -> serde_json::Value {
+        self.next_request_id += 1;
+        let id = serde_json::Value::String(format!("use_aws-{}", self.next_request_id));
+        self.outgoing.push(JsonRpcRequest {
+            jsonrpc: "2.0".to_string(),
+            id: id.clone(),
+            method: method.to_string(),
+            params,
+        });
+        id
+    }
+
+    fn request_roots(&mut self) {
+        let id = self.send_request("roots/list", None);
+        self.pending_roots_request = Some(id);
+    }
+
     async fn handle_message(&mut self, message: JsonRpcMessage) -> Result<Option<JsonRpcResponse>> {
         match message {
             JsonRpcMessage::Request(request) => {
@@ -96,18 +248,40 @@ impl AwsMcpServer {
                 self.handle_notification(notification).await?;
                 Ok(None)
             }
-            JsonRpcMessage::Response(_) => {
-                // We don't send requests, so we shouldn't receive responses
+            JsonRpcMessage::Response(response) => {
+                self.handle_response(response);
                 Ok(None)
             }
         }
     }
 
+    fn handle_response(&mut self, response: JsonRpcResponse) {
+        if self.pending_roots_request.as_ref() != Some(&response.id) {
+            tracing::warn!("Ignoring response to unknown request {}", response.id);
+            return;
+        }
+        self.pending_roots_request = None;
+
+        if let Some(error) = response.error {
+            tracing::warn!("Client failed to list roots: {}", error.message);
+            return;
+        }
+        match response.result.map(serde_json::from_value::<ListRootsResult>) {
+            Some(Ok(result)) => {
+                self.roots = Roots::from_uris(result.roots.iter().map(|root| root.uri.as_str()));
+                tracing::info!("Updated roots: {:?}", self.roots.dirs());
+            },
+            Some(Err(e)) => tracing::warn!("Invalid roots/list result: {}", e),
+            None => tracing::warn!("Empty roots/list result"),
+        }
+    }
+
     async fn handle_request(&mut self, request: JsonRpcRequest) -> Result<JsonRpcResponse> {
         match request.method.as_str() {
             "initialize" => self.handle_initialize(request).await,
             "tools/call" => self.handle_tool_call(request).await,
             "tools/list" => self.handle_tools_list(request).await,
+            "completion/complete" => self.handle_completion(request).await,
             _ => {
                 let error = JsonRpcError {
                     code: -32601, // Method not found
@@ -124,19 +298,39 @@ impl AwsMcpServer {
         }
     }
 
-    async fn handle_initialize(&self, request: JsonRpcRequest) -> Result<JsonRpcResponse> {
-        let capabilities = serde_json::json!({
+    async fn handle_initialize(&mut self, request: JsonRpcRequest) -> Result<JsonRpcResponse> {
+        self.client_name = request
+            .params
+            .as_ref()
+            .and_then(|params| params.pointer("/clientInfo/name"))
+            .and_then(|name| name.as_str())
+            .map(|name| name.to_string());
+        self.client_supports_roots = request
+            .params
+            .as_ref()
+            .and_then(|params| params.pointer("/capabilities/roots"))
+            .is_some();
+        if !self.client_supports_roots {
+            // Without client roots, local files are confined to the server's working directory
+            self.roots = Roots::from_dirs(std::env::current_dir().ok());
+        }
+
+        let mut capabilities = serde_json::json!({
             "protocolVersion": "2024-11-05",
             "capabilities": {
                 "tools": {
                     "listChanged": true
-                }
+                },
+                "completions": {}
             },
             "serverInfo": {
                 "name": "use_aws",
                 "version": env!("CARGO_PKG_VERSION")
             }
         });
+        if let Some(version) = &self.aws_cli_version {
+            capabilities["serverInfo"]["awsCliVersion"] = serde_json::json!(version);
+        }
 
         Ok(JsonRpcResponse {
             jsonrpc: "2.0".to_string(),
@@ -147,7 +341,7 @@ impl AwsMcpServer {
     }
 
     async fn handle_tools_list(&self, request: JsonRpcRequest) -> Result<JsonRpcResponse> {
-        let tools = serde_json::json!({
+        let mut tools = serde_json::json!({
             "tools": [
                 {
                     "name": "use_aws",
@@ -168,6 +362,13 @@ impl AwsMcpServer {
                                 "description": "Optional parameters for the AWS CLI command",
                                 "additionalProperties": true
                             },
+                            "positional_args": {
+                                "type": "array",
+                                "items": {
+                                    "type": "string"
+                                },
+                                "description": "Optional positional arguments for commands that take them, e.g. the source and destination of `s3 cp` or the S3 URI of `s3 ls`"
+                            },
                             "region": {
                                 "type": "string",
                                 "description": "AWS region (e.g., us-west-2, eu-west-1)"
@@ -179,6 +380,42 @@ impl AwsMcpServer {
                             "label": {
                                 "type": "string",
                                 "description": "Optional label for the operation"
+                            },
+                            "endpoint": {
+                                "type": "string",
+                                "description": "Optional name of a configured endpoint, such as a local AWS emulator, to send the request to instead of AWS"
+                            },
+                            "max_items": {
+                                "type": "integer",
+                                "minimum": 1,
+                                "description": "Optional maximum number of items returned by list and describe operations. Fewer items are returned when they don't fit the response."
+                            },
+                            "cursor": {
+                                "type": "string",
+                                "description": "Optional `next_cursor` of a previous response, to fetch the next page of the same command"
+                            },
+                            "query": {
+                                "type": "string",
+                                "description": "Optional JMESPath expression applied by the server to the JSON output, e.g. `Reservations[].Instances[].InstanceId`. Unlike the CLI's own `--query`, it applies to the paginated result."
+                            },
+                            "output_format": {
+                                "type": "string",
+                                "enum": [
+                                    "json",
+                                    "yaml",
+                                    "text",
+                                    "table",
+                                    "csv"
+                                ],
+                                "description": "Optional presentation of the output, converted by the server from the CLI JSON output. `json` also returns the parsed result as structured content, `yaml`, `text` and `table` are easier to read, `csv` flattens a list of records into rows. Without it the output is returned as the CLI prints it."
+                            },
+                            "cache": {
+                                "type": "string",
+                                "enum": [
+                                    "use",
+                                    "bypass"
+                                ],
+                                "description": "Optional `bypass` to run a read-only command even if the server has a cached result of it, e.g. when waiting for a resource to change state. The fresh result replaces the cached one."
                             }
                         },
                         "required": ["service_name", "operation_name", "region"]
@@ -186,6 +423,10 @@ impl AwsMcpServer {
                 }
             ]
         });
+        let endpoint_names = self.policy.endpoint_names();
+        if !endpoint_names.is_empty() {
+            tools["tools"][0]["inputSchema"]["properties"]["endpoint"]["enum"] = serde_json::json!(endpoint_names);
+        }
 
         Ok(JsonRpcResponse {
             jsonrpc: "2.0".to_string(),
@@ -195,13 +436,31 @@ impl AwsMcpServer {
         })
     }
 
+    async fn handle_completion(&self, request: JsonRpcRequest) -> Result<JsonRpcResponse> {
+        let params = request.params.ok_or_else(|| {
+            McpError::InvalidRequest("Missing params for completion/complete".to_string())
+        })?;
+
+        let params: CompleteParams = serde_json::from_value(params)
+            .map_err(McpError::Serialization)?;
+
+        let completion = completion::complete(&self.catalog, &AwsConfig::load(), &params);
+
+        Ok(JsonRpcResponse {
+            jsonrpc: "2.0".to_string(),
+            id: request.id,
+            result: Some(serde_json::json!({ "completion": completion })),
+            error: None,
+        })
+    }
+
     async fn handle_tool_call(&mut self, request: JsonRpcRequest) -> Result<JsonRpcResponse> {
         let params = request.params.ok_or_else(|| {
             McpError::InvalidRequest("Missing params for tools/call".to_string())
         })?;
 
         let tool_call: ToolCall = serde_json::from_value(params)
-            .map_err(|e| McpError::Serialization(e))?;
+            .map_err(McpError::Serialization)?;
 
         if tool_call.name != "use_aws" {
             let error = JsonRpcError {
@@ -218,54 +477,83 @@ impl AwsMcpServer {
         }
 
         let use_aws_request: UseAwsRequest = serde_json::from_value(tool_call.arguments)
-            .map_err(|e| McpError::Serialization(e))?;
+            .map_err(McpError::Serialization)?;
 
         // Generate a human-readable description of the command
-        let use_aws = UseAws::from(use_aws_request.clone());
-        let mut description_output = Vec::new();
-        if let Err(e) = use_aws.queue_description(&mut description_output) {
-            tracing::warn!("Failed to generate command description: {}", e);
-        }
+        let mut use_aws = UseAws::from(use_aws_request.clone());
+        use_aws.redaction = self.redaction.clone();
+        use_aws.retry = self.retry.clone();
+        let model = self.catalog.model(&use_aws.service_name);
+        let checked = match use_aws.validate().await {
+            Ok(()) => self
+                .roots
+                .check(&use_aws)
+                .map_err(|e| e.to_string())
+                .and_then(|()| self.policy.apply(&mut use_aws, model.as_deref()).map_err(|e| e.to_string()))
+                .map_err(|e| (Decision::Denied, e)),
+            Err(e) => Err((Decision::Invalid, e.to_string())),
+        };
+        // Calls over a limit don't reach AWS, not even for the preflight
+        let checked = checked.and_then(|()| {
+            self.limiter
+                .check(&use_aws, Instant::now())
+                .map_err(|e| (Decision::Limited, e.to_string()))
+        });
 
-        let result = use_aws.invoke().await;
+        let started = Instant::now();
+        // Cached results are keyed by the account the preflight resolves, or else the one the
+        // profile configures, which is only read when the result may be cached. Writes of an
+        // unknown account invalidate the service's results of every account.
+        let account = (self.identities.is_none() && self.cache.applies_to(&use_aws))
+            .then(|| {
+                AwsConfig::load()
+                    .profile(use_aws.profile_name.as_deref().unwrap_or("default"))
+                    .and_then(|profile| profile.account_id())
+                    .map(str::to_string)
+            })
+            .flatten();
+        let cached = Cached::new(self.executor.as_ref(), &self.cache, account);
+        let executor = Observed::new(&cached);
+        // The preflight is retried like the command, but runs outside `executor` so the audit
+        // only records the exit status of the command itself
+        if let (Ok(()), Some(identities)) = (&checked, &self.identities) {
+            use_aws.identity = identities.resolve(&Retrying::new(&cached, &use_aws.retry), &use_aws).await;
+            if let Some(identity) = &use_aws.identity {
+                cached.set_account(Some(identity.account.clone()));
+            }
+        }
+        let checked = checked.and_then(|()| {
+            self.policy
+                .check_account(&use_aws)
+                .map_err(|e| (Decision::Denied, e.to_string()))
+        });
+        // Only calls the account policy allows count against the limits
+        let checked = checked.and_then(|()| {
+            self.limiter
+                .admit(&use_aws, Instant::now())
+                .map_err(|e| (Decision::Limited, e.to_string()))
+        });
+        let (decision, result) = match checked {
+            Ok(()) => (Decision::Allowed, self.run_tool(&use_aws, &executor, &cached).await),
+            Err((decision, e)) => (decision, Err(eyre::eyre!(e))),
+        };
+        self.audit(&use_aws, decision, executor.exit_status(), started.elapsed(), &result);
 
         match result {
-            Ok(invoke_output) => {
-                let response: UseAwsResponse = invoke_output.into();
-                
-                // Include the description in the response if available
-                let description = if !description_output.is_empty() {
-                    String::from_utf8(description_output).unwrap_or_default()
-                } else {
-                    String::new()
-                };
-
-                let content = serde_json::json!([
-                    {
-                        "type": "text",
-                        "text": format!("{}\n\nResult:\n{}", 
-                            description,
-                            serde_json::to_string(&response).unwrap_or_default()
-                        )
-                    }
-                ]);
-
-                let tool_result = serde_json::json!({
-                    "content": content
-                });
-
-                Ok(JsonRpcResponse {
-                    jsonrpc: "2.0".to_string(),
-                    id: request.id,
-                    result: Some(tool_result),
-                    error: None,
-                })
-            }
+            Ok(tool_result) => Ok(JsonRpcResponse {
+                jsonrpc: "2.0".to_string(),
+                id: request.id,
+                result: Some(tool_result),
+                error: None,
+            }),
             Err(e) => {
                 let error = JsonRpcError {
                     code: -32000,
                     message: format!("Tool execution failed: {}", e),
-                    data: None,
+                    data: match e.downcast_ref::<McpError>() {
+                        Some(McpError::AwsCli(error)) => serde_json::to_value(error).ok(),
+                        _ => None,
+                    },
                 };
                 Ok(JsonRpcResponse {
                     jsonrpc: "2.0".to_string(),
@@ -277,10 +565,111 @@ impl AwsMcpServer {
         }
     }
 
-    async fn handle_notification(&self, notification: JsonRpcNotification) -> Result<()> {
+    /// Runs a checked command and builds the tool result
+    async fn run_tool(&self, use_aws: &UseAws, executor: &dyn AwsExecutor, cached: &Cached<'_>) -> eyre::Result<serde_json::Value> {
+        let mut description_output = Vec::new();
+        if let Err(e) = use_aws.queue_description(&mut description_output) {
+            tracing::warn!("Failed to generate command description: {}", e);
+        }
+
+        let result = match self.paginator(use_aws) {
+            Some(paginator) => use_aws.invoke_paginated(executor, &paginator, self.max_items).await,
+            None if use_aws.cursor.is_some() => Err(eyre::eyre!(
+                "'{} {}' doesn't return pages, remove the cursor",
+                use_aws.service_name,
+                use_aws.operation_name
+            )),
+            None => use_aws.invoke_with(executor).await,
+        };
+        let mut response: UseAwsResponse = result?.into();
+        response.cached = cached.note();
+        response.identity = use_aws.identity.clone();
+
+        // Include the description in the response if available
+        let description = if !description_output.is_empty() {
+            String::from_utf8(description_output).unwrap_or_default()
+        } else {
+            String::new()
+        };
+
+        let content = serde_json::json!([
+            {
+                "type": "text",
+                "text": format!("{}\n\nResult:\n{}",
+                    description,
+                    serde_json::to_string(&response).unwrap_or_default()
+                )
+            }
+        ]);
+
+        let mut tool_result = serde_json::json!({
+            "content": content
+        });
+        // JSON output is also returned parsed, unless truncation left it invalid
+        if use_aws.output_format == Some(OutputFormat::Json) {
+            if let Ok(stdout) = serde_json::from_str::<serde_json::Value>(&response.stdout) {
+                let mut structured = serde_json::to_value(&response).unwrap_or_default();
+                structured["stdout"] = stdout;
+                tool_result["structuredContent"] = structured;
+            }
+        }
+        Ok(tool_result)
+    }
+
+    /// Appends the call to the audit log, if one is configured. A failure to write is logged
+    /// but doesn't fail the call.
+    fn audit(
+        &self,
+        use_aws: &UseAws,
+        decision: Decision,
+        exit_status: Option<i32>,
+        duration: Duration,
+        result: &eyre::Result<serde_json::Value>,
+    ) {
+        let Some(audit_log) = &self.audit_log else {
+            return;
+        };
+        let (output_bytes, error) = match result {
+            Ok(tool_result) => (tool_result.to_string().len(), None),
+            Err(e) => {
+                let error = e.to_string();
+                (error.len(), Some(error))
+            },
+        };
+        let entry = AuditEntry::new(use_aws, self.client_name.clone(), decision).finish(
+            exit_status,
+            duration,
+            output_bytes,
+            error,
+        );
+        if let Err(e) = audit_log.append(&entry) {
+            tracing::warn!("Failed to write audit log '{}': {:#}", audit_log.path().display(), e);
+        }
+    }
+
+    /// Returns the paginator of a read-only command the server paginates itself
+    fn paginator(&self, use_aws: &UseAws) -> Option<Paginator> {
+        if use_aws.requires_acceptance() || customizations::is_customization(&use_aws.service_name, &use_aws.operation_name) {
+            return None;
+        }
+        let model = self.catalog.model(&use_aws.service_name)?;
+        model
+            .paginator(&use_aws.operation_name)
+            .filter(|paginator| pagination::applies(use_aws, paginator))
+            .cloned()
+    }
+
+    async fn handle_notification(&mut self, notification: JsonRpcNotification) -> Result<()> {
         match notification.method.as_str() {
             "notifications/initialized" => {
                 // Server is initialized, we can start handling requests
+                if self.client_supports_roots {
+                    self.request_roots();
+                }
+                Ok(())
+            }
+            "notifications/roots/list_changed" => {
+                self.request_roots();
                 Ok(())
             }
             _ => {
@@ -297,7 +686,7 @@ impl AwsMcpServer {
         }
 
         let use_aws_request: UseAwsRequest = serde_json::from_value(tool_call.arguments.clone())
-            .map_err(|e| McpError::Serialization(e))?;
+            .map_err(McpError::Serialization)?;
 
         let use_aws = UseAws::from(use_aws_request);
         let mut output = Vec::new();
@@ -309,6 +698,14 @@ impl AwsMcpServer {
     }
 }
 
+fn write_message(writer: &mut impl Write, message: &impl Serialize) -> Result<()> {
+    let message_str = serde_json::to_string(message)
+        .map_err(McpError::Serialization)?;
+    writeln!(writer, "{}", message_str)
+        .map_err(McpError::Io)?;
+    writer.flush().map_err(McpError::Io)
+}
+
 #[derive(Debug, Clone, Deserialize)]
 pub struct ToolCall {
     pub name: String,
@@ -320,3 +717,345 @@ impl Default for AwsMcpServer {
         Self::new()
     }
 } 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::executor::{CommandOutput, MockExecutor};
+
+    /// Runs the server over the given client messages and returns the server's replies
+    async fn serve(server: &mut AwsMcpServer, messages: &[serde_json::Value]) -> Vec<serde_json::Value> {
+        let input: String = messages.iter().map(|m| format!("{}\n", m)).collect();
+        let mut output = Vec::new();
+        server.serve(input.as_bytes(), &mut output).await.unwrap();
+        output
+            .lines()
+            .map(|line| serde_json::from_str(&line.unwrap()).unwrap())
+            .collect()
+    }
+
+    fn tool_call(id: u64, arguments: serde_json::Value) -> serde_json::Value {
+        serde_json::json!({
+            "jsonrpc": "2.0",
+            "id": id,
+            "method": "tools/call",
+            "params": {"name": "use_aws", "arguments": arguments}
+        })
+    }
+
+    #[tokio::test]
+    async fn test_tool_call_with_mock_executor() {
+        let executor = Arc::new(
+            MockExecutor::new()
+                .respond_to("sts", "get-caller-identity", CommandOutput::success(r#"{"Account": "123456789012"}"#))
+                .respond(CommandOutput::failure(254, "An error occurred (AccessDenied)")),
+        );
+        let mut server =
+            AwsMcpServer::with_executor(Arc::new(ServiceCatalog::default()), executor.clone()).with_aws_cli_version("2.15.0");
+
+        let replies = serve(&mut server, &[
+            serde_json::json!({"jsonrpc": "2.0", "id": 1, "method": "initialize", "params": {"capabilities": {}}}),
+            serde_json::json!({"jsonrpc": "2.0", "method": "notifications/initialized"}),
+            tool_call(2, serde_json::json!({
+                "service_name": "sts",
+                "operation_name": "get-caller-identity",
+                "region": "us-east-1"
+            })),
+            tool_call(3, serde_json::json!({
+                "service_name": "s3api",
+                "operation_name": "list-buckets",
+                "region": "us-east-1"
+            })),
+        ])
+        .await;
+
+        assert_eq!(replies.len(), 3);
+        assert_eq!(replies[0]["result"]["serverInfo"]["awsCliVersion"], "2.15.0");
+        let text = replies[1]["result"]["content"][0]["text"].as_str().unwrap();
+        assert!(text.contains("Service name: sts"));
+        assert!(text.contains("123456789012"));
+        assert_eq!(replies[2]["error"]["code"], -32000);
+        assert!(replies[2]["error"]["message"].as_str().unwrap().contains("AccessDenied"));
+        assert_eq!(replies[2]["error"]["data"]["category"], "access-denied");
+        assert_eq!(replies[2]["error"]["data"]["code"], "AccessDenied");
+        assert!(replies[2]["error"]["data"]["hint"].as_str().unwrap().contains("`s3:ListBuckets`"));
+        assert_eq!(executor.calls().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_tool_call_policy() {
+        let config: crate::config::Config = serde_json::from_value(serde_json::json!({
+            "access": "read-only",
+            "endpoints": {"localstack": {"url": "http://localhost:4566"}}
+        }))
+        .unwrap();
+        let executor = Arc::new(MockExecutor::new().respond(CommandOutput::success(r#"{"Location": "/demo"}"#)));
+        let mut server = AwsMcpServer::with_executor(Arc::new(ServiceCatalog::default()), executor.clone())
+            .with_policy(Policy::from_config(&config));
+
+        let replies = serve(&mut server, &[
+            serde_json::json!({"jsonrpc": "2.0", "id": 1, "method": "tools/list"}),
+            tool_call(2, serde_json::json!({
+                "service_name": "s3api",
+                "operation_name": "create-bucket",
+                "parameters": {"bucket": "demo"},
+                "region": "us-east-1",
+                "endpoint": "localstack"
+            })),
+            tool_call(3, serde_json::json!({
+                "service_name": "s3api",
+                "operation_name": "create-bucket",
+                "parameters": {"bucket": "demo"},
+                "region": "us-east-1"
+            })),
+            tool_call(4, serde_json::json!({
+                "service_name": "s3api",
+                "operation_name": "list-buckets",
+                "parameters": {"endpoint-url": "http://attacker.example"},
+                "region": "us-east-1"
+            })),
+        ])
+        .await;
+
+        assert_eq!(
+            replies[0]["result"]["tools"][0]["inputSchema"]["properties"]["endpoint"]["enum"],
+            serde_json::json!(["localstack"])
+        );
+        assert!(replies[1]["result"]["content"][0]["text"].as_str().unwrap().contains("Endpoint: localstack (http://localhost:4566)"));
+        assert!(replies[2]["error"]["message"].as_str().unwrap().contains("AWS is read-only"));
+        assert!(replies[3]["error"]["message"].as_str().unwrap().contains("can't be set directly"));
+        let calls = executor.calls();
+        assert_eq!(calls.len(), 1);
+        assert_eq!(calls[0].endpoint_url.as_deref(), Some("http://localhost:4566"));
+    }
+
+    #[tokio::test]
+    async fn test_tool_call_pagination() {
+        let dir = tempfile::tempdir().unwrap();
+        crate::service_model::tests::write_data_dir(dir.path());
+        let page = |id: &str, next_token: &str| {
+            CommandOutput::success(serde_json::json!({"Reservations": [{"ReservationId": id}], "NextToken": next_token}).to_string())
+        };
+        let executor = Arc::new(MockExecutor::new().respond(page("r-1", "t1")).respond(page("r-2", "t2")));
+        let mut server = AwsMcpServer::with_executor(Arc::new(ServiceCatalog::from_data_dir(dir.path())), executor.clone())
+            .with_max_items(1);
+        let describe_instances = serde_json::json!({
+            "service_name": "ec2",
+            "operation_name": "describe-instances",
+            "region": "us-east-1"
+        });
+
+        let replies = serve(&mut server, &[tool_call(1, describe_instances.clone())]).await;
+        let text = replies[0]["result"]["content"][0]["text"].as_str().unwrap();
+        let response: serde_json::Value = serde_json::from_str(text.split_once("Result:\n").unwrap().1).unwrap();
+        assert!(response["stdout"].as_str().unwrap().contains("r-1"));
+        let next_cursor = response["next_cursor"].as_str().unwrap().to_string();
+
+        let mut next_page = describe_instances.clone();
+        next_page["cursor"] = next_cursor.clone().into();
+        let mut not_paginated = serde_json::json!({"service_name": "sts", "operation_name": "get-caller-identity", "region": "us-east-1"});
+        not_paginated["cursor"] = next_cursor.into();
+        let replies = serve(&mut server, &[tool_call(2, next_page), tool_call(3, not_paginated)]).await;
+        assert!(replies[0]["result"]["content"][0]["text"].as_str().unwrap().contains("r-2"));
+        assert!(replies[1]["error"]["message"].as_str().unwrap().contains("doesn't return pages"));
+        let calls = executor.calls();
+        assert_eq!(calls.len(), 2);
+        assert_eq!(calls[1].parameters.as_ref().unwrap()["NextToken"], "t1");
+    }
+
+    #[tokio::test]
+    async fn test_tool_call_output_format() {
+        let buckets = serde_json::json!({"Buckets": [{"Name": "logs"}, {"Name": "assets"}]}).to_string();
+        let executor = Arc::new(MockExecutor::new().respond(CommandOutput::success(buckets)));
+        let mut server = AwsMcpServer::with_executor(Arc::new(ServiceCatalog::default()), executor.clone());
+        let list_buckets = |format: &str| {
+            serde_json::json!({
+                "service_name": "s3api",
+                "operation_name": "list-buckets",
+                "region": "us-east-1",
+                "output_format": format
+            })
+        };
+
+        let replies = serve(&mut server, &[tool_call(1, list_buckets("json")), tool_call(2, list_buckets("csv"))]).await;
+        assert_eq!(replies[0]["result"]["structuredContent"]["stdout"]["Buckets"][1]["Name"], "assets");
+        assert_eq!(replies[0]["result"]["structuredContent"]["exit_status"], "0");
+        let text = replies[1]["result"]["content"][0]["text"].as_str().unwrap();
+        let response: serde_json::Value = serde_json::from_str(text.split_once("Result:\n").unwrap().1).unwrap();
+        assert_eq!(response["stdout"], "Name\r\nlogs\r\nassets\r\n");
+        assert!(replies[1]["result"].get("structuredContent").is_none());
+        // Formats are applied to the cached result
+        assert!(response["cached"].as_str().unwrap().starts_with("Returned from the cache"));
+        let calls = executor.calls();
+        assert_eq!(calls.len(), 1);
+        assert!(calls[0].canonical_command(None).ends_with("list-buckets --output json"));
+    }
+
+    #[tokio::test]
+    async fn test_tool_call_audit_log() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("audit.jsonl");
+        let executor = Arc::new(
+            MockExecutor::new()
+                .respond(CommandOutput::success(r#"{"Account": "123456789012"}"#))
+                .respond(CommandOutput::failure(254, "An error occurred (AccessDenied)")),
+        );
+        let mut server = AwsMcpServer::with_executor(Arc::new(ServiceCatalog::default()), executor)
+            .with_audit_log(
+                AuditLog::from_config(&crate::audit::AuditConfig {
+                    path: Some(path.clone()),
+                    ..Default::default()
+                })
+                .unwrap(),
+            );
+        serve(&mut server, &[
+            serde_json::json!({"jsonrpc": "2.0", "id": 1, "method": "initialize", "params": {"capabilities": {}, "clientInfo": {"name": "test-client", "version": "1.0"}}}),
+            tool_call(2, serde_json::json!({"service_name": "sts", "operation_name": "get-caller-identity", "region": "us-east-1", "profile_name": "prod"})),
+            tool_call(3, serde_json::json!({
+                "service_name": "secretsmanager",
+                "operation_name": "delete-secret",
+                "parameters": {"secret-id": "prod/db"},
+                "region": "us-east-1",
+                "label": "Clean up"
+            })),
+            tool_call(4, serde_json::json!({"service_name": "s3", "operation_name": "ls", "parameters": {"profile": "prod"}, "region": "us-east-1"})),
+        ])
+        .await;
+
+        let entries: Vec<AuditEntry> = std::fs::read_to_string(&path)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(entries.len(), 3);
+        assert_eq!(entries[0].client.as_deref(), Some("test-client"));
+        assert_eq!((entries[0].decision, entries[0].exit_status), (Decision::Allowed, Some(0)));
+        assert_eq!(entries[0].profile.as_deref(), Some("prod"));
+        assert!(entries[0].output_bytes > 0);
+        assert_eq!(entries[1].risk_tier, crate::use_aws::RiskTier::Destructive);
+        assert_eq!(entries[1].exit_status, Some(254));
+        assert_eq!(entries[1].label.as_deref(), Some("Clean up"));
+        assert!(entries[1].error.as_ref().unwrap().contains("AccessDenied"));
+        assert_eq!((entries[2].decision, entries[2].exit_status), (Decision::Denied, None));
+    }
+
+    #[tokio::test]
+    async fn test_tool_call_limits() {
+        let executor = Arc::new(MockExecutor::new().respond(CommandOutput::success("{}")));
+        let mut server = AwsMcpServer::with_executor(Arc::new(ServiceCatalog::default()), executor.clone())
+            .with_limits(LimitsConfig {
+                session: crate::limits::Limits {
+                    max_calls: Some(1),
+                    ..Default::default()
+                },
+                ..Default::default()
+            });
+        let call = serde_json::json!({"service_name": "sts", "operation_name": "get-caller-identity", "region": "us-east-1"});
+        let replies = serve(&mut server, &[tool_call(1, call.clone()), tool_call(2, call)]).await;
+        assert!(replies[0]["result"].is_object());
+        assert_eq!(
+            replies[1]["error"]["message"],
+            "Tool execution failed: Budget of 1 calls reached for this session; start a new session to continue"
+        );
+        assert_eq!(executor.calls().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_tool_call_retry() {
+        let throttled = || {
+            CommandOutput::failure(254, "An error occurred (ThrottlingException) when calling the ListFunctions operation: Rate exceeded")
+        };
+        let executor = Arc::new(
+            MockExecutor::new()
+                .respond(throttled())
+                .respond(CommandOutput::success(r#"{"Functions": []}"#))
+                .respond(throttled()),
+        );
+        let mut server = AwsMcpServer::with_executor(Arc::new(ServiceCatalog::default()), executor.clone())
+            .with_retry(RetryConfig {
+                base_delay_ms: 0,
+                ..RetryConfig::default()
+            });
+        let replies = serve(&mut server, &[
+            tool_call(1, serde_json::json!({"service_name": "lambda", "operation_name": "list-functions", "region": "us-east-1"})),
+            tool_call(2, serde_json::json!({"service_name": "lambda", "operation_name": "create-alias", "region": "us-east-1"})),
+        ])
+        .await;
+        let text = replies[0]["result"]["content"][0]["text"].as_str().unwrap();
+        let response: serde_json::Value = serde_json::from_str(text.split_once("Result:\n").unwrap().1).unwrap();
+        assert_eq!(response["attempts"], 2);
+        // Writes aren't retried
+        assert!(replies[1]["error"]["message"].as_str().unwrap().contains("ThrottlingException"));
+        assert_eq!(executor.calls().len(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_tool_call_identity() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("audit.jsonl");
+        let config: crate::config::Config = serde_json::from_value(serde_json::json!({
+            "accounts": {"123456789012": "read-only"}
+        }))
+        .unwrap();
+        let executor = Arc::new(
+            MockExecutor::new()
+                .respond_to(
+                    "sts",
+                    "get-caller-identity",
+                    CommandOutput::success(r#"{"Account": "123456789012", "Arn": "arn:aws:sts::123456789012:assumed-role/Admin/alice"}"#),
+                )
+                .respond_to("iam", "list-account-aliases", CommandOutput::success(r#"{"AccountAliases": ["acme-prod"]}"#))
+                .respond_to("ec2", "describe-regions", CommandOutput::success(r#"{"Regions": []}"#)),
+        );
+        let mut server = AwsMcpServer::with_executor(Arc::new(ServiceCatalog::default()), executor.clone())
+            .with_policy(Policy::from_config(&config))
+            .with_limits(LimitsConfig {
+                session: crate::limits::Limits {
+                    max_calls: Some(2),
+                    ..Default::default()
+                },
+                ..Default::default()
+            })
+            .with_audit_log(
+                AuditLog::from_config(&crate::audit::AuditConfig {
+                    path: Some(path.clone()),
+                    ..Default::default()
+                })
+                .unwrap(),
+            )
+            .with_identity_preflight();
+        let command = |operation_name: &str, profile_name: &str| {
+            serde_json::json!({"service_name": "ec2", "operation_name": operation_name, "region": "us-east-1", "profile_name": profile_name})
+        };
+        let replies = serve(&mut server, &[
+            tool_call(1, command("describe-regions", "alice-prod")),
+            tool_call(2, command("reboot-instances", "alice-prod")),
+            tool_call(3, command("describe-regions", "alice-prod")),
+            tool_call(4, command("describe-regions", "bob-dev")),
+        ])
+        .await;
+
+        let text = replies[0]["result"]["content"][0]["text"].as_str().unwrap();
+        assert!(text.contains("Account: 123456789012 (acme-prod)\nPrincipal: arn:aws:sts::123456789012:assumed-role/Admin/alice"));
+        let response: serde_json::Value = serde_json::from_str(text.split_once("Result:\n").unwrap().1).unwrap();
+        assert_eq!(response["identity"]["account_alias"], "acme-prod");
+        assert_eq!(
+            replies[1]["error"]["message"],
+            "Tool execution failed: 'ec2 reboot-instances' modifies resources, but account 123456789012 (acme-prod) is read-only"
+        );
+        // The denied call didn't count against the budget, and the call over budget didn't
+        // resolve an identity. The identity was resolved once, the third call came from the cache.
+        assert!(replies[2]["result"].is_object());
+        assert!(replies[3]["error"]["message"].as_str().unwrap().contains("Budget of 2 calls reached"));
+        assert_eq!(executor.calls().len(), 3);
+
+        // The denied command never ran, whatever the preflight exited with
+        let entries: Vec<AuditEntry> = std::fs::read_to_string(&path)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!((entries[1].decision, entries[1].exit_status), (Decision::Denied, None));
+        assert_eq!((entries[3].decision, entries[3].exit_status), (Decision::Limited, None));
+    }
+}
@@ -0,0 +1,384 @@
+//! An executor that calls AWS APIs directly over HTTPS, signing requests itself, so no AWS CLI
+//! installation is needed. Supports a core set of services and operations; see
+//! [NativeExecutor::supports].
+
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use eyre::{Result, WrapErr, eyre};
+use sha2::{Digest, Sha256};
+
+use crate::aws_config::AwsConfig;
+use crate::executor::{AwsExecutor, CommandOutput};
+use crate::protocol::{self, HttpRequest, HttpResponse};
+use crate::service_model::{ServiceCatalog, ServiceModel};
+use crate::sigv4::{self, Credentials, SigningScope};
+use crate::use_aws::UseAws;
+
+const USER_AGENT: &str = concat!("UseAws-MCP-Server/", env!("CARGO_PKG_VERSION"));
+
+/// Exit codes of AWS CLI v2, used so callers can't tell the backends apart
+const EXIT_USAGE_ERROR: i32 = 252;
+const EXIT_CONFIG_ERROR: i32 = 253;
+const EXIT_SERVICE_ERROR: i32 = 254;
+const EXIT_OTHER_ERROR: i32 = 255;
+
+/// Runs commands as signed HTTP requests. Output, errors and exit codes follow the AWS CLI,
+/// but results are not paginated: only the first page is returned, with its `NextToken`.
+#[derive(Debug)]
+pub struct NativeExecutor {
+    catalog: Arc<ServiceCatalog>,
+    client: reqwest::Client,
+    endpoint_url: Option<String>,
+    credentials: Option<Credentials>,
+}
+
+impl NativeExecutor {
+    /// Creates an executor serializing requests according to the service models in `catalog`
+    pub fn new(catalog: Arc<ServiceCatalog>) -> Self {
+        let client = reqwest::Client::builder()
+            .user_agent(USER_AGENT)
+            .build()
+            .expect("the TLS backend is statically linked");
+        Self {
+            catalog,
+            client,
+            endpoint_url: None,
+            credentials: None,
+        }
+    }
+
+    /// Sends every request to `url` instead of the regional AWS endpoint
+    pub fn with_endpoint_url(mut self, url: impl Into<String>) -> Self {
+        self.endpoint_url = Some(url.into());
+        self
+    }
+
+    /// Signs with `credentials` instead of resolving them from the environment and profiles
+    pub fn with_credentials(mut self, credentials: Credentials) -> Self {
+        self.credentials = Some(credentials);
+        self
+    }
+
+    /// Returns whether the operation can run without the AWS CLI: any STS, Lambda, DynamoDB
+    /// and CloudWatch Logs operation, S3 list, get and head operations and EC2 describe
+    /// operations
+    pub fn supports(service_name: &str, operation_name: &str) -> bool {
+        match service_name {
+            "sts" | "lambda" | "dynamodb" | "logs" => true,
+            "s3api" => ["list-", "get-", "head-"].iter().any(|prefix| operation_name.starts_with(prefix)),
+            "ec2" => operation_name.starts_with("describe-"),
+            _ => false,
+        }
+    }
+
+    /// Resolves credentials like the AWS CLI does for static keys: the profile of the command,
+    /// then the `AWS_ACCESS_KEY_ID` environment variables, then `AWS_PROFILE` or the default
+    /// profile
+    fn resolve_credentials(&self, profile_name: Option<&str>) -> Result<Credentials> {
+        if let Some(credentials) = &self.credentials {
+            return Ok(credentials.clone());
+        }
+        let env = |name: &str| std::env::var(name).ok().filter(|value| !value.is_empty());
+        if profile_name.is_none() {
+            if let (Some(access_key_id), Some(secret_access_key)) = (env("AWS_ACCESS_KEY_ID"), env("AWS_SECRET_ACCESS_KEY")) {
+                return Ok(Credentials {
+                    access_key_id,
+                    secret_access_key,
+                    session_token: env("AWS_SESSION_TOKEN"),
+                });
+            }
+        }
+        let name = profile_name
+            .map(|name| name.to_string())
+            .or_else(|| env("AWS_PROFILE"))
+            .unwrap_or_else(|| "default".to_string());
+        let config = AwsConfig::load();
+        let profile = config
+            .profile(&name)
+            .ok_or_else(|| eyre!("The config profile ({}) could not be found", name))?;
+        match (profile.get("aws_access_key_id"), profile.get("aws_secret_access_key")) {
+            (Some(access_key_id), Some(secret_access_key)) => Ok(Credentials {
+                access_key_id: access_key_id.to_string(),
+                secret_access_key: secret_access_key.to_string(),
+                session_token: profile.get("aws_session_token").map(|token| token.to_string()),
+            }),
+            _ => Err(eyre!(
+                "Profile ({}) has no static credentials. The native backend does not support SSO, \
+                 assume-role or credential_process profiles; use the AWS CLI backend",
+                name
+            )),
+        }
+    }
+
+    /// The endpoint of the command, set by the server from a configured endpoint, then the
+    /// executor's endpoint, then the regional AWS endpoint
+    fn endpoint(&self, model: &ServiceModel, command: &UseAws) -> String {
+        if let Some(url) = command.endpoint_url.as_ref().or(self.endpoint_url.as_ref()) {
+            return url.trim_end_matches('/').to_string();
+        }
+        let region = &command.region;
+        let suffix = if region.starts_with("cn-") { "amazonaws.com.cn" } else { "amazonaws.com" };
+        format!("https://{}.{}.{}", model.metadata.endpoint_prefix, region, suffix)
+    }
+
+    async fn send(&self, command: &UseAws, model: &ServiceModel, mut request: HttpRequest) -> Result<HttpResponse> {
+        let credentials = self.resolve_credentials(command.profile_name.as_deref())?;
+        let endpoint = self.endpoint(model, command);
+        let url = reqwest::Url::parse(&format!("{}{}", endpoint, request.path_and_query()))
+            .wrap_err_with(|| format!("Invalid endpoint URL '{}'", endpoint))?;
+        let host = match (url.host_str(), url.port()) {
+            (Some(host), Some(port)) => format!("{}:{}", host, port),
+            (Some(host), None) => host.to_string(),
+            (None, _) => return Err(eyre!("Invalid endpoint URL '{}'", endpoint)),
+        };
+        let service = model
+            .metadata
+            .signing_name
+            .as_deref()
+            .unwrap_or(&model.metadata.endpoint_prefix);
+        if service == "s3" {
+            request.set_header("x-amz-content-sha256", &hex::encode(Sha256::digest(&request.body)));
+        }
+        let scope = SigningScope {
+            region: &command.region,
+            service,
+            double_encode_path: service != "s3",
+        };
+        sigv4::sign(&mut request, &host, &credentials, scope, chrono::Utc::now());
+
+        tracing::info!("Sending {} {}", request.method, url);
+        let method = reqwest::Method::from_bytes(request.method.as_bytes())?;
+        let mut builder = self.client.request(method, url).body(request.body);
+        for (name, value) in &request.headers {
+            builder = builder.header(name, value);
+        }
+        let response = builder.send().await?;
+        Ok(HttpResponse {
+            status: response.status().as_u16(),
+            headers: response
+                .headers()
+                .iter()
+                .map(|(name, value)| (name.to_string(), value.to_str_lossy()))
+                .collect(),
+            body: response.bytes().await?.to_vec(),
+        })
+    }
+}
+
+trait HeaderValueExt {
+    fn to_str_lossy(&self) -> String;
+}
+
+impl HeaderValueExt for reqwest::header::HeaderValue {
+    fn to_str_lossy(&self) -> String {
+        String::from_utf8_lossy(self.as_bytes()).into_owned()
+    }
+}
+
+#[async_trait]
+impl AwsExecutor for NativeExecutor {
+    async fn execute(&self, command: &UseAws) -> Result<CommandOutput> {
+        let usage_error = |message: String| Ok(CommandOutput::failure(EXIT_USAGE_ERROR, format!("\n{}\n", message)));
+        if !Self::supports(&command.service_name, &command.operation_name) {
+            return usage_error(format!(
+                "'{} {}' is not supported by the native backend",
+                command.service_name, command.operation_name
+            ));
+        }
+        let Some(model) = self.catalog.model(&command.service_name) else {
+            return usage_error(format!(
+                "No service model found for '{}', set {} to a botocore data directory",
+                command.service_name,
+                crate::service_model::BOTOCORE_DATA_ENV_VAR
+            ));
+        };
+        let Some(operation) = model.operation(&command.operation_name) else {
+            return usage_error(format!("Invalid choice: '{}'", command.operation_name));
+        };
+        let request = match command
+            .parameters
+            .as_ref()
+            .map_or_else(|| Ok(Default::default()), |parameters| protocol::build_input(&model, operation, parameters))
+            .and_then(|input| protocol::serialize_request(&model, operation, &input))
+        {
+            Ok(request) => request,
+            Err(e) => return usage_error(e.to_string()),
+        };
+
+        let response = match self.send(command, &model, request).await {
+            Ok(response) => response,
+            Err(e) if e.is::<reqwest::Error>() => {
+                let endpoint = self.endpoint(&model, command);
+                return Ok(CommandOutput::failure(
+                    EXIT_OTHER_ERROR,
+                    format!("\nCould not connect to the endpoint URL: \"{}\"\n", endpoint),
+                ));
+            },
+            Err(e) => return Ok(CommandOutput::failure(EXIT_CONFIG_ERROR, format!("\n{:#}\n", e))),
+        };
+
+        if !(200..300).contains(&response.status) {
+            let error = protocol::parse_error(&model, &response);
+            return Ok(CommandOutput::failure(
+                EXIT_SERVICE_ERROR,
+                format!(
+                    "\nAn error occurred ({}) when calling the {} operation: {}\n",
+                    error.code, operation.name, error.message
+                ),
+            ));
+        }
+
+        let parsed = protocol::parse_response(&model, operation, &response)?;
+        if let Some(payload) = &parsed.payload {
+            match command.local_positional_paths().first() {
+                Some(outfile) => std::fs::write(outfile, payload)
+                    .wrap_err_with(|| format!("Unable to write output file '{}'", outfile))?,
+                None => return usage_error("the following arguments are required: outfile".to_string()),
+            }
+        }
+        if operation.output.is_none() {
+            return Ok(CommandOutput::success(""));
+        }
+        Ok(CommandOutput::success(cli_json(&parsed.output)))
+    }
+}
+
+/// Prints JSON the way the AWS CLI does, indented by four spaces with a trailing newline
+pub(crate) fn cli_json(value: &serde_json::Value) -> String {
+    let mut stdout = Vec::new();
+    let formatter = serde_json::ser::PrettyFormatter::with_indent(b"    ");
+    let mut serializer = serde_json::Serializer::with_formatter(&mut stdout, formatter);
+    // Serializing a `Value` into memory can't fail
+    let _ = serde::Serialize::serialize(value, &mut serializer);
+    stdout.push(b'\n');
+    String::from_utf8_lossy(&stdout).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fake_endpoint::FakeEndpoint;
+
+    fn credentials() -> Credentials {
+        Credentials {
+            access_key_id: "AKIDEXAMPLE".to_string(),
+            secret_access_key: "wJalrXUtnFEMI/K7MDENG+bPxRfiCYEXAMPLEKEY".to_string(),
+            session_token: Some("session".to_string()),
+        }
+    }
+
+    async fn executor(endpoint: &FakeEndpoint, data_dir: &std::path::Path) -> NativeExecutor {
+        crate::service_model::tests::write_data_dir(data_dir);
+        NativeExecutor::new(Arc::new(ServiceCatalog::from_data_dir(data_dir)))
+            .with_endpoint_url(endpoint.url())
+            .with_credentials(credentials())
+    }
+
+    fn describe_instances(parameters: serde_json::Value) -> UseAws {
+        serde_json::from_value(serde_json::json!({
+            "service_name": "ec2",
+            "operation_name": "describe-instances",
+            "parameters": parameters,
+            "region": "eu-west-1"
+        }))
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_native_describe_instances() {
+        let dir = tempfile::tempdir().unwrap();
+        let endpoint = FakeEndpoint::start().await;
+        endpoint.respond(
+            200,
+            r#"<DescribeInstancesResponse xmlns="http://ec2.amazonaws.com/doc/2016-11-15/">
+    <requestId>req-1</requestId>
+    <reservationSet>
+        <item>
+            <reservationId>r-1</reservationId>
+            <instancesSet>
+                <item><instanceId>i-1</instanceId><launchTime>2024-01-02T03:04:05.000Z</launchTime></item>
+            </instancesSet>
+        </item>
+    </reservationSet>
+</DescribeInstancesResponse>"#,
+        );
+        let executor = executor(&endpoint, dir.path()).await;
+
+        let output = executor
+            .execute(&describe_instances(serde_json::json!({"instance-ids": ["i-1"]})))
+            .await
+            .unwrap();
+        assert_eq!(output.exit_code, 0, "{}", output.stderr);
+        assert_eq!(
+            output.stdout,
+            r#"{
+    "Reservations": [
+        {
+            "ReservationId": "r-1",
+            "Instances": [
+                {
+                    "InstanceId": "i-1",
+                    "LaunchTime": "2024-01-02T03:04:05+00:00"
+                }
+            ]
+        }
+    ]
+}
+"#
+        );
+
+        let requests = endpoint.requests();
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0].method, "POST");
+        assert_eq!(requests[0].body, b"Action=DescribeInstances&Version=2016-11-15&InstanceId.1=i-1");
+        let authorization = requests[0].header("authorization").unwrap();
+        assert!(authorization.starts_with("AWS4-HMAC-SHA256 Credential=AKIDEXAMPLE/"));
+        assert!(authorization.contains("/eu-west-1/ec2/aws4_request"));
+        assert_eq!(requests[0].header("x-amz-security-token"), Some("session"));
+    }
+
+    #[tokio::test]
+    async fn test_native_errors() {
+        let dir = tempfile::tempdir().unwrap();
+        let endpoint = FakeEndpoint::start().await;
+        endpoint.respond(
+            400,
+            r#"<Response><Errors><Error><Code>InvalidInstanceID.NotFound</Code><Message>The instance ID 'i-2' does not exist</Message></Error></Errors><RequestID>req-2</RequestID></Response>"#,
+        );
+        let executor = executor(&endpoint, dir.path()).await;
+
+        let output = executor
+            .execute(&describe_instances(serde_json::json!({"instance-ids": "i-2"})))
+            .await
+            .unwrap();
+        assert_eq!(output.exit_code, EXIT_SERVICE_ERROR);
+        assert_eq!(
+            output.stderr,
+            "\nAn error occurred (InvalidInstanceID.NotFound) when calling the DescribeInstances operation: The instance ID 'i-2' does not exist\n"
+        );
+
+        let invalid = executor
+            .execute(&describe_instances(serde_json::json!({"max-results": "many"})))
+            .await
+            .unwrap();
+        assert_eq!(invalid.exit_code, EXIT_USAGE_ERROR);
+
+        let mut stop = describe_instances(serde_json::json!({"instance-ids": "i-1"}));
+        stop.operation_name = "stop-instances".to_string();
+        let unsupported = executor.execute(&stop).await.unwrap();
+        assert_eq!(unsupported.exit_code, EXIT_USAGE_ERROR);
+        assert!(unsupported.stderr.contains("not supported by the native backend"));
+        assert_eq!(endpoint.requests().len(), 1);
+    }
+
+    #[test]
+    fn test_supports() {
+        assert!(NativeExecutor::supports("sts", "get-caller-identity"));
+        assert!(NativeExecutor::supports("s3api", "list-objects-v2"));
+        assert!(!NativeExecutor::supports("s3api", "put-object"));
+        assert!(NativeExecutor::supports("ec2", "describe-vpcs"));
+        assert!(!NativeExecutor::supports("ec2", "terminate-instances"));
+        assert!(!NativeExecutor::supports("s3", "cp"));
+    }
+}
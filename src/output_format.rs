@@ -0,0 +1,386 @@
+//! Presentation of command results. Commands with an `output_format` always run with
+//! `--output json`, and the server converts the parsed JSON itself, so everything before
+//! presentation works on structured data whatever format the client asked for.
+
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+use crate::native::cli_json;
+
+/// How results are presented to the client
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputFormat {
+    /// Indented JSON, also returned as structured content of the tool result
+    Json,
+    Yaml,
+    /// Tab separated values like the AWS CLI's `text` output
+    Text,
+    /// The records of the result as a table with a column per field
+    Table,
+    /// The records of the result with nested fields flattened into columns
+    Csv,
+}
+
+impl OutputFormat {
+    /// The name clients pass as `output_format`
+    pub fn name(&self) -> &'static str {
+        match self {
+            OutputFormat::Json => "json",
+            OutputFormat::Yaml => "yaml",
+            OutputFormat::Text => "text",
+            OutputFormat::Table => "table",
+            OutputFormat::Csv => "csv",
+        }
+    }
+
+    pub fn render(&self, value: &Value) -> String {
+        match self {
+            OutputFormat::Json => cli_json(value),
+            OutputFormat::Yaml => {
+                let mut out = String::new();
+                yaml(value, 0, &mut out);
+                out
+            },
+            OutputFormat::Text => {
+                let mut rows = Vec::new();
+                text(value, None, &mut rows);
+                rows.iter().map(|row| format!("{}\n", row)).collect()
+            },
+            OutputFormat::Table => table(&records(value)),
+            OutputFormat::Csv => csv(&records(value)),
+        }
+    }
+}
+
+fn yaml(value: &Value, indent: usize, out: &mut String) {
+    let pad = " ".repeat(indent);
+    match value {
+        Value::Object(entries) if !entries.is_empty() => {
+            for (key, item) in entries {
+                out.push_str(&format!("{}{}:", pad, yaml_scalar(&Value::String(key.clone()))));
+                match item {
+                    Value::Object(nested) if !nested.is_empty() => {
+                        out.push('\n');
+                        yaml(item, indent + 2, out);
+                    },
+                    // Sequences under a key aren't indented, like the AWS CLI prints them
+                    Value::Array(items) if !items.is_empty() => {
+                        out.push('\n');
+                        yaml(item, indent, out);
+                    },
+                    item => out.push_str(&format!(" {}\n", yaml_scalar(item))),
+                }
+            }
+        },
+        Value::Array(items) if !items.is_empty() => {
+            for item in items {
+                // Render the item one level deeper, then put the dash in place of the
+                // indentation of its first line
+                let mut nested = String::new();
+                yaml(item, indent + 2, &mut nested);
+                out.push_str(&format!("{}- {}", pad, nested.trim_start_matches(' ')));
+            }
+        },
+        scalar => out.push_str(&format!("{}{}\n", pad, yaml_scalar(scalar))),
+    }
+}
+
+/// Formats a scalar or an empty collection, quoting strings that YAML would read differently
+fn yaml_scalar(value: &Value) -> String {
+    let s = match value {
+        Value::String(s) => s,
+        Value::Object(_) => return "{}".to_string(),
+        Value::Array(_) => return "[]".to_string(),
+        value => return value.to_string(),
+    };
+    let is_keyword = matches!(
+        s.to_ascii_lowercase().as_str(),
+        "null" | "~" | "true" | "false" | "yes" | "no" | "on" | "off" | "y" | "n"
+    );
+    // Dates like `2024-01-02` would be read back as timestamps
+    let is_date = s.len() >= 10 && s.as_bytes()[..4].iter().all(u8::is_ascii_digit) && s.as_bytes()[4] == b'-';
+    let is_plain = !s.is_empty()
+        && !is_keyword
+        && !is_date
+        && s.parse::<f64>().is_err()
+        && s.trim() == s
+        && !s.starts_with(['-', '?', ':', ',', '[', ']', '{', '}', '#', '&', '*', '!', '|', '>', '\'', '"', '%', '@', '`'])
+        && !s.contains(": ")
+        && !s.contains(" #")
+        && !s.ends_with(':')
+        && !s.chars().any(char::is_control);
+    if is_plain {
+        s.clone()
+    } else {
+        // A JSON string is a valid double-quoted YAML scalar
+        value.to_string()
+    }
+}
+
+/// Follows the AWS CLI's `text` output: the scalar members of an object form a row prefixed
+/// with the uppercased key the object was found under, nested collections follow as rows of
+/// their own
+fn text(value: &Value, name: Option<&str>, rows: &mut Vec<String>) {
+    let row = |values: Vec<String>| {
+        name.map(|name| name.to_uppercase())
+            .into_iter()
+            .chain(values)
+            .collect::<Vec<_>>()
+            .join("\t")
+    };
+    match value {
+        Value::Object(entries) => {
+            let mut keys: Vec<&String> = entries.keys().collect();
+            keys.sort();
+            let scalars: Vec<String> = keys
+                .iter()
+                .map(|key| &entries[key.as_str()])
+                .filter(|item| is_scalar(item))
+                .map(text_scalar)
+                .collect();
+            if !scalars.is_empty() {
+                rows.push(row(scalars));
+            }
+            for key in keys {
+                let item = &entries[key.as_str()];
+                if !is_scalar(item) {
+                    text(item, Some(key), rows);
+                }
+            }
+        },
+        Value::Array(items) if items.iter().all(is_scalar) => {
+            if !items.is_empty() {
+                rows.push(row(items.iter().map(text_scalar).collect()));
+            }
+        },
+        Value::Array(items) => {
+            for item in items {
+                text(item, name, rows);
+            }
+        },
+        scalar => rows.push(row(vec![text_scalar(scalar)])),
+    }
+}
+
+fn is_scalar(value: &Value) -> bool {
+    !matches!(value, Value::Array(_) | Value::Object(_))
+}
+
+/// Formats scalars the way the AWS CLI's Python formatters do
+fn text_scalar(value: &Value) -> String {
+    match value {
+        Value::String(s) => s.clone(),
+        Value::Null => "None".to_string(),
+        Value::Bool(true) => "True".to_string(),
+        Value::Bool(false) => "False".to_string(),
+        value => value.to_string(),
+    }
+}
+
+/// Finds the records of a result: the elements of a list, or of the only list member of an
+/// object like `{"Buckets": [...], "Owner": {...}}`, or else the result itself
+fn records(value: &Value) -> Vec<Map<String, Value>> {
+    match value {
+        Value::Array(items) => items.iter().map(record).collect(),
+        Value::Object(entries) => {
+            let mut lists = entries.values().filter(|item| item.is_array());
+            match (lists.next(), lists.next()) {
+                (Some(list), None) => records(list),
+                _ => vec![record(value)],
+            }
+        },
+        scalar => vec![record(scalar)],
+    }
+}
+
+/// Flattens nested objects into dotted column names, e.g. `State.Name`
+fn record(value: &Value) -> Map<String, Value> {
+    fn flatten(prefix: &str, value: &Value, record: &mut Map<String, Value>) {
+        match value {
+            Value::Object(entries) if !entries.is_empty() => {
+                for (key, item) in entries {
+                    let name = if prefix.is_empty() { key.clone() } else { format!("{}.{}", prefix, key) };
+                    flatten(&name, item, record);
+                }
+            },
+            value => {
+                record.insert(prefix.to_string(), value.clone());
+            },
+        }
+    }
+    let mut flattened = Map::new();
+    match value {
+        Value::Object(_) => flatten("", value, &mut flattened),
+        value => flatten("Value", value, &mut flattened),
+    }
+    flattened
+}
+
+/// Columns in the order they first appear
+fn columns(records: &[Map<String, Value>]) -> Vec<&str> {
+    let mut columns: Vec<&str> = Vec::new();
+    for key in records.iter().flat_map(|record| record.keys()) {
+        if !columns.contains(&key.as_str()) {
+            columns.push(key);
+        }
+    }
+    columns
+}
+
+/// Formats a field of a record: lists of scalars are joined, other collections kept as JSON
+fn cell(value: Option<&Value>) -> String {
+    match value {
+        None | Some(Value::Null) => String::new(),
+        Some(Value::String(s)) => s.clone(),
+        Some(Value::Array(items)) if items.iter().all(is_scalar) => {
+            items.iter().map(|item| cell(Some(item))).collect::<Vec<_>>().join(", ")
+        },
+        Some(value) => value.to_string(),
+    }
+}
+
+fn table(records: &[Map<String, Value>]) -> String {
+    let columns = columns(records);
+    if columns.is_empty() {
+        return String::new();
+    }
+    let rows: Vec<Vec<String>> = records
+        .iter()
+        .map(|record| columns.iter().map(|column| cell(record.get(*column)).replace('\n', " ")).collect())
+        .collect();
+    let widths: Vec<usize> = columns
+        .iter()
+        .enumerate()
+        .map(|(i, column)| {
+            rows.iter()
+                .map(|row| row[i].chars().count())
+                .chain([column.chars().count()])
+                .max()
+                .unwrap_or(0)
+        })
+        .collect();
+    let separator = format!(
+        "+{}+\n",
+        widths.iter().map(|width| "-".repeat(width + 2)).collect::<Vec<_>>().join("+")
+    );
+    let line = |cells: Vec<&str>| {
+        let cells: Vec<String> = cells
+            .iter()
+            .zip(&widths)
+            .map(|(cell, width)| format!(" {}{} ", cell, " ".repeat(width - cell.chars().count())))
+            .collect();
+        format!("|{}|\n", cells.join("|"))
+    };
+    let mut out = separator.clone();
+    out.push_str(&line(columns.clone()));
+    out.push_str(&separator);
+    for row in &rows {
+        out.push_str(&line(row.iter().map(String::as_str).collect()));
+    }
+    if !rows.is_empty() {
+        out.push_str(&separator);
+    }
+    out
+}
+
+/// Writes RFC 4180 CSV with a header row
+fn csv(records: &[Map<String, Value>]) -> String {
+    let columns = columns(records);
+    if columns.is_empty() {
+        return String::new();
+    }
+    let field = |value: &str| {
+        if value.contains([',', '"', '\n', '\r']) {
+            format!("\"{}\"", value.replace('"', "\"\""))
+        } else {
+            value.to_string()
+        }
+    };
+    let mut out = columns.iter().map(|column| field(column)).collect::<Vec<_>>().join(",");
+    out.push_str("\r\n");
+    for record in records {
+        let fields: Vec<String> = columns.iter().map(|column| field(&cell(record.get(*column)))).collect();
+        out.push_str(&fields.join(","));
+        out.push_str("\r\n");
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn buckets() -> Value {
+        json!({
+            "Buckets": [
+                {"Name": "logs", "CreationDate": "2024-01-02T03:04:05+00:00", "Tags": ["prod", "eu"]},
+                {"Name": "assets, public", "CreationDate": "2024-02-03T04:05:06+00:00", "Owner": {"ID": "1"}}
+            ],
+            "Owner": {"DisplayName": "admin", "ID": "abc"}
+        })
+    }
+
+    #[test]
+    fn test_yaml() {
+        assert_eq!(OutputFormat::Yaml.render(&buckets()), r#"Buckets:
+- Name: logs
+  CreationDate: '2024-01-02T03:04:05+00:00'
+  Tags:
+  - prod
+  - eu
+- Name: assets, public
+  CreationDate: '2024-02-03T04:05:06+00:00'
+  Owner:
+    ID: '1'
+Owner:
+  DisplayName: admin
+  ID: abc
+"#
+        .replace('\'', "\""));
+        assert_eq!(
+            OutputFormat::Yaml.render(&json!({"a": [], "b": "yes", "c": "multi\nline", "d": null, "e": [[1, 2]]})),
+            "a: []\nb: \"yes\"\nc: \"multi\\nline\"\nd: null\ne:\n- - 1\n  - 2\n"
+        );
+    }
+
+    #[test]
+    fn test_text() {
+        assert_eq!(
+            OutputFormat::Text.render(&buckets()),
+            "BUCKETS\t2024-01-02T03:04:05+00:00\tlogs\n\
+             TAGS\tprod\teu\n\
+             BUCKETS\t2024-02-03T04:05:06+00:00\tassets, public\n\
+             OWNER\t1\n\
+             OWNER\tadmin\tabc\n"
+        );
+        assert_eq!(OutputFormat::Text.render(&json!(["i-1", "i-2"])), "i-1\ti-2\n");
+    }
+
+    #[test]
+    fn test_table() {
+        assert_eq!(
+            OutputFormat::Table.render(&buckets()),
+            "+----------------+---------------------------+----------+----------+\n\
+             | Name           | CreationDate              | Tags     | Owner.ID |\n\
+             +----------------+---------------------------+----------+----------+\n\
+             | logs           | 2024-01-02T03:04:05+00:00 | prod, eu |          |\n\
+             | assets, public | 2024-02-03T04:05:06+00:00 |          | 1        |\n\
+             +----------------+---------------------------+----------+----------+\n"
+        );
+        assert_eq!(OutputFormat::Table.render(&json!({"Buckets": []})), "");
+    }
+
+    #[test]
+    fn test_csv() {
+        assert_eq!(
+            OutputFormat::Csv.render(&buckets()),
+            "Name,CreationDate,Tags,Owner.ID\r\n\
+             logs,2024-01-02T03:04:05+00:00,\"prod, eu\",\r\n\
+             \"assets, public\",2024-02-03T04:05:06+00:00,,1\r\n"
+        );
+        assert_eq!(OutputFormat::Csv.render(&json!(["a", "b\"c"])), "Value\r\na\r\n\"b\"\"c\"\r\n");
+        assert_eq!(OutputFormat::Csv.render(&json!({"Account": "123", "Arn": "arn:aws:iam::123:user/x"})), "Account,Arn\r\n123,arn:aws:iam::123:user/x\r\n");
+    }
+}
@@ -0,0 +1,409 @@
+//! Server-side pagination. Instead of letting the AWS CLI aggregate every page, the server
+//! requests one page at a time and returns the first items of the listing with an opaque
+//! cursor, so every response fits the tool response budget and a follow-up call picks up
+//! exactly where the previous one stopped.
+
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use eyre::{Result, WrapErr};
+use indexmap::IndexMap;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use sha2::{Digest, Sha256};
+
+use crate::executor::{AwsExecutor, CommandOutput};
+use crate::redact::Masked;
+use crate::service_model::Paginator;
+use crate::truncate::OUTPUT_BUDGET;
+use crate::use_aws::{UseAws, cli_option_name};
+
+/// Items returned per call unless the request or the configuration asks for another number
+pub const DEFAULT_MAX_ITEMS: usize = 100;
+
+/// Pages requested in one call at most, for filters that leave many pages empty
+const MAX_PAGES: usize = 20;
+
+/// Options of the AWS CLI's own pagination. Commands using them are passed through as is.
+const CLI_PAGINATION_OPTIONS: [&str; 4] = ["max-items", "starting-token", "page-size", "no-paginate"];
+
+/// A position in a listing: the input tokens requesting a page and the number of items of
+/// that page already returned
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct Cursor {
+    /// Fingerprint of the command the cursor belongs to
+    command: String,
+    /// Input token values, empty for the first page
+    tokens: Map<String, Value>,
+    skip: usize,
+}
+
+impl Cursor {
+    fn encode(&self) -> String {
+        URL_SAFE_NO_PAD.encode(serde_json::to_vec(self).unwrap_or_default())
+    }
+
+    fn decode(cursor: &str, fingerprint: &str) -> Result<Self> {
+        let decoded: Self = URL_SAFE_NO_PAD
+            .decode(cursor)
+            .ok()
+            .and_then(|bytes| serde_json::from_slice(&bytes).ok())
+            .ok_or_else(|| eyre::eyre!("Invalid cursor '{}'", cursor))?;
+        if decoded.command != fingerprint {
+            eyre::bail!("The cursor belongs to a different command; repeat the parameters of the call that returned it");
+        }
+        Ok(decoded)
+    }
+}
+
+/// Items of a listing that fit one response
+#[derive(Debug)]
+pub struct Page {
+    /// The output of the last command run, with stdout replaced by the page's items
+    pub output: CommandOutput,
+    /// Cursor of the first item not returned, if there is one
+    pub next_cursor: Option<String>,
+    /// Secrets masked in the returned items
+    pub masked: Vec<Masked>,
+}
+
+/// Whether the server paginates `command` itself. Commands using the CLI's own pagination
+/// options keep the CLI's behavior.
+pub fn applies(command: &UseAws, paginator: &Paginator) -> bool {
+    let uses_cli_pagination = command
+        .parameters
+        .iter()
+        .flat_map(|parameters| parameters.keys())
+        .any(|name| CLI_PAGINATION_OPTIONS.contains(&cli_option_name(name).as_str()));
+    !paginator.result_key.is_empty() && !uses_cli_pagination
+}
+
+/// Fetches pages of `command` starting at its cursor until `max_items` items, or the
+/// command's own `max_items`, are collected or the listing ends. The items of the main result
+/// key are returned as the first page's output, trimmed to fit [OUTPUT_BUDGET]. A failing page
+/// is returned as is.
+pub async fn paginate(
+    command: &UseAws,
+    paginator: &Paginator,
+    executor: &dyn AwsExecutor,
+    max_items: usize,
+) -> Result<Page> {
+    let fingerprint = fingerprint(command);
+    let max_items = command.max_items.unwrap_or(max_items).max(1);
+    let mut position = match &command.cursor {
+        Some(cursor) => Cursor::decode(cursor, &fingerprint)?,
+        None => Cursor {
+            command: fingerprint.clone(),
+            tokens: Map::new(),
+            skip: 0,
+        },
+    };
+    let Some((result_key, secondary_keys)) = paginator.result_key.split_first() else {
+        eyre::bail!("'{} {}' has no result key to paginate", command.service_name, command.operation_name);
+    };
+
+    let mut first_page: Option<Value> = None;
+    let mut items: Vec<(Value, Cursor)> = Vec::new();
+    let mut secondary: Vec<Vec<Value>> = vec![Vec::new(); secondary_keys.len()];
+    let mut next = None;
+    let mut last_output = None;
+    for _ in 0..MAX_PAGES {
+        let output = executor.execute(&page_command(command, paginator, &position.tokens)).await?;
+        if output.exit_code != 0 {
+            return Ok(Page {
+                output,
+                next_cursor: None,
+                masked: Vec::new(),
+            });
+        }
+        let page: Value = serde_json::from_str(&output.stdout).wrap_err("The page is not valid JSON")?;
+        last_output = Some(output);
+
+        for (index, item) in array_at(&page, result_key).into_iter().enumerate().skip(position.skip) {
+            let cursor = Cursor {
+                skip: index,
+                ..position.clone()
+            };
+            items.push((item, cursor));
+        }
+        if position.skip == 0 {
+            for (values, key) in secondary.iter_mut().zip(secondary_keys) {
+                values.extend(array_at(&page, key));
+            }
+        }
+
+        let has_more = paginator
+            .more_results
+            .as_ref()
+            .is_none_or(|expression| search(&page, expression).is_some());
+        next = output_tokens(&page, paginator)
+            .filter(|tokens| has_more && *tokens != position.tokens)
+            .map(|tokens| Cursor {
+                command: fingerprint.clone(),
+                tokens,
+                skip: 0,
+            });
+        first_page.get_or_insert(page);
+        match &next {
+            Some(cursor) if items.len() < max_items => position = cursor.clone(),
+            _ => break,
+        }
+    }
+
+    let (Some(mut base), Some(mut output)) = (first_page, last_output) else {
+        eyre::bail!("No page was requested");
+    };
+    for expression in paginator.output_token.iter().chain(&paginator.more_results) {
+        for alternative in expression.split("||").map(str::trim).filter(|path| !path.contains('[')) {
+            remove(&mut base, alternative);
+        }
+    }
+    for (key, values) in secondary_keys.iter().zip(secondary) {
+        set(&mut base, key, Value::Array(values));
+    }
+
+    // The budget applies to the output after redaction and the query, which usually makes it
+    // much smaller
+    let render = |count: usize| -> Result<(String, Vec<Masked>)> {
+        let mut page = base.clone();
+        set(&mut page, result_key, items[..count].iter().map(|(item, _)| item.clone()).collect());
+        let masked = command.redaction.redact_json(&mut page);
+        Ok((command.present(&command.project(page)?), masked))
+    };
+    // Keep as many items as fit the budget, but at least one so every call makes progress
+    let mut kept = items.len().min(max_items);
+    let (mut stdout, mut masked) = render(kept)?;
+    if stdout.len() > OUTPUT_BUDGET && kept > 1 {
+        let (mut fits, mut too_many) = (1, kept);
+        while too_many - fits > 1 {
+            let count = (fits + too_many) / 2;
+            if render(count)?.0.len() <= OUTPUT_BUDGET {
+                fits = count;
+            } else {
+                too_many = count;
+            }
+        }
+        kept = fits;
+        (stdout, masked) = render(kept)?;
+    }
+
+    output.stdout = stdout;
+    let next_cursor = match items.get(kept) {
+        Some((_, cursor)) => Some(cursor.encode()),
+        None => next.map(|cursor| cursor.encode()),
+    };
+    Ok(Page {
+        output,
+        next_cursor,
+        masked,
+    })
+}
+
+/// Identifies a command by everything but its cursor, so cursors can't be replayed against
+/// a different listing
+fn fingerprint(command: &UseAws) -> String {
+    let digest = Sha256::digest(command.canonical_command(None).as_bytes());
+    hex::encode(&digest[..8])
+}
+
+/// The command requesting a single page with the given input tokens, which replace any token
+/// the client passed
+fn page_command(command: &UseAws, paginator: &Paginator, tokens: &Map<String, Value>) -> UseAws {
+    let mut page = command.clone();
+    page.single_page = true;
+    if !tokens.is_empty() {
+        let parameters = page.parameters.get_or_insert_with(IndexMap::new);
+        for input_token in &paginator.input_token {
+            let option = cli_option_name(input_token);
+            parameters.retain(|name, _| cli_option_name(name) != option);
+        }
+        parameters.extend(tokens.iter().map(|(name, value)| (name.clone(), value.clone())));
+    }
+    page
+}
+
+/// The input tokens of the page after `page`, if the page returned any
+fn output_tokens(page: &Value, paginator: &Paginator) -> Option<Map<String, Value>> {
+    let tokens: Map<String, Value> = paginator
+        .input_token
+        .iter()
+        .zip(&paginator.output_token)
+        .filter_map(|(input, output)| Some((input.clone(), search(page, output)?.clone())))
+        .collect();
+    (!tokens.is_empty()).then_some(tokens)
+}
+
+/// Evaluates the subset of JMESPath paginators use: `a.b`, `a[-1].b` and `x || y`. Like
+/// JMESPath, null, false and empty values don't count as a result.
+fn search<'a>(value: &'a Value, expression: &str) -> Option<&'a Value> {
+    expression.split("||").find_map(|alternative| {
+        let mut current = value;
+        for segment in alternative.trim().split('.') {
+            let (name, indexes) = segment.split_once('[').unwrap_or((segment, ""));
+            if !name.is_empty() {
+                current = current.get(name)?;
+            }
+            for index in indexes.split(['[', ']']).filter(|index| !index.is_empty()) {
+                let items = current.as_array()?;
+                let index: i64 = index.parse().ok()?;
+                let index = if index < 0 { items.len().checked_sub(index.unsigned_abs() as usize)? } else { index as usize };
+                current = items.get(index)?;
+            }
+        }
+        let is_empty = match current {
+            Value::Null | Value::Bool(false) => true,
+            Value::String(s) => s.is_empty(),
+            Value::Array(items) => items.is_empty(),
+            Value::Object(entries) => entries.is_empty(),
+            Value::Number(_) | Value::Bool(true) => false,
+        };
+        (!is_empty).then_some(current)
+    })
+}
+
+fn array_at(page: &Value, path: &str) -> Vec<Value> {
+    search(page, path).and_then(Value::as_array).cloned().unwrap_or_default()
+}
+
+/// Sets the member at a dotted path, if its parent exists
+fn set(value: &mut Value, path: &str, new_value: Value) {
+    let (parent, name) = path.rsplit_once('.').map_or((None, path), |(parent, name)| (Some(parent), name));
+    let parent = match parent {
+        Some(parent) => parent.split('.').try_fold(value, |current, segment| current.get_mut(segment)),
+        None => Some(value),
+    };
+    if let Some(object) = parent.and_then(Value::as_object_mut) {
+        object.insert(name.to_string(), new_value);
+    }
+}
+
+/// Removes the member at a dotted path
+fn remove(value: &mut Value, path: &str) {
+    let (parent, name) = path.rsplit_once('.').map_or((None, path), |(parent, name)| (Some(parent), name));
+    let parent = match parent {
+        Some(parent) => parent.split('.').try_fold(value, |current, segment| current.get_mut(segment)),
+        None => Some(value),
+    };
+    if let Some(object) = parent.and_then(Value::as_object_mut) {
+        object.shift_remove(name);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::executor::MockExecutor;
+
+    fn paginator() -> Paginator {
+        serde_json::from_value(serde_json::json!({
+            "input_token": "NextToken",
+            "output_token": "NextToken",
+            "limit_key": "MaxResults",
+            "result_key": "Reservations"
+        }))
+        .unwrap()
+    }
+
+    fn command(arguments: Value) -> UseAws {
+        let mut request = serde_json::json!({
+            "service_name": "ec2",
+            "operation_name": "describe-instances",
+            "region": "us-east-1"
+        });
+        request.as_object_mut().unwrap().extend(arguments.as_object().unwrap().clone());
+        serde_json::from_value(request).unwrap()
+    }
+
+    fn page(ids: &[&str], next_token: Option<&str>) -> CommandOutput {
+        let reservations: Vec<Value> = ids.iter().map(|id| serde_json::json!({"ReservationId": id})).collect();
+        let mut page = serde_json::json!({"Reservations": reservations});
+        if let Some(next_token) = next_token {
+            page["NextToken"] = next_token.into();
+        }
+        CommandOutput::success(page.to_string())
+    }
+
+    fn reservation_ids(page: &Page) -> Vec<String> {
+        let output: Value = serde_json::from_str(&page.output.stdout).unwrap();
+        output["Reservations"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|reservation| reservation["ReservationId"].as_str().unwrap().to_string())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_paginate_with_cursor() {
+        let executor = MockExecutor::new()
+            .respond(page(&["r-1", "r-2"], Some("t1")))
+            .respond(page(&["r-3", "r-4"], Some("t2")))
+            .respond(page(&["r-3", "r-4"], Some("t2")))
+            .respond(page(&["r-5"], None));
+        let mut cmd = command(serde_json::json!({"max_items": 3}));
+
+        let first = paginate(&cmd, &paginator(), &executor, DEFAULT_MAX_ITEMS).await.unwrap();
+        assert_eq!(reservation_ids(&first), ["r-1", "r-2", "r-3"]);
+        assert!(!first.output.stdout.contains("NextToken"));
+        let calls = executor.calls();
+        assert!(calls[0].single_page && calls[0].parameters.is_none());
+        assert_eq!(calls[1].parameters.as_ref().unwrap()["NextToken"], "t1");
+        assert!(calls[1].canonical_command(None).ends_with("--next-token t1 --no-paginate --output json"));
+
+        cmd.cursor = first.next_cursor;
+        let second = paginate(&cmd, &paginator(), &executor, DEFAULT_MAX_ITEMS).await.unwrap();
+        assert_eq!(reservation_ids(&second), ["r-4", "r-5"]);
+        assert_eq!(second.next_cursor, None);
+        assert_eq!(executor.calls()[2].parameters.as_ref().unwrap()["NextToken"], "t1");
+
+        let other = command(serde_json::json!({"parameters": {"InstanceIds": ["i-1"]}, "cursor": cmd.cursor}));
+        let error = paginate(&other, &paginator(), &executor, DEFAULT_MAX_ITEMS).await.unwrap_err();
+        assert!(error.to_string().contains("different command"));
+    }
+
+    #[tokio::test]
+    async fn test_page_fits_budget() {
+        let ids: Vec<String> = (0..100).map(|i| format!("r-{:04}-{}", i, "x".repeat(1000))).collect();
+        let ids: Vec<&str> = ids.iter().map(String::as_str).collect();
+        let executor = MockExecutor::new().respond(page(&ids, None)).respond(page(&ids, None));
+        let mut cmd = command(serde_json::json!({}));
+
+        let first = paginate(&cmd, &paginator(), &executor, DEFAULT_MAX_ITEMS).await.unwrap();
+        let kept = reservation_ids(&first).len();
+        assert!(first.output.stdout.len() <= OUTPUT_BUDGET);
+        assert!(kept > 1 && kept < 100);
+
+        cmd.cursor = first.next_cursor;
+        let second = paginate(&cmd, &paginator(), &executor, DEFAULT_MAX_ITEMS).await.unwrap();
+        assert!(reservation_ids(&second)[0].starts_with(&format!("r-{:04}", kept)));
+    }
+
+    #[tokio::test]
+    async fn test_failed_page() {
+        let executor = MockExecutor::new().respond(CommandOutput::failure(254, "An error occurred (UnauthorizedOperation)"));
+        let page = paginate(&command(serde_json::json!({})), &paginator(), &executor, 10).await.unwrap();
+        assert_eq!(page.output.exit_code, 254);
+        assert_eq!(page.next_cursor, None);
+        assert!(paginate(&command(serde_json::json!({"cursor": "not a cursor"})), &paginator(), &executor, 10).await.is_err());
+    }
+
+    #[test]
+    fn test_search() {
+        let page = serde_json::json!({
+            "IsTruncated": false,
+            "NextMarker": "",
+            "Contents": [{"Key": "a"}, {"Key": "b"}],
+            "Paging": {"Next": "n"}
+        });
+        assert_eq!(search(&page, "NextMarker || Contents[-1].Key"), Some(&Value::from("b")));
+        assert_eq!(search(&page, "Contents[0].Key"), Some(&Value::from("a")));
+        assert_eq!(search(&page, "Paging.Next"), Some(&Value::from("n")));
+        assert_eq!(search(&page, "IsTruncated"), None);
+        assert_eq!(search(&page, "Contents[5].Key"), None);
+    }
+
+    #[test]
+    fn test_applies() {
+        assert!(applies(&command(serde_json::json!({})), &paginator()));
+        assert!(!applies(&command(serde_json::json!({"parameters": {"max-items": 5}})), &paginator()));
+    }
+}
@@ -0,0 +1,329 @@
+use std::collections::BTreeMap;
+
+use serde::Deserialize;
+
+use crate::config::Config;
+use crate::service_model::ServiceModel;
+use crate::use_aws::{UseAws, cli_option_name, cli_values};
+
+/// AWS CLI options that change where or how requests are sent, or how their output is
+/// produced. The server sets these itself from the request fields and configured endpoints,
+/// so they can't be passed as parameters.
+const RESERVED_OPTIONS: [&str; 13] = [
+    "endpoint-url",
+    "no-verify-ssl",
+    "ca-bundle",
+    "profile",
+    "region",
+    "query",
+    "output",
+    "no-paginate",
+    "debug",
+    "cli-input-json",
+    "cli-input-yaml",
+    "no-sign-request",
+    "color",
+];
+
+/// What commands may do against a target
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Access {
+    /// Only operations that don't modify resources, see [UseAws::requires_acceptance]
+    ReadOnly,
+    #[default]
+    ReadWrite,
+}
+
+/// A named endpoint requests can be sent to instead of AWS, e.g. a LocalStack container
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Endpoint {
+    pub url: String,
+    /// Emulators are writable unless configured otherwise
+    #[serde(default)]
+    pub access: Access,
+}
+
+/// Errors raised when a command is not allowed by the policy
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum PolicyError {
+    #[error("Parameter '{0}' can't be set directly; use the `endpoint`, `profile_name` and `region` fields")]
+    ReservedOption(String),
+    #[error("Invalid parameter name '{0}'; names must not start with '-' or contain '=' or whitespace")]
+    InvalidParameterName(String),
+    #[error("A value of parameter '{0}' starts with '--', which the AWS CLI would read as an option")]
+    OptionLikeValue(String),
+    #[error("Unknown endpoint '{0}'; configured endpoints: {1}")]
+    UnknownEndpoint(String, String),
+    #[error("'{operation}' modifies resources, but {target} is read-only")]
+    ReadOnly { operation: String, target: String },
+    #[error("The account of profile '{0}' couldn't be resolved, so the account policy can't be checked")]
+    UnknownAccount(String),
+}
+
+/// Decides where commands are sent and whether they may run
+#[derive(Debug, Clone, Default)]
+pub struct Policy {
+    /// Access to real AWS accounts
+    access: Access,
+    /// Access to AWS accounts by account id
+    accounts: BTreeMap<String, Access>,
+    endpoints: BTreeMap<String, Endpoint>,
+    /// Endpoints used by every command of an AWS profile
+    profile_endpoints: BTreeMap<String, String>,
+}
+
+impl Policy {
+    pub fn from_config(config: &Config) -> Self {
+        Self {
+            access: config.access,
+            accounts: config.accounts.clone(),
+            endpoints: config.endpoints.clone(),
+            profile_endpoints: config.profile_endpoints.clone(),
+        }
+    }
+
+    /// Returns the names of the configured endpoints in sorted order
+    pub fn endpoint_names(&self) -> Vec<&str> {
+        self.endpoints.keys().map(|name| name.as_str()).collect()
+    }
+
+    /// Resolves the endpoint of the command, from its `endpoint` field or its profile, and
+    /// checks the command is allowed against that target. Parameters are checked so they can't
+    /// smuggle in options, using `model` to tell which ones take a string.
+    pub fn apply(&self, command: &mut UseAws, model: Option<&ServiceModel>) -> Result<(), PolicyError> {
+        for (name, value) in command.parameters.iter().flatten() {
+            if name.starts_with('-') || name.contains('=') || name.chars().any(char::is_whitespace) {
+                return Err(PolicyError::InvalidParameterName(name.clone()));
+            }
+            if RESERVED_OPTIONS.contains(&cli_option_name(name).as_str()) {
+                return Err(PolicyError::ReservedOption(name.clone()));
+            }
+            // A string parameter takes exactly one value, which the CLI never reads as an option
+            let shape = model.and_then(|model| command.parameter_shape(model, name));
+            if shape.is_some_and(|shape| shape.shape_type == "string") && value.is_string() {
+                continue;
+            }
+            if cli_values(value, shape, model).iter().any(|token| token.starts_with("--")) {
+                return Err(PolicyError::OptionLikeValue(name.clone()));
+            }
+        }
+
+        command.endpoint_url = None;
+        if command.endpoint.is_none() {
+            command.endpoint = command
+                .profile_name
+                .as_ref()
+                .and_then(|profile_name| self.profile_endpoints.get(profile_name))
+                .cloned();
+        }
+        let (access, target) = match &command.endpoint {
+            Some(name) => {
+                let endpoint = self.endpoints.get(name).ok_or_else(|| {
+                    let names = self.endpoint_names();
+                    let names = if names.is_empty() { "none".to_string() } else { names.join(", ") };
+                    PolicyError::UnknownEndpoint(name.clone(), names)
+                })?;
+                command.endpoint_url = Some(endpoint.url.clone());
+                (endpoint.access, format!("endpoint '{}'", name))
+            },
+            None => (self.access, "AWS".to_string()),
+        };
+
+        if access == Access::ReadOnly && command.requires_acceptance() {
+            return Err(PolicyError::ReadOnly {
+                operation: format!("{} {}", command.service_name, command.operation_name),
+                target,
+            });
+        }
+        Ok(())
+    }
+
+    /// Whether account policies apply, so commands need a resolved identity
+    pub fn has_account_rules(&self) -> bool {
+        !self.accounts.is_empty()
+    }
+
+    /// Checks the command is allowed in the account it acts as, see [UseAws::identity]. Writes
+    /// to AWS whose account is unknown are refused while account policies are configured.
+    pub fn check_account(&self, command: &UseAws) -> Result<(), PolicyError> {
+        if command.endpoint.is_some() || !command.requires_acceptance() || !self.has_account_rules() {
+            return Ok(());
+        }
+        let Some(identity) = &command.identity else {
+            return Err(PolicyError::UnknownAccount(
+                command.profile_name.clone().unwrap_or_else(|| "default".to_string()),
+            ));
+        };
+        if self.accounts.get(&identity.account) == Some(&Access::ReadOnly) {
+            return Err(PolicyError::ReadOnly {
+                operation: format!("{} {}", command.service_name, command.operation_name),
+                target: format!("account {}", identity),
+            });
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy() -> Policy {
+        let config: Config = serde_json::from_value(serde_json::json!({
+            "access": "read-only",
+            "endpoints": {
+                "localstack": {"url": "http://localhost:4566"},
+                "moto": {"url": "http://localhost:5000", "access": "read-only"}
+            },
+            "profile_endpoints": {"local": "localstack"}
+        }))
+        .unwrap();
+        Policy::from_config(&config)
+    }
+
+    fn command(arguments: serde_json::Value) -> UseAws {
+        let mut request = serde_json::json!({"service_name": "s3api", "region": "us-east-1"});
+        request.as_object_mut().unwrap().extend(arguments.as_object().unwrap().clone());
+        serde_json::from_value(request).unwrap()
+    }
+
+    #[test]
+    fn test_endpoints() {
+        let policy = policy();
+        let mut by_name = command(serde_json::json!({"operation_name": "create-bucket", "endpoint": "localstack"}));
+        policy.apply(&mut by_name, None).unwrap();
+        assert_eq!(by_name.endpoint_url.as_deref(), Some("http://localhost:4566"));
+        assert!(by_name.canonical_command(None).contains("--endpoint-url http://localhost:4566"));
+
+        let mut by_profile = command(serde_json::json!({"operation_name": "create-bucket", "profile_name": "local"}));
+        policy.apply(&mut by_profile, None).unwrap();
+        assert_eq!(by_profile.endpoint.as_deref(), Some("localstack"));
+        assert_eq!(by_profile.endpoint_url.as_deref(), Some("http://localhost:4566"));
+
+        let mut unknown = command(serde_json::json!({"operation_name": "list-buckets", "endpoint": "http://evil"}));
+        assert_eq!(
+            policy.apply(&mut unknown, None).unwrap_err(),
+            PolicyError::UnknownEndpoint("http://evil".to_string(), "localstack, moto".to_string())
+        );
+    }
+
+    #[test]
+    fn test_access() {
+        let policy = policy();
+        let mut read = command(serde_json::json!({"operation_name": "list-buckets"}));
+        assert!(policy.apply(&mut read, None).is_ok());
+        assert_eq!(read.endpoint_url, None);
+
+        let mut write = command(serde_json::json!({"operation_name": "delete-bucket"}));
+        assert!(matches!(policy.apply(&mut write, None), Err(PolicyError::ReadOnly { target, .. }) if target == "AWS"));
+
+        let mut read_only_endpoint = command(serde_json::json!({"operation_name": "delete-bucket", "endpoint": "moto"}));
+        assert!(policy.apply(&mut read_only_endpoint, None).is_err());
+
+        assert!(Policy::default().apply(&mut write, None).is_ok());
+    }
+
+    #[test]
+    fn test_accounts() {
+        let config: Config = serde_json::from_value(serde_json::json!({
+            "accounts": {"111111111111": "read-only", "222222222222": "read-write"}
+        }))
+        .unwrap();
+        let policy = Policy::from_config(&config);
+        let identity = |account: &str| crate::identity::Identity {
+            account: account.to_string(),
+            arn: format!("arn:aws:iam::{}:user/ci", account),
+            account_alias: None,
+        };
+
+        let mut write = command(serde_json::json!({"operation_name": "delete-bucket", "profile_name": "whatever"}));
+        assert_eq!(policy.check_account(&write), Err(PolicyError::UnknownAccount("whatever".to_string())));
+        write.identity = Some(identity("111111111111"));
+        assert_eq!(
+            policy.check_account(&write).unwrap_err().to_string(),
+            "'s3api delete-bucket' modifies resources, but account 111111111111 is read-only"
+        );
+        write.identity = Some(identity("222222222222"));
+        assert!(policy.check_account(&write).is_ok());
+
+        let mut read = command(serde_json::json!({"operation_name": "list-buckets"}));
+        read.identity = Some(identity("111111111111"));
+        assert!(policy.check_account(&read).is_ok());
+    }
+
+    #[test]
+    fn test_reserved_options() {
+        let reserved = [
+            "endpoint-url",
+            "endpoint_url",
+            "no-verify-ssl",
+            "profile",
+            "query",
+            "output",
+            "no-paginate",
+            "debug",
+            "cli-input-json",
+            "cli_input_yaml",
+            "no-sign-request",
+            "color",
+        ];
+        for name in reserved {
+            let mut cmd = command(serde_json::json!({
+                "operation_name": "list-buckets",
+                "parameters": {name: "http://localhost:1234"}
+            }));
+            assert_eq!(Policy::default().apply(&mut cmd, None), Err(PolicyError::ReservedOption(name.to_string())));
+        }
+    }
+
+    #[test]
+    fn test_option_smuggling() {
+        for name in ["--endpoint-url", "endpoint-url=http://attacker", "profile=prod", "bucket --profile"] {
+            let mut cmd = command(serde_json::json!({
+                "operation_name": "list-buckets",
+                "parameters": {name: ""}
+            }));
+            assert_eq!(
+                Policy::default().apply(&mut cmd, None),
+                Err(PolicyError::InvalidParameterName(name.to_string()))
+            );
+        }
+
+        let values = [
+            serde_json::json!(["i-1", "--endpoint-url", "http://attacker"]),
+            serde_json::json!(["--profile=prod"]),
+            serde_json::json!("--endpoint-url=http://attacker"),
+        ];
+        for value in values {
+            let mut cmd = command(serde_json::json!({
+                "operation_name": "describe-instances",
+                "parameters": {"instance-ids": value}
+            }));
+            assert_eq!(
+                Policy::default().apply(&mut cmd, None),
+                Err(PolicyError::OptionLikeValue("instance-ids".to_string()))
+            );
+        }
+
+        // The model says `InstanceIds` is a list, so its values are separate arguments, while
+        // the single value of the string `NextToken` can't be read as an option
+        let model: ServiceModel = serde_json::from_str(crate::service_model::tests::EC2_MODEL).unwrap();
+        let mut cmd = command(serde_json::json!({
+            "service_name": "ec2",
+            "operation_name": "describe-instances",
+            "parameters": {"InstanceIds": ["--endpoint-url", "http://attacker"]}
+        }));
+        assert_eq!(
+            Policy::default().apply(&mut cmd, Some(&model)),
+            Err(PolicyError::OptionLikeValue("InstanceIds".to_string()))
+        );
+        let mut cmd = command(serde_json::json!({
+            "service_name": "ec2",
+            "operation_name": "describe-instances",
+            "parameters": {"NextToken": "--token"}
+        }));
+        assert!(Policy::default().apply(&mut cmd, Some(&model)).is_ok());
+    }
+}
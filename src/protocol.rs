@@ -0,0 +1,1153 @@
+//! Serialization of requests and parsing of responses for the AWS wire protocols, driven by
+//! the botocore service models. Covers the `json`, `rest-json`, `query` and `ec2` protocols,
+//! and `rest-xml` requests without a body.
+
+use chrono::{DateTime, SecondsFormat, Utc};
+use eyre::{Result, bail, eyre};
+use indexmap::IndexMap;
+use serde_json::{Map, Value};
+
+use crate::service_model::{Operation, ServiceModel, Shape, ShapeRef, xform_name};
+use crate::use_aws::cli_option_name;
+
+/// An HTTP request before it is signed and sent
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct HttpRequest {
+    pub method: String,
+    /// The percent-encoded path
+    pub path: String,
+    /// Query parameters, not yet encoded
+    pub query: Vec<(String, String)>,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl HttpRequest {
+    pub fn new(method: &str, path: &str) -> Self {
+        Self {
+            method: method.to_string(),
+            path: path.to_string(),
+            ..Default::default()
+        }
+    }
+
+    pub fn header(&self, name: &str) -> Option<&str> {
+        find_header(&self.headers, name)
+    }
+
+    /// Sets a header, replacing any existing value regardless of case
+    pub fn set_header(&mut self, name: &str, value: &str) {
+        self.headers.retain(|(existing, _)| !existing.eq_ignore_ascii_case(name));
+        self.headers.push((name.to_string(), value.to_string()));
+    }
+
+    /// The path and encoded query string
+    pub fn path_and_query(&self) -> String {
+        if self.query.is_empty() {
+            return self.path.clone();
+        }
+        format!("{}?{}", self.path, form_encode(&self.query))
+    }
+}
+
+/// A received HTTP response
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct HttpResponse {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl HttpResponse {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        find_header(&self.headers, name)
+    }
+}
+
+fn find_header<'a>(headers: &'a [(String, String)], name: &str) -> Option<&'a str> {
+    headers
+        .iter()
+        .find(|(existing, _)| existing.eq_ignore_ascii_case(name))
+        .map(|(_, value)| value.as_str())
+}
+
+/// The wire protocols supported for native requests
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Protocol {
+    Json,
+    RestJson,
+    Query,
+    Ec2,
+    RestXml,
+}
+
+impl Protocol {
+    pub fn from_model(model: &ServiceModel) -> Option<Self> {
+        match model.metadata.protocol.as_str() {
+            "json" => Some(Self::Json),
+            "rest-json" => Some(Self::RestJson),
+            "query" => Some(Self::Query),
+            "ec2" => Some(Self::Ec2),
+            "rest-xml" => Some(Self::RestXml),
+            _ => None,
+        }
+    }
+}
+
+/// A parsed successful response
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParsedOutput {
+    /// The output as printed by the AWS CLI
+    pub output: Value,
+    /// A streaming body, e.g. the object of `s3api get-object`, which the CLI writes to a file
+    pub payload: Option<Vec<u8>>,
+}
+
+/// An error returned by a service
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ServiceError {
+    pub code: String,
+    pub message: String,
+    pub request_id: Option<String>,
+}
+
+/// Percent-encodes everything but the unreserved characters, as SigV4 requires
+pub fn percent_encode(s: &str) -> String {
+    let mut encoded = String::with_capacity(s.len());
+    for byte in s.bytes() {
+        if byte.is_ascii_alphanumeric() || b"-_.~".contains(&byte) {
+            encoded.push(byte as char);
+        } else {
+            encoded.push_str(&format!("%{:02X}", byte));
+        }
+    }
+    encoded
+}
+
+fn form_encode(pairs: &[(String, String)]) -> String {
+    pairs
+        .iter()
+        .map(|(name, value)| format!("{}={}", percent_encode(name), percent_encode(value)))
+        .collect::<Vec<_>>()
+        .join("&")
+}
+
+/// Converts CLI-style parameters into the operation's input, keyed by member name and typed
+/// according to the model. Accepts the same values the CLI does: scalars as strings, JSON
+/// strings for structures, lists and maps, and shorthand for maps of scalars.
+pub fn build_input(model: &ServiceModel, operation: &Operation, parameters: &IndexMap<String, Value>) -> Result<Map<String, Value>> {
+    let mut input = Map::new();
+    for (param_name, value) in parameters {
+        if value.is_null() {
+            continue;
+        }
+        let cli_name = cli_option_name(param_name);
+        let (name, member) = model
+            .input_member(operation, &cli_name)
+            .ok_or_else(|| eyre!("Unknown parameter '{}' for operation {}", param_name, operation.name))?;
+        let value = input_value(model, &member.shape, value).map_err(|e| eyre!("Invalid value for '{}': {}", param_name, e))?;
+        input.insert(name.to_string(), value);
+    }
+    if let Some(shape) = operation.input.as_ref().and_then(|input| model.shape(&input.shape)) {
+        for required in &shape.required {
+            if !input.contains_key(required) {
+                bail!("Missing required parameter '--{}'", xform_name(required, '-'));
+            }
+        }
+    }
+    Ok(input)
+}
+
+fn input_value(model: &ServiceModel, shape_name: &str, value: &Value) -> Result<Value> {
+    let shape = model
+        .shape(shape_name)
+        .ok_or_else(|| eyre!("Unknown shape '{}'", shape_name))?;
+    let parsed = match value {
+        Value::String(s) if matches!(shape.shape_type.as_str(), "structure" | "list" | "map") => {
+            serde_json::from_str(s).ok()
+        },
+        _ => None,
+    };
+    let value = parsed.as_ref().unwrap_or(value);
+    Ok(match (shape.shape_type.as_str(), value) {
+        ("structure", Value::Object(entries)) => {
+            let mut object = Map::new();
+            for (key, value) in entries {
+                if value.is_null() {
+                    continue;
+                }
+                let cli_name = cli_option_name(key);
+                let (name, member) = shape
+                    .members
+                    .iter()
+                    .find(|(name, _)| *name == key || xform_name(name, '-') == cli_name)
+                    .ok_or_else(|| eyre!("Unknown member '{}'", key))?;
+                object.insert(name.clone(), input_value(model, &member.shape, value)?);
+            }
+            Value::Object(object)
+        },
+        ("list", Value::Array(items)) => {
+            let member = list_member(shape)?;
+            Value::Array(
+                items
+                    .iter()
+                    .map(|item| input_value(model, &member.shape, item))
+                    .collect::<Result<_>>()?,
+            )
+        },
+        ("list", value) => Value::Array(vec![input_value(model, &list_member(shape)?.shape, value)?]),
+        ("map", Value::Object(entries)) => {
+            let value_ref = shape.value.as_ref().ok_or_else(|| eyre!("Map without a value shape"))?;
+            let mut object = Map::new();
+            for (key, value) in entries {
+                object.insert(key.clone(), input_value(model, &value_ref.shape, value)?);
+            }
+            Value::Object(object)
+        },
+        ("map", Value::String(s)) => {
+            let value_ref = shape.value.as_ref().ok_or_else(|| eyre!("Map without a value shape"))?;
+            let mut object = Map::new();
+            for pair in s.split(',') {
+                let (key, value) = pair.split_once('=').ok_or_else(|| eyre!("Expected key=value pairs"))?;
+                object.insert(key.to_string(), input_value(model, &value_ref.shape, &Value::from(value))?);
+            }
+            Value::Object(object)
+        },
+        ("structure" | "map", value) => bail!("Expected a {}, got {}", shape.shape_type, value),
+        ("boolean", Value::Bool(b)) => Value::Bool(*b),
+        ("boolean", Value::String(s)) if s == "true" || s == "false" => Value::Bool(s == "true"),
+        ("integer" | "long", Value::Number(n)) if n.is_i64() => value.clone(),
+        ("integer" | "long", Value::String(s)) => Value::from(s.parse::<i64>().map_err(|_| eyre!("Expected an integer, got '{}'", s))?),
+        ("float" | "double", Value::Number(_)) => value.clone(),
+        ("float" | "double", Value::String(s)) => Value::from(s.parse::<f64>().map_err(|_| eyre!("Expected a number, got '{}'", s))?),
+        ("timestamp", value) => {
+            let timestamp = parse_timestamp(value).ok_or_else(|| eyre!("Expected a timestamp, got {}", value))?;
+            Value::from(timestamp.to_rfc3339_opts(SecondsFormat::AutoSi, true))
+        },
+        ("boolean" | "integer" | "long" | "float" | "double", value) => {
+            bail!("Expected a {}, got {}", shape.shape_type, value)
+        },
+        (_, Value::String(s)) => Value::from(s.as_str()),
+        (_, Value::Bool(_) | Value::Number(_)) => Value::from(value.to_string()),
+        (_, value) => bail!("Expected a {}, got {}", shape.shape_type, value),
+    })
+}
+
+fn list_member(shape: &Shape) -> Result<&ShapeRef> {
+    shape.member.as_ref().ok_or_else(|| eyre!("List without a member shape"))
+}
+
+/// Parses an ISO 8601 timestamp, an HTTP date or seconds since the epoch
+fn parse_timestamp(value: &Value) -> Option<DateTime<Utc>> {
+    match value {
+        Value::Number(n) => {
+            let seconds = n.as_f64()?;
+            DateTime::from_timestamp_millis((seconds * 1000.0).round() as i64)
+        },
+        Value::String(s) => DateTime::parse_from_rfc3339(s)
+            .or_else(|_| DateTime::parse_from_rfc2822(s))
+            .map(|timestamp| timestamp.to_utc())
+            .ok()
+            .or_else(|| {
+                chrono::NaiveDateTime::parse_from_str(s, "%Y-%m-%dT%H:%M:%S%.f")
+                    .map(|timestamp| timestamp.and_utc())
+                    .ok()
+            })
+            .or_else(|| parse_timestamp(&Value::from(s.parse::<f64>().ok()?))),
+        _ => None,
+    }
+}
+
+/// Formats a timestamp the way the AWS CLI prints it
+fn format_timestamp(timestamp: DateTime<Utc>) -> String {
+    timestamp.to_rfc3339_opts(SecondsFormat::AutoSi, false)
+}
+
+/// Serializes an operation's input, as built by [build_input], into an unsigned request
+pub fn serialize_request(model: &ServiceModel, operation: &Operation, input: &Map<String, Value>) -> Result<HttpRequest> {
+    let protocol = Protocol::from_model(model)
+        .ok_or_else(|| eyre!("Unsupported protocol '{}'", model.metadata.protocol))?;
+    let input_shape = operation.input.as_ref().and_then(|input| model.shape(&input.shape));
+    match protocol {
+        Protocol::Json => {
+            let mut request = HttpRequest::new("POST", "/");
+            let target_prefix = model.metadata.target_prefix.as_deref().unwrap_or_default();
+            request.set_header("X-Amz-Target", &format!("{}.{}", target_prefix, operation.name));
+            let json_version = model.metadata.json_version.as_deref().unwrap_or("1.0");
+            request.set_header("Content-Type", &format!("application/x-amz-json-{}", json_version));
+            let body = match input_shape {
+                Some(shape) => json_body(model, shape, &Value::Object(input.clone())),
+                None => Value::Object(Map::new()),
+            };
+            request.body = body.to_string().into_bytes();
+            Ok(request)
+        },
+        Protocol::Query | Protocol::Ec2 => {
+            let mut params = vec![
+                ("Action".to_string(), operation.name.clone()),
+                ("Version".to_string(), model.metadata.api_version.clone()),
+            ];
+            if let Some(shape) = input_shape {
+                query_params(model, shape, &Value::Object(input.clone()), "", protocol == Protocol::Ec2, &mut params);
+            }
+            let mut request = HttpRequest::new("POST", "/");
+            request.set_header("Content-Type", "application/x-www-form-urlencoded; charset=utf-8");
+            request.body = form_encode(&params).into_bytes();
+            Ok(request)
+        },
+        Protocol::RestJson | Protocol::RestXml => rest_request(model, operation, input_shape, input, protocol),
+    }
+}
+
+/// Converts typed input into a JSON protocol body: timestamps become epoch seconds and
+/// members use their wire names
+fn json_body(model: &ServiceModel, shape: &Shape, value: &Value) -> Value {
+    match (shape.shape_type.as_str(), value) {
+        ("structure", Value::Object(entries)) => {
+            let mut object = Map::new();
+            for (name, member) in &shape.members {
+                let (Some(value), Some(member_shape)) = (entries.get(name), model.shape(&member.shape)) else {
+                    continue;
+                };
+                let wire_name = member.location_name.as_deref().unwrap_or(name);
+                object.insert(wire_name.to_string(), json_body(model, member_shape, value));
+            }
+            Value::Object(object)
+        },
+        ("list", Value::Array(items)) => match shape.member.as_ref().and_then(|member| model.shape(&member.shape)) {
+            Some(member_shape) => Value::Array(items.iter().map(|item| json_body(model, member_shape, item)).collect()),
+            None => value.clone(),
+        },
+        ("map", Value::Object(entries)) => match shape.value.as_ref().and_then(|value| model.shape(&value.shape)) {
+            Some(value_shape) => Value::Object(
+                entries
+                    .iter()
+                    .map(|(key, value)| (key.clone(), json_body(model, value_shape, value)))
+                    .collect(),
+            ),
+            None => value.clone(),
+        },
+        ("timestamp", value) => match parse_timestamp(value) {
+            Some(timestamp) => Value::from(timestamp.timestamp_millis() as f64 / 1000.0),
+            None => value.clone(),
+        },
+        _ => value.clone(),
+    }
+}
+
+/// Flattens typed input into `query`/`ec2` protocol parameters, e.g. `Filter.1.Name`
+fn query_params(model: &ServiceModel, shape: &Shape, value: &Value, prefix: &str, ec2: bool, params: &mut Vec<(String, String)>) {
+    let join = |prefix: &str, name: &str| {
+        if prefix.is_empty() {
+            name.to_string()
+        } else {
+            format!("{}.{}", prefix, name)
+        }
+    };
+    match (shape.shape_type.as_str(), value) {
+        ("structure", Value::Object(entries)) => {
+            for (name, member) in &shape.members {
+                let (Some(value), Some(member_shape)) = (entries.get(name), model.shape(&member.shape)) else {
+                    continue;
+                };
+                let wire_name = if ec2 {
+                    member
+                        .query_name
+                        .clone()
+                        .or_else(|| member.location_name.as_deref().map(capitalize))
+                        .unwrap_or_else(|| name.clone())
+                } else {
+                    member.location_name.clone().unwrap_or_else(|| name.clone())
+                };
+                let member_prefix = join(prefix, &wire_name);
+                let flattened = member.flattened || member_shape.flattened;
+                if !ec2 && flattened && member_shape.shape_type == "list" {
+                    query_list(model, member_shape, value, &member_prefix, None, params);
+                } else {
+                    query_params(model, member_shape, value, &member_prefix, ec2, params);
+                }
+            }
+        },
+        ("list", Value::Array(_)) => {
+            let item_name = if ec2 {
+                None
+            } else {
+                Some(shape.member.as_ref().and_then(|m| m.location_name.as_deref()).unwrap_or("member"))
+            };
+            if !ec2 && value.as_array().is_some_and(|items| items.is_empty()) {
+                params.push((prefix.to_string(), String::new()));
+            }
+            query_list(model, shape, value, prefix, item_name, params);
+        },
+        ("map", Value::Object(entries)) => {
+            let key_name = shape.key.as_ref().and_then(|k| k.location_name.as_deref()).unwrap_or("key");
+            let value_name = shape.value.as_ref().and_then(|v| v.location_name.as_deref()).unwrap_or("value");
+            let value_shape = shape.value.as_ref().and_then(|v| model.shape(&v.shape));
+            let entry_prefix = if shape.flattened { prefix.to_string() } else { join(prefix, "entry") };
+            for (i, (key, value)) in entries.iter().enumerate() {
+                let entry = join(&entry_prefix, &(i + 1).to_string());
+                params.push((join(&entry, key_name), key.clone()));
+                if let Some(value_shape) = value_shape {
+                    query_params(model, value_shape, value, &join(&entry, value_name), ec2, params);
+                }
+            }
+        },
+        (_, value) => params.push((prefix.to_string(), scalar_string(value))),
+    }
+}
+
+fn query_list(model: &ServiceModel, shape: &Shape, value: &Value, prefix: &str, item_name: Option<&str>, params: &mut Vec<(String, String)>) {
+    let (Some(items), Some(member_shape)) = (value.as_array(), shape.member.as_ref().and_then(|m| model.shape(&m.shape))) else {
+        return;
+    };
+    for (i, item) in items.iter().enumerate() {
+        let item_prefix = match item_name {
+            Some(item_name) => format!("{}.{}.{}", prefix, item_name, i + 1),
+            None => format!("{}.{}", prefix, i + 1),
+        };
+        query_params(model, member_shape, item, &item_prefix, item_name.is_none(), params);
+    }
+}
+
+fn capitalize(name: &str) -> String {
+    let mut chars = name.chars();
+    match chars.next() {
+        Some(first) => first.to_uppercase().chain(chars).collect(),
+        None => String::new(),
+    }
+}
+
+fn scalar_string(value: &Value) -> String {
+    match value {
+        Value::String(s) => s.clone(),
+        value => value.to_string(),
+    }
+}
+
+fn rest_request(
+    model: &ServiceModel,
+    operation: &Operation,
+    shape: Option<&Shape>,
+    input: &Map<String, Value>,
+    protocol: Protocol,
+) -> Result<HttpRequest> {
+    let http = operation
+        .http
+        .as_ref()
+        .ok_or_else(|| eyre!("Operation {} has no HTTP binding", operation.name))?;
+    let (path_template, static_query) = match http.request_uri.split_once('?') {
+        Some((path, query)) => (path, Some(query)),
+        None => (http.request_uri.as_str(), None),
+    };
+    let members = shape.map(|shape| &shape.members);
+    let uri_value = |label: &str| {
+        let (name, _) = members?
+            .iter()
+            .find(|(_, member)| member.location.as_deref() == Some("uri") && member.location_name.as_deref() == Some(label))?;
+        input.get(name).map(scalar_string)
+    };
+
+    let mut path = String::new();
+    let mut rest = path_template;
+    while let Some(start) = rest.find('{') {
+        let end = rest[start..].find('}').map(|end| start + end).ok_or_else(|| eyre!("Invalid request URI"))?;
+        path.push_str(&rest[..start]);
+        let label = &rest[start + 1..end];
+        let (label, greedy) = match label.strip_suffix('+') {
+            Some(label) => (label, true),
+            None => (label, false),
+        };
+        let value = uri_value(label).ok_or_else(|| eyre!("Missing required parameter '--{}'", xform_name(label, '-')))?;
+        if greedy {
+            path.push_str(&value.split('/').map(percent_encode).collect::<Vec<_>>().join("/"));
+        } else {
+            path.push_str(&percent_encode(&value));
+        }
+        rest = &rest[end + 1..];
+    }
+    path.push_str(rest);
+
+    let mut request = HttpRequest::new(&http.method, &path);
+    for pair in static_query.into_iter().flat_map(|query| query.split('&')) {
+        let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+        request.query.push((name.to_string(), value.to_string()));
+    }
+
+    let mut body_members = Map::new();
+    for (name, member) in members.into_iter().flatten() {
+        let Some(value) = input.get(name) else {
+            continue;
+        };
+        let wire_name = member.location_name.as_deref().unwrap_or(name);
+        match member.location.as_deref() {
+            Some("uri") => {},
+            Some("querystring") => match value {
+                Value::Array(items) => request
+                    .query
+                    .extend(items.iter().map(|item| (wire_name.to_string(), scalar_string(item)))),
+                Value::Object(entries) => request
+                    .query
+                    .extend(entries.iter().map(|(key, value)| (key.clone(), scalar_string(value)))),
+                value => request.query.push((wire_name.to_string(), scalar_string(value))),
+            },
+            Some("header") => request.set_header(wire_name, &scalar_string(value)),
+            Some("headers") => {
+                for (key, value) in value.as_object().into_iter().flatten() {
+                    request.set_header(&format!("{}{}", wire_name, key), &scalar_string(value));
+                }
+            },
+            _ => {
+                body_members.insert(name.clone(), value.clone());
+            },
+        }
+    }
+
+    let payload = shape.and_then(|shape| shape.payload.as_deref());
+    if let Some(payload) = payload {
+        let payload_shape = shape
+            .and_then(|shape| shape.members.get(payload))
+            .and_then(|member| model.shape(&member.shape));
+        match (body_members.get(payload), payload_shape) {
+            (None, _) => {},
+            (Some(value), Some(payload_shape)) if matches!(payload_shape.shape_type.as_str(), "blob" | "string") => {
+                request.body = scalar_string(value).into_bytes();
+            },
+            (Some(value), Some(payload_shape)) if protocol == Protocol::RestJson => {
+                request.set_header("Content-Type", "application/json");
+                request.body = json_body(model, payload_shape, value).to_string().into_bytes();
+            },
+            _ => bail!("Request bodies of {} are not supported", operation.name),
+        }
+    } else if !body_members.is_empty() {
+        match (protocol, shape) {
+            (Protocol::RestJson, Some(shape)) => {
+                request.set_header("Content-Type", "application/json");
+                request.body = json_body(model, shape, &Value::Object(body_members)).to_string().into_bytes();
+            },
+            _ => bail!("Request bodies of {} are not supported", operation.name),
+        }
+    }
+    Ok(request)
+}
+
+/// Parses a successful response into the output the AWS CLI would print
+pub fn parse_response(model: &ServiceModel, operation: &Operation, response: &HttpResponse) -> Result<ParsedOutput> {
+    let protocol = Protocol::from_model(model)
+        .ok_or_else(|| eyre!("Unsupported protocol '{}'", model.metadata.protocol))?;
+    let Some(output_ref) = operation.output.as_ref() else {
+        return Ok(ParsedOutput {
+            output: Value::Object(Map::new()),
+            payload: None,
+        });
+    };
+    let shape = model
+        .shape(&output_ref.shape)
+        .ok_or_else(|| eyre!("Unknown shape '{}'", output_ref.shape))?;
+    let (output, payload) = match protocol {
+        Protocol::Json => (json_output(model, shape, &parse_json_body(&response.body)?), None),
+        Protocol::Query => {
+            let root = parse_xml(&response.body)?;
+            let result = match &output_ref.result_wrapper {
+                Some(wrapper) => root.child(wrapper),
+                None => Some(&root),
+            };
+            let output = result.map(|result| xml_output(model, shape, result));
+            (output.unwrap_or_else(|| Value::Object(Map::new())), None)
+        },
+        Protocol::Ec2 => (xml_output(model, shape, &parse_xml(&response.body)?), None),
+        Protocol::RestJson | Protocol::RestXml => rest_output(model, shape, response, protocol)?,
+    };
+    Ok(ParsedOutput { output, payload })
+}
+
+fn parse_json_body(body: &[u8]) -> Result<Value> {
+    if body.iter().all(u8::is_ascii_whitespace) {
+        return Ok(Value::Object(Map::new()));
+    }
+    serde_json::from_slice(body).map_err(|e| eyre!("Invalid JSON response: {}", e))
+}
+
+fn rest_output(model: &ServiceModel, shape: &Shape, response: &HttpResponse, protocol: Protocol) -> Result<(Value, Option<Vec<u8>>)> {
+    let mut output = Map::new();
+    let mut payload = None;
+    let payload_member = shape.payload.as_deref();
+    for (name, member) in &shape.members {
+        let Some(member_shape) = model.shape(&member.shape) else {
+            continue;
+        };
+        let wire_name = member.location_name.as_deref().unwrap_or(name);
+        match member.location.as_deref() {
+            Some("header") => {
+                if let Some(value) = response.header(wire_name) {
+                    output.insert(name.clone(), scalar_output(member_shape, value));
+                }
+            },
+            Some("headers") => {
+                let prefix = wire_name.to_ascii_lowercase();
+                let entries: Map<String, Value> = response
+                    .headers
+                    .iter()
+                    .filter_map(|(header, value)| {
+                        let key = header.to_ascii_lowercase().strip_prefix(&prefix)?.to_string();
+                        Some((key, Value::from(value.as_str())))
+                    })
+                    .collect();
+                if !entries.is_empty() {
+                    output.insert(name.clone(), Value::Object(entries));
+                }
+            },
+            Some("statusCode") => {
+                output.insert(name.clone(), Value::from(response.status));
+            },
+            _ if payload_member == Some(name.as_str()) => match member_shape.shape_type.as_str() {
+                "blob" | "string" => payload = Some(response.body.clone()),
+                _ if response.body.is_empty() => {},
+                _ if protocol == Protocol::RestJson => {
+                    output.insert(name.clone(), json_output(model, member_shape, &parse_json_body(&response.body)?));
+                },
+                _ => {
+                    output.insert(name.clone(), xml_output(model, member_shape, &parse_xml(&response.body)?));
+                },
+            },
+            _ => {},
+        }
+    }
+    if payload_member.is_none() && !response.body.iter().all(u8::is_ascii_whitespace) {
+        let body = match protocol {
+            Protocol::RestJson => json_output(model, shape, &parse_json_body(&response.body)?),
+            _ => xml_output(model, shape, &parse_xml(&response.body)?),
+        };
+        for (name, value) in body.as_object().into_iter().flatten() {
+            let member = &shape.members[name];
+            if member.location.is_none() {
+                output.insert(name.clone(), value.clone());
+            }
+        }
+    }
+    Ok((Value::Object(output), payload))
+}
+
+/// Converts a JSON response body into CLI output: members keyed by name and timestamps
+/// formatted as ISO 8601
+fn json_output(model: &ServiceModel, shape: &Shape, value: &Value) -> Value {
+    match (shape.shape_type.as_str(), value) {
+        ("structure", Value::Object(entries)) => {
+            let mut object = Map::new();
+            for (name, member) in &shape.members {
+                let wire_name = member.location_name.as_deref().unwrap_or(name);
+                let (Some(value), Some(member_shape)) = (entries.get(wire_name), model.shape(&member.shape)) else {
+                    continue;
+                };
+                object.insert(name.clone(), json_output(model, member_shape, value));
+            }
+            Value::Object(object)
+        },
+        ("list", Value::Array(items)) => match shape.member.as_ref().and_then(|member| model.shape(&member.shape)) {
+            Some(member_shape) => Value::Array(items.iter().map(|item| json_output(model, member_shape, item)).collect()),
+            None => value.clone(),
+        },
+        ("map", Value::Object(entries)) => match shape.value.as_ref().and_then(|value| model.shape(&value.shape)) {
+            Some(value_shape) => Value::Object(
+                entries
+                    .iter()
+                    .map(|(key, value)| (key.clone(), json_output(model, value_shape, value)))
+                    .collect(),
+            ),
+            None => value.clone(),
+        },
+        ("timestamp", value) => match parse_timestamp(value) {
+            Some(timestamp) => Value::from(format_timestamp(timestamp)),
+            None => value.clone(),
+        },
+        _ => value.clone(),
+    }
+}
+
+/// An XML element with its text and child elements
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+struct XmlNode {
+    name: String,
+    text: String,
+    children: Vec<XmlNode>,
+}
+
+impl XmlNode {
+    fn child(&self, name: &str) -> Option<&XmlNode> {
+        self.children.iter().find(|child| child.name == name)
+    }
+
+    /// Finds the first element with the name anywhere below this one
+    fn descendant(&self, name: &str) -> Option<&XmlNode> {
+        self.children
+            .iter()
+            .find_map(|child| if child.name == name { Some(child) } else { child.descendant(name) })
+    }
+}
+
+/// Parses an XML document into its root element, ignoring namespaces and attributes
+fn parse_xml(body: &[u8]) -> Result<XmlNode> {
+    use quick_xml::events::Event;
+
+    let mut reader = quick_xml::Reader::from_reader(body);
+    let mut stack = vec![XmlNode::default()];
+    let mut buf = Vec::new();
+    let name_of = |name: quick_xml::name::LocalName<'_>| String::from_utf8_lossy(name.as_ref()).into_owned();
+    loop {
+        match reader.read_event_into(&mut buf).map_err(|e| eyre!("Invalid XML response: {}", e))? {
+            Event::Start(start) => stack.push(XmlNode {
+                name: name_of(start.local_name()),
+                ..Default::default()
+            }),
+            Event::Empty(empty) => {
+                let node = XmlNode {
+                    name: name_of(empty.local_name()),
+                    ..Default::default()
+                };
+                stack.last_mut().expect("the document node is never popped").children.push(node);
+            },
+            Event::Text(text) => {
+                let text = text.unescape().map_err(|e| eyre!("Invalid XML response: {}", e))?;
+                stack.last_mut().expect("the document node is never popped").text.push_str(&text);
+            },
+            Event::CData(data) => {
+                let text = String::from_utf8_lossy(&data);
+                stack.last_mut().expect("the document node is never popped").text.push_str(&text);
+            },
+            Event::End(_) => {
+                let node = stack.pop().filter(|_| !stack.is_empty()).ok_or_else(|| eyre!("Invalid XML response"))?;
+                stack.last_mut().expect("checked above").children.push(node);
+            },
+            Event::Eof => break,
+            _ => {},
+        }
+        buf.clear();
+    }
+    let document = stack.pop().filter(|_| stack.is_empty()).ok_or_else(|| eyre!("Invalid XML response"))?;
+    document.children.into_iter().next().ok_or_else(|| eyre!("Empty XML response"))
+}
+
+/// Converts an XML element into CLI output according to its shape
+fn xml_output(model: &ServiceModel, shape: &Shape, node: &XmlNode) -> Value {
+    match shape.shape_type.as_str() {
+        "structure" => {
+            let mut object = Map::new();
+            for (name, member) in &shape.members {
+                let Some(member_shape) = model.shape(&member.shape) else {
+                    continue;
+                };
+                if member.location.is_some() {
+                    continue;
+                }
+                let wire_name = member.location_name.as_deref().unwrap_or(name);
+                let flattened = member.flattened || member_shape.flattened;
+                if flattened && member_shape.shape_type == "list" {
+                    let item_shape = member_shape.member.as_ref().and_then(|m| model.shape(&m.shape));
+                    let items: Vec<Value> = node
+                        .children
+                        .iter()
+                        .filter(|child| child.name == wire_name)
+                        .filter_map(|child| Some(xml_output(model, item_shape?, child)))
+                        .collect();
+                    if !items.is_empty() {
+                        object.insert(name.clone(), Value::Array(items));
+                    }
+                } else if let Some(child) = node.child(wire_name) {
+                    object.insert(name.clone(), xml_output(model, member_shape, child));
+                }
+            }
+            Value::Object(object)
+        },
+        "list" => match shape.member.as_ref().and_then(|member| model.shape(&member.shape)) {
+            Some(member_shape) => Value::Array(
+                node.children
+                    .iter()
+                    .map(|child| xml_output(model, member_shape, child))
+                    .collect(),
+            ),
+            None => Value::Array(Vec::new()),
+        },
+        "map" => {
+            let key_name = shape.key.as_ref().and_then(|k| k.location_name.as_deref()).unwrap_or("key");
+            let value_name = shape.value.as_ref().and_then(|v| v.location_name.as_deref()).unwrap_or("value");
+            let value_shape = shape.value.as_ref().and_then(|v| model.shape(&v.shape));
+            let mut object = Map::new();
+            for entry in &node.children {
+                let (Some(key), Some(value), Some(value_shape)) = (entry.child(key_name), entry.child(value_name), value_shape) else {
+                    continue;
+                };
+                object.insert(key.text.clone(), xml_output(model, value_shape, value));
+            }
+            Value::Object(object)
+        },
+        _ => scalar_output(shape, &node.text),
+    }
+}
+
+/// Converts the text of an XML element or header into a typed value
+fn scalar_output(shape: &Shape, text: &str) -> Value {
+    match shape.shape_type.as_str() {
+        "boolean" => Value::Bool(text == "true"),
+        "integer" | "long" => text.parse::<i64>().map(Value::from).unwrap_or_else(|_| Value::from(text)),
+        "float" | "double" => text.parse::<f64>().map(Value::from).unwrap_or_else(|_| Value::from(text)),
+        "timestamp" => match parse_timestamp(&Value::from(text)) {
+            Some(timestamp) => Value::from(format_timestamp(timestamp)),
+            None => Value::from(text),
+        },
+        _ => Value::from(text),
+    }
+}
+
+/// Extracts the error code, message and request id of a failed response
+pub fn parse_error(model: &ServiceModel, response: &HttpResponse) -> ServiceError {
+    let mut error = ServiceError {
+        request_id: ["x-amzn-RequestId", "x-amz-request-id"]
+            .iter()
+            .find_map(|name| response.header(name))
+            .map(|id| id.to_string()),
+        ..Default::default()
+    };
+    match Protocol::from_model(model) {
+        Some(Protocol::Json | Protocol::RestJson) => {
+            let body: Value = serde_json::from_slice(&response.body).unwrap_or_default();
+            let code = response
+                .header("x-amzn-ErrorType")
+                .or_else(|| body.get("__type").and_then(Value::as_str))
+                .or_else(|| body.get("code").and_then(Value::as_str))
+                .unwrap_or_default();
+            // Codes can be qualified, `aws.protocoltests#FooError`, and carry a URL suffix
+            let code = code.split(':').next().unwrap_or_default();
+            error.code = code.rsplit('#').next().unwrap_or_default().to_string();
+            error.message = ["message", "Message", "errorMessage"]
+                .iter()
+                .find_map(|key| body.get(key).and_then(Value::as_str))
+                .unwrap_or_default()
+                .to_string();
+        },
+        _ => {
+            if let Ok(root) = parse_xml(&response.body) {
+                let text = |name: &str| root.descendant(name).map(|node| node.text.clone());
+                error.code = text("Code").unwrap_or_default();
+                error.message = text("Message").unwrap_or_default();
+                error.request_id = error.request_id.or_else(|| text("RequestId")).or_else(|| text("RequestID"));
+            }
+        },
+    }
+    if error.code.is_empty() {
+        // Responses to HEAD requests have no body to take the code from
+        error.code = response.status.to_string();
+    }
+    error
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn model(json: Value) -> ServiceModel {
+        serde_json::from_value(json).unwrap()
+    }
+
+    fn parameters(json: Value) -> IndexMap<String, Value> {
+        serde_json::from_value(json).unwrap()
+    }
+
+    fn ec2_model() -> ServiceModel {
+        serde_json::from_str(crate::service_model::tests::EC2_MODEL).unwrap()
+    }
+
+    fn sts_model() -> ServiceModel {
+        model(serde_json::json!({
+            "metadata": {"apiVersion": "2011-06-15", "endpointPrefix": "sts", "protocol": "query"},
+            "operations": {
+                "GetCallerIdentity": {
+                    "name": "GetCallerIdentity",
+                    "input": {"shape": "GetCallerIdentityRequest"},
+                    "output": {"shape": "GetCallerIdentityResponse", "resultWrapper": "GetCallerIdentityResult"}
+                }
+            },
+            "shapes": {
+                "GetCallerIdentityRequest": {"type": "structure", "members": {}},
+                "GetCallerIdentityResponse": {
+                    "type": "structure",
+                    "members": {"UserId": {"shape": "String"}, "Account": {"shape": "String"}, "Arn": {"shape": "String"}}
+                },
+                "String": {"type": "string"}
+            }
+        }))
+    }
+
+    #[test]
+    fn test_build_input() {
+        let model = ec2_model();
+        let operation = model.operation("describe-instances").unwrap();
+        let input = build_input(&model, operation, &parameters(serde_json::json!({
+            "instance-ids": "i-1",
+            "dry_run": "false",
+            "filters": r#"[{"Name": "instance-state-name", "Values": ["running"]}]"#,
+            "max-results": "5"
+        })))
+        .unwrap();
+        assert_eq!(
+            Value::Object(input),
+            serde_json::json!({
+                "InstanceIds": ["i-1"],
+                "DryRun": false,
+                "Filters": [{"Name": "instance-state-name", "Values": ["running"]}],
+                "MaxResults": 5
+            })
+        );
+
+        let unknown = parameters(serde_json::json!({"bogus": "1"}));
+        assert!(build_input(&model, operation, &unknown).is_err());
+        let invalid = parameters(serde_json::json!({"max-results": "many"}));
+        assert!(build_input(&model, operation, &invalid).is_err());
+        let stop = model.operation("stop-instances").unwrap();
+        let missing = build_input(&model, stop, &IndexMap::new()).unwrap_err();
+        assert!(missing.to_string().contains("--instance-ids"));
+    }
+
+    #[test]
+    fn test_serialize_ec2() {
+        let model = ec2_model();
+        let operation = model.operation("describe-instances").unwrap();
+        let input = build_input(&model, operation, &parameters(serde_json::json!({
+            "instance-ids": ["i-1", "i-2"],
+            "filters": [{"Name": "tag:Env", "Values": ["prod"]}]
+        })))
+        .unwrap();
+        let request = serialize_request(&model, operation, &input).unwrap();
+        assert_eq!(request.method, "POST");
+        assert_eq!(
+            String::from_utf8(request.body).unwrap(),
+            "Action=DescribeInstances&Version=2016-11-15&InstanceId.1=i-1&InstanceId.2=i-2&Filter.1.Name=tag%3AEnv&Filter.1.Value.1=prod"
+        );
+    }
+
+    #[test]
+    fn test_query_response() {
+        let model = sts_model();
+        let operation = model.operation("get-caller-identity").unwrap();
+        let request = serialize_request(&model, operation, &Map::new()).unwrap();
+        assert_eq!(request.body, b"Action=GetCallerIdentity&Version=2011-06-15");
+
+        let response = HttpResponse {
+            status: 200,
+            headers: vec![],
+            body: br#"<GetCallerIdentityResponse xmlns="https://sts.amazonaws.com/doc/2011-06-15/">
+  <GetCallerIdentityResult>
+    <Arn>arn:aws:iam::123456789012:user/alice</Arn>
+    <UserId>AIDAEXAMPLE</UserId>
+    <Account>123456789012</Account>
+  </GetCallerIdentityResult>
+  <ResponseMetadata><RequestId>01234567-89ab</RequestId></ResponseMetadata>
+</GetCallerIdentityResponse>"#
+                .to_vec(),
+        };
+        let parsed = parse_response(&model, operation, &response).unwrap();
+        assert_eq!(
+            serde_json::to_string(&parsed.output).unwrap(),
+            r#"{"UserId":"AIDAEXAMPLE","Account":"123456789012","Arn":"arn:aws:iam::123456789012:user/alice"}"#
+        );
+
+        let error = HttpResponse {
+            status: 403,
+            headers: vec![],
+            body: br#"<ErrorResponse><Error><Type>Sender</Type><Code>ExpiredToken</Code><Message>The security token included in the request is expired</Message></Error><RequestId>abc</RequestId></ErrorResponse>"#.to_vec(),
+        };
+        assert_eq!(parse_error(&model, &error), ServiceError {
+            code: "ExpiredToken".to_string(),
+            message: "The security token included in the request is expired".to_string(),
+            request_id: Some("abc".to_string()),
+        });
+    }
+
+    #[test]
+    fn test_json_protocol() {
+        let model = model(serde_json::json!({
+            "metadata": {
+                "apiVersion": "2014-03-28", "endpointPrefix": "logs", "protocol": "json",
+                "jsonVersion": "1.1", "targetPrefix": "Logs_20140328"
+            },
+            "operations": {
+                "DescribeLogGroups": {
+                    "name": "DescribeLogGroups",
+                    "input": {"shape": "DescribeLogGroupsRequest"},
+                    "output": {"shape": "DescribeLogGroupsResponse"}
+                }
+            },
+            "shapes": {
+                "DescribeLogGroupsRequest": {
+                    "type": "structure",
+                    "members": {"logGroupNamePrefix": {"shape": "String"}, "limit": {"shape": "Integer"}}
+                },
+                "DescribeLogGroupsResponse": {
+                    "type": "structure",
+                    "members": {"logGroups": {"shape": "LogGroups"}}
+                },
+                "LogGroups": {"type": "list", "member": {"shape": "LogGroup"}},
+                "LogGroup": {
+                    "type": "structure",
+                    "members": {"logGroupName": {"shape": "String"}, "creationTime": {"shape": "Timestamp"}}
+                },
+                "Timestamp": {"type": "timestamp"},
+                "String": {"type": "string"},
+                "Integer": {"type": "integer"}
+            }
+        }));
+        let operation = model.operation("describe-log-groups").unwrap();
+        let input = build_input(&model, operation, &parameters(serde_json::json!({
+            "log-group-name-prefix": "/aws/lambda/",
+            "limit": 10
+        })))
+        .unwrap();
+        let request = serialize_request(&model, operation, &input).unwrap();
+        assert_eq!(request.header("X-Amz-Target"), Some("Logs_20140328.DescribeLogGroups"));
+        assert_eq!(request.header("Content-Type"), Some("application/x-amz-json-1.1"));
+        assert_eq!(request.body, br#"{"logGroupNamePrefix":"/aws/lambda/","limit":10}"#);
+
+        let response = HttpResponse {
+            status: 200,
+            headers: vec![],
+            body: br#"{"logGroups": [{"logGroupName": "/aws/lambda/f", "creationTime": 1700000000}]}"#.to_vec(),
+        };
+        let parsed = parse_response(&model, operation, &response).unwrap();
+        assert_eq!(
+            parsed.output,
+            serde_json::json!({"logGroups": [{"logGroupName": "/aws/lambda/f", "creationTime": "2023-11-14T22:13:20+00:00"}]})
+        );
+
+        let error = HttpResponse {
+            status: 400,
+            headers: vec![("x-amzn-RequestId".to_string(), "req-1".to_string())],
+            body: br#"{"__type": "com.amazonaws.logs#ResourceNotFoundException", "message": "The specified log group does not exist."}"#.to_vec(),
+        };
+        let error = parse_error(&model, &error);
+        assert_eq!(error.code, "ResourceNotFoundException");
+        assert_eq!(error.request_id.as_deref(), Some("req-1"));
+    }
+
+    #[test]
+    fn test_rest_xml() {
+        let model = model(serde_json::json!({
+            "metadata": {"apiVersion": "2006-03-01", "endpointPrefix": "s3", "protocol": "rest-xml"},
+            "operations": {
+                "ListObjectsV2": {
+                    "name": "ListObjectsV2",
+                    "http": {"method": "GET", "requestUri": "/{Bucket}?list-type=2"},
+                    "input": {"shape": "ListObjectsV2Request"},
+                    "output": {"shape": "ListObjectsV2Output"}
+                },
+                "GetObject": {
+                    "name": "GetObject",
+                    "http": {"method": "GET", "requestUri": "/{Bucket}/{Key+}"},
+                    "input": {"shape": "GetObjectRequest"},
+                    "output": {"shape": "GetObjectOutput"}
+                }
+            },
+            "shapes": {
+                "ListObjectsV2Request": {
+                    "type": "structure",
+                    "required": ["Bucket"],
+                    "members": {
+                        "Bucket": {"shape": "String", "location": "uri", "locationName": "Bucket"},
+                        "Prefix": {"shape": "String", "location": "querystring", "locationName": "prefix"},
+                        "MaxKeys": {"shape": "Integer", "location": "querystring", "locationName": "max-keys"}
+                    }
+                },
+                "ListObjectsV2Output": {
+                    "type": "structure",
+                    "members": {
+                        "IsTruncated": {"shape": "Boolean"},
+                        "Contents": {"shape": "ObjectList"},
+                        "KeyCount": {"shape": "Integer"}
+                    }
+                },
+                "ObjectList": {"type": "list", "member": {"shape": "Object"}, "flattened": true},
+                "Object": {
+                    "type": "structure",
+                    "members": {"Key": {"shape": "String"}, "LastModified": {"shape": "Timestamp"}, "Size": {"shape": "Integer"}}
+                },
+                "GetObjectRequest": {
+                    "type": "structure",
+                    "required": ["Bucket", "Key"],
+                    "members": {
+                        "Bucket": {"shape": "String", "location": "uri", "locationName": "Bucket"},
+                        "Key": {"shape": "String", "location": "uri", "locationName": "Key"}
+                    }
+                },
+                "GetObjectOutput": {
+                    "type": "structure",
+                    "members": {
+                        "Body": {"shape": "Body", "streaming": true},
+                        "ContentLength": {"shape": "Integer", "location": "header", "locationName": "Content-Length"},
+                        "Metadata": {"shape": "Metadata", "location": "headers", "locationName": "x-amz-meta-"}
+                    },
+                    "payload": "Body"
+                },
+                "Metadata": {"type": "map", "key": {"shape": "String"}, "value": {"shape": "String"}},
+                "Body": {"type": "blob", "streaming": true},
+                "Timestamp": {"type": "timestamp"},
+                "Boolean": {"type": "boolean"},
+                "String": {"type": "string"},
+                "Integer": {"type": "integer"}
+            }
+        }));
+        let list = model.operation("list-objects-v2").unwrap();
+        let input = build_input(&model, list, &parameters(serde_json::json!({
+            "bucket": "my-bucket",
+            "prefix": "logs/2024 01/",
+            "max-keys": 2
+        })))
+        .unwrap();
+        let request = serialize_request(&model, list, &input).unwrap();
+        assert_eq!(request.method, "GET");
+        assert_eq!(request.path_and_query(), "/my-bucket?list-type=2&prefix=logs%2F2024%2001%2F&max-keys=2");
+        assert!(request.body.is_empty());
+
+        let response = HttpResponse {
+            status: 200,
+            headers: vec![],
+            body: br#"<?xml version="1.0" encoding="UTF-8"?>
+<ListBucketResult xmlns="http://s3.amazonaws.com/doc/2006-03-01/">
+  <IsTruncated>false</IsTruncated>
+  <Contents><Key>logs/a.txt</Key><LastModified>2024-01-02T03:04:05.000Z</LastModified><Size>12</Size></Contents>
+  <Contents><Key>logs/b&amp;c.txt</Key><LastModified>2024-01-02T03:04:06.000Z</LastModified><Size>0</Size></Contents>
+  <KeyCount>2</KeyCount>
+</ListBucketResult>"#
+                .to_vec(),
+        };
+        let parsed = parse_response(&model, list, &response).unwrap();
+        assert_eq!(
+            parsed.output,
+            serde_json::json!({
+                "IsTruncated": false,
+                "Contents": [
+                    {"Key": "logs/a.txt", "LastModified": "2024-01-02T03:04:05+00:00", "Size": 12},
+                    {"Key": "logs/b&c.txt", "LastModified": "2024-01-02T03:04:06+00:00", "Size": 0}
+                ],
+                "KeyCount": 2
+            })
+        );
+
+        let get = model.operation("get-object").unwrap();
+        let input = build_input(&model, get, &parameters(serde_json::json!({"bucket": "b", "key": "dir/a b.txt"}))).unwrap();
+        assert_eq!(serialize_request(&model, get, &input).unwrap().path, "/b/dir/a%20b.txt");
+        let response = HttpResponse {
+            status: 200,
+            headers: vec![
+                ("Content-Length".to_string(), "5".to_string()),
+                ("x-amz-meta-owner".to_string(), "alice".to_string()),
+            ],
+            body: b"hello".to_vec(),
+        };
+        let parsed = parse_response(&model, get, &response).unwrap();
+        assert_eq!(parsed.output, serde_json::json!({"ContentLength": 5, "Metadata": {"owner": "alice"}}));
+        assert_eq!(parsed.payload.as_deref(), Some(&b"hello"[..]));
+    }
+
+    #[test]
+    fn test_percent_encode() {
+        assert_eq!(percent_encode("a-b_c.d~e"), "a-b_c.d~e");
+        assert_eq!(percent_encode("a b/ü"), "a%20b%2F%C3%BC");
+    }
+}
@@ -0,0 +1,221 @@
+//! Retries of throttled and transiently failing commands. Reads are retried with exponential
+//! backoff and full jitter; writes only when they are known to be idempotent, since a write
+//! that failed on the way back may still have been applied.
+
+use std::hash::{BuildHasher, Hasher};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::Duration;
+
+use async_trait::async_trait;
+use eyre::Result;
+use serde::Deserialize;
+
+use crate::aws_error::AwsError;
+use crate::executor::{AwsExecutor, CommandOutput};
+use crate::use_aws::UseAws;
+
+/// Parameters that make a write idempotent when the request sets them, since AWS returns the
+/// result of the first request for a repeated token. Compared ignoring case and separators.
+const IDEMPOTENCY_TOKENS: [&str; 3] = ["clienttoken", "clientrequesttoken", "idempotencytoken"];
+
+/// Retries of failed commands, configured under `retry` in the config file
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RetryConfig {
+    /// Runs of a command at most, including the first. 1 turns retries off.
+    pub max_attempts: u32,
+    /// Upper bound of the delay before the first retry, doubled for each further retry
+    pub base_delay_ms: u64,
+    /// Upper bound of any delay
+    pub max_delay_ms: u64,
+    /// Writes that are safe to repeat, as `service operation`, e.g. `s3api put-bucket-tagging`
+    pub idempotent_operations: Vec<String>,
+}
+
+impl Default for RetryConfig {
+    fn default() -> Self {
+        Self {
+            max_attempts: 3,
+            base_delay_ms: 200,
+            max_delay_ms: 5000,
+            idempotent_operations: Vec::new(),
+        }
+    }
+}
+
+impl RetryConfig {
+    /// Whether `command` may be run again after a retryable failure
+    pub fn applies_to(&self, command: &UseAws) -> bool {
+        !command.requires_acceptance() || self.is_idempotent(command)
+    }
+
+    fn is_idempotent(&self, command: &UseAws) -> bool {
+        let operation = format!("{} {}", command.service_name, command.operation_name);
+        if self.idempotent_operations.contains(&operation) {
+            return true;
+        }
+        command.parameters.iter().flatten().any(|(name, value)| {
+            let name: String = name.chars().filter(|c| c.is_ascii_alphanumeric()).collect();
+            IDEMPOTENCY_TOKENS.contains(&name.to_ascii_lowercase().as_str()) && !value.is_null()
+        })
+    }
+
+    /// The delay before retry number `retry`, counted from 1: a random duration up to
+    /// `base_delay_ms` doubled `retry - 1` times, capped at `max_delay_ms`
+    pub fn delay(&self, retry: u32) -> Duration {
+        let cap = self
+            .base_delay_ms
+            .saturating_mul(1u64.checked_shl(retry.saturating_sub(1)).unwrap_or(u64::MAX))
+            .min(self.max_delay_ms);
+        if cap == 0 {
+            return Duration::ZERO;
+        }
+        let mut hasher = std::collections::hash_map::RandomState::new().build_hasher();
+        hasher.write_u32(retry);
+        Duration::from_millis(hasher.finish() % (cap + 1))
+    }
+}
+
+/// Whether a failed command is worth running again: it was throttled, the service was briefly
+/// unavailable, or the request never got a response
+pub fn is_retryable(output: &CommandOutput) -> bool {
+    output.exit_code != 0 && AwsError::parse(&output.stderr, output.exit_code).category.is_transient()
+}
+
+/// Runs commands through an executor, running them again after retryable failures. Counts the
+/// runs of each command; see [Retrying::attempts].
+#[derive(Debug)]
+pub struct Retrying<'a> {
+    executor: &'a dyn AwsExecutor,
+    config: &'a RetryConfig,
+    attempts: AtomicU32,
+}
+
+impl<'a> Retrying<'a> {
+    pub fn new(executor: &'a dyn AwsExecutor, config: &'a RetryConfig) -> Self {
+        Self {
+            executor,
+            config,
+            attempts: AtomicU32::new(0),
+        }
+    }
+
+    /// The most runs any one command took, 0 if none ran
+    pub fn attempts(&self) -> u32 {
+        self.attempts.load(Ordering::Relaxed)
+    }
+}
+
+#[async_trait]
+impl AwsExecutor for Retrying<'_> {
+    async fn execute(&self, command: &UseAws) -> Result<CommandOutput> {
+        let max_attempts = if self.config.applies_to(command) {
+            self.config.max_attempts.max(1)
+        } else {
+            1
+        };
+        let mut attempt = 1;
+        loop {
+            let output = self.executor.execute(command).await?;
+            self.attempts.fetch_max(attempt, Ordering::Relaxed);
+            if attempt >= max_attempts || !is_retryable(&output) {
+                return Ok(output);
+            }
+            let delay = self.config.delay(attempt);
+            tracing::debug!(
+                "Retrying '{} {}' in {:?} after: {}",
+                command.service_name,
+                command.operation_name,
+                delay,
+                output.stderr.trim()
+            );
+            tokio::time::sleep(delay).await;
+            attempt += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::executor::MockExecutor;
+
+    fn command(operation_name: &str, parameters: serde_json::Value) -> UseAws {
+        serde_json::from_value(serde_json::json!({
+            "service_name": "ec2",
+            "operation_name": operation_name,
+            "parameters": parameters,
+            "region": "us-east-1"
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_is_retryable() {
+        let throttled = CommandOutput::failure(
+            254,
+            "\nAn error occurred (ThrottlingException) when calling the DescribeInstances operation (reached max retries: 2): Rate exceeded\n",
+        );
+        assert!(is_retryable(&throttled));
+        assert!(is_retryable(&CommandOutput::failure(
+            255,
+            "\nCould not connect to the endpoint URL: \"https://ec2.us-east-1.amazonaws.com/\"\n"
+        )));
+        // An error code decides even if the message mentions a connection
+        assert!(!is_retryable(&CommandOutput::failure(
+            254,
+            "An error occurred (AccessDenied) when calling the GetObject operation: Connection reset by peer"
+        )));
+        assert!(!is_retryable(&CommandOutput::failure(252, "aws: error: argument --instance-ids: expected one argument")));
+
+        let config = RetryConfig {
+            idempotent_operations: vec!["ec2 create-tags".to_string()],
+            ..RetryConfig::default()
+        };
+        assert!(config.applies_to(&command("describe-instances", serde_json::json!({}))));
+        assert!(!config.applies_to(&command("run-instances", serde_json::json!({"image-id": "ami-1"}))));
+        assert!(config.applies_to(&command("run-instances", serde_json::json!({"client-token": "abc"}))));
+        assert!(config.applies_to(&command("create-tags", serde_json::json!({}))));
+
+        let config = RetryConfig {
+            base_delay_ms: 100,
+            max_delay_ms: 250,
+            ..RetryConfig::default()
+        };
+        for retry in 1..40 {
+            let cap = [100, 200].get(retry as usize - 1).copied().unwrap_or(250);
+            assert!(config.delay(retry) <= Duration::from_millis(cap));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_retrying() {
+        let throttled = || {
+            CommandOutput::failure(254, "An error occurred (RequestLimitExceeded) when calling the RunInstances operation: Request limit exceeded.")
+        };
+        let config = RetryConfig {
+            base_delay_ms: 0,
+            ..RetryConfig::default()
+        };
+
+        let executor = MockExecutor::new().respond(throttled()).respond(CommandOutput::success("{}"));
+        let retrying = Retrying::new(&executor, &config);
+        let output = retrying.execute(&command("describe-instances", serde_json::json!({}))).await.unwrap();
+        assert_eq!(output.exit_code, 0);
+        assert_eq!(retrying.attempts(), 2);
+
+        // Gives up after max_attempts
+        let executor = MockExecutor::new().respond(throttled()).respond(throttled()).respond(throttled());
+        let retrying = Retrying::new(&executor, &config);
+        let output = retrying.execute(&command("describe-instances", serde_json::json!({}))).await.unwrap();
+        assert_eq!(output.exit_code, 254);
+        assert_eq!((retrying.attempts(), executor.calls().len()), (3, 3));
+
+        // Writes without an idempotency token run once
+        let executor = MockExecutor::new().respond(throttled()).respond(CommandOutput::success("{}"));
+        let retrying = Retrying::new(&executor, &config);
+        let output = retrying.execute(&command("run-instances", serde_json::json!({}))).await.unwrap();
+        assert_eq!(output.exit_code, 254);
+        assert_eq!(retrying.attempts(), 1);
+    }
+}
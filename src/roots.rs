@@ -0,0 +1,308 @@
+use std::path::{Component, Path, PathBuf};
+
+use serde::Deserialize;
+
+use crate::use_aws::UseAws;
+
+/// Parameter value prefixes that make the AWS CLI read a local file
+const FILE_URI_PREFIXES: [&str; 2] = ["fileb://", "file://"];
+
+/// A single root as returned by the client's `roots/list`
+#[derive(Debug, Clone, Deserialize)]
+pub struct Root {
+    pub uri: String,
+    pub name: Option<String>,
+}
+
+/// The result of a `roots/list` request
+#[derive(Debug, Clone, Deserialize)]
+pub struct ListRootsResult {
+    pub roots: Vec<Root>,
+}
+
+/// Errors raised when a command references files outside the allowed roots
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum RootsError {
+    #[error("Local file references are not allowed until the client has provided its roots")]
+    NoRoots,
+    #[error("Local path '{0}' is outside the client's roots")]
+    OutsideRoots(String),
+    #[error("Local path '{0}' could not be resolved: {1}")]
+    Unresolvable(String, String),
+    #[error("Local path '{0}' uses '~' or environment variables, which the AWS CLI expands; pass the expanded path")]
+    Expandable(String),
+}
+
+/// The directories local file references must stay inside of
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Roots {
+    dirs: Vec<PathBuf>,
+}
+
+impl Roots {
+    /// Builds roots from the `file://` URIs sent by the client. Roots that are not file URIs
+    /// or do not exist on disk are ignored.
+    pub fn from_uris<'a>(uris: impl IntoIterator<Item = &'a str>) -> Self {
+        let dirs = uris
+            .into_iter()
+            .filter_map(|uri| {
+                let path = file_uri_to_path(uri);
+                if path.is_none() {
+                    tracing::warn!("Ignoring non-file root '{}'", uri);
+                }
+                path
+            })
+            .filter_map(|path| match path.canonicalize() {
+                Ok(dir) => Some(dir),
+                Err(e) => {
+                    tracing::warn!("Ignoring root '{}': {}", path.display(), e);
+                    None
+                },
+            })
+            .collect();
+        Self { dirs }
+    }
+
+    /// Roots consisting of the given directories, canonicalized
+    pub fn from_dirs(dirs: impl IntoIterator<Item = PathBuf>) -> Self {
+        Self {
+            dirs: dirs.into_iter().filter_map(|dir| dir.canonicalize().ok()).collect(),
+        }
+    }
+
+    pub fn dirs(&self) -> &[PathBuf] {
+        &self.dirs
+    }
+
+    /// Checks every local file the command would read or write
+    pub fn check(&self, use_aws: &UseAws) -> Result<(), RootsError> {
+        for path in local_paths(use_aws) {
+            self.check_path(&path)?;
+        }
+        Ok(())
+    }
+
+    /// Resolves `path` (relative to the working directory the CLI runs in) through any
+    /// symlinks and makes sure it ends up inside one of the roots.
+    pub fn check_path(&self, path: &str) -> Result<PathBuf, RootsError> {
+        if self.dirs.is_empty() {
+            return Err(RootsError::NoRoots);
+        }
+        if is_expandable(path) {
+            return Err(RootsError::Expandable(path.to_string()));
+        }
+        let resolved = resolve(Path::new(path)).map_err(|e| RootsError::Unresolvable(path.to_string(), e))?;
+        if self.dirs.iter().any(|dir| resolved.starts_with(dir)) {
+            Ok(resolved)
+        } else {
+            Err(RootsError::OutsideRoots(path.to_string()))
+        }
+    }
+}
+
+/// Returns the local paths referenced by a command: parameters with `file://` and `fileb://`
+/// values and local positional arguments like the source of `s3 cp`
+pub fn local_paths(use_aws: &UseAws) -> Vec<String> {
+    let mut paths: Vec<String> = use_aws.local_positional_paths().into_iter().map(|p| p.to_string()).collect();
+    let Some(parameters) = &use_aws.parameters else {
+        return paths;
+    };
+    for value in parameters.values() {
+        let values = match value {
+            serde_json::Value::Array(values) => values.iter().collect(),
+            value => vec![value],
+        };
+        for value in values.into_iter().filter_map(|v| v.as_str()) {
+            if let Some(path) = FILE_URI_PREFIXES.iter().find_map(|prefix| value.strip_prefix(prefix)) {
+                paths.push(path.to_string());
+            }
+        }
+    }
+    paths
+}
+
+/// Whether the AWS CLI would expand part of `path` before reading it. Like Python's
+/// `os.path.expanduser` and `os.path.expandvars`, it replaces a leading `~` and `$NAME` or
+/// `${NAME}` anywhere in `file://` paths.
+fn is_expandable(path: &str) -> bool {
+    path.starts_with('~')
+        || path
+            .split('$')
+            .skip(1)
+            .any(|rest| rest.starts_with(|c: char| c == '{' || c == '_' || c.is_ascii_alphanumeric()))
+}
+
+/// Canonicalizes a path that may not exist yet: the longest existing ancestor is resolved
+/// through symlinks and the remaining components are appended, refusing `..` in them.
+fn resolve(path: &Path) -> Result<PathBuf, String> {
+    let path = if path.is_absolute() {
+        path.to_path_buf()
+    } else {
+        std::env::current_dir().map_err(|e| e.to_string())?.join(path)
+    };
+
+    let mut existing = path.as_path();
+    let mut remainder = Vec::new();
+    loop {
+        match existing.canonicalize() {
+            Ok(mut resolved) => {
+                for component in remainder.iter().rev() {
+                    match component {
+                        Component::Normal(name) => resolved.push(name),
+                        Component::CurDir => {},
+                        _ => return Err("path traversal in a non-existent path".to_string()),
+                    }
+                }
+                return Ok(resolved);
+            },
+            Err(e) => {
+                let (Some(parent), Some(component)) = (existing.parent(), existing.components().next_back()) else {
+                    return Err(e.to_string());
+                };
+                remainder.push(component);
+                existing = parent;
+            },
+        }
+    }
+}
+
+/// Converts a `file://` URI into a path, decoding percent-escapes
+fn file_uri_to_path(uri: &str) -> Option<PathBuf> {
+    let rest = uri.strip_prefix("file://")?;
+    // Skip an optional authority, e.g. file://localhost/tmp
+    let path = &rest[rest.find('/')?..];
+    let bytes = path.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' && i + 2 < bytes.len() {
+            let hex = std::str::from_utf8(&bytes[i + 1..i + 3]).ok();
+            if let Some(byte) = hex.and_then(|hex| u8::from_str_radix(hex, 16).ok()) {
+                decoded.push(byte);
+                i += 3;
+                continue;
+            }
+        }
+        decoded.push(bytes[i]);
+        i += 1;
+    }
+    Some(PathBuf::from(String::from_utf8(decoded).ok()?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    macro_rules! use_aws {
+        ($value:tt) => {
+            serde_json::from_value::<UseAws>(serde_json::json!($value)).unwrap()
+        };
+    }
+
+    #[test]
+    fn test_file_uri_to_path() {
+        assert_eq!(file_uri_to_path("file:///home/me/project"), Some(PathBuf::from("/home/me/project")));
+        assert_eq!(file_uri_to_path("file://localhost/tmp/a%20b"), Some(PathBuf::from("/tmp/a b")));
+        assert_eq!(file_uri_to_path("https://example.com"), None);
+    }
+
+    #[test]
+    fn test_local_paths() {
+        let cmd = use_aws! {{
+            "service_name": "cloudformation",
+            "operation_name": "create-stack",
+            "parameters": {
+                "template-body": "file://template.yaml",
+                "tags": ["fileb://tags.json", "Key=a,Value=b"],
+                "stack-name": "file"
+            },
+            "region": "us-west-2"
+        }};
+        let mut paths = local_paths(&cmd);
+        paths.sort();
+        assert_eq!(paths, vec!["tags.json", "template.yaml"]);
+
+        let cmd = use_aws! {{
+            "service_name": "s3",
+            "operation_name": "cp",
+            "positional_args": ["s3://bucket/report.csv", "reports/report.csv"],
+            "region": "us-west-2"
+        }};
+        assert_eq!(local_paths(&cmd), vec!["reports/report.csv"]);
+    }
+
+    #[test]
+    fn test_check_path() {
+        let root = tempfile::tempdir().unwrap();
+        let outside = tempfile::tempdir().unwrap();
+        std::fs::write(root.path().join("input.json"), "{}").unwrap();
+        std::fs::write(outside.path().join("secret.json"), "{}").unwrap();
+        let roots = Roots::from_uris([format!("file://{}", root.path().display()).as_str()]);
+
+        let inside = root.path().join("input.json");
+        assert!(roots.check_path(inside.to_str().unwrap()).is_ok());
+        let not_yet_created = root.path().join("out").join("result.json");
+        assert!(roots.check_path(not_yet_created.to_str().unwrap()).is_ok());
+
+        let secret = outside.path().join("secret.json");
+        assert!(matches!(
+            roots.check_path(secret.to_str().unwrap()),
+            Err(RootsError::OutsideRoots(_))
+        ));
+        let traversal = format!("{}/../{}", root.path().display(), secret.display());
+        assert!(roots.check_path(&traversal).is_err());
+        let missing_traversal = root.path().join("missing").join("..").join("..").join("x");
+        assert!(roots.check_path(missing_traversal.to_str().unwrap()).is_err());
+
+        assert_eq!(Roots::default().check_path("input.json"), Err(RootsError::NoRoots));
+    }
+
+    #[test]
+    fn test_expandable_paths() {
+        let root = tempfile::tempdir().unwrap();
+        // Directories literally named like the expansions must not make the paths look safe
+        std::fs::create_dir_all(root.path().join("~").join(".aws")).unwrap();
+        std::fs::create_dir_all(root.path().join("$HOME")).unwrap();
+        let roots = Roots::from_dirs([root.path().to_path_buf()]);
+
+        let cmd = use_aws! {{
+            "service_name": "secretsmanager",
+            "operation_name": "create-secret",
+            "parameters": {"secret-binary": "fileb://~/.aws/credentials"},
+            "region": "us-west-2"
+        }};
+        assert_eq!(
+            roots.check(&cmd),
+            Err(RootsError::Expandable("~/.aws/credentials".to_string()))
+        );
+        for path in ["$HOME/.ssh/id_rsa", "${HOME}/.ssh/id_rsa", "data/$USER.json"] {
+            assert_eq!(roots.check_path(path), Err(RootsError::Expandable(path.to_string())));
+        }
+
+        let price = root.path().join("price-$5.json");
+        assert!(roots.check_path(price.to_str().unwrap()).is_err());
+        let plain = root.path().join("cost$.json");
+        assert!(roots.check_path(plain.to_str().unwrap()).is_ok());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_symlink_escape() {
+        let root = tempfile::tempdir().unwrap();
+        let outside = tempfile::tempdir().unwrap();
+        std::fs::write(outside.path().join("secret.json"), "{}").unwrap();
+        std::os::unix::fs::symlink(outside.path(), root.path().join("link")).unwrap();
+        let roots = Roots::from_dirs([root.path().to_path_buf()]);
+
+        let escaped = root.path().join("link").join("secret.json");
+        assert!(matches!(
+            roots.check_path(escaped.to_str().unwrap()),
+            Err(RootsError::OutsideRoots(_))
+        ));
+        let escaped_new_file = root.path().join("link").join("new.json");
+        assert!(matches!(
+            roots.check_path(escaped_new_file.to_str().unwrap()),
+            Err(RootsError::OutsideRoots(_))
+        ));
+    }
+}
@@ -0,0 +1,578 @@
+use std::collections::{BTreeMap, HashMap};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, OnceLock};
+
+use indexmap::IndexMap;
+use serde::Deserialize;
+
+use crate::customizations;
+
+/// Environment variable pointing at a botocore `data` directory
+pub const BOTOCORE_DATA_ENV_VAR: &str = "USE_AWS_BOTOCORE_DATA";
+
+/// Default locations of the data directory bundled with AWS CLI v2 installers
+const KNOWN_DATA_DIRS: [&str; 3] = [
+    "/usr/local/aws-cli/v2/current/dist/awscli/botocore/data",
+    "/usr/local/aws-cli/aws/dist/awscli/botocore/data",
+    "/opt/homebrew/opt/awscli/libexec/lib/awscli/botocore/data",
+];
+
+/// CLI service names that differ from the botocore model directory name
+const CLI_SERVICE_ALIASES: [(&str, &str); 3] = [("s3api", "s3"), ("configservice", "config"), ("deploy", "codedeploy")];
+
+/// Regions used when no endpoint data is available
+const FALLBACK_REGIONS: [&str; 17] = [
+    "af-south-1",
+    "ap-east-1",
+    "ap-northeast-1",
+    "ap-northeast-2",
+    "ap-south-1",
+    "ap-southeast-1",
+    "ap-southeast-2",
+    "ca-central-1",
+    "eu-central-1",
+    "eu-north-1",
+    "eu-west-1",
+    "eu-west-2",
+    "sa-east-1",
+    "us-east-1",
+    "us-east-2",
+    "us-west-1",
+    "us-west-2",
+];
+
+/// A botocore service model (`service-2.json`)
+#[derive(Debug, Clone, Deserialize)]
+pub struct ServiceModel {
+    pub metadata: Metadata,
+    pub operations: BTreeMap<String, Operation>,
+    pub shapes: BTreeMap<String, Shape>,
+    /// Paginators by operation name, from the `paginators-1.json` next to the model
+    #[serde(skip)]
+    pub paginators: BTreeMap<String, Paginator>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Metadata {
+    pub api_version: String,
+    pub endpoint_prefix: String,
+    pub protocol: String,
+    pub signing_name: Option<String>,
+    pub target_prefix: Option<String>,
+    pub json_version: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Operation {
+    pub name: String,
+    pub http: Option<Http>,
+    pub input: Option<ShapeRef>,
+    pub output: Option<ShapeRef>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Http {
+    pub method: String,
+    pub request_uri: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ShapeRef {
+    pub shape: String,
+    pub location: Option<String>,
+    pub location_name: Option<String>,
+    /// Name of the member in `ec2` protocol requests
+    pub query_name: Option<String>,
+    /// Element wrapping the output of `query` protocol responses
+    pub result_wrapper: Option<String>,
+    #[serde(default)]
+    pub flattened: bool,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Shape {
+    #[serde(rename = "type")]
+    pub shape_type: String,
+    /// Members in model order, which is also the order the AWS CLI prints them in
+    #[serde(default)]
+    pub members: IndexMap<String, ShapeRef>,
+    pub member: Option<ShapeRef>,
+    pub key: Option<ShapeRef>,
+    pub value: Option<ShapeRef>,
+    #[serde(default)]
+    pub required: Vec<String>,
+    #[serde(default)]
+    pub flattened: bool,
+    /// The member sent or received as the whole HTTP body in REST protocols
+    pub payload: Option<String>,
+}
+
+/// How the results of an operation are split across pages (`paginators-1.json`). Tokens and
+/// keys are JMESPath-like expressions such as `NextMarker || Contents[-1].Key`.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct Paginator {
+    #[serde(deserialize_with = "one_or_many")]
+    pub input_token: Vec<String>,
+    #[serde(deserialize_with = "one_or_many")]
+    pub output_token: Vec<String>,
+    pub limit_key: Option<String>,
+    /// Keys of the lists aggregated across pages, the first being the main one
+    #[serde(default, deserialize_with = "one_or_many")]
+    pub result_key: Vec<String>,
+    /// Boolean output member telling whether more pages follow, e.g. `IsTruncated`
+    pub more_results: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct Paginators {
+    pagination: BTreeMap<String, Paginator>,
+}
+
+fn one_or_many<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<Vec<String>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany {
+        One(String),
+        Many(Vec<String>),
+    }
+    Ok(match OneOrMany::deserialize(deserializer)? {
+        OneOrMany::One(value) => vec![value],
+        OneOrMany::Many(values) => values,
+    })
+}
+
+impl ServiceModel {
+    /// Looks up an operation by its CLI name, e.g. `describe-instances`
+    pub fn operation(&self, cli_name: &str) -> Option<&Operation> {
+        self.operations.values().find(|op| xform_name(&op.name, '-') == cli_name)
+    }
+
+    /// Returns the CLI names of all operations in sorted order
+    pub fn operation_names(&self) -> Vec<String> {
+        let mut names: Vec<String> = self.operations.keys().map(|name| xform_name(name, '-')).collect();
+        names.sort();
+        names
+    }
+
+    pub fn shape(&self, name: &str) -> Option<&Shape> {
+        self.shapes.get(name)
+    }
+
+    /// Looks up the paginator of an operation by its CLI name
+    pub fn paginator(&self, cli_name: &str) -> Option<&Paginator> {
+        self.paginators.get(&self.operation(cli_name)?.name)
+    }
+
+    /// Looks up an input member of an operation by its CLI parameter name, e.g. `instance-ids`
+    pub fn input_member(&self, operation: &Operation, cli_name: &str) -> Option<(&str, &ShapeRef)> {
+        let input = self.shape(&operation.input.as_ref()?.shape)?;
+        input
+            .members
+            .iter()
+            .find(|(name, _)| xform_name(name, '-') == cli_name)
+            .map(|(name, member)| (name.as_str(), member))
+    }
+}
+
+/// Lazily loads service models from a botocore data directory
+#[derive(Debug, Default)]
+pub struct ServiceCatalog {
+    data_dir: Option<PathBuf>,
+    models: Mutex<HashMap<String, Option<Arc<ServiceModel>>>>,
+}
+
+impl ServiceCatalog {
+    /// Returns the process-wide catalog, discovering the data directory on first use
+    pub fn shared() -> Arc<Self> {
+        static SHARED: OnceLock<Arc<ServiceCatalog>> = OnceLock::new();
+        SHARED.get_or_init(|| Arc::new(Self::discover())).clone()
+    }
+
+    /// Locates the botocore data directory used by the installed AWS CLI. Falls back to an
+    /// empty catalog when none can be found.
+    pub fn discover() -> Self {
+        let data_dir = std::env::var_os(BOTOCORE_DATA_ENV_VAR)
+            .map(PathBuf::from)
+            .or_else(|| KNOWN_DATA_DIRS.iter().map(PathBuf::from).find(|dir| dir.is_dir()))
+            .or_else(data_dir_near_aws_binary);
+        match data_dir {
+            Some(dir) => {
+                tracing::info!("Using AWS service models from {}", dir.display());
+                Self::from_data_dir(dir)
+            },
+            None => {
+                tracing::warn!("No AWS service models found, set {} to enable them", BOTOCORE_DATA_ENV_VAR);
+                Self::default()
+            },
+        }
+    }
+
+    pub fn from_data_dir(data_dir: impl Into<PathBuf>) -> Self {
+        Self {
+            data_dir: Some(data_dir.into()),
+            models: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Returns the known CLI service names in sorted order
+    pub fn service_names(&self) -> Vec<String> {
+        let mut names: Vec<String> = customizations::services().map(|service| service.to_string()).collect();
+        if let Some(entries) = self.data_dir.as_ref().and_then(|dir| std::fs::read_dir(dir).ok()) {
+            for entry in entries.flatten() {
+                if !entry.path().is_dir() {
+                    continue;
+                }
+                let dir_name = entry.file_name().to_string_lossy().to_string();
+                names.push(cli_service_name(&dir_name).to_string());
+            }
+        }
+        names.sort();
+        names.dedup();
+        names
+    }
+
+    /// Returns the CLI operation names of a service, including CLI customizations
+    pub fn operation_names(&self, service_name: &str) -> Vec<String> {
+        let mut names = self.model(service_name).map(|model| model.operation_names()).unwrap_or_default();
+        names.extend(customizations::commands(service_name).map(|c| c.to_string()));
+        names.sort();
+        names.dedup();
+        names
+    }
+
+    /// Returns the regions known from the botocore endpoint data
+    pub fn regions(&self) -> Vec<String> {
+        let endpoints = self
+            .data_dir
+            .as_ref()
+            .and_then(|dir| read_json_file(&dir.join("endpoints.json")))
+            .and_then(|bytes| serde_json::from_slice::<serde_json::Value>(&bytes).ok());
+        let mut regions: Vec<String> = endpoints
+            .as_ref()
+            .and_then(|endpoints| endpoints.get("partitions")?.as_array())
+            .map(|partitions| {
+                partitions
+                    .iter()
+                    .filter_map(|partition| partition.get("regions")?.as_object())
+                    .flat_map(|regions| regions.keys().cloned())
+                    .collect()
+            })
+            .unwrap_or_default();
+        if regions.is_empty() {
+            regions = FALLBACK_REGIONS.iter().map(|r| r.to_string()).collect();
+        }
+        regions.sort();
+        regions
+    }
+
+    /// Returns the service model for a CLI service name, loading it on first use
+    pub fn model(&self, service_name: &str) -> Option<Arc<ServiceModel>> {
+        let mut models = self.models.lock().unwrap_or_else(|e| e.into_inner());
+        models
+            .entry(service_name.to_string())
+            .or_insert_with(|| self.load_model(service_name).map(Arc::new))
+            .clone()
+    }
+
+    fn load_model(&self, service_name: &str) -> Option<ServiceModel> {
+        let service_dir = self.data_dir.as_ref()?.join(model_dir_name(service_name));
+        let mut versions: Vec<PathBuf> = std::fs::read_dir(service_dir)
+            .ok()?
+            .flatten()
+            .map(|entry| entry.path())
+            .filter(|path| path.is_dir())
+            .collect();
+        versions.sort();
+        let (version, bytes) = versions
+            .iter()
+            .rev()
+            .find_map(|version| Some((version, read_json_file(&version.join("service-2.json"))?)))?;
+        let mut model: ServiceModel = match serde_json::from_slice(&bytes) {
+            Ok(model) => model,
+            Err(e) => {
+                tracing::warn!("Failed to parse service model for {}: {}", service_name, e);
+                return None;
+            },
+        };
+        if let Some(bytes) = read_json_file(&version.join("paginators-1.json")) {
+            match serde_json::from_slice::<Paginators>(&bytes) {
+                Ok(paginators) => model.paginators = paginators.pagination,
+                Err(e) => tracing::warn!("Failed to parse paginators for {}: {}", service_name, e),
+            }
+        }
+        Some(model)
+    }
+}
+
+/// Converts a model name such as `DescribeDBInstances` into the name the CLI exposes
+/// (`describe-db-instances`), following botocore's `xform_name`.
+pub fn xform_name(name: &str, sep: char) -> String {
+    if name.contains(sep) {
+        return name.to_string();
+    }
+    let chars: Vec<char> = name.chars().collect();
+    // Names ending in a plural acronym, e.g. `ListAMIs`, keep the acronym together
+    let upper_run = chars.iter().rev().skip(1).take_while(|c| c.is_ascii_uppercase()).count();
+    let split_at = if chars.last() == Some(&'s') && upper_run >= 2 {
+        chars.len() - upper_run - 1
+    } else {
+        chars.len()
+    };
+
+    let mut out = String::with_capacity(name.len() + 4);
+    for (i, c) in chars[..split_at].iter().enumerate() {
+        if i > 0 && c.is_ascii_uppercase() {
+            let prev = chars[i - 1];
+            let next_is_lower = chars.get(i + 1).is_some_and(|n| n.is_ascii_lowercase()) && i + 1 < split_at;
+            if prev.is_ascii_lowercase() || prev.is_ascii_digit() || next_is_lower {
+                out.push(sep);
+            }
+        }
+        out.push(c.to_ascii_lowercase());
+    }
+    if split_at < chars.len() {
+        if split_at > 0 {
+            out.push(sep);
+        }
+        out.extend(chars[split_at..].iter().map(|c| c.to_ascii_lowercase()));
+    }
+    out
+}
+
+fn cli_service_name(model_dir_name: &str) -> &str {
+    CLI_SERVICE_ALIASES
+        .iter()
+        .find(|(_, dir)| *dir == model_dir_name)
+        .map(|(cli, _)| *cli)
+        .unwrap_or(model_dir_name)
+}
+
+fn model_dir_name(service_name: &str) -> &str {
+    CLI_SERVICE_ALIASES
+        .iter()
+        .find(|(cli, _)| *cli == service_name)
+        .map(|(_, dir)| *dir)
+        .unwrap_or(service_name)
+}
+
+/// Reads a JSON data file, transparently handling the gzipped variants newer botocore ships
+fn read_json_file(path: &Path) -> Option<Vec<u8>> {
+    if let Ok(bytes) = std::fs::read(path) {
+        return Some(bytes);
+    }
+    let mut gz_path = path.as_os_str().to_owned();
+    gz_path.push(".gz");
+    let file = std::fs::File::open(gz_path).ok()?;
+    let mut bytes = Vec::new();
+    flate2::read::GzDecoder::new(file).read_to_end(&mut bytes).ok()?;
+    Some(bytes)
+}
+
+/// Finds the data directory relative to the `aws` executable on `PATH`, which covers
+/// both the bundled v2 installer layout and pip installs inside a virtualenv.
+fn data_dir_near_aws_binary() -> Option<PathBuf> {
+    let path = std::env::var_os("PATH")?;
+    let binary = std::env::split_paths(&path)
+        .map(|dir| dir.join("aws"))
+        .find(|candidate| candidate.is_file())?;
+    let binary = binary.canonicalize().unwrap_or(binary);
+    for dir in binary.ancestors().skip(1).take(4) {
+        let bundled = dir.join("dist").join("awscli").join("botocore").join("data");
+        if bundled.is_dir() {
+            return Some(bundled);
+        }
+        let Ok(entries) = std::fs::read_dir(dir.join("lib")) else {
+            continue;
+        };
+        for entry in entries.flatten() {
+            let site_packages = entry.path().join("site-packages");
+            for candidate in [
+                site_packages.join("awscli").join("botocore").join("data"),
+                site_packages.join("botocore").join("data"),
+            ] {
+                if candidate.is_dir() {
+                    return Some(candidate);
+                }
+            }
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    pub(crate) const EC2_MODEL: &str = r#"{
+        "metadata": {
+            "apiVersion": "2016-11-15",
+            "endpointPrefix": "ec2",
+            "protocol": "ec2"
+        },
+        "operations": {
+            "DescribeInstances": {
+                "name": "DescribeInstances",
+                "http": {"method": "POST", "requestUri": "/"},
+                "input": {"shape": "DescribeInstancesRequest"},
+                "output": {"shape": "DescribeInstancesResult"}
+            },
+            "StopInstances": {
+                "name": "StopInstances",
+                "http": {"method": "POST", "requestUri": "/"},
+                "input": {"shape": "StopInstancesRequest"}
+            }
+        },
+        "shapes": {
+            "DescribeInstancesRequest": {
+                "type": "structure",
+                "members": {
+                    "InstanceIds": {"shape": "InstanceIdStringList", "locationName": "InstanceId"},
+                    "DryRun": {"shape": "Boolean", "locationName": "dryRun"},
+                    "Filters": {"shape": "FilterList", "locationName": "Filter"},
+                    "MaxResults": {"shape": "Integer", "locationName": "maxResults"},
+                    "NextToken": {"shape": "String", "locationName": "nextToken"}
+                }
+            },
+            "DescribeInstancesResult": {
+                "type": "structure",
+                "members": {
+                    "Reservations": {"shape": "ReservationList", "locationName": "reservationSet"},
+                    "NextToken": {"shape": "String", "locationName": "nextToken"}
+                }
+            },
+            "ReservationList": {"type": "list", "member": {"shape": "Reservation", "locationName": "item"}},
+            "Reservation": {
+                "type": "structure",
+                "members": {
+                    "ReservationId": {"shape": "String", "locationName": "reservationId"},
+                    "Instances": {"shape": "InstanceList", "locationName": "instancesSet"}
+                }
+            },
+            "InstanceList": {"type": "list", "member": {"shape": "Instance", "locationName": "item"}},
+            "Instance": {
+                "type": "structure",
+                "members": {
+                    "InstanceId": {"shape": "String", "locationName": "instanceId"},
+                    "LaunchTime": {"shape": "DateTime", "locationName": "launchTime"}
+                }
+            },
+            "DateTime": {"type": "timestamp"},
+            "StopInstancesRequest": {
+                "type": "structure",
+                "required": ["InstanceIds"],
+                "members": {
+                    "InstanceIds": {"shape": "InstanceIdStringList"},
+                    "Force": {"shape": "Boolean"}
+                }
+            },
+            "InstanceIdStringList": {"type": "list", "member": {"shape": "String", "locationName": "InstanceId"}},
+            "FilterList": {"type": "list", "member": {"shape": "Filter", "locationName": "Filter"}},
+            "Filter": {
+                "type": "structure",
+                "members": {
+                    "Name": {"shape": "String"},
+                    "Values": {"shape": "ValueStringList", "locationName": "Value"}
+                }
+            },
+            "ValueStringList": {"type": "list", "member": {"shape": "String", "locationName": "item"}},
+            "TagMap": {"type": "map", "key": {"shape": "String"}, "value": {"shape": "String"}},
+            "Boolean": {"type": "boolean"},
+            "Integer": {"type": "integer"},
+            "String": {"type": "string"}
+        }
+    }"#;
+
+    /// Writes a botocore-style data directory containing the test EC2 model
+    pub(crate) fn write_data_dir(root: &Path) {
+        let ec2 = root.join("ec2").join("2016-11-15");
+        std::fs::create_dir_all(&ec2).unwrap();
+        std::fs::write(ec2.join("service-2.json"), EC2_MODEL).unwrap();
+        std::fs::write(
+            ec2.join("paginators-1.json"),
+            r#"{"pagination": {"DescribeInstances": {
+                "input_token": "NextToken",
+                "output_token": "NextToken",
+                "limit_key": "MaxResults",
+                "result_key": "Reservations"
+            }}}"#,
+        )
+        .unwrap();
+        let s3 = root.join("s3").join("2006-03-01");
+        std::fs::create_dir_all(&s3).unwrap();
+        std::fs::write(
+            s3.join("service-2.json"),
+            r#"{
+                "metadata": {"apiVersion": "2006-03-01", "endpointPrefix": "s3", "protocol": "rest-xml"},
+                "operations": {"ListBuckets": {"name": "ListBuckets"}},
+                "shapes": {}
+            }"#,
+        )
+        .unwrap();
+        std::fs::write(
+            root.join("endpoints.json"),
+            r#"{"partitions": [{"regions": {"us-east-1": {}, "eu-west-1": {}}}]}"#,
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn test_xform_name() {
+        assert_eq!(xform_name("DescribeInstances", '-'), "describe-instances");
+        assert_eq!(xform_name("DescribeDBInstances", '-'), "describe-db-instances");
+        assert_eq!(xform_name("ListObjectsV2", '-'), "list-objects-v2");
+        assert_eq!(xform_name("GetCallerIdentity", '-'), "get-caller-identity");
+        assert_eq!(xform_name("ListAMIs", '-'), "list-amis");
+        assert_eq!(xform_name("DryRun", '-'), "dry-run");
+        assert_eq!(xform_name("already-kebab", '-'), "already-kebab");
+    }
+
+    #[test]
+    fn test_catalog() {
+        let dir = tempfile::tempdir().unwrap();
+        write_data_dir(dir.path());
+        let catalog = ServiceCatalog::from_data_dir(dir.path());
+
+        assert_eq!(catalog.service_names(), vec!["ec2", "logs", "s3", "s3api"]);
+        assert_eq!(catalog.operation_names("ec2"), vec!["describe-instances", "stop-instances"]);
+        assert_eq!(catalog.operation_names("s3api"), vec!["list-buckets"]);
+        assert!(catalog.operation_names("s3").contains(&"sync".to_string()));
+        assert_eq!(catalog.regions(), vec!["eu-west-1", "us-east-1"]);
+
+        let model = catalog.model("ec2").unwrap();
+        let operation = model.operation("describe-instances").unwrap();
+        let (name, member) = model.input_member(operation, "instance-ids").unwrap();
+        assert_eq!(name, "InstanceIds");
+        assert_eq!(model.shape(&member.shape).unwrap().shape_type, "list");
+
+        let paginator = model.paginator("describe-instances").unwrap();
+        assert_eq!(paginator.input_token, ["NextToken"]);
+        assert_eq!(paginator.result_key, ["Reservations"]);
+        assert!(model.paginator("stop-instances").is_none());
+    }
+
+    #[test]
+    fn test_gzipped_model() {
+        let dir = tempfile::tempdir().unwrap();
+        let version_dir = dir.path().join("ec2").join("2016-11-15");
+        std::fs::create_dir_all(&version_dir).unwrap();
+        let file = std::fs::File::create(version_dir.join("service-2.json.gz")).unwrap();
+        let mut encoder = flate2::write::GzEncoder::new(file, flate2::Compression::default());
+        std::io::Write::write_all(&mut encoder, EC2_MODEL.as_bytes()).unwrap();
+        encoder.finish().unwrap();
+
+        let catalog = ServiceCatalog::from_data_dir(dir.path());
+        assert!(catalog.model("ec2").is_some());
+        assert!(catalog.model("lambda").is_none());
+    }
+
+    #[test]
+    fn test_empty_catalog() {
+        let catalog = ServiceCatalog::default();
+        assert_eq!(catalog.service_names(), vec!["logs", "s3"]);
+        assert!(catalog.regions().contains(&"us-west-2".to_string()));
+    }
+}
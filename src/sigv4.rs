@@ -0,0 +1,190 @@
+//! AWS Signature Version 4 request signing
+
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256};
+
+use crate::protocol::{HttpRequest, percent_encode};
+
+const ALGORITHM: &str = "AWS4-HMAC-SHA256";
+
+/// Static credentials used to sign requests
+#[derive(Clone, PartialEq, Eq)]
+pub struct Credentials {
+    pub access_key_id: String,
+    pub secret_access_key: String,
+    pub session_token: Option<String>,
+}
+
+impl std::fmt::Debug for Credentials {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Credentials")
+            .field("access_key_id", &self.access_key_id)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Where and as what a request is signed
+#[derive(Debug, Clone, Copy)]
+pub struct SigningScope<'a> {
+    pub region: &'a str,
+    pub service: &'a str,
+    /// S3 signs the path as sent, every other service signs it URI-encoded a second time
+    pub double_encode_path: bool,
+}
+
+/// Signs `request` for `host`, adding the `x-amz-date`, `x-amz-security-token` and
+/// `authorization` headers
+pub fn sign(request: &mut HttpRequest, host: &str, credentials: &Credentials, scope: SigningScope<'_>, time: DateTime<Utc>) {
+    let amz_date = time.format("%Y%m%dT%H%M%SZ").to_string();
+    let date = time.format("%Y%m%d").to_string();
+    request.set_header("x-amz-date", &amz_date);
+    if let Some(token) = &credentials.session_token {
+        request.set_header("x-amz-security-token", token);
+    }
+
+    let mut headers: Vec<(String, String)> = request
+        .headers
+        .iter()
+        .map(|(name, value)| (name.to_ascii_lowercase(), value.split_whitespace().collect::<Vec<_>>().join(" ")))
+        .chain(std::iter::once(("host".to_string(), host.to_string())))
+        .collect();
+    headers.sort();
+    let canonical_headers: String = headers.iter().map(|(name, value)| format!("{}:{}\n", name, value)).collect();
+    let signed_headers = headers.iter().map(|(name, _)| name.as_str()).collect::<Vec<_>>().join(";");
+
+    let canonical_request = [
+        request.method.as_str(),
+        &canonical_uri(&request.path, scope.double_encode_path),
+        &canonical_query(&request.query),
+        &canonical_headers,
+        &signed_headers,
+        &hex::encode(Sha256::digest(&request.body)),
+    ]
+    .join("\n");
+
+    let credential_scope = format!("{}/{}/{}/aws4_request", date, scope.region, scope.service);
+    let string_to_sign = format!(
+        "{}\n{}\n{}\n{}",
+        ALGORITHM,
+        amz_date,
+        credential_scope,
+        hex::encode(Sha256::digest(canonical_request.as_bytes()))
+    );
+
+    let key = signing_key(&credentials.secret_access_key, &date, scope.region, scope.service);
+    let signature = hex::encode(hmac(&key, string_to_sign.as_bytes()));
+    request.set_header(
+        "authorization",
+        &format!(
+            "{} Credential={}/{}, SignedHeaders={}, Signature={}",
+            ALGORITHM, credentials.access_key_id, credential_scope, signed_headers, signature
+        ),
+    );
+}
+
+/// Derives the key for a day, region and service from the secret access key
+fn signing_key(secret_access_key: &str, date: &str, region: &str, service: &str) -> Vec<u8> {
+    let key = hmac(format!("AWS4{}", secret_access_key).as_bytes(), date.as_bytes());
+    let key = hmac(&key, region.as_bytes());
+    let key = hmac(&key, service.as_bytes());
+    hmac(&key, b"aws4_request")
+}
+
+fn hmac(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts keys of any length");
+    mac.update(data);
+    mac.finalize().into_bytes().to_vec()
+}
+
+fn canonical_uri(path: &str, double_encode: bool) -> String {
+    let path = if path.is_empty() { "/" } else { path };
+    if double_encode {
+        path.split('/').map(percent_encode).collect::<Vec<_>>().join("/")
+    } else {
+        path.to_string()
+    }
+}
+
+fn canonical_query(query: &[(String, String)]) -> String {
+    let mut pairs: Vec<(String, String)> = query
+        .iter()
+        .map(|(name, value)| (percent_encode(name), percent_encode(value)))
+        .collect();
+    pairs.sort();
+    pairs
+        .iter()
+        .map(|(name, value)| format!("{}={}", name, value))
+        .collect::<Vec<_>>()
+        .join("&")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Examples from the AWS Signature Version 4 documentation and test suite
+    fn example_credentials() -> Credentials {
+        Credentials {
+            access_key_id: "AKIDEXAMPLE".to_string(),
+            secret_access_key: "wJalrXUtnFEMI/K7MDENG+bPxRfiCYEXAMPLEKEY".to_string(),
+            session_token: None,
+        }
+    }
+
+    fn example_time() -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339("2015-08-30T12:36:00Z").unwrap().to_utc()
+    }
+
+    #[test]
+    fn test_signing_key() {
+        let key = signing_key("wJalrXUtnFEMI/K7MDENG+bPxRfiCYEXAMPLEKEY", "20150830", "us-east-1", "iam");
+        assert_eq!(hex::encode(key), "c4afb1cc5771d871763a393e44b703571b55cc28424d1a5e86da6ed3c154a4b9");
+    }
+
+    #[test]
+    fn test_sign_get_vanilla() {
+        let mut request = HttpRequest::new("GET", "/");
+        let scope = SigningScope {
+            region: "us-east-1",
+            service: "service",
+            double_encode_path: true,
+        };
+        sign(&mut request, "example.amazonaws.com", &example_credentials(), scope, example_time());
+        assert_eq!(
+            request.header("authorization").unwrap(),
+            "AWS4-HMAC-SHA256 Credential=AKIDEXAMPLE/20150830/us-east-1/service/aws4_request, \
+             SignedHeaders=host;x-amz-date, \
+             Signature=5fa00fa31553b73ebf1942676e86291e8372ff2a2260956d9b8aae1d763fbf31"
+        );
+    }
+
+    #[test]
+    fn test_sign_query() {
+        let mut request = HttpRequest::new("GET", "/");
+        request.query = vec![
+            ("Action".to_string(), "ListUsers".to_string()),
+            ("Version".to_string(), "2010-05-08".to_string()),
+        ];
+        request.set_header("Content-Type", "application/x-www-form-urlencoded; charset=utf-8");
+        let scope = SigningScope {
+            region: "us-east-1",
+            service: "iam",
+            double_encode_path: true,
+        };
+        sign(&mut request, "iam.amazonaws.com", &example_credentials(), scope, example_time());
+        assert!(request.header("authorization").unwrap().ends_with(
+            "SignedHeaders=content-type;host;x-amz-date, \
+             Signature=5d672d79c15b13162d9279b0855cfba6789a8edb4c82c400e06b5924a6f2b5d7"
+        ));
+    }
+
+    #[test]
+    fn test_credentials_debug_hides_secrets() {
+        let mut credentials = example_credentials();
+        credentials.session_token = Some("token".to_string());
+        let debug = format!("{:?}", credentials);
+        assert!(debug.contains("AKIDEXAMPLE"));
+        assert!(!debug.contains("wJalrXUtnFEMI") && !debug.contains("token"));
+    }
+}
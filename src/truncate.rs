@@ -0,0 +1,274 @@
+//! Truncation of command output to the response budget. JSON output stays valid JSON: the
+//! longest arrays keep their first elements and the longest strings their beginning, and what
+//! was dropped is reported next to the output. Other output is cut at a line or character
+//! boundary, never inside a multibyte character.
+
+use serde_json::Value;
+
+use crate::MAX_TOOL_RESPONSE_SIZE;
+use crate::native::cli_json;
+
+/// The size stdout and stderr must each fit in, so the whole response stays within
+/// [MAX_TOOL_RESPONSE_SIZE] after JSON escaping
+pub const OUTPUT_BUDGET: usize = MAX_TOOL_RESPONSE_SIZE / 3;
+
+/// Appended to text output that was cut
+const TEXT_MARKER: &str = " ... truncated";
+
+/// Appended to JSON strings that were shortened
+const STRING_MARKER: &str = "... (truncated)";
+
+/// Strings are never shortened below this many bytes
+const MIN_STRING_LENGTH: usize = 100;
+
+/// Output cut to fit a budget
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Truncated {
+    pub text: String,
+    /// What was dropped, if anything was
+    pub note: Option<String>,
+}
+
+/// Truncates `output` to at most `budget` bytes, keeping JSON valid where possible
+pub fn truncate(output: &str, budget: usize) -> Truncated {
+    if output.len() <= budget {
+        return Truncated {
+            text: output.to_string(),
+            note: None,
+        };
+    }
+    match serde_json::from_str::<Value>(output) {
+        Ok(mut value @ (Value::Array(_) | Value::Object(_))) => {
+            let mut notes = shorten_arrays(&mut value, budget);
+            let mut text = cli_json(&value);
+            if text.len() > budget {
+                notes.extend(shorten_strings(&mut value, budget));
+                text = cli_json(&value);
+            }
+            if text.len() > budget {
+                let cut = truncate_text(&text, budget);
+                notes.push(format!(
+                    "Output was cut after {} of {} bytes and is no longer valid JSON",
+                    cut.len() - TEXT_MARKER.len(),
+                    text.len()
+                ));
+                text = cut;
+            }
+            Truncated {
+                text,
+                note: Some(notes.join("; ")),
+            }
+        },
+        _ => {
+            let text = truncate_text(output, budget);
+            let note = format!("Output was cut after {} of {} bytes", text.len() - TEXT_MARKER.len(), output.len());
+            Truncated { text, note: Some(note) }
+        },
+    }
+}
+
+/// Cuts text at the last line break, or else character boundary, that keeps it within `budget`
+fn truncate_text(text: &str, budget: usize) -> String {
+    let mut cut = floor_char_boundary(text, budget.saturating_sub(TEXT_MARKER.len()));
+    if let Some(line_end) = text[..cut].rfind('\n').filter(|line_end| *line_end > cut / 2) {
+        cut = line_end + 1;
+    }
+    format!("{}{}", &text[..cut], TEXT_MARKER)
+}
+
+fn floor_char_boundary(text: &str, index: usize) -> usize {
+    if index >= text.len() {
+        return text.len();
+    }
+    (0..=index).rev().find(|i| text.is_char_boundary(*i)).unwrap_or(0)
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Segment {
+    Key(String),
+    Index(usize),
+}
+
+/// Renders a path the way a `query` would select it, e.g. `Reservations[0].Instances`
+fn display(path: &[Segment]) -> String {
+    let mut rendered = String::new();
+    for segment in path {
+        match segment {
+            Segment::Key(key) if rendered.is_empty() => rendered.push_str(key),
+            Segment::Key(key) => rendered.push_str(&format!(".{}", key)),
+            Segment::Index(index) => rendered.push_str(&format!("[{}]", index)),
+        }
+    }
+    if rendered.is_empty() { "@".to_string() } else { rendered }
+}
+
+fn get_mut<'a>(value: &'a mut Value, path: &[Segment]) -> Option<&'a mut Value> {
+    path.iter().try_fold(value, |current, segment| match segment {
+        Segment::Key(key) => current.get_mut(key.as_str()),
+        Segment::Index(index) => current.get_mut(*index),
+    })
+}
+
+/// Arrays and strings that could be shortened, with their approximate serialized size
+#[derive(Default)]
+struct Candidates {
+    arrays: Vec<(Vec<Segment>, usize)>,
+    strings: Vec<(Vec<Segment>, usize)>,
+}
+
+/// Returns the approximate compact serialized size of `value`, collecting arrays of more
+/// than one element and strings that can still be shortened along the way
+fn collect(value: &Value, path: &mut Vec<Segment>, candidates: &mut Candidates) -> usize {
+    match value {
+        Value::Null | Value::Bool(_) => 5,
+        Value::Number(number) => number.to_string().len(),
+        Value::String(s) => {
+            if s.len() > MIN_STRING_LENGTH + STRING_MARKER.len() {
+                candidates.strings.push((path.clone(), s.len()));
+            }
+            s.len() + 2
+        },
+        Value::Array(items) => {
+            let mut size = 2;
+            for (index, item) in items.iter().enumerate() {
+                path.push(Segment::Index(index));
+                size += collect(item, path, candidates) + 1;
+                path.pop();
+            }
+            if items.len() > 1 {
+                candidates.arrays.push((path.clone(), size));
+            }
+            size
+        },
+        Value::Object(entries) => {
+            let mut size = 2;
+            for (key, item) in entries {
+                path.push(Segment::Key(key.clone()));
+                size += key.len() + 4 + collect(item, path, candidates);
+                path.pop();
+            }
+            size
+        },
+    }
+}
+
+fn largest(candidates: Vec<(Vec<Segment>, usize)>) -> Option<Vec<Segment>> {
+    candidates.into_iter().max_by_key(|(_, size)| *size).map(|(path, _)| path)
+}
+
+/// Shortens the largest arrays, one at a time, to the most elements that fit the budget
+fn shorten_arrays(value: &mut Value, budget: usize) -> Vec<String> {
+    let mut notes = Vec::new();
+    while cli_json(value).len() > budget {
+        let mut candidates = Candidates::default();
+        collect(value, &mut Vec::new(), &mut candidates);
+        let Some(path) = largest(candidates.arrays) else {
+            break;
+        };
+        let Some(Value::Array(array)) = get_mut(value, &path) else {
+            break;
+        };
+        let items = std::mem::take(array);
+        let total = items.len();
+        let fits = |value: &mut Value, count: usize| {
+            if let Some(array) = get_mut(value, &path) {
+                *array = Value::Array(items[..count].to_vec());
+            }
+            cli_json(value).len() <= budget
+        };
+        // Keep at least one element, the next round can shorten nested arrays instead
+        let (mut kept, mut too_many) = (1, total);
+        while too_many - kept > 1 {
+            let count = (kept + too_many) / 2;
+            if fits(value, count) {
+                kept = count;
+            } else {
+                too_many = count;
+            }
+        }
+        fits(value, kept);
+        notes.push(format!("Kept the first {} of {} elements of `{}`", kept, total, display(&path)));
+    }
+    notes
+}
+
+/// Shortens the longest strings, one at a time, by as much as the output exceeds the budget
+fn shorten_strings(value: &mut Value, budget: usize) -> Vec<String> {
+    let mut notes = Vec::new();
+    loop {
+        let excess = cli_json(value).len().saturating_sub(budget);
+        if excess == 0 {
+            break;
+        }
+        let mut candidates = Candidates::default();
+        collect(value, &mut Vec::new(), &mut candidates);
+        let Some(path) = largest(candidates.strings) else {
+            break;
+        };
+        let Some(Value::String(s)) = get_mut(value, &path) else {
+            break;
+        };
+        let total = s.len();
+        // Escaping can make a string longer in the output than in memory, so this may take
+        // another round
+        let kept = floor_char_boundary(s, total.saturating_sub(excess + STRING_MARKER.len()).max(MIN_STRING_LENGTH));
+        s.truncate(kept);
+        s.push_str(STRING_MARKER);
+        notes.push(format!("Kept the first {} of {} bytes of `{}`", kept, total, display(&path)));
+    }
+    notes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_small_output_unchanged() {
+        let output = r#"{"Buckets": []}"#;
+        assert_eq!(truncate(output, 100), Truncated {
+            text: output.to_string(),
+            note: None,
+        });
+    }
+
+    #[test]
+    fn test_truncate_arrays() {
+        let instances: Vec<Value> = (0..200)
+            .map(|i| serde_json::json!({"InstanceId": format!("i-{:04}", i), "Tags": [{"Key": "Name", "Value": "웹 서버"}]}))
+            .collect();
+        let output = serde_json::json!({"Reservations": [{"Instances": instances}], "NextToken": null}).to_string();
+
+        let truncated = truncate(&output, 4000);
+        assert!(truncated.text.len() <= 4000);
+        let value: Value = serde_json::from_str(&truncated.text).unwrap();
+        let kept = value["Reservations"][0]["Instances"].as_array().unwrap();
+        assert_eq!(kept[0]["InstanceId"], "i-0000");
+        assert_eq!(
+            truncated.note.unwrap(),
+            format!("Kept the first {} of 200 elements of `Reservations[0].Instances`", kept.len())
+        );
+    }
+
+    #[test]
+    fn test_truncate_strings() {
+        let output = serde_json::json!({"Events": [{"Message": "한국어 로그 ".repeat(2000)}]}).to_string();
+        let truncated = truncate(&output, 2000);
+        assert!(truncated.text.len() <= 2000);
+        let value: Value = serde_json::from_str(&truncated.text).unwrap();
+        assert!(value["Events"][0]["Message"].as_str().unwrap().ends_with(STRING_MARKER));
+        assert!(truncated.note.unwrap().contains("of `Events[0].Message`"));
+    }
+
+    #[test]
+    fn test_truncate_text() {
+        // A cut at byte 10 would land inside the three-byte characters
+        let output = "名前: テスト\n".repeat(10);
+        let truncated = truncate(&output, 10 + TEXT_MARKER.len());
+        assert_eq!(truncated.text, format!("名前: {}", TEXT_MARKER));
+
+        let truncated = truncate(&output, 60);
+        assert_eq!(truncated.text, format!("{}{}", "名前: テスト\n".repeat(2), TEXT_MARKER));
+        assert_eq!(truncated.note.unwrap(), format!("Output was cut after 36 of {} bytes", output.len()));
+    }
+}
@@ -1,35 +1,101 @@
-use std::collections::HashMap;
 use std::io::Write;
-use std::process::Stdio;
 
-use bstr::ByteSlice;
 use convert_case::{Case, Casing};
 use crossterm::{
     queue,
     style,
 };
-use eyre::{Result, WrapErr};
+use eyre::Result;
+use indexmap::IndexMap;
 use serde::{Deserialize, Serialize};
 
-use crate::{InvokeOutput, MAX_TOOL_RESPONSE_SIZE, OutputKind};
+use crate::aws_error::AwsError;
+use crate::cache::CacheMode;
+use crate::customizations;
+use crate::executor::{AwsExecutor, CliExecutor, CommandOutput};
+use crate::identity::Identity;
+use crate::jmespath::Expression;
+use crate::native::cli_json;
+use crate::output_format::OutputFormat;
+use crate::pagination;
+use crate::redact::{self, Masked, Redaction};
+use crate::retry::{RetryConfig, Retrying};
+use crate::service_model::{xform_name, Paginator, ServiceModel, Shape};
+use crate::truncate::{self, OUTPUT_BUDGET};
+use crate::{InvokeOutput, McpError, OutputKind};
 
 const READONLY_OPS: [&str; 6] = ["get", "describe", "list", "ls", "search", "batch_get"];
 
-/// The environment variable name where we set additional metadata for the AWS CLI user agent.
-const USER_AGENT_ENV_VAR: &str = "AWS_EXECUTION_ENV";
-const USER_AGENT_APP_NAME: &str = "UseAws-MCP-Server";
-const USER_AGENT_VERSION_KEY: &str = "Version";
-const USER_AGENT_VERSION_VALUE: &str = env!("CARGO_PKG_VERSION");
+/// Operations that delete resources or cut off access to them
+const DESTRUCTIVE_OPS: [&str; 9] = [
+    "delete",
+    "terminate",
+    "remove",
+    "purge",
+    "deregister",
+    "revoke",
+    "detach",
+    "disassociate",
+    "rb",
+];
+
+/// How much harm a command can do
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum RiskTier {
+    ReadOnly,
+    Write,
+    /// Deletes resources or cuts off access to them, e.g. `ec2 terminate-instances`
+    Destructive,
+}
 
 /// The main UseAws struct that handles AWS CLI operations
 #[derive(Debug, Clone, Deserialize)]
 pub struct UseAws {
     pub service_name: String,
     pub operation_name: String,
-    pub parameters: Option<HashMap<String, serde_json::Value>>,
+    pub parameters: Option<IndexMap<String, serde_json::Value>>,
+    #[serde(default)]
+    pub positional_args: Option<Vec<String>>,
     pub region: String,
     pub profile_name: Option<String>,
     pub label: Option<String>,
+    /// Name of a configured endpoint, e.g. `localstack`
+    #[serde(default)]
+    pub endpoint: Option<String>,
+    /// The URL requests are sent to instead of AWS. Only ever set by the server from a
+    /// configured endpoint, never deserialized from a request.
+    #[serde(skip)]
+    pub endpoint_url: Option<String>,
+    /// Items returned per call for commands the server paginates
+    #[serde(default)]
+    pub max_items: Option<usize>,
+    /// Continuation cursor returned by a previous call of the same command
+    #[serde(default)]
+    pub cursor: Option<String>,
+    /// JMESPath expression applied by the server to the JSON output
+    #[serde(default)]
+    pub query: Option<String>,
+    /// How the server presents the output, converted from the CLI's JSON output
+    #[serde(default)]
+    pub output_format: Option<OutputFormat>,
+    /// Whether the result may come from the server's cache of read-only results
+    #[serde(default)]
+    pub cache: Option<CacheMode>,
+    /// Requests a single page as JSON, so the server can paginate the command itself
+    #[serde(skip)]
+    pub single_page: bool,
+    /// How secrets are masked in the output. Set by the server from its configuration.
+    #[serde(skip)]
+    pub redaction: Redaction,
+    /// How throttled and transiently failing commands are retried. Set by the server from its
+    /// configuration.
+    #[serde(skip)]
+    pub retry: RetryConfig,
+    /// The account and principal the command acts as. Only ever set by the server from its
+    /// identity preflight.
+    #[serde(skip)]
+    pub identity: Option<Identity>,
 }
 
 /// Request structure for MCP tool calls
@@ -37,10 +103,24 @@ pub struct UseAws {
 pub struct UseAwsRequest {
     pub service_name: String,
     pub operation_name: String,
-    pub parameters: Option<HashMap<String, serde_json::Value>>,
+    pub parameters: Option<IndexMap<String, serde_json::Value>>,
+    #[serde(default)]
+    pub positional_args: Option<Vec<String>>,
     pub region: String,
     pub profile_name: Option<String>,
     pub label: Option<String>,
+    #[serde(default)]
+    pub endpoint: Option<String>,
+    #[serde(default)]
+    pub max_items: Option<usize>,
+    #[serde(default)]
+    pub cursor: Option<String>,
+    #[serde(default)]
+    pub query: Option<String>,
+    #[serde(default)]
+    pub output_format: Option<OutputFormat>,
+    #[serde(default)]
+    pub cache: Option<CacheMode>,
 }
 
 /// Response structure for MCP tool calls
@@ -49,6 +129,24 @@ pub struct UseAwsResponse {
     pub exit_status: String,
     pub stdout: String,
     pub stderr: String,
+    /// Pass as `cursor` with the same command to fetch the next page
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub next_cursor: Option<String>,
+    /// What was left out of stdout to fit the response
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub truncated: Option<String>,
+    /// What was masked in the output because it looked like a secret
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub redacted: Option<String>,
+    /// Runs of the command, reported when it was retried
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub attempts: Option<u32>,
+    /// How old a result returned from the cache is
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cached: Option<String>,
+    /// The account and principal the command ran as
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub identity: Option<Identity>,
 }
 
 impl UseAws {
@@ -56,88 +154,119 @@ impl UseAws {
         !READONLY_OPS.iter().any(|op| self.operation_name.starts_with(op))
     }
 
-    pub async fn invoke(&self) -> Result<InvokeOutput> {
-        let mut command = tokio::process::Command::new("aws");
-
-        // Set up environment variables
-        let mut env_vars: std::collections::HashMap<String, String> = std::env::vars().collect();
+    pub fn risk_tier(&self) -> RiskTier {
+        if !self.requires_acceptance() {
+            RiskTier::ReadOnly
+        } else if self.operation_name == "rm" || DESTRUCTIVE_OPS.iter().any(|op| self.operation_name.starts_with(op)) {
+            RiskTier::Destructive
+        } else {
+            RiskTier::Write
+        }
+    }
 
-        // Set up additional metadata for the AWS CLI user agent
-        let user_agent_metadata_value = format!(
-            "{} {}/{}",
-            USER_AGENT_APP_NAME, USER_AGENT_VERSION_KEY, USER_AGENT_VERSION_VALUE
-        );
+    pub async fn invoke(&self) -> Result<InvokeOutput> {
+        self.invoke_with(&CliExecutor::default()).await
+    }
 
-        // If the user agent metadata env var already exists, append to it, otherwise set it
-        if let Some(existing_value) = env_vars.get(USER_AGENT_ENV_VAR) {
-            if !existing_value.is_empty() {
-                env_vars.insert(
-                    USER_AGENT_ENV_VAR.to_string(),
-                    format!("{} {}", existing_value, user_agent_metadata_value),
-                );
-            } else {
-                env_vars.insert(USER_AGENT_ENV_VAR.to_string(), user_agent_metadata_value);
+    /// Invokes the command through `executor`, retrying it as configured by `retry`. Secrets in
+    /// JSON output are masked before the `query` and the `output_format` are applied.
+    pub async fn invoke_with(&self, executor: &dyn AwsExecutor) -> Result<InvokeOutput> {
+        self.check_output_options()?;
+        let executor = Retrying::new(executor, &self.retry);
+        let mut output = executor.execute(self).await?;
+        let mut masked = Vec::new();
+        let structured = self.query.is_some() || self.output_format.is_some();
+        if output.exit_code == 0 && !output.stdout.trim().is_empty() {
+            match serde_json::from_str(&output.stdout) {
+                Ok(mut value) => {
+                    masked = self.redaction.redact_json(&mut value);
+                    if structured || !masked.is_empty() {
+                        output.stdout = self.present(&self.project(value)?);
+                    }
+                },
+                Err(_) if structured => eyre::bail!(
+                    "The output of '{} {}' is not JSON, so `query` and `output_format` can't be applied",
+                    self.service_name,
+                    self.operation_name
+                ),
+                Err(_) => (output.stdout, masked) = self.redaction.redact_text(&output.stdout, "stdout"),
             }
-        } else {
-            env_vars.insert(USER_AGENT_ENV_VAR.to_string(), user_agent_metadata_value);
         }
+        self.invoke_output(output, None, masked, executor.attempts())
+    }
 
-        command.envs(env_vars).arg("--region").arg(&self.region);
-        if let Some(profile_name) = self.profile_name.as_deref() {
-            command.arg("--profile").arg(profile_name);
+    /// Applies the `query` of the request, if any, to JSON output
+    pub(crate) fn project(&self, output: serde_json::Value) -> Result<serde_json::Value> {
+        match &self.query {
+            Some(query) => Ok(Expression::parse(query)?.search(&output)?),
+            None => Ok(output),
         }
-        command.arg(&self.service_name).arg(&self.operation_name);
-        if let Some(parameters) = self.cli_parameters() {
-            for (name, val) in parameters {
-                command.arg(name);
-                if !val.is_empty() {
-                    command.arg(val);
-                }
-            }
+    }
+
+    /// Renders JSON output in the requested `output_format`, as JSON if none was requested
+    pub(crate) fn present(&self, output: &serde_json::Value) -> String {
+        match self.output_format {
+            Some(format) => format.render(output),
+            None => cli_json(output),
         }
-        let output = command
-            .stdout(Stdio::piped())
-            .stderr(Stdio::piped())
-            .spawn()
-            .wrap_err_with(|| format!("Unable to spawn command '{:?}'", self))?
-            .wait_with_output()
-            .await
-            .wrap_err_with(|| format!("Unable to spawn command '{:?}'", self))?;
-        let status = output.status.code().unwrap_or(0).to_string();
-        let stdout = output.stdout.to_str_lossy();
-        let stderr = output.stderr.to_str_lossy();
-
-        let stdout = format!(
-            "{}{}",
-            &stdout[0..stdout.len().min(MAX_TOOL_RESPONSE_SIZE / 3)],
-            if stdout.len() > MAX_TOOL_RESPONSE_SIZE / 3 {
-                " ... truncated"
-            } else {
-                ""
-            }
-        );
+    }
 
-        let stderr = format!(
-            "{}{}",
-            &stderr[0..stderr.len().min(MAX_TOOL_RESPONSE_SIZE / 3)],
-            if stderr.len() > MAX_TOOL_RESPONSE_SIZE / 3 {
-                " ... truncated"
-            } else {
-                ""
-            }
-        );
+    /// Invokes the command one page at a time and returns the first `max_items` items, or
+    /// the number the request asks for, with a cursor for the rest. See [pagination::paginate].
+    pub async fn invoke_paginated(
+        &self,
+        executor: &dyn AwsExecutor,
+        paginator: &Paginator,
+        max_items: usize,
+    ) -> Result<InvokeOutput> {
+        self.check_output_options()?;
+        let executor = Retrying::new(executor, &self.retry);
+        let page = pagination::paginate(self, paginator, &executor, max_items).await?;
+        self.invoke_output(page.output, page.next_cursor, page.masked, executor.attempts())
+    }
 
-        if status.eq("0") {
-            Ok(InvokeOutput {
-                output: OutputKind::Json(serde_json::json!({
-                    "exit_status": status,
-                    "stdout": stdout,
-                    "stderr": stderr.clone()
-                })),
-            })
-        } else {
-            Err(eyre::eyre!(stderr))
+    /// Builds the tool output of a command, failing with an [AwsError] parsed from its stderr
+    /// if it exited with an error. Secrets in stderr are masked, and output over budget is truncated, see
+    /// [truncate::truncate]. `attempts` is reported when the command was retried.
+    fn invoke_output(
+        &self,
+        mut output: CommandOutput,
+        next_cursor: Option<String>,
+        mut masked: Vec<Masked>,
+        attempts: u32,
+    ) -> Result<InvokeOutput> {
+        let (stderr, stderr_masked) = self.redaction.redact_text(&output.stderr, "stderr");
+        output.stderr = stderr;
+        masked.extend(stderr_masked);
+        let stdout = truncate::truncate(&output.stdout, OUTPUT_BUDGET);
+        let stderr = truncate::truncate(&output.stderr, OUTPUT_BUDGET);
+        if output.exit_code != 0 {
+            let mut error = AwsError::parse(&output.stderr, output.exit_code).with_hint(self);
+            error.stderr = stderr.text;
+            error.attempts = (attempts > 1).then_some(attempts);
+            return Err(McpError::AwsCli(Box::new(error)).into());
+        }
+
+        let mut json = serde_json::json!({
+            "exit_status": output.exit_code.to_string(),
+            "stdout": stdout.text,
+            "stderr": stderr.text
+        });
+        if let Some(next_cursor) = next_cursor {
+            json["next_cursor"] = next_cursor.into();
+        }
+        if let Some(note) = stdout.note {
+            json["truncated"] = note.into();
+        }
+        if let Some(note) = redact::note(&masked) {
+            json["redacted"] = note.into();
         }
+        if attempts > 1 {
+            json["attempts"] = attempts.into();
+        }
+        Ok(InvokeOutput {
+            output: OutputKind::Json(json),
+        })
     }
 
     pub fn queue_description(&self, updates: &mut impl Write) -> Result<()> {
@@ -147,6 +276,9 @@ impl UseAws {
             style::Print(format!("Service name: {}\n", self.service_name)),
             style::Print(format!("Operation name: {}\n", self.operation_name)),
         )?;
+        if let Some(positional_args) = self.positional_args.as_ref().filter(|args| !args.is_empty()) {
+            queue!(updates, style::Print(format!("Positional arguments: {}\n", positional_args.join(" "))))?;
+        }
         if let Some(parameters) = &self.parameters {
             queue!(updates, style::Print("Parameters: \n".to_string()))?;
             for (name, value) in parameters {
@@ -161,14 +293,40 @@ impl UseAws {
             }
         }
 
+        if let Some(query) = &self.query {
+            queue!(updates, style::Print(format!("Query: {}\n", query)))?;
+        }
+
+        if let Some(format) = self.output_format.filter(|format| *format != OutputFormat::Json) {
+            queue!(updates, style::Print(format!("Output format: {}\n", format.name())))?;
+        }
+
+        if self.cache == Some(CacheMode::Bypass) {
+            queue!(updates, style::Print("Cache: bypass\n"))?;
+        }
+
         if let Some(ref profile_name) = self.profile_name {
             queue!(updates, style::Print(format!("Profile name: {}\n", profile_name)))?;
         } else {
             queue!(updates, style::Print("Profile name: default\n".to_string()))?;
         }
 
+        if let Some(identity) = &self.identity {
+            queue!(
+                updates,
+                style::Print(format!("Account: {}\n", identity)),
+                style::Print(format!("Principal: {}\n", identity.arn)),
+            )?;
+        }
+
         queue!(updates, style::Print(format!("Region: {}", self.region)))?;
 
+        match (&self.endpoint, &self.endpoint_url) {
+            (Some(endpoint), Some(url)) => queue!(updates, style::Print(format!("\nEndpoint: {} ({})", endpoint, url)))?,
+            (Some(endpoint), None) => queue!(updates, style::Print(format!("\nEndpoint: {}", endpoint)))?,
+            _ => {},
+        }
+
         if let Some(ref label) = self.label {
             queue!(updates, style::Print(format!("\nLabel: {}", label)))?;
         }
@@ -176,24 +334,215 @@ impl UseAws {
     }
 
     pub async fn validate(&mut self) -> Result<()> {
+        let positional_args = self.positional_args.as_deref().unwrap_or_default();
+        customizations::classify(&self.service_name, &self.operation_name, positional_args)
+            .map_err(|e| eyre::eyre!(e))?;
+        self.check_output_options()?;
+        if let Some(query) = &self.query {
+            Expression::parse(query)?;
+        }
         Ok(())
     }
 
+    /// Refuses the CLI's own `--query` and `--output` as parameters. They would change the
+    /// output before secrets are masked, e.g. leave a queried secret without its field name.
+    fn check_output_options(&self) -> Result<()> {
+        for name in self.parameters.iter().flat_map(|parameters| parameters.keys()) {
+            match cli_option_name(name).as_str() {
+                "query" => eyre::bail!("Parameter '{}' can't be set directly; use the `query` field", name),
+                "output" => eyre::bail!("Parameter '{}' can't be set directly; use the `output_format` field", name),
+                _ => {},
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the positional arguments that refer to local files or directories
+    pub fn local_positional_paths(&self) -> Vec<&str> {
+        let positional_args = self.positional_args.as_deref().unwrap_or_default();
+        customizations::classify(&self.service_name, &self.operation_name, positional_args)
+            .unwrap_or_default()
+            .into_iter()
+            .filter(|(kind, arg)| *kind == customizations::PositionalKind::LocalPath && *arg != "-")
+            .map(|(_, arg)| arg)
+            .collect()
+    }
+
+    /// Returns the arguments passed to the `aws` executable. Parameters keep the order the
+    /// client sent them in.
+    pub fn cli_args(&self, model: Option<&ServiceModel>) -> Vec<String> {
+        self.args_with(self.cli_parameters(model).unwrap_or_default())
+    }
+
+    /// Renders the full command line with parameters sorted by option name and shell
+    /// quoting applied, so equivalent requests render identically. Suitable for logs and as
+    /// a cache key.
+    pub fn canonical_command(&self, model: Option<&ServiceModel>) -> String {
+        let mut parameters = self.cli_parameters(model).unwrap_or_default();
+        parameters.sort_by(|a, b| a.0.cmp(&b.0));
+        let args = self.args_with(parameters);
+        std::iter::once("aws")
+            .chain(args.iter().map(|arg| arg.as_str()))
+            .map(shell_quote)
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+
+    fn args_with(&self, parameters: Vec<(String, Vec<String>)>) -> Vec<String> {
+        let mut args = vec!["--region".to_string(), self.region.clone()];
+        if let Some(profile_name) = &self.profile_name {
+            args.extend(["--profile".to_string(), profile_name.clone()]);
+        }
+        if let Some(endpoint_url) = &self.endpoint_url {
+            args.extend(["--endpoint-url".to_string(), endpoint_url.clone()]);
+        }
+        args.extend([self.service_name.clone(), self.operation_name.clone()]);
+        args.extend(self.positional_args.iter().flatten().cloned());
+        for (name, values) in parameters {
+            args.push(name);
+            args.extend(values);
+        }
+        if self.single_page {
+            args.push("--no-paginate".to_string());
+        }
+        // Queries, pagination and output formats work on JSON, whatever output format the
+        // profile configures
+        if self.single_page || self.query.is_some() || self.output_format.is_some() {
+            args.extend(["--output".to_string(), "json".to_string()]);
+        }
+        args
+    }
+
     /// Returns the CLI arguments properly formatted as kebab case if parameters is
-    /// [Option::Some], otherwise None
-    fn cli_parameters(&self) -> Option<Vec<(String, String)>> {
-        if let Some(parameters) = &self.parameters {
-            let mut params = vec![];
-            for (param_name, val) in parameters {
-                let param_name = format!("--{}", param_name.trim_start_matches("--").to_case(Case::Kebab));
-                let param_val = val.as_str().map(|s| s.to_string()).unwrap_or(val.to_string());
-                params.push((param_name, param_val));
+    /// [Option::Some], otherwise None. Values are converted according to their type in the
+    /// service model when one is available:
+    /// - `true` becomes a bare flag, `false` its `--no-` form if the model has the option as a
+    ///   boolean, and is omitted otherwise
+    /// - lists of scalars become multiple values, empty lists are omitted
+    /// - maps of scalars use shorthand syntax, other objects and nested lists are passed as JSON
+    fn cli_parameters(&self, model: Option<&ServiceModel>) -> Option<Vec<(String, Vec<String>)>> {
+        let parameters = self.parameters.as_ref()?;
+        let mut params = vec![];
+        for (param_name, val) in parameters {
+            let name = cli_option_name(param_name);
+            let shape = model.and_then(|model| self.parameter_shape(model, param_name));
+            let is_boolean = shape.map(|shape| shape.shape_type == "boolean");
+            match (val, is_boolean) {
+                (serde_json::Value::Null, _) => {},
+                // An option without values would take the next argument as its value
+                (serde_json::Value::Array(items), _) if items.is_empty() => {},
+                (serde_json::Value::Bool(flag), _) => {
+                    if let Some(param) = boolean_parameter(&name, *flag, is_boolean) {
+                        params.push(param);
+                    }
+                },
+                (serde_json::Value::String(s), Some(true)) if s == "true" || s == "false" => {
+                    if let Some(param) = boolean_parameter(&name, s == "true", Some(true)) {
+                        params.push(param);
+                    }
+                },
+                (serde_json::Value::String(s), _) if s.is_empty() => params.push((format!("--{}", name), vec![])),
+                (val, _) => params.push((format!("--{}", name), cli_values(val, shape, model))),
             }
-            Some(params)
-        } else {
-            None
         }
+        Some(params)
+    }
+
+    /// Returns the shape of the parameter `param_name` in the service model, if it has one
+    pub(crate) fn parameter_shape<'a>(&self, model: &'a ServiceModel, param_name: &str) -> Option<&'a Shape> {
+        let operation = model.operation(&self.operation_name)?;
+        let (_, member) = model.input_member(operation, &cli_option_name(param_name))?;
+        model.shape(&member.shape)
+    }
+}
+
+/// Maps a boolean parameter to `--name` or `--no-name`. The negated form exists for every
+/// boolean in the service models, but not for flags of CLI customizations like `s3 cp` or
+/// options whose shape is unknown, so `false` is omitted unless the model says the option is
+/// a boolean.
+fn boolean_parameter(name: &str, flag: bool, is_boolean: Option<bool>) -> Option<(String, Vec<String>)> {
+    match (flag, is_boolean) {
+        (true, _) => Some((format!("--{}", name), vec![])),
+        (false, Some(true)) => Some((format!("--no-{}", name), vec![])),
+        (false, _) => None,
+    }
+}
+
+/// Quotes an argument for a POSIX shell if it contains anything but safe characters
+fn shell_quote(arg: &str) -> String {
+    let is_safe = |c: char| c.is_ascii_alphanumeric() || "-_./:=,@+%".contains(c);
+    if !arg.is_empty() && arg.chars().all(is_safe) {
+        arg.to_string()
+    } else {
+        format!("'{}'", arg.replace('\'', r"'\''"))
+    }
+}
+
+/// Formats a parameter name as a CLI option name without the leading dashes
+pub(crate) fn cli_option_name(param_name: &str) -> String {
+    let name = param_name.trim_start_matches("--");
+    if name.contains('_') {
+        name.to_case(Case::Kebab)
+    } else {
+        xform_name(name, '-')
+    }
+}
+
+/// Converts a non-boolean parameter value into one or more CLI argument values
+pub(crate) fn cli_values(val: &serde_json::Value, shape: Option<&Shape>, model: Option<&ServiceModel>) -> Vec<String> {
+    let member_shape = |shape_ref: Option<&crate::service_model::ShapeRef>| {
+        model.zip(shape_ref).and_then(|(model, shape_ref)| model.shape(&shape_ref.shape))
+    };
+    match val {
+        serde_json::Value::Array(items) => {
+            let scalar_members = match shape.filter(|shape| shape.shape_type == "list") {
+                Some(list) => member_shape(list.member.as_ref()).is_none_or(is_scalar_shape),
+                None => items.iter().all(is_scalar_value),
+            };
+            if scalar_members && items.iter().all(is_scalar_value) {
+                items.iter().map(scalar_to_string).collect()
+            } else {
+                vec![val.to_string()]
+            }
+        },
+        serde_json::Value::Object(entries) => {
+            let scalar_map = shape.is_some_and(|shape| {
+                shape.shape_type == "map" && member_shape(shape.value.as_ref()).is_none_or(is_scalar_shape)
+            });
+            match shorthand(entries).filter(|_| scalar_map) {
+                Some(shorthand) => vec![shorthand],
+                None => vec![val.to_string()],
+            }
+        },
+        val => vec![scalar_to_string(val)],
+    }
+}
+
+/// Renders a map of scalars in the CLI shorthand syntax (`k1=v1,k2=v2`), if no key or value
+/// contains characters that would need escaping
+fn shorthand(entries: &serde_json::Map<String, serde_json::Value>) -> Option<String> {
+    let is_plain = |s: &str| !s.is_empty() && !s.contains([',', '=', '[', ']', '{', '}', '"', '\'', ' ']);
+    let mut pairs = Vec::with_capacity(entries.len());
+    for (key, value) in entries {
+        let value = is_scalar_value(value).then(|| scalar_to_string(value))?;
+        if !is_plain(key) || !is_plain(&value) {
+            return None;
+        }
+        pairs.push(format!("{}={}", key, value));
     }
+    (!pairs.is_empty()).then(|| pairs.join(","))
+}
+
+fn is_scalar_shape(shape: &Shape) -> bool {
+    !matches!(shape.shape_type.as_str(), "structure" | "list" | "map")
+}
+
+fn is_scalar_value(val: &serde_json::Value) -> bool {
+    !matches!(val, serde_json::Value::Array(_) | serde_json::Value::Object(_) | serde_json::Value::Null)
+}
+
+fn scalar_to_string(val: &serde_json::Value) -> String {
+    val.as_str().map(|s| s.to_string()).unwrap_or(val.to_string())
 }
 
 impl From<UseAwsRequest> for UseAws {
@@ -202,9 +551,21 @@ impl From<UseAwsRequest> for UseAws {
             service_name: request.service_name,
             operation_name: request.operation_name,
             parameters: request.parameters,
+            positional_args: request.positional_args,
             region: request.region,
             profile_name: request.profile_name,
             label: request.label,
+            endpoint: request.endpoint,
+            endpoint_url: None,
+            max_items: request.max_items,
+            cursor: request.cursor,
+            query: request.query,
+            output_format: request.output_format,
+            cache: request.cache,
+            single_page: false,
+            redaction: Redaction::default(),
+            retry: RetryConfig::default(),
+            identity: None,
         }
     }
 }
@@ -216,16 +577,32 @@ impl From<InvokeOutput> for UseAwsResponse {
                 let exit_status = json.get("exit_status").and_then(|v| v.as_str()).unwrap_or("0").to_string();
                 let stdout = json.get("stdout").and_then(|v| v.as_str()).unwrap_or("").to_string();
                 let stderr = json.get("stderr").and_then(|v| v.as_str()).unwrap_or("").to_string();
+                let next_cursor = json.get("next_cursor").and_then(|v| v.as_str()).map(|v| v.to_string());
+                let truncated = json.get("truncated").and_then(|v| v.as_str()).map(|v| v.to_string());
+                let redacted = json.get("redacted").and_then(|v| v.as_str()).map(|v| v.to_string());
+                let attempts = json.get("attempts").and_then(|v| v.as_u64()).map(|v| v as u32);
                 Self {
                     exit_status,
                     stdout,
                     stderr,
+                    next_cursor,
+                    truncated,
+                    redacted,
+                    attempts,
+                    cached: None,
+                    identity: None,
                 }
             }
             OutputKind::Text(text) => Self {
                 exit_status: "0".to_string(),
                 stdout: text,
                 stderr: "".to_string(),
+                next_cursor: None,
+                truncated: None,
+                redacted: None,
+                attempts: None,
+                cached: None,
+                identity: None,
             },
         }
     }
@@ -267,6 +644,16 @@ mod tests {
             "label": ""
         }};
         assert!(cmd.requires_acceptance());
+        assert_eq!(cmd.risk_tier(), RiskTier::Write);
+
+        let tier = |service_name: &str, operation_name: &str| {
+            use_aws! {{"service_name": service_name, "operation_name": operation_name, "region": "us-west-2"}}.risk_tier()
+        };
+        assert_eq!(tier("ec2", "describe-instances"), RiskTier::ReadOnly);
+        assert_eq!(tier("ec2", "terminate-instances"), RiskTier::Destructive);
+        assert_eq!(tier("s3", "rm"), RiskTier::Destructive);
+        assert_eq!(tier("s3", "rb"), RiskTier::Destructive);
+        assert_eq!(tier("s3", "cp"), RiskTier::Write);
     }
 
     #[test]
@@ -282,21 +669,146 @@ mod tests {
             "profile_name": "default",
             "label": ""
         }};
-        let params = cmd.cli_parameters().unwrap();
+        let params = cmd.cli_parameters(None).unwrap();
         assert!(
-            params.iter().any(|p| p.0 == "--table-name" && p.1 == "table-name"),
+            params.iter().any(|p| p.0 == "--table-name" && p.1 == ["table-name"]),
             "not found in {:?}",
             params
         );
         assert!(
             params
                 .iter()
-                .any(|p| p.0 == "--key-condition-expression" && p.1 == "PartitionKey = :pkValue"),
+                .any(|p| p.0 == "--key-condition-expression" && p.1 == ["PartitionKey = :pkValue"]),
             "not found in {:?}",
             params
         );
     }
 
+    #[test]
+    fn test_cli_parameters_without_model() {
+        let cmd = use_aws! {{
+            "service_name": "ec2",
+            "operation_name": "describe-instances",
+            "parameters": {
+                "dry-run": true,
+                "include-all-instances": false,
+                "instance-ids": ["i-1", "i-2"],
+                "max-results": 5,
+                "filters": [{"Name": "tag:Env", "Values": ["prod"]}],
+                "owners": [],
+                "next-token": null
+            },
+            "region": "us-west-2"
+        }};
+        let mut params = cmd.cli_parameters(None).unwrap();
+        params.sort();
+        assert_eq!(params, vec![
+            ("--dry-run".to_string(), vec![]),
+            ("--filters".to_string(), vec![r#"[{"Name":"tag:Env","Values":["prod"]}]"#.to_string()]),
+            ("--instance-ids".to_string(), vec!["i-1".to_string(), "i-2".to_string()]),
+            ("--max-results".to_string(), vec!["5".to_string()]),
+        ]);
+
+        let cmd = use_aws! {{
+            "service_name": "s3",
+            "operation_name": "cp",
+            "parameters": {"recursive": true, "dryrun": false},
+            "region": "us-west-2"
+        }};
+        assert_eq!(cmd.cli_parameters(None).unwrap(), vec![("--recursive".to_string(), vec![])]);
+    }
+
+    #[test]
+    fn test_cli_parameters_with_model() {
+        let model: ServiceModel = serde_json::from_str(crate::service_model::tests::EC2_MODEL).unwrap();
+        let cmd = use_aws! {{
+            "service_name": "ec2",
+            "operation_name": "describe-instances",
+            "parameters": {
+                "DryRun": "false",
+                "InstanceIds": ["i-1"],
+                "Filters": [{"Name": "instance-state-name", "Values": ["running"]}]
+            },
+            "region": "us-west-2"
+        }};
+        let mut params = cmd.cli_parameters(Some(&model)).unwrap();
+        params.sort();
+        assert_eq!(params, vec![
+            ("--filters".to_string(), vec![r#"[{"Name":"instance-state-name","Values":["running"]}]"#.to_string()]),
+            ("--instance-ids".to_string(), vec!["i-1".to_string()]),
+            ("--no-dry-run".to_string(), vec![]),
+        ]);
+    }
+
+    #[test]
+    fn test_parameter_order() {
+        let cmd = use_aws! {{
+            "service_name": "dynamodb",
+            "operation_name": "query",
+            "parameters": {
+                "table-name": "orders",
+                "key-condition-expression": "pk = :pk",
+                "expression-attribute-values": "{\":pk\": {\"S\": \"o'1\"}}",
+                "consistent-read": true
+            },
+            "region": "us-west-2",
+            "profile_name": "dev"
+        }};
+        assert_eq!(cmd.cli_args(None), vec![
+            "--region",
+            "us-west-2",
+            "--profile",
+            "dev",
+            "dynamodb",
+            "query",
+            "--table-name",
+            "orders",
+            "--key-condition-expression",
+            "pk = :pk",
+            "--expression-attribute-values",
+            r#"{":pk": {"S": "o'1"}}"#,
+            "--consistent-read",
+        ]);
+        assert_eq!(
+            cmd.canonical_command(None),
+            r#"aws --region us-west-2 --profile dev dynamodb query --consistent-read --expression-attribute-values '{":pk": {"S": "o'\''1"}}' --key-condition-expression 'pk = :pk' --table-name orders"#
+        );
+
+        let reordered = use_aws! {{
+            "service_name": "dynamodb",
+            "operation_name": "query",
+            "parameters": {
+                "consistent-read": true,
+                "key-condition-expression": "pk = :pk",
+                "table-name": "orders",
+                "expression-attribute-values": "{\":pk\": {\"S\": \"o'1\"}}"
+            },
+            "region": "us-west-2",
+            "profile_name": "dev"
+        }};
+        assert_eq!(reordered.canonical_command(None), cmd.canonical_command(None));
+    }
+
+    #[test]
+    fn test_cli_values_map_shorthand() {
+        let map = Shape {
+            shape_type: "map".to_string(),
+            members: Default::default(),
+            member: None,
+            key: None,
+            value: None,
+            required: vec![],
+            flattened: false,
+            payload: None,
+        };
+        let tags = serde_json::json!({"Env": "prod", "Team": "core"});
+        assert_eq!(cli_values(&tags, Some(&map), None), vec!["Env=prod,Team=core"]);
+        let tags = serde_json::json!({"Name": "a,b"});
+        assert_eq!(cli_values(&tags, Some(&map), None), vec![r#"{"Name":"a,b"}"#]);
+        let structure = serde_json::json!({"Enabled": true});
+        assert_eq!(cli_values(&structure, None, None), vec![r#"{"Enabled":true}"#]);
+    }
+
     #[test]
     fn test_queue_description() {
         let cmd = use_aws! {{
@@ -326,6 +838,30 @@ mod tests {
         assert!(output_str.contains("Label: List S3 buckets"));
     }
 
+    #[tokio::test]
+    async fn test_validate_positional_args() {
+        let mut cmd = use_aws! {{
+            "service_name": "s3",
+            "operation_name": "sync",
+            "positional_args": ["./site", "s3://bucket/site/"],
+            "region": "us-west-2"
+        }};
+        assert!(cmd.validate().await.is_ok());
+        assert_eq!(cmd.local_positional_paths(), vec!["./site"]);
+
+        let mut output = Vec::new();
+        cmd.queue_description(&mut output).unwrap();
+        assert!(String::from_utf8(output).unwrap().contains("Positional arguments: ./site s3://bucket/site/"));
+
+        let mut cmd = use_aws! {{
+            "service_name": "ec2",
+            "operation_name": "describe-instances",
+            "positional_args": ["i-1"],
+            "region": "us-west-2"
+        }};
+        assert!(cmd.validate().await.is_err());
+    }
+
     #[test]
     fn test_queue_description_empty_parameters() {
         let cmd = use_aws! {{
@@ -346,40 +882,180 @@ mod tests {
         assert!(!output_str.contains("Parameters:"));
     }
 
+    #[cfg(unix)]
     #[tokio::test]
     async fn test_environment_variables_passed_through() {
-        // Print current environment variables for debugging
-        println!("Current environment variables:");
-        for (key, value) in std::env::vars() {
-            if key.contains("AWS") {
-                println!("  {}: {}", key, value);
-            }
-        }
-        println!();
+        let fake = crate::fake_aws::FakeAws::new();
+        fake.respond(crate::executor::CommandOutput::success(r#"{"Account": "123456789012"}"#));
 
-        // Test the use_aws tool with a simple AWS command
         let use_aws = UseAws {
             service_name: "sts".to_string(),
             operation_name: "get-caller-identity".to_string(),
             parameters: None,
+            positional_args: None,
             region: "us-east-1".to_string(),
             profile_name: None, // This should use AWS_PROFILE from environment
             label: Some("Test AWS credentials".to_string()),
+            endpoint: None,
+            endpoint_url: None,
+            max_items: None,
+            cursor: None,
+            query: None,
+            output_format: None,
+            cache: None,
+            single_page: false,
+            redaction: Redaction::default(),
+            retry: RetryConfig::default(),
+            identity: None,
         };
+        let output = use_aws.invoke_with(&fake.executor()).await.unwrap();
+        assert!(output.as_json().unwrap()["stdout"].as_str().unwrap().contains("123456789012"));
 
-        println!("Testing AWS credentials with use_aws tool...");
-        match use_aws.invoke().await {
-            Ok(output) => {
-                println!("Success! Output: {:?}", output);
-                // If we get here, it means the environment variables were passed through correctly
-                assert!(true, "Environment variables were passed through successfully");
-            }
-            Err(e) => {
-                println!("Error: {}", e);
-                // This test will fail if credentials are not found, which indicates
-                // that environment variables are not being passed through correctly
-                panic!("Failed to invoke AWS command: {}", e);
+        let invocations = fake.invocations();
+        assert_eq!(invocations.len(), 1);
+        let env = &invocations[0].env;
+        for (key, value) in std::env::vars() {
+            if key.starts_with("AWS_") && key != "AWS_EXECUTION_ENV" || key == "HOME" {
+                assert_eq!(env.get(&key), Some(&value), "{} was not passed through", key);
             }
         }
+        assert!(env["AWS_EXECUTION_ENV"].ends_with(&format!("UseAws-MCP-Server Version/{}", env!("CARGO_PKG_VERSION"))));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_invoke_arguments() {
+        let fake = crate::fake_aws::FakeAws::new();
+        fake.respond_always(crate::executor::CommandOutput::success("{}"));
+
+        let cmd = use_aws! {{
+            "service_name": "s3",
+            "operation_name": "cp",
+            "positional_args": ["s3://bucket/key", "-"],
+            "parameters": {"only-show-errors": true, "exclude": ["*.tmp", "*.log"]},
+            "region": "eu-west-1",
+            "profile_name": "dev"
+        }};
+        cmd.invoke_with(&fake.executor()).await.unwrap();
+        assert_eq!(fake.invocations()[0].args, vec![
+            "--region",
+            "eu-west-1",
+            "--profile",
+            "dev",
+            "s3",
+            "cp",
+            "s3://bucket/key",
+            "-",
+            "--only-show-errors",
+            "--exclude",
+            "*.tmp",
+            "*.log",
+        ]);
+    }
+
+    #[tokio::test]
+    async fn test_invoke_truncates_output() {
+        // Three-byte characters put the old byte-index cut inside a character
+        let tags: Vec<serde_json::Value> = (0..5000).map(|i| serde_json::json!({"Key": "이름", "Value": format!("서버-{}", i)})).collect();
+        let executor = crate::executor::MockExecutor::new()
+            .respond(CommandOutput::success("가".repeat(crate::MAX_TOOL_RESPONSE_SIZE)))
+            .respond(CommandOutput::success(serde_json::json!({"Tags": tags}).to_string()));
+        let cmd = use_aws! {{
+            "service_name": "ec2",
+            "operation_name": "describe-tags",
+            "region": "ap-northeast-2"
+        }};
+
+        let output = cmd.invoke_with(&executor).await.unwrap();
+        let stdout = output.as_json().unwrap()["stdout"].as_str().unwrap().to_string();
+        assert!(stdout.len() <= OUTPUT_BUDGET);
+        assert!(stdout.ends_with(" ... truncated"));
+
+        let response = UseAwsResponse::from(cmd.invoke_with(&executor).await.unwrap());
+        let value: serde_json::Value = serde_json::from_str(&response.stdout).unwrap();
+        let kept = value["Tags"].as_array().unwrap().len();
+        assert!(kept > 0 && kept < 5000);
+        assert_eq!(response.truncated.unwrap(), format!("Kept the first {} of 5000 elements of `Tags`", kept));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_invoke_failure() {
+        let fake = crate::fake_aws::FakeAws::new();
+        fake.respond(crate::executor::CommandOutput::failure(
+            254,
+            "An error occurred (AccessDenied) when calling the ListBuckets operation: Access Denied",
+        ));
+
+        let err = use_aws! {{
+            "service_name": "s3api",
+            "operation_name": "list-buckets",
+            "region": "us-west-2"
+        }}
+        .invoke_with(&fake.executor())
+        .await
+        .unwrap_err();
+        assert!(err.to_string().contains("AccessDenied"));
     }
-} 
\ No newline at end of file
+
+    #[tokio::test]
+    async fn test_invoke_with_query() {
+        let executor = crate::executor::MockExecutor::new()
+            .respond(CommandOutput::success(r#"{"Buckets": [{"Name": "logs"}, {"Name": "assets"}], "Owner": {"ID": "1"}}"#))
+            .respond(CommandOutput::success(r#"{"Buckets": [{"Name": "logs"}]}"#));
+        let mut cmd = use_aws! {{
+            "service_name": "s3api",
+            "operation_name": "list-buckets",
+            "region": "us-west-2",
+            "query": "sort(Buckets[].Name)"
+        }};
+        cmd.validate().await.unwrap();
+        assert!(cmd.canonical_command(None).ends_with("list-buckets --output json"));
+        let output = cmd.invoke_with(&executor).await.unwrap();
+        let stdout = output.as_json().unwrap()["stdout"].as_str().unwrap();
+        assert_eq!(serde_json::from_str::<serde_json::Value>(stdout).unwrap(), serde_json::json!(["assets", "logs"]));
+
+        cmd.query = Some("sum(Buckets)".to_string());
+        let err = cmd.invoke_with(&executor).await.unwrap_err();
+        assert_eq!(err.to_string(), "sum() expected an array of numbers, got array containing object");
+
+        cmd.query = Some("Buckets[?Name == 'logs'".to_string());
+        let err = cmd.validate().await.unwrap_err();
+        assert!(err.to_string().starts_with("Invalid query at position 23"), "{}", err);
+    }
+
+    #[tokio::test]
+    async fn test_invoke_redacts_secrets() {
+        let secret = r#"{"ARN": "arn:aws:secretsmanager:us-west-2:123456789012:secret:prod/db", "SecretString": "{\"password\": \"hunter2\"}"}"#;
+        let executor = crate::executor::MockExecutor::new()
+            .respond(CommandOutput::success(secret))
+            .respond(CommandOutput::success(secret));
+        let mut cmd = use_aws! {{
+            "service_name": "secretsmanager",
+            "operation_name": "get-secret-value",
+            "parameters": {"secret-id": "prod/db"},
+            "region": "us-west-2",
+            "query": "SecretString"
+        }};
+        let output = cmd.invoke_with(&executor).await.unwrap();
+        let json = output.as_json().unwrap();
+        assert_eq!(json["stdout"], "\"<redacted>\"\n");
+        assert_eq!(json["redacted"], "Masked 1 value(s): `SecretString` (secret field)");
+
+        // The CLI's own query would return the bare secret with no field name to match
+        let mut cli_query = cmd.clone();
+        cli_query.query = None;
+        cli_query.parameters.as_mut().unwrap().insert("query".to_string(), "SecretString".into());
+        let err = cli_query.invoke_with(&executor).await.unwrap_err();
+        assert_eq!(err.to_string(), "Parameter 'query' can't be set directly; use the `query` field");
+        cli_query.parameters.as_mut().unwrap().shift_remove("query");
+        cli_query.parameters.as_mut().unwrap().insert("output".to_string(), "text".into());
+        assert!(cli_query.validate().await.is_err());
+
+        cmd.query = None;
+        cmd.redaction.enabled = false;
+        let output = cmd.invoke_with(&executor).await.unwrap();
+        assert!(output.as_json().unwrap()["stdout"].as_str().unwrap().contains("hunter2"));
+        assert!(output.as_json().unwrap().get("redacted").is_none());
+    }
+}